@@ -0,0 +1,290 @@
+//! The attributes of `esi:include` beyond its core `src`, `alt` and `onerror`, each parsed into a
+//! type of its own and looked up on an [`Include`](crate::Include) with
+//! [`Include::get`](crate::Include::get):
+//!
+//! ```
+//! use esi::attributes::AcceptStatus;
+//!
+//! let events = esi::parse_tags(
+//!     "esi",
+//!     &mut esi::Reader::from_str(r#"<esi:include src="/a" accept-status="404"/>"#),
+//!     &mut |event| {
+//!         if let esi::Event::ESI(esi::Tag::Include(include)) = event {
+//!             assert!(include.get::<AcceptStatus>().is_some_and(|codes| codes.0.contains(404)));
+//!         }
+//!         Ok(())
+//!     },
+//! );
+//! # events.unwrap();
+//! ```
+//!
+//! New attributes are added here without changing the shape of [`Include`](crate::Include).
+
+use crate::StatusCodes;
+use fastly::http::{HeaderName, Method as HttpMethod};
+use std::any::Any;
+use std::fmt;
+use std::rc::Rc;
+
+/// An attribute of `esi:include` that's parsed into a value of its own type.
+pub trait FromAttribute: Clone + fmt::Debug + Sized + 'static {
+    /// The name of the attribute, e.g. `accept-status`.
+    const NAME: &'static str;
+
+    /// Parses the value of the attribute, with character references already resolved.
+    fn from_attribute(value: &str) -> Parsed<Self>;
+}
+
+/// The result of [`FromAttribute::from_attribute`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Parsed<T> {
+    /// The value is valid.
+    Valid(T),
+    /// The value isn't valid, but the attribute is used as `T` anyway, raising a
+    /// [`Warning::InvalidAttribute`](crate::Warning::InvalidAttribute).
+    Warn(T),
+    /// The value isn't valid, and the include fails to parse with
+    /// [`ExecutionError::InvalidAttributeValue`](crate::ExecutionError::InvalidAttributeValue).
+    Invalid,
+}
+
+/// `critical="true"`, which requires the include to succeed before any output is written when
+/// processing with [`Processor::process_with_validation`](crate::Processor::process_with_validation).
+/// Values other than `true` and `false` are warned about and treated as `false`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Critical(pub bool);
+
+impl FromAttribute for Critical {
+    const NAME: &'static str = "critical";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        flag(value).map(Self)
+    }
+}
+
+/// `accept-status`, the statuses other than 2xx that count as a success, e.g. `404,410`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AcceptStatus(pub StatusCodes);
+
+impl FromAttribute for AcceptStatus {
+    const NAME: &'static str = "accept-status";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        match value.parse() {
+            Ok(codes) => Parsed::Valid(Self(codes)),
+            Err(_) => Parsed::Invalid,
+        }
+    }
+}
+
+/// `forward-headers`, the space-separated, case-insensitive names of the headers of the original
+/// request to forward to the include's fragments. They override
+/// [`Configuration::header_forwarding`](crate::Configuration::header_forwarding) as set by
+/// [`Configuration::forward_headers_override`](crate::Configuration::forward_headers_override);
+/// an empty list forwards none.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardHeaders(pub Vec<HeaderName>);
+
+impl FromAttribute for ForwardHeaders {
+    const NAME: &'static str = "forward-headers";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        match value
+            .split_ascii_whitespace()
+            .map(HeaderName::try_from)
+            .collect()
+        {
+            Ok(names) => Parsed::Valid(Self(names)),
+            Err(_) => Parsed::Invalid,
+        }
+    }
+}
+
+/// `headers`, the comma-separated, case-insensitive names of the headers of the original request
+/// to forward to the include's fragments, e.g. `headers="cookie, accept-language"`. It's the
+/// comma-separated form of [`ForwardHeaders`], and forwards the same way; an include with both
+/// uses `forward-headers`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Headers(pub Vec<HeaderName>);
+
+impl FromAttribute for Headers {
+    const NAME: &'static str = "headers";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        match value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(HeaderName::try_from)
+            .collect()
+        {
+            Ok(names) => Parsed::Valid(Self(names)),
+            Err(_) => Parsed::Invalid,
+        }
+    }
+}
+
+/// `method`, the method of the include's fragment requests, and of its `alt`: `GET`, `POST` or
+/// `HEAD`, in any case. Without it, fragments are requested with the method of the original
+/// request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Method(pub HttpMethod);
+
+impl FromAttribute for Method {
+    const NAME: &'static str = "method";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "GET" => Parsed::Valid(Self(HttpMethod::GET)),
+            "POST" => Parsed::Valid(Self(HttpMethod::POST)),
+            "HEAD" => Parsed::Valid(Self(HttpMethod::HEAD)),
+            _ => Parsed::Invalid,
+        }
+    }
+}
+
+/// `no-store="true"`, which sends the include's fragment requests straight to the backend, with
+/// the cache override of [`Request::set_pass`](fastly::Request::set_pass), so that their responses
+/// are neither taken from the cache nor stored in it. Values other than `true` and `false` are
+/// warned about and treated as `false`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NoStore(pub bool);
+
+impl FromAttribute for NoStore {
+    const NAME: &'static str = "no-store";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        flag(value).map(Self)
+    }
+}
+
+/// `raw="true"`, which copies the fragment body byte for byte, exempt from
+/// [`Configuration::validate_fragment_utf8`](crate::Configuration::validate_fragment_utf8) and its
+/// charset transcoding. Size limits and fragment markers still apply. Values other than `true`
+/// and `false` are warned about and treated as `false`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Raw(pub bool);
+
+impl FromAttribute for Raw {
+    const NAME: &'static str = "raw";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        flag(value).map(Self)
+    }
+}
+
+/// `add-header`, headers to add to the include's fragment requests, and to its `alt`, as
+/// `Name: value` pairs, e.g. `add-header="X-Fragment-Auth: $(HTTP_COOKIE{auth})"`. Several
+/// headers are separated with `|`, or given in several `add-header` attributes. The values are
+/// evaluated like the include's URLs, when the include is reached, and a header whose name or
+/// evaluated value can't be sent fails processing with
+/// [`ExecutionError::InvalidRequestHeader`](crate::ExecutionError::InvalidRequestHeader). Holds the
+/// names and values as they're written.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddHeader(pub Vec<(String, String)>);
+
+impl FromAttribute for AddHeader {
+    const NAME: &'static str = "add-header";
+
+    fn from_attribute(value: &str) -> Parsed<Self> {
+        match split_header_list(value)
+            .map(|header| {
+                let (name, value) = header.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect()
+        {
+            Some(headers) => Parsed::Valid(Self(headers)),
+            None => Parsed::Invalid,
+        }
+    }
+}
+
+// Splits a list of headers at each `|`, except inside the parentheses of a variable or function
+// call, where it separates a default value, as in `$(HTTP_COOKIE{auth}|none)`.
+fn split_header_list(list: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0usize;
+    let mut quoted = false;
+    list.split(move |c: char| {
+        match c {
+            '\'' if depth > 0 => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        depth == 0 && c == '|'
+    })
+    .filter(|header| !header.trim().is_empty())
+}
+
+impl<T> Parsed<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Parsed<U> {
+        match self {
+            Self::Valid(value) => Parsed::Valid(f(value)),
+            Self::Warn(value) => Parsed::Warn(f(value)),
+            Self::Invalid => Parsed::Invalid,
+        }
+    }
+}
+
+// Parses `true` or `false`, taking anything else as `false` with a warning.
+fn flag(value: &str) -> Parsed<bool> {
+    match value {
+        "true" => Parsed::Valid(true),
+        "false" => Parsed::Valid(false),
+        _ => Parsed::Warn(false),
+    }
+}
+
+// A parsed attribute value, kept in an include without its type
+pub(crate) trait AttributeValue: fmt::Debug {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: FromAttribute> AttributeValue for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Parses an attribute into a value without its type.
+type AttributeParser = fn(&str) -> Parsed<Rc<dyn AttributeValue>>;
+
+fn parse_erased<T: FromAttribute>(value: &str) -> Parsed<Rc<dyn AttributeValue>> {
+    T::from_attribute(value).map(|value| Rc::new(value) as Rc<dyn AttributeValue>)
+}
+
+/// The attributes the parser knows, in the order they're checked and reported.
+pub(crate) const KNOWN_ATTRIBUTES: [(&str, AttributeParser); 8] = [
+    (Critical::NAME, parse_erased::<Critical>),
+    (AcceptStatus::NAME, parse_erased::<AcceptStatus>),
+    (ForwardHeaders::NAME, parse_erased::<ForwardHeaders>),
+    (Headers::NAME, parse_erased::<Headers>),
+    (Method::NAME, parse_erased::<Method>),
+    (NoStore::NAME, parse_erased::<NoStore>),
+    (Raw::NAME, parse_erased::<Raw>),
+    (AddHeader::NAME, parse_erased::<AddHeader>),
+];
+
+/// The attributes that may be given more than once, whose values are joined with `|`.
+pub(crate) const REPEATABLE_ATTRIBUTES: [&str; 1] = [AddHeader::NAME];
+
+/// The typed attributes of an include, by name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AttributeMap(Vec<(&'static str, Rc<dyn AttributeValue>)>);
+
+impl AttributeMap {
+    pub fn get<T: FromAttribute>(&self) -> Option<&T> {
+        self.0
+            .iter()
+            .find(|(name, _)| *name == T::NAME)
+            .and_then(|(_, value)| value.as_any().downcast_ref())
+    }
+
+    pub fn insert(&mut self, name: &'static str, value: Rc<dyn AttributeValue>) {
+        match self.0.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::ProcessorScratch;
+use std::cell::Cell;
+
+/// Keeps count of the content a document holds in memory while it waits on fragments: queued
+/// source content and the buffers of `esi:try` arms and `esi:group` blocks, at any depth. It's
+/// updated where those buffers grow and where they're written out, and keeps the peaks for
+/// [`crate::ProcessingReport::peak_buffered_bytes`] and
+/// [`crate::ProcessingReport::peak_queue_len`].
+#[derive(Debug, Default)]
+pub(crate) struct BufferAccountant {
+    // The bytes buffered right now
+    buffered: Cell<usize>,
+    peak_buffered: Cell<usize>,
+    peak_queue_len: Cell<usize>,
+}
+
+impl BufferAccountant {
+    pub fn grow(&self, bytes: usize) {
+        let buffered = self.buffered.get() + bytes;
+        self.buffered.set(buffered);
+        self.peak_buffered
+            .set(self.peak_buffered.get().max(buffered));
+    }
+
+    pub fn shrink(&self, bytes: usize) {
+        self.buffered.set(self.buffered.get().saturating_sub(bytes));
+    }
+
+    /// Gives back a buffer whose content has been written out, or dropped.
+    pub fn release(&self, scratch: &ProcessorScratch, buffer: Vec<u8>) {
+        self.shrink(buffer.len());
+        scratch.recycle(buffer);
+    }
+
+    /// Records the length of the queue of the document.
+    pub fn queue_len(&self, len: usize) {
+        self.peak_queue_len.set(self.peak_queue_len.get().max(len));
+    }
+
+    pub fn peak_buffered_bytes(&self) -> usize {
+        self.peak_buffered.get()
+    }
+
+    pub fn peak_queue_len(&self) -> usize {
+        self.peak_queue_len.get()
+    }
+}
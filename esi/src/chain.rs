@@ -0,0 +1,51 @@
+use crate::request::DeferredRequest;
+use crate::{ExecutionError, Result, INCLUDE_CHAIN_HEADER};
+use fastly::Request;
+
+/// The URLs of the documents a document was included from, ending with its own, for detecting
+/// include cycles when fragment bodies are processed as ESI documents themselves.
+///
+/// The chain of a document comes from the [`INCLUDE_CHAIN_HEADER`] of its original request,
+/// which is set on every fragment request, so that a processor run on the body of a fragment
+/// carries on from its parent. A document without it starts a new chain.
+#[derive(Debug)]
+pub(crate) struct IncludeChain {
+    urls: Vec<String>,
+    max_depth: usize,
+}
+
+impl IncludeChain {
+    pub fn new(original: &Request, max_depth: usize) -> Self {
+        let urls = match original.get_header_str(INCLUDE_CHAIN_HEADER) {
+            Some(chain) => chain.split_whitespace().map(str::to_string).collect(),
+            None => vec![original.get_url_str().to_string()],
+        };
+        Self { urls, max_depth }
+    }
+
+    /// Checks that a fragment request neither includes a document it was included from nor goes
+    /// deeper than the limit, then adds the chain it continues to it. Fragments are told apart by
+    /// their URL once it's been resolved and normalized, like the URL in a [`crate::FragmentKey`].
+    pub fn extend(&self, request: DeferredRequest) -> Result<DeferredRequest> {
+        let url = request.url();
+        // The content of a `data:` URI is never requested.
+        if url.scheme() == "data" {
+            return Ok(request);
+        }
+        let url = url.as_str();
+
+        let mut chain = self.urls.clone();
+        chain.push(url.to_string());
+        if self.urls.iter().any(|ancestor| ancestor == url) {
+            return Err(ExecutionError::IncludeCycle(chain));
+        }
+        if self.urls.len() > self.max_depth {
+            return Err(ExecutionError::IncludeTooDeep(
+                url.to_string(),
+                self.max_depth,
+            ));
+        }
+        let chain = chain.join(" ");
+        Ok(request.with_header(INCLUDE_CHAIN_HEADER, chain))
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Where the processor reads the time from, for [`crate::PhaseTimings`]. The system clock by
+/// default, or any function returning an [`Instant`], so that tests can control the time.
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// use std::cell::Cell;
+/// use std::time::{Duration, Instant};
+///
+/// // A clock that moves forward a millisecond every time it's read
+/// let start = Instant::now();
+/// let ticks = Cell::new(0);
+/// let clock = esi::Clock::new(move || {
+///     ticks.set(ticks.get() + 1);
+///     start + Duration::from_millis(ticks.get())
+/// });
+/// let config = esi::Configuration::default().with_clock(clock);
+/// ```
+#[derive(Clone, Default)]
+pub struct Clock(Option<Rc<dyn Fn() -> Instant>>);
+
+impl Clock {
+    pub fn new(now: impl Fn() -> Instant + 'static) -> Self {
+        Self(Some(Rc::new(now)))
+    }
+
+    /// The system clock, which is the default.
+    pub fn system() -> Self {
+        Self(None)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.0 {
+            Some(now) => now(),
+            None => Instant::now(),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.is_system() {
+            "Clock::system"
+        } else {
+            "Clock"
+        })
+    }
+}
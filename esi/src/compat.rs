@@ -0,0 +1,97 @@
+//! The `Processor::new(body, request, configuration)` and `Processor::execute` API of earlier
+//! releases, implemented on top of [`crate::Processor`] for applications that haven't moved off
+//! it yet. Enabled with the `compat` feature.
+//!
+//! It's deprecated from the start, and will be removed in a later release.
+//!
+//! # Migrating
+//!
+//! The document is given to the processing method rather than the constructor, and the
+//! dispatcher takes the fragment request alone:
+//!
+//! ```rust,ignore
+//! // Before
+//! let processor = esi::compat::Processor::new(body, Some(req), configuration);
+//! let output = processor.execute(Some(&|(req, index)| dispatch(req, index)), None)?;
+//!
+//! // After
+//! let processor = esi::Processor::new(Some(req), configuration);
+//! let mut writer = esi::Writer::new(Vec::new());
+//! processor.process_document(
+//!     esi::Reader::from_reader(body),
+//!     &mut writer,
+//!     Some(&|req| dispatch(req)),
+//!     None,
+//! )?;
+//! let output = fastly::Body::from(writer.into_inner());
+//! ```
+//!
+//! Dispatchers that used the index to tell fragments apart can count the requests they're
+//! given instead, since they're dispatched in the order of their includes in the document, or
+//! tell them apart by URL. To send the output to the client as it's produced, use
+//! [`crate::Processor::process_response`].
+
+#![allow(deprecated)]
+
+use crate::{Configuration, FragmentResponseProcessor, PendingFragmentContent, Result};
+use fastly::{Body, Request};
+use quick_xml::Writer;
+use std::cell::Cell;
+
+type LegacyDispatcher<'a> = dyn Fn((Request, usize)) -> Result<PendingFragmentContent> + 'a;
+
+/// An ESI processor for a single document, with the API of earlier releases. See the
+/// [module documentation](self) for how to move to [`crate::Processor`].
+#[deprecated(note = "use `esi::Processor::process_document` or `esi::Processor::process_response`")]
+pub struct Processor {
+    // The document to process
+    body: Body,
+    // The processor that does the work
+    processor: crate::Processor,
+}
+
+impl Processor {
+    /// Create a processor for the ESI document in `body`.
+    pub fn new(
+        body: impl Into<Body>,
+        original_request_metadata: Option<Request>,
+        configuration: Configuration,
+    ) -> Self {
+        Self {
+            body: body.into(),
+            processor: crate::Processor::new(original_request_metadata, configuration),
+        }
+    }
+
+    /// Process the document, returning the output once it's complete.
+    ///
+    /// `dispatch_fragment_request` is given each fragment request together with its index,
+    /// counting from 0 in the order requests are dispatched, which is the order of their includes
+    /// in the document. A request for an include's `alt` URL gets an index of its own, as does
+    /// any request retried. Errors are the same as those of
+    /// [`crate::Processor::process_document`].
+    pub fn execute(
+        self,
+        dispatch_fragment_request: Option<&LegacyDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<Body> {
+        let next_index = Cell::new(0);
+        let dispatch = |req: Request| {
+            let index = next_index.replace(next_index.get() + 1);
+            dispatch_fragment_request.expect("only called when a dispatcher was given")((
+                req, index,
+            ))
+        };
+
+        let mut writer = Writer::new(Vec::new());
+        let reader = self.processor.reader_with_config(self.body);
+        self.processor.process_document(
+            reader,
+            &mut writer,
+            dispatch_fragment_request.map(|_| &dispatch as _),
+            process_fragment_response,
+        )?;
+
+        Ok(Body::from(writer.into_inner()))
+    }
+}
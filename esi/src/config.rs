@@ -1,3 +1,10 @@
+use crate::expression::fnv1a;
+use crate::{BoundarySink, Clock, GeoResolver, KeySpec, SchemeHandler, SchemeTarget, StatusCodes};
+use fastly::http::header::{ACCEPT_LANGUAGE, COOKIE};
+use fastly::http::HeaderName;
+use std::fmt;
+use std::time::Duration;
+
 /// This struct is used to configure optional behaviour within the ESI processor.
 ///
 /// ## Usage Example
@@ -10,8 +17,413 @@
 pub struct Configuration {
     /// The XML namespace to use when scanning for ESI tags. Defaults to `esi`.
     pub namespace: String,
-    /// Disables URL unescaping, only good with non-HTML content, eg JSON
+    /// Whether include URLs are XML-escaped, so that entity references like `&amp;` in them are
+    /// unescaped, once, as they're parsed. Defaults to `true`; disable for non-HTML content, eg JSON.
     pub is_escaped: bool,
+    /// Rejects include URLs containing characters that need percent-encoding, instead of encoding them.
+    pub strict_urls: bool,
+    /// Whether ESI variables and functions in the `src`, `alt` and `srcset` URLs of includes are
+    /// evaluated. Defaults to `true`.
+    pub vars_in_attributes: bool,
+    /// Whether fragment requests for relative include URLs keep the original request's Host
+    /// header, instead of using the host of the URL. The port of their URL then follows that
+    /// header too. Defaults to `false`.
+    pub preserve_host: bool,
+    /// What to do with fragment URLs that use plain HTTP. Defaults to
+    /// [`InsecureFragmentPolicy::Allow`].
+    pub upgrade_insecure_fragments: InsecureFragmentPolicy,
+    /// Which headers of the original request are copied to fragment requests. Defaults to
+    /// [`HeaderForwarding::All`].
+    pub header_forwarding: HeaderForwarding,
+    /// How the `forward-headers` attribute of an include combines with
+    /// [`Self::header_forwarding`]. Defaults to [`ForwardHeadersOverride::Replace`].
+    pub forward_headers_override: ForwardHeadersOverride,
+    /// The handlers of fragment URLs with custom schemes, by lowercase scheme. Defaults to none.
+    pub scheme_handlers: Vec<(String, SchemeHandler)>,
+    /// Fragment response statuses other than 2xx that count as a success, for includes without an
+    /// `accept-status` attribute. Defaults to none.
+    pub accept_status: StatusCodes,
+    /// Statuses of successful fragment responses whose body is left out of the output. Defaults
+    /// to `204,304`.
+    pub empty_body_status: StatusCodes,
+    /// How the bodies of fragment responses are checked for valid UTF-8. Defaults to
+    /// [`Utf8Policy::Passthrough`].
+    pub validate_fragment_utf8: Utf8Policy,
+    /// The most bytes of the body of a failed fragment response kept in
+    /// [`crate::ExecutionError::FragmentFailed`]. Defaults to 256.
+    pub error_body_snippet: usize,
+    /// Whether [`crate::Processor::prefetch`] also fetches includes in `esi:except` arms. Defaults to `false`.
+    pub prefetch_except: bool,
+    /// The headers that take part in the [`crate::FragmentKey`] of fragment requests, on top of
+    /// the method and URL. Defaults to none.
+    pub fragment_key: KeySpec,
+    /// Whether identical fragment requests in a document are only dispatched once, sharing their
+    /// response. Defaults to `false`.
+    pub fragment_deduplication: bool,
+    /// Logs every parsed event at debug level, instead of periodic summaries. Defaults to `false`.
+    pub trace_events: bool,
+    /// Adds an `X-ESI-Debug` header summarizing the outcome of each include to the response
+    /// returned by [`crate::Processor::process_to_response`]. Defaults to `None`.
+    pub debug_header: Option<DebugHeader>,
+    /// Hashes the output of documents that are composed in full before they're sent, for an
+    /// `ETag`. Defaults to `false`.
+    pub compute_etag: bool,
+    /// Whether documents are only processed when their response asks for it with a
+    /// `Surrogate-Control` header. Defaults to `false`.
+    pub require_surrogate_control: bool,
+    /// The most warnings kept in [`crate::ProcessingReport::warnings`]. Defaults to 100.
+    pub max_warnings: usize,
+    /// The most bytes of the source document that are processed. Defaults to `None`, for no limit.
+    pub max_document_size: Option<usize>,
+    /// The most events of the source document that are parsed, counting those inside `esi:try`
+    /// arms and `esi:group` blocks. Defaults to `None`, for no limit.
+    pub max_events: Option<usize>,
+    /// The longest attribute value an `<esi:include>` tag can have, in bytes. Defaults to 16 KiB.
+    pub max_attribute_length: usize,
+    /// How many levels deep fragments can be included, when their bodies are processed as ESI
+    /// documents too. Defaults to `None`, for no include chain tracking.
+    pub max_include_depth: Option<usize>,
+    /// The most includes a document can have, counting each URL of a `srcset` but not `alt`
+    /// requests or retries. Defaults to `None`, for no limit.
+    pub max_fragments: Option<usize>,
+    /// The most bytes of a fragment body that are written. Defaults to `None`, for no limit.
+    pub max_fragment_size: Option<usize>,
+    /// What to do with source documents over [`Self::max_document_size`] or [`Self::max_events`],
+    /// or that are still being processed at the [`Self::deadline`]. Defaults to truncating them.
+    pub oversized_document: OversizedDocumentPolicy,
+    /// Whether the content of `<esi:comment>` blocks is processed like the rest of the document,
+    /// instead of being left out along with any ESI tags in it. Defaults to `false`.
+    pub process_in_comments: bool,
+    /// Whether the `text` attribute of `<esi:comment>` tags is evaluated and written as an HTML
+    /// comment, for debugging. Defaults to `false`.
+    pub debug_comments: bool,
+    /// Whether the composed output is checked for problems such as invalid UTF-8 or unprocessed
+    /// ESI tags as it's written, which are reported as [`crate::Warning::InvalidOutput`].
+    /// Defaults to `false`.
+    pub debug_validate_output: bool,
+    /// Whether deviations from the ESI 1.0 specification are reported. Defaults to
+    /// [`ComplianceLevel::Off`].
+    pub spec_compliance: ComplianceLevel,
+    /// Whether features that come after the client response has started fail processing,
+    /// instead of raising a [`crate::Warning::TooLate`]. Defaults to `false`.
+    pub strict_prestream: bool,
+    /// Whether tags in the ESI namespace that aren't supported, such as a misspelled
+    /// `esi:includ`, fail processing with [`crate::ExecutionError::UnknownEsiTag`], instead of
+    /// being left out of the output with a [`crate::Warning::UnknownTag`]. Defaults to `false`.
+    pub strict_mode: bool,
+    /// How content outside of ESI tags is written to the output. Defaults to
+    /// [`OutputMode::Writer`].
+    pub output_mode: OutputMode,
+    /// Whether fragments still pending when processing stops are polled once more, without
+    /// blocking, so that those that have completed are reported with their status. Defaults to
+    /// `false`.
+    pub poll_abandoned: bool,
+    /// Whether the includes skipped with `onerror="continue"` are retried once the rest of the
+    /// document has been written. Defaults to `None`, for no retries.
+    pub retry_pass: Option<RetryPass>,
+    /// How the content written for each include is marked in the output. Defaults to `None`, for
+    /// no markers.
+    pub fragment_markers: Option<MarkerStyle>,
+    /// How long processing a document may take before the output is cut short. Defaults to
+    /// `None`, for no limit.
+    pub deadline: Option<Duration>,
+    /// How long the response to each fragment request is waited for, from when it's dispatched,
+    /// before the include is handled as failed. Defaults to `None`, for no limit.
+    pub fragment_timeout: Option<Duration>,
+    /// Where the time is read from for [`crate::ProcessingReport::phases`] and
+    /// [`crate::ProcessingReport::callbacks`]. Defaults to the system clock.
+    pub clock: Clock,
+    /// Where the client's geolocation is looked up for the `$(GEO)` variable, once per document.
+    /// Defaults to the host's geolocation data.
+    pub geo_resolver: GeoResolver,
+    /// How long a single call of a callback can take before it raises a
+    /// [`crate::Warning::SlowCallback`]. Defaults to 50 milliseconds.
+    pub callback_overrun_threshold: Option<Duration>,
+    /// How parsing the document is interleaved with writing out the fragments that have
+    /// completed. Defaults to checking for them at every event, and writing out all of them.
+    pub scheduling: Scheduling,
+    /// How the markup of source documents read by [`crate::Processor::process_response`] and
+    /// [`crate::Processor::reader_with_config`] is parsed, and whether markup that can't be
+    /// parsed fails processing. Defaults to [`ReaderOptions::default`].
+    pub reader_options: ReaderOptions,
+}
+
+/// How fragment URLs that use plain HTTP are handled, whether they're written in the template or
+/// relative URLs resolved against an `http` original request. `data:` URIs are never affected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InsecureFragmentPolicy {
+    /// Fetch them over HTTP.
+    #[default]
+    Allow,
+    /// Fetch them over HTTPS instead.
+    Upgrade,
+    /// Don't fetch them, and fail with [`crate::ExecutionError::InsecureFragmentUrl`]. Like any
+    /// other failed include, this falls back to the `alt` URL or is skipped with
+    /// `onerror="continue"`.
+    Reject,
+}
+
+/// How source documents over [`Configuration::max_document_size`] or
+/// [`Configuration::max_events`] are handled, along with those still being processed at the
+/// [`Configuration::deadline`].
+///
+/// Either way, parsing stops at the first event that ends past the size limit, or once the
+/// event limit is found to have been passed. Content before it
+/// has already been streamed to the client. At the deadline, processing stops before the next
+/// element is written, or between two chunks of a fragment body, and the fragments that are still
+/// pending are dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OversizedDocumentPolicy {
+    /// Write out the content before the limit, once the fragments it includes have completed,
+    /// followed by `marker`. At the deadline, `marker` is written straight away. The truncation is recorded in
+    /// [`crate::ProcessingReport::truncated`].
+    Truncate { marker: String },
+    /// Fail with [`crate::ExecutionError::DocumentTooLarge`],
+    /// [`crate::ExecutionError::EventBudgetExceeded`] or
+    /// [`crate::ExecutionError::DeadlineExceeded`], without waiting for the fragments that are
+    /// still pending.
+    Fail,
+}
+
+impl Default for OversizedDocumentPolicy {
+    fn default() -> Self {
+        Self::Truncate {
+            marker: "<!-- esi: document truncated -->".to_string(),
+        }
+    }
+}
+
+/// Whether templates that rely on features outside the [ESI 1.0 specification](https://www.w3.org/TR/esi-lang/)
+/// are reported, for templates that also have to work with other ESI implementations.
+///
+/// Deviations are found where each feature is parsed or used, so only the parts of a template
+/// that are processed are checked. Each one is reported as a [`crate::Warning::SpecDeviation`]
+/// with a short code:
+///
+/// - `include-attribute`: an `esi:include` attribute other than `src`, `alt` and `onerror`, such
+///   as `srcset`, `critical`, `accept-status` or one in the ESI namespace
+/// - `include-body`: an `esi:include` that isn't self-closing, for its fallback content
+/// - `tag`: the `esi:group`, `esi:response` and `esi:retry` tags
+/// - `missing-except`: an `esi:try` without an `esi:except` arm
+/// - `multiple-attempts`: an `esi:try` with more than one `esi:attempt` arm
+/// - `attribute-template`: an attribute of another element set from variables with `esi:attr-`
+/// - `variable`: a variable other than `HTTP_ACCEPT_LANGUAGE`, `HTTP_COOKIE`, `HTTP_HOST`,
+///   `HTTP_REFERER`, `HTTP_USER_AGENT` and `QUERY_STRING`
+/// - `function`: a function call, such as `$bucket(...)`
+/// - `data-uri`: an include of a `data:` URI
+/// - `vars-attribute`: the `name` and `raw` attributes of a self-closing `esi:vars`
+/// - `test-operator`: the `has` operator in the `test` of an `esi:when`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ComplianceLevel {
+    /// Don't look for deviations.
+    #[default]
+    Off,
+    /// Report each deviation as a warning, and otherwise process the template as usual.
+    Warn,
+    /// Fail with [`crate::ExecutionError::SpecDeviation`] at the first deviation.
+    Enforce,
+}
+
+/// Which headers of the original request are copied to fragment requests. Whatever the policy,
+/// a fragment request always carries the `Host` header for its URL.
+///
+/// When headers are left out, the fragment request is given a header listing those that were
+/// copied, for the dispatcher. See [`crate::FORWARDED_HEADERS_HEADER`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HeaderForwarding {
+    /// Copy every header.
+    #[default]
+    All,
+    /// Copy no headers.
+    None,
+    /// Copy only the listed headers.
+    Allowlist(Vec<HeaderName>),
+}
+
+/// How the headers listed in the `forward-headers` attribute of an include, e.g.
+/// `forward-headers="authorization accept-language"`, combine with
+/// [`Configuration::header_forwarding`]. An empty `forward-headers=""` always forwards no headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForwardHeadersOverride {
+    /// Forward only the listed headers, whatever the configured policy.
+    #[default]
+    Replace,
+    /// Forward the listed headers as well as those the configured policy allows. With
+    /// [`HeaderForwarding::All`], every header is still forwarded.
+    Extend,
+}
+
+/// How the bodies of fragment responses are checked for valid UTF-8, for pages served as UTF-8
+/// that include fragments from backends that may not be.
+///
+/// Except with [`Self::Passthrough`], a fragment whose `Content-Type` declares the ISO-8859-1
+/// charset, e.g. `text/html; charset=iso-8859-1`, is transcoded to UTF-8 first. Other charsets
+/// aren't transcoded, and are checked like UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Copy fragment bodies as they are.
+    #[default]
+    Passthrough,
+    /// Replace each invalid sequence with U+FFFD, the replacement character.
+    Lossy,
+    /// Fail fragments with invalid sequences with [`crate::ExecutionError::FragmentBodyError`],
+    /// falling back to the `alt` URL or the rest of the include like any other failed fragment.
+    /// Bodies are checked in chunks of 8 KiB as they're copied, so when an invalid sequence comes
+    /// later than that, the start of the body may have been written already.
+    Reject,
+}
+
+/// An end-of-document pass that gives the includes skipped with `onerror="continue"` another
+/// chance, for fragments whose backends fail transiently. See [`Configuration::with_retry_pass`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPass {
+    /// The most includes that are retried, in the order they were skipped.
+    pub max_retries: usize,
+    /// How much of the [`Configuration::deadline`] has to remain for the pass to start.
+    pub min_remaining_budget: Duration,
+}
+
+/// How parsing the document is interleaved with writing out the fragments that completed in the
+/// meantime. See [`Configuration::with_scheduling`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scheduling {
+    /// How many events of the source document are parsed between two checks for completed
+    /// fragments, while fragments are pending. The queue is only checked before content is
+    /// written or queued, so a check can come later than that. Defaults to 1.
+    pub events_per_drain_check: usize,
+    /// The most fragments written out by a single check, where an `esi:try` or `esi:group` block
+    /// counts as one. Defaults to `None`, for all those that have completed.
+    pub max_drain_per_check: Option<usize>,
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Self {
+            events_per_drain_check: 1,
+            max_drain_per_check: None,
+        }
+    }
+}
+
+/// How the markup of source documents is parsed. See [`Configuration::with_reader_options`].
+///
+/// The defaults suit HTML, whose void elements like `<br>` and `<img>` have no closing tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReaderOptions {
+    /// Whether each closing tag has to match the name of the element it closes, so that an
+    /// unclosed `<br>` makes the `</p>` after it malformed. Defaults to `false`.
+    pub check_end_names: bool,
+    /// Whether the whitespace at the start and end of text is left out, along with text that's
+    /// only whitespace. Defaults to `false`.
+    pub trim_text: bool,
+    /// Whether self-closing elements are read as an opening tag followed by a closing tag, so
+    /// that `<x/>` is written as `<x></x>`. ESI tags are read that way too, so a self-closing
+    /// `<esi:vars name="..."/>` becomes an empty `esi:vars` block. Defaults to `false`.
+    pub expand_empty_elements: bool,
+    /// Whether markup that can't be parsed fails processing with
+    /// [`crate::ExecutionError::XMLError`], holding where it is in the document, instead of being
+    /// skipped with a [`crate::Warning::MalformedMarkup`]. Unlike the other options, this applies
+    /// to documents given to [`crate::Processor::process_document`] with a reader of their own
+    /// too. Defaults to `false`.
+    pub strict: bool,
+}
+
+impl ReaderOptions {
+    /// Options for documents that have to be well-formed XML: closing tags are checked, and
+    /// markup that can't be parsed fails processing.
+    pub fn strict() -> Self {
+        Self {
+            check_end_names: true,
+            strict: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// How the boundaries of the content written for each include are marked, for tools that
+/// post-process the output and need to know which parts of it came from which fragment. See
+/// [`Configuration::with_fragment_markers`].
+#[derive(Clone, Debug)]
+pub enum MarkerStyle {
+    /// Wrap the content in `<!--esi-frag start slot=N src=URL -->` and
+    /// `<!--esi-frag end slot=N -->` comments, with the URL redacted like in
+    /// [`crate::IncludeReport::url`].
+    HtmlComment,
+    /// Write nothing, and call the sink with each [`crate::FragmentBoundary`] instead, as the
+    /// output reaches it.
+    Callback(BoundarySink),
+}
+
+/// How content outside of ESI tags is written to the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Each event of the source document is written back out with [`quick_xml::Writer`], which
+    /// may normalize some of it, such as `<br />` becoming `<br/>`.
+    #[default]
+    Writer,
+    /// The bytes of the source document are copied to the output exactly as they were read.
+    /// Only the content in place of ESI tags is generated, along with the tags with attributes in
+    /// the ESI namespace, such as `esi:attr-` templates, and any content that isn't valid UTF-8.
+    RawPassthrough,
+}
+
+/// The level of detail of the `X-ESI-Debug` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugHeader {
+    /// Counts of the include outcomes, see [`crate::ProcessingReport::to_debug_header`].
+    Summary,
+    /// The counts followed by the outcome and redacted URL of every include, see
+    /// [`crate::ProcessingReport::to_verbose_debug_header`].
+    Verbose,
+}
+
+/// The effective options of a [`Configuration`], each written as a string, for logging the
+/// configuration a service is actually running with and comparing it across deployments.
+///
+/// ```
+/// let description = esi::Configuration::default().with_namespace("app").describe();
+/// assert_eq!(description.get("namespace"), Some("app"));
+/// assert!(description.to_string().starts_with("namespace=app is_escaped=true"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigDescription {
+    /// Every option as a name and a value, in a fixed order. Options that aren't set are `none`,
+    /// and durations are in milliseconds.
+    pub options: Vec<(&'static str, String)>,
+}
+
+impl ConfigDescription {
+    /// The value of the option `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// A hash of the description that stays the same across releases for the same options, for
+    /// telling configurations apart in logs. See [`crate::ProcessingReport::config_hash`].
+    pub fn hash(&self) -> u64 {
+        fnv1a(self.to_string().as_bytes())
+    }
+}
+
+/// Writes the options on one line, as `name=value` pairs separated by spaces. Values that are
+/// empty or contain whitespace are quoted.
+impl fmt::Display for ConfigDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.options.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            if value.is_empty() || value.contains(char::is_whitespace) {
+                write!(f, "{name}={value:?}")?;
+            } else {
+                write!(f, "{name}={value}")?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for Configuration {
@@ -19,6 +431,49 @@ impl Default for Configuration {
         Self {
             namespace: String::from("esi"),
             is_escaped: true,
+            strict_urls: false,
+            vars_in_attributes: true,
+            preserve_host: false,
+            upgrade_insecure_fragments: InsecureFragmentPolicy::Allow,
+            header_forwarding: HeaderForwarding::All,
+            forward_headers_override: ForwardHeadersOverride::Replace,
+            scheme_handlers: Vec::new(),
+            accept_status: StatusCodes::default(),
+            empty_body_status: "204,304".parse().expect("valid status codes"),
+            validate_fragment_utf8: Utf8Policy::Passthrough,
+            error_body_snippet: 256,
+            prefetch_except: false,
+            fragment_key: KeySpec::default(),
+            fragment_deduplication: false,
+            trace_events: false,
+            debug_header: None,
+            compute_etag: false,
+            require_surrogate_control: false,
+            max_warnings: 100,
+            max_document_size: None,
+            max_events: None,
+            max_attribute_length: 16 * 1024,
+            max_include_depth: None,
+            max_fragments: None,
+            max_fragment_size: None,
+            oversized_document: OversizedDocumentPolicy::default(),
+            process_in_comments: false,
+            debug_comments: false,
+            debug_validate_output: false,
+            spec_compliance: ComplianceLevel::Off,
+            strict_prestream: false,
+            strict_mode: false,
+            output_mode: OutputMode::Writer,
+            poll_abandoned: false,
+            retry_pass: None,
+            fragment_markers: None,
+            deadline: None,
+            fragment_timeout: None,
+            clock: Clock::system(),
+            geo_resolver: GeoResolver::host(),
+            callback_overrun_threshold: Some(Duration::from_millis(50)),
+            scheduling: Scheduling::default(),
+            reader_options: ReaderOptions::default(),
         }
     }
 }
@@ -27,6 +482,8 @@ impl Configuration {
     /// Sets an alternative ESI namespace, which is used to identify ESI instructions.
     ///
     /// For example, setting this to `test` would cause the processor to only match tags like `<test:include>`.
+    /// Only one namespace is recognized in a document, and every option applies to all of its
+    /// tags; tags in any other namespace, including `esi` once it's replaced, are left as content.
     pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
         self.namespace = namespace.into();
         self
@@ -36,4 +493,703 @@ impl Configuration {
         self.is_escaped = is_escaped.into();
         self
     }
+    /// Fails on include URLs containing characters that aren't valid in URLs, such as spaces, quotes
+    /// or braces. By default these are percent-encoded, the way browsers would.
+    pub fn with_strict_urls(mut self, strict_urls: impl Into<bool>) -> Self {
+        self.strict_urls = strict_urls.into();
+        self
+    }
+    /// Evaluates ESI variables and functions in include URLs, such as
+    /// `src="/fragment?lang=$(HTTP_ACCEPT_LANGUAGE)"`, before they're requested. Turn it off for
+    /// templates whose URLs hold a literal `$(` that has to reach the backend as it's written.
+    pub fn with_vars_in_attributes(mut self, vars_in_attributes: impl Into<bool>) -> Self {
+        self.vars_in_attributes = vars_in_attributes.into();
+        self
+    }
+    /// Keeps the Host header of the original request on fragment requests for relative include
+    /// URLs, such as `/header`. By default the Host header is always taken from the fragment URL,
+    /// which for relative URLs is the host of the original request URL. With it, relative URLs
+    /// also take the port of that Host header, or the default port if it has none.
+    pub fn with_preserve_host(mut self, preserve_host: impl Into<bool>) -> Self {
+        self.preserve_host = preserve_host.into();
+        self
+    }
+    /// Sets how fragment URLs that use plain HTTP are handled, see [`InsecureFragmentPolicy`].
+    pub fn with_upgrade_insecure_fragments(mut self, policy: InsecureFragmentPolicy) -> Self {
+        self.upgrade_insecure_fragments = policy;
+        self
+    }
+    /// Sets the fragment response statuses, on top of 2xx, that count as a success for includes
+    /// without an `accept-status` attribute. An accepted response is written out like any other
+    /// successful one, rather than falling back to the include's `alt` or failing.
+    pub fn with_accept_status(mut self, accept_status: StatusCodes) -> Self {
+        self.accept_status = accept_status;
+        self
+    }
+    /// Sets the statuses of successful fragment responses whose body is left out of the output,
+    /// such as a 404 accepted with `accept-status` whose body is an error page.
+    pub fn with_empty_body_status(mut self, empty_body_status: StatusCodes) -> Self {
+        self.empty_body_status = empty_body_status;
+        self
+    }
+    /// Sets how fragment bodies are checked for valid UTF-8, so that a page served as UTF-8 stays
+    /// valid when a backend returns a fragment in another encoding. See [`Utf8Policy`].
+    pub fn with_validate_fragment_utf8(mut self, validate_fragment_utf8: Utf8Policy) -> Self {
+        self.validate_fragment_utf8 = validate_fragment_utf8;
+        self
+    }
+    /// Sets how many bytes of the body of a fragment response with a failing status are kept in
+    /// the [`crate::ExecutionError::FragmentFailed`] it raises, such as an error envelope with a
+    /// trace id. The values of the `Authorization`, `Proxy-Authorization` and `Cookie` headers of
+    /// the fragment request are redacted from it. `0` leaves the body out.
+    pub fn with_error_body_snippet(mut self, max_bytes: usize) -> Self {
+        self.error_body_snippet = max_bytes;
+        self
+    }
+    /// Makes [`crate::Processor::prefetch`] fetch the includes in `esi:except` arms as well as
+    /// `esi:attempt` arms. These are normally only needed when an attempt fails.
+    pub fn with_prefetch_except(mut self, prefetch_except: impl Into<bool>) -> Self {
+        self.prefetch_except = prefetch_except.into();
+        self
+    }
+    /// Sets which headers of the original request are copied to fragment requests, such as only
+    /// `Accept-Language` and `Cookie`. Includes can override it with `forward-headers`.
+    pub fn with_header_forwarding(mut self, header_forwarding: HeaderForwarding) -> Self {
+        self.header_forwarding = header_forwarding;
+        self
+    }
+    /// Sets whether the `forward-headers` attribute of an include replaces the configured
+    /// [`HeaderForwarding`] policy or adds to it.
+    pub fn with_forward_headers_override(
+        mut self,
+        forward_headers_override: ForwardHeadersOverride,
+    ) -> Self {
+        self.forward_headers_override = forward_headers_override;
+        self
+    }
+    /// Sends fragment URLs with the given custom scheme, such as `frag` for internal URLs like
+    /// `frag://catalog/nav`, through `handler` instead of the usual URL checks. The handler gets
+    /// the URL as it's written, once any expressions in it have been evaluated, and returns the
+    /// [`SchemeTarget`] to request. Relative URLs in a document whose original request has the
+    /// scheme keep its host, and take only their path and query from the include, before they're
+    /// passed to the handler. Registering a scheme again replaces its handler.
+    pub fn with_scheme_handler(
+        mut self,
+        scheme: &str,
+        handler: impl Fn(&str) -> Result<SchemeTarget, String> + 'static,
+    ) -> Self {
+        let scheme = scheme.to_ascii_lowercase();
+        self.scheme_handlers
+            .retain(|(registered, _)| *registered != scheme);
+        self.scheme_handlers
+            .push((scheme, SchemeHandler::new(handler)));
+        self
+    }
+    /// Sets the headers that take part in the key of fragment requests, for fragments whose
+    /// response depends on headers as well as the URL. See [`crate::FragmentKey`].
+    pub fn with_fragment_key(mut self, fragment_key: KeySpec) -> Self {
+        self.fragment_key = fragment_key;
+        self
+    }
+    /// Dispatches identical fragment requests only once per document, as told apart by their
+    /// [`crate::FragmentKey`] and the headers they forward, such as a header and footer included
+    /// several times. The includes that share a request each get a copy of its response, buffered
+    /// in full once it's complete, and each falls back to its own `alt` URL, fallback content or
+    /// `onerror="continue"` if it fails.
+    ///
+    /// Only the `src` requests of includes outside `esi:except` arms are shared, and a request
+    /// that's handed to the caller of [`crate::ProcessingSession::poll_progress`] isn't.
+    pub fn with_fragment_deduplication(mut self, fragment_deduplication: impl Into<bool>) -> Self {
+        self.fragment_deduplication = fragment_deduplication.into();
+        self
+    }
+    /// Logs every parsed XML event and buffering decision, which is useful when debugging a document
+    /// but expensive on large ones. Only has an effect when debug logging is enabled.
+    pub fn with_trace_events(mut self, trace_events: impl Into<bool>) -> Self {
+        self.trace_events = trace_events.into();
+        self
+    }
+    /// Adds an `X-ESI-Debug` header to the response returned by
+    /// [`crate::Processor::process_to_response`], such as `slots=7 ok=5 alt=1 err=1 ms=143`.
+    ///
+    /// Streamed responses have already sent their headers by the time the includes are resolved,
+    /// so [`crate::Processor::process_response`] doesn't add the header. The same value can be
+    /// built from the returned [`crate::ProcessingReport`] instead, for example to log it.
+    pub fn with_debug_header(mut self, debug_header: impl Into<Option<DebugHeader>>) -> Self {
+        self.debug_header = debug_header.into();
+        self
+    }
+    /// Hashes the output as it's written, and exposes the result as a strong ETag in
+    /// [`crate::ProcessingReport::etag`]. [`crate::Processor::process_to_response`] also sets
+    /// the `ETag` header of the response, and answers with a `304 Not Modified` without a body
+    /// when it matches the `If-None-Match` header of the original request.
+    ///
+    /// Only documents that are composed in full before they're sent are hashed, which are those
+    /// processed with [`crate::Processor::process_to_response`] or
+    /// [`crate::Processor::process_with_validation`]. When the output is streamed, the option has
+    /// no effect and raises a [`crate::Warning::IgnoredOption`].
+    pub fn with_compute_etag(mut self, compute_etag: bool) -> Self {
+        self.compute_etag = compute_etag;
+        self
+    }
+    /// Only processes documents whose response has a `Surrogate-Control` header with a
+    /// `content="ESI/1.0"` directive, as CDNs do, so that the origin decides which responses are
+    /// ESI documents. [`crate::Processor::process_response`] and
+    /// [`crate::Processor::process_to_response`] send any other document to the client as it is.
+    /// Either way, the `Surrogate-Control` header isn't copied to the client response. See
+    /// [`crate::Processor::should_process`].
+    pub fn with_require_surrogate_control(
+        mut self,
+        require_surrogate_control: impl Into<bool>,
+    ) -> Self {
+        self.require_surrogate_control = require_surrogate_control.into();
+        self
+    }
+    /// Sets how many [`crate::Warning`]s are kept in the [`crate::ProcessingReport`]. Any further
+    /// warnings are only counted, so that a badly broken document can't use up memory.
+    pub fn with_max_warnings(mut self, max_warnings: usize) -> Self {
+        self.max_warnings = max_warnings;
+        self
+    }
+    /// Stops processing source documents after `max_document_size` bytes, so that a broken origin
+    /// sending an endless body can't keep the processor busy. See [`OversizedDocumentPolicy`] for
+    /// what happens then.
+    pub fn with_max_document_size(mut self, max_document_size: usize) -> Self {
+        self.max_document_size = Some(max_document_size);
+        self
+    }
+    /// Stops processing source documents after `max_events` parsed events, so that a document
+    /// made of a great many tiny elements can't keep the processor busy while staying under
+    /// [`Self::max_document_size`]. Events are counted in batches of
+    /// [`crate::EVENT_BUDGET_CHECK_INTERVAL`], so up to that many more can be parsed before
+    /// processing stops, and once more at the end of the document. The limit is handled like the
+    /// size limit, see [`OversizedDocumentPolicy`], and the count is reported in
+    /// [`crate::ProcessingReport::events_parsed`].
+    pub fn with_max_events(mut self, max_events: usize) -> Self {
+        self.max_events = Some(max_events);
+        self
+    }
+    /// Fails processing with [`crate::ExecutionError::AttributeTooLarge`] at any `<esi:include>`
+    /// tag with an attribute value longer than `max_attribute_length` bytes.
+    pub fn with_max_attribute_length(mut self, max_attribute_length: usize) -> Self {
+        self.max_attribute_length = max_attribute_length;
+        self
+    }
+    /// Tracks the chain of documents each fragment is included from, for fragment bodies that are
+    /// processed as ESI documents themselves, by a processor created in a
+    /// `process_fragment_response` callback with the fragment request as its original request.
+    ///
+    /// Fragment requests carry the chain in the [`crate::INCLUDE_CHAIN_HEADER`] header, which the
+    /// processor of a fragment picks up from its original request. An include of a document
+    /// already in the chain fails with [`crate::ExecutionError::IncludeCycle`], and one more than
+    /// `max_include_depth` levels below the first document with
+    /// [`crate::ExecutionError::IncludeTooDeep`]. Either is handled like a failed request, so the
+    /// include can still fall back to its `alt` URL or be skipped with `onerror="continue"`.
+    pub fn with_max_include_depth(mut self, max_include_depth: usize) -> Self {
+        self.max_include_depth = Some(max_include_depth);
+        self
+    }
+    /// Fails processing with [`crate::ExecutionError::TooManyFragments`] at the include past
+    /// `max_fragments`, before it's requested, so that a template with a great many includes
+    /// can't use up the quota of the backends. Includes in `esi:try` arms count too.
+    pub fn with_max_fragments(mut self, max_fragments: usize) -> Self {
+        self.max_fragments = Some(max_fragments);
+        self
+    }
+    /// Stops copying a fragment body once it's over `max_fragment_size` bytes, and fails the
+    /// fragment with [`crate::ExecutionError::FragmentTooLarge`], which falls back to its `alt`
+    /// URL, fallback content or `onerror="continue"` like any failed fragment. Like a body that
+    /// can't be read in full, what was written of it to the client stays written, followed by
+    /// [`crate::TRUNCATED_FRAGMENT_MARKER`], while inside `esi:try` and `esi:group` blocks it's
+    /// dropped.
+    pub fn with_max_fragment_size(mut self, max_fragment_size: usize) -> Self {
+        self.max_fragment_size = Some(max_fragment_size);
+        self
+    }
+    /// Sets how source documents over the maximum size or number of events are handled.
+    pub fn with_oversized_document_policy(mut self, policy: OversizedDocumentPolicy) -> Self {
+        self.oversized_document = policy;
+        self
+    }
+    /// Processes the content of `<esi:comment>` blocks like the rest of the document, with only
+    /// the comment tags themselves left out. By default the whole block is left out, including
+    /// any ESI tags in it, each of which raises a [`crate::Warning::IgnoredTag`].
+    pub fn with_process_in_comments(mut self, process_in_comments: impl Into<bool>) -> Self {
+        self.process_in_comments = process_in_comments.into();
+        self
+    }
+    /// Writes an HTML comment for each `<esi:comment text="..."/>` tag, holding the raw text and
+    /// the result of evaluating it as an expression, along with any evaluation errors. The values
+    /// of the `Authorization`, `Proxy-Authorization` and `Cookie` request headers are redacted.
+    /// Meant for debugging templates, not for production traffic.
+    pub fn with_debug_comments(mut self, debug_comments: impl Into<bool>) -> Self {
+        self.debug_comments = debug_comments.into();
+        self
+    }
+    /// Checks the output as it's written for invalid UTF-8, unprocessed ESI tags, misnested
+    /// fragment markers and, for XML documents, unbalanced elements, raising a
+    /// [`crate::Warning::InvalidOutput`] with the byte offset of each problem. Meant for
+    /// debugging and tests, not for production traffic.
+    pub fn with_debug_validate_output(mut self, debug_validate_output: impl Into<bool>) -> Self {
+        self.debug_validate_output = debug_validate_output.into();
+        self
+    }
+    /// Reports templates that rely on features outside the ESI 1.0 specification, as warnings or
+    /// by failing. See [`ComplianceLevel`] for what's reported.
+    pub fn with_spec_compliance(mut self, spec_compliance: ComplianceLevel) -> Self {
+        self.spec_compliance = spec_compliance;
+        self
+    }
+    /// Fails processing with [`crate::ExecutionError::TooLate`] when a feature that has to come
+    /// before the client response starts comes after it, such as an `esi:response` tag after
+    /// content, instead of ignoring it with a [`crate::Warning::TooLate`], so that tests can
+    /// catch templates that rely on it.
+    pub fn with_strict_prestream(mut self, strict_prestream: impl Into<bool>) -> Self {
+        self.strict_prestream = strict_prestream.into();
+        self
+    }
+    /// Fails processing with [`crate::ExecutionError::UnknownEsiTag`] at tags in the ESI
+    /// namespace that aren't supported, rather than leaving them out of the output, so that a
+    /// misspelled tag is caught instead of silently doing nothing.
+    pub fn with_strict_mode(mut self, strict_mode: impl Into<bool>) -> Self {
+        self.strict_mode = strict_mode.into();
+        self
+    }
+    /// Sets how content outside of ESI tags is written. With [`OutputMode::RawPassthrough`], the
+    /// output is byte for byte the same as the source document outside of the ESI tags.
+    pub fn with_output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.output_mode = output_mode;
+        self
+    }
+    /// Polls the fragments still pending when processing fails or is cut short once more, without
+    /// blocking, so that those that had completed are reported with their status in
+    /// [`crate::AbandonedFragment::status`].
+    pub fn with_poll_abandoned(mut self, poll_abandoned: impl Into<bool>) -> Self {
+        self.poll_abandoned = poll_abandoned.into();
+        self
+    }
+    /// Retries up to `max_retries` of the includes skipped with `onerror="continue"` once the
+    /// rest of the document has been written, as long as `min_remaining_budget` of the
+    /// [`Self::deadline`] remains, or there is no deadline.
+    ///
+    /// A skipped include leaves `<template data-esi-slot="N"></template>` in its place, `N` being
+    /// the number of the include among those skipped, from 0. The body of a successful retry is
+    /// appended to the output as `<template data-esi-slot-content="N">...</template>`, for a
+    /// script on the page to move into place. Retry requests carry the
+    /// [`crate::RETRY_HEADER`] header, and their successes are reported as
+    /// [`crate::IncludeOutcome::Retried`]. Includes skipped inside `esi:try` and `esi:group`
+    /// blocks aren't retried.
+    pub fn with_retry_pass(mut self, max_retries: usize, min_remaining_budget: Duration) -> Self {
+        self.retry_pass = Some(RetryPass {
+            max_retries,
+            min_remaining_budget,
+        });
+        self
+    }
+    /// Marks the content written for each include in the output with `style`, whether it's the
+    /// body of the `src` or `alt` response, the content of the include used as its fallback, or
+    /// the placeholder left for a [`RetryPass`] along with the content of the retry. Slots are
+    /// numbered from 0, in the order the includes are reached in the document, and the `alt`
+    /// response or retry of an include keeps its number. Nothing is marked for the arms of an
+    /// `esi:try` block that are left out of the output.
+    pub fn with_fragment_markers(mut self, style: MarkerStyle) -> Self {
+        self.fragment_markers = Some(style);
+        self
+    }
+    /// Cuts the output short once processing has taken longer than `deadline`, so that a slow or
+    /// very large fragment can't keep the client waiting indefinitely. The deadline is checked
+    /// before each element is written, and while fragment bodies are being copied. See
+    /// [`OversizedDocumentPolicy`] for what happens then.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+    /// Stops waiting for the response to a fragment request once `timeout` has passed since it
+    /// was dispatched, so that one slow backend can't hold up the rest of the document. The
+    /// include is then handled like one whose response failed: it falls back to its `alt` URL,
+    /// whose request gets a timeout of its own, its fallback content or `onerror="continue"`, and
+    /// otherwise fails with [`crate::ExecutionError::FragmentTimeout`].
+    ///
+    /// The host can't wait on a request with a timeout, so the timeout is checked whenever one of
+    /// the document's fragment requests completes, and a response that comes in late counts as
+    /// timed out. Requests that a [`crate::ProcessingSession`] hands out to be waited on are only
+    /// checked once they're resumed.
+    pub fn with_fragment_timeout(mut self, timeout: Duration) -> Self {
+        self.fragment_timeout = Some(timeout);
+        self
+    }
+    /// Reads the time from `clock` when timing the phases of processing, instead of the system
+    /// clock, for example to make them predictable in tests.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Looks up the client's geolocation for the `$(GEO)` variable with `resolver`, instead of
+    /// the host's geolocation data. It's called at most once per document, however many times
+    /// the variable is evaluated.
+    pub fn with_geo_resolver(mut self, resolver: GeoResolver) -> Self {
+        self.geo_resolver = resolver;
+        self
+    }
+    /// Raises a [`crate::Warning::SlowCallback`] for every call of a callback, such as the
+    /// dispatcher or the response processor, that takes longer than `threshold`, or never with
+    /// `None`. Whatever the threshold, the time spent in callbacks is added up in
+    /// [`crate::ProcessingReport::callbacks`].
+    pub fn with_callback_overrun_threshold(
+        mut self,
+        threshold: impl Into<Option<Duration>>,
+    ) -> Self {
+        self.callback_overrun_threshold = threshold.into();
+        self
+    }
+    /// Sets how often the fragments that have completed are written out while the document is
+    /// being parsed. Checking less often lets parsing get further ahead, so that the requests of
+    /// later includes are dispatched sooner, at the cost of buffering more of the document behind
+    /// the pending fragments. How it went is counted in [`crate::ProcessingReport::scheduling`].
+    pub fn with_scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = scheduling;
+        self
+    }
+    /// Sets how the markup of source documents is parsed. Documents read by
+    /// [`crate::Processor::process_response`] are parsed with these options, and
+    /// [`crate::Processor::reader_with_config`] makes a reader with them for
+    /// [`crate::Processor::process_document`].
+    pub fn with_reader_options(mut self, reader_options: ReaderOptions) -> Self {
+        self.reader_options = reader_options;
+        self
+    }
+}
+
+/// Presets for the most common kinds of deployments, which can be adjusted further with the
+/// `with_*` methods. Options that a preset doesn't list keep their default.
+impl Configuration {
+    /// For HTML pages composed from fragments of a storefront, favoring a page that renders over
+    /// one that's complete. Differs from the default in that:
+    ///
+    /// - `header_forwarding` only forwards `Accept-Language` and `Cookie` to fragments
+    /// - `validate_fragment_utf8` replaces invalid UTF-8 in fragments, with [`Utf8Policy::Lossy`]
+    /// - `max_document_size` is 1 MiB, and `deadline` 5 seconds, after which the page is cut
+    ///   short with the `<!-- esi: document truncated -->` comment
+    pub fn preset_html_storefront() -> Self {
+        Self {
+            header_forwarding: HeaderForwarding::Allowlist(vec![ACCEPT_LANGUAGE, COOKIE]),
+            validate_fragment_utf8: Utf8Policy::Lossy,
+            max_document_size: Some(1024 * 1024),
+            deadline: Some(Duration::from_secs(5)),
+            ..Self::default()
+        }
+    }
+
+    /// For JSON documents, which have to be complete to be parsed. Differs from the default in
+    /// that:
+    ///
+    /// - `is_escaped` is off, so include URLs are used as they're written
+    /// - `strict_urls` rejects include URLs with characters that need percent-encoding
+    /// - `validate_fragment_utf8` fails fragments with invalid UTF-8, with [`Utf8Policy::Reject`]
+    /// - `max_document_size` is 1 MiB, and larger documents fail with
+    ///   [`crate::ExecutionError::DocumentTooLarge`], with [`OversizedDocumentPolicy::Fail`]
+    /// - `output_mode` copies the document outside of ESI tags byte for byte, with
+    ///   [`OutputMode::RawPassthrough`]
+    pub fn preset_json_api() -> Self {
+        Self {
+            is_escaped: false,
+            strict_urls: true,
+            validate_fragment_utf8: Utf8Policy::Reject,
+            max_document_size: Some(1024 * 1024),
+            oversized_document: OversizedDocumentPolicy::Fail,
+            output_mode: OutputMode::RawPassthrough,
+            ..Self::default()
+        }
+    }
+
+    /// For templates that also have to work with other ESI implementations. Differs from the
+    /// default in that:
+    ///
+    /// - `spec_compliance` fails at the first feature outside ESI 1.0, with
+    ///   [`ComplianceLevel::Enforce`]
+    /// - `strict_prestream` fails at features that come after the client response has started,
+    ///   such as an `esi:response` tag after content
+    /// - `strict_urls` rejects include URLs with characters that need percent-encoding
+    /// - `strict_mode` fails at tags in the ESI namespace that aren't supported
+    /// - `oversized_document` fails documents past their deadline, with
+    ///   [`OversizedDocumentPolicy::Fail`]
+    pub fn preset_spec_strict() -> Self {
+        Self {
+            strict_urls: true,
+            oversized_document: OversizedDocumentPolicy::Fail,
+            spec_compliance: ComplianceLevel::Enforce,
+            strict_prestream: true,
+            strict_mode: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Configuration {
+    /// Describes every option of the configuration as it's applied. See [`ConfigDescription`].
+    pub fn describe(&self) -> ConfigDescription {
+        // Every field is named, so that a new option can't be left out of the description.
+        let Self {
+            namespace,
+            is_escaped,
+            strict_urls,
+            vars_in_attributes,
+            preserve_host,
+            upgrade_insecure_fragments,
+            header_forwarding,
+            forward_headers_override,
+            scheme_handlers,
+            accept_status,
+            empty_body_status,
+            validate_fragment_utf8,
+            error_body_snippet,
+            prefetch_except,
+            fragment_key,
+            fragment_deduplication,
+            trace_events,
+            debug_header,
+            compute_etag,
+            require_surrogate_control,
+            max_warnings,
+            max_document_size,
+            max_events,
+            max_attribute_length,
+            max_include_depth,
+            max_fragments,
+            max_fragment_size,
+            oversized_document,
+            process_in_comments,
+            debug_comments,
+            debug_validate_output,
+            spec_compliance,
+            strict_prestream,
+            strict_mode,
+            output_mode,
+            poll_abandoned,
+            retry_pass,
+            fragment_markers,
+            deadline,
+            fragment_timeout,
+            clock,
+            geo_resolver,
+            callback_overrun_threshold,
+            scheduling,
+            reader_options,
+        } = self;
+        let none = || "none".to_string();
+
+        ConfigDescription {
+            options: vec![
+                ("namespace", namespace.clone()),
+                ("is_escaped", is_escaped.to_string()),
+                ("strict_urls", strict_urls.to_string()),
+                ("vars_in_attributes", vars_in_attributes.to_string()),
+                ("preserve_host", preserve_host.to_string()),
+                (
+                    "upgrade_insecure_fragments",
+                    match upgrade_insecure_fragments {
+                        InsecureFragmentPolicy::Allow => "allow",
+                        InsecureFragmentPolicy::Upgrade => "upgrade",
+                        InsecureFragmentPolicy::Reject => "reject",
+                    }
+                    .to_string(),
+                ),
+                (
+                    "header_forwarding",
+                    match header_forwarding {
+                        HeaderForwarding::All => "all".to_string(),
+                        HeaderForwarding::None => none(),
+                        HeaderForwarding::Allowlist(names) => format!(
+                            "allowlist {}",
+                            names
+                                .iter()
+                                .map(HeaderName::as_str)
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        ),
+                    },
+                ),
+                (
+                    "forward_headers_override",
+                    match forward_headers_override {
+                        ForwardHeadersOverride::Replace => "replace",
+                        ForwardHeadersOverride::Extend => "extend",
+                    }
+                    .to_string(),
+                ),
+                (
+                    "scheme_handlers",
+                    if scheme_handlers.is_empty() {
+                        none()
+                    } else {
+                        scheme_handlers
+                            .iter()
+                            .map(|(scheme, _)| scheme.as_str())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    },
+                ),
+                ("accept_status", accept_status.to_string()),
+                ("empty_body_status", empty_body_status.to_string()),
+                (
+                    "validate_fragment_utf8",
+                    match validate_fragment_utf8 {
+                        Utf8Policy::Passthrough => "passthrough",
+                        Utf8Policy::Lossy => "lossy",
+                        Utf8Policy::Reject => "reject",
+                    }
+                    .to_string(),
+                ),
+                ("error_body_snippet", error_body_snippet.to_string()),
+                ("prefetch_except", prefetch_except.to_string()),
+                (
+                    "fragment_key",
+                    fragment_key
+                        .headers()
+                        .iter()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                ("fragment_deduplication", fragment_deduplication.to_string()),
+                ("trace_events", trace_events.to_string()),
+                (
+                    "debug_header",
+                    match debug_header {
+                        Some(DebugHeader::Summary) => "summary".to_string(),
+                        Some(DebugHeader::Verbose) => "verbose".to_string(),
+                        None => none(),
+                    },
+                ),
+                ("compute_etag", compute_etag.to_string()),
+                (
+                    "require_surrogate_control",
+                    require_surrogate_control.to_string(),
+                ),
+                ("max_warnings", max_warnings.to_string()),
+                (
+                    "max_document_size",
+                    max_document_size.map_or_else(none, |size| size.to_string()),
+                ),
+                (
+                    "max_events",
+                    max_events.map_or_else(none, |max| max.to_string()),
+                ),
+                ("max_attribute_length", max_attribute_length.to_string()),
+                (
+                    "max_include_depth",
+                    max_include_depth.map_or_else(none, |depth| depth.to_string()),
+                ),
+                (
+                    "max_fragments",
+                    max_fragments.map_or_else(none, |max| max.to_string()),
+                ),
+                (
+                    "max_fragment_size",
+                    max_fragment_size.map_or_else(none, |size| size.to_string()),
+                ),
+                (
+                    "oversized_document",
+                    match oversized_document {
+                        OversizedDocumentPolicy::Truncate { marker } => {
+                            format!("truncate {marker}")
+                        }
+                        OversizedDocumentPolicy::Fail => "fail".to_string(),
+                    },
+                ),
+                ("process_in_comments", process_in_comments.to_string()),
+                ("debug_comments", debug_comments.to_string()),
+                ("debug_validate_output", debug_validate_output.to_string()),
+                (
+                    "spec_compliance",
+                    match spec_compliance {
+                        ComplianceLevel::Off => "off",
+                        ComplianceLevel::Warn => "warn",
+                        ComplianceLevel::Enforce => "enforce",
+                    }
+                    .to_string(),
+                ),
+                ("strict_prestream", strict_prestream.to_string()),
+                ("strict_mode", strict_mode.to_string()),
+                (
+                    "output_mode",
+                    match output_mode {
+                        OutputMode::Writer => "writer",
+                        OutputMode::RawPassthrough => "raw_passthrough",
+                    }
+                    .to_string(),
+                ),
+                ("poll_abandoned", poll_abandoned.to_string()),
+                (
+                    "retry_pass",
+                    retry_pass.map_or_else(none, |pass| {
+                        format!(
+                            "max {} min_remaining {}ms",
+                            pass.max_retries,
+                            pass.min_remaining_budget.as_millis()
+                        )
+                    }),
+                ),
+                (
+                    "fragment_markers",
+                    match fragment_markers {
+                        Some(MarkerStyle::HtmlComment) => "html_comment".to_string(),
+                        Some(MarkerStyle::Callback(_)) => "callback".to_string(),
+                        None => none(),
+                    },
+                ),
+                (
+                    "deadline_ms",
+                    deadline.map_or_else(none, |deadline| deadline.as_millis().to_string()),
+                ),
+                (
+                    "fragment_timeout_ms",
+                    fragment_timeout.map_or_else(none, |timeout| timeout.as_millis().to_string()),
+                ),
+                (
+                    "clock",
+                    if clock.is_system() {
+                        "system"
+                    } else {
+                        "custom"
+                    }
+                    .to_string(),
+                ),
+                (
+                    "geo_resolver",
+                    if geo_resolver.is_host() {
+                        "host"
+                    } else {
+                        "custom"
+                    }
+                    .to_string(),
+                ),
+                (
+                    "callback_overrun_threshold_ms",
+                    callback_overrun_threshold
+                        .map_or_else(none, |threshold| threshold.as_millis().to_string()),
+                ),
+                (
+                    "scheduling",
+                    format!(
+                        "events_per_drain_check {} max_drain_per_check {}",
+                        scheduling.events_per_drain_check,
+                        scheduling
+                            .max_drain_per_check
+                            .map_or_else(none, |max| max.to_string())
+                    ),
+                ),
+                (
+                    "reader_options",
+                    format!(
+                        "check_end_names {} trim_text {} expand_empty_elements {} strict {}",
+                        reader_options.check_end_names,
+                        reader_options.trim_text,
+                        reader_options.expand_empty_elements,
+                        reader_options.strict
+                    ),
+                ),
+            ],
+        }
+    }
 }
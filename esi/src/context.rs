@@ -0,0 +1,23 @@
+use crate::TryArmKind;
+
+/// Where a fragment comes from in the document, given to the fragment response processor of
+/// [`crate::Processor::process_document_with_context`] and
+/// [`crate::Processor::process_response_with_context`] along with its request and response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FragmentContext {
+    /// The `src` attribute of the include as it's written in the document, before the variables
+    /// in it are evaluated. For an include taken from a `srcset`, it's the URL of the fragment.
+    pub src: String,
+    /// Whether the request is for the include's `alt` URL, after its `src` failed.
+    pub is_alt: bool,
+    /// Whether the request is the retry of a skipped include, made by a
+    /// [`crate::RetryPass`].
+    pub is_retry: bool,
+    /// The kind of the innermost `esi:try` arm the include is in, if it's in one.
+    pub arm: Option<TryArmKind>,
+    /// The number of the include, counting from 0 in the order includes are processed, which is
+    /// their order in the document. The `alt` request and the retry of an include share its
+    /// index.
+    pub index: usize,
+}
@@ -0,0 +1,227 @@
+use crate::{ExecutionError, Result};
+use log::debug;
+use std::cell::{Cell, RefCell};
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// The size of the chunks fragment bodies are copied in
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024;
+// How many chunks are copied between checks of the deadline and the client, and flushes of the
+// sink
+const DEFAULT_CHECK_INTERVAL: usize = 16;
+
+/// The limits that copying fragment bodies to the output works within.
+///
+/// A budget has an optional deadline, and a flag that's set once the client has gone away. Both
+/// are checked before each fragment is written, and every few chunks while a fragment body is
+/// being copied, so that a large fragment can't hold up processing past either. Clones of a
+/// budget share the same flag, and the buffer that chunks are read into, so that it's allocated
+/// once for a document rather than once for each fragment. A budget can also limit the size of
+/// each fragment body, which is checked at every chunk.
+#[allow(clippy::return_self_not_must_use)]
+#[derive(Clone, Debug)]
+pub struct CopyBudget {
+    deadline: Option<Instant>,
+    client_gone: Rc<Cell<bool>>,
+    // The most bytes of each body that are copied
+    max_body_size: Option<usize>,
+    chunk_size: usize,
+    // The buffer chunks are read into, sized on first use
+    buffer: Rc<RefCell<Vec<u8>>>,
+    // The number of chunks copied between checks
+    check_interval: usize,
+}
+
+impl Default for CopyBudget {
+    fn default() -> Self {
+        Self {
+            deadline: None,
+            client_gone: Rc::default(),
+            max_body_size: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: Rc::default(),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+}
+
+impl CopyBudget {
+    /// Creates a budget without a deadline, which copies in 8KiB chunks and checks itself every
+    /// 16 of them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops copying once `deadline` has passed.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stops copying a body at the chunk that would take it over `max_body_size` bytes.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+
+    /// Sets the size of the chunks that bodies are copied in.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how many chunks are copied between checks of the deadline and the client, and
+    /// flushes of the sink.
+    pub fn with_check_interval(mut self, chunks: usize) -> Self {
+        self.check_interval = chunks.max(1);
+        self
+    }
+
+    /// Records that the client has gone away, so that nothing more is copied to it.
+    pub fn mark_client_gone(&self) {
+        self.client_gone.set(true);
+    }
+
+    /// Returns whether the client has gone away, either as marked by
+    /// [`Self::mark_client_gone`] or because writing to it failed.
+    pub fn is_client_gone(&self) -> bool {
+        self.client_gone.get()
+    }
+
+    /// Returns whether the deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Returns how long there is until the deadline, if there is one.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // Checks the budget before writing something to the output.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_client_gone() {
+            Err(ExecutionError::ClientDisconnected)
+        } else if self.is_expired() {
+            Err(ExecutionError::DeadlineExceeded)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How a call to [`copy_fragment_body`] ended. Each outcome carries the number of bytes that were
+/// written to the sink, which stay written.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CopyOutcome {
+    /// The whole body was copied.
+    Complete { bytes: usize },
+    /// The deadline passed before the whole body was copied.
+    DeadlineExceeded { bytes: usize },
+    /// The client went away before the whole body was copied, or writing to it failed.
+    ClientGone { bytes: usize },
+    /// Reading the body failed.
+    ReadFailed { bytes: usize, error: std::io::Error },
+    /// The body is larger than the budget's maximum body size, which is `limit`.
+    TooLarge { bytes: usize, limit: usize },
+}
+
+impl CopyOutcome {
+    // Turns an unfinished copy of the body of `url` into the error that ends processing.
+    pub(crate) fn into_result(self, url: &str) -> Result<usize> {
+        match self {
+            Self::Complete { bytes } => Ok(bytes),
+            Self::DeadlineExceeded { .. } => Err(ExecutionError::DeadlineExceeded),
+            Self::ClientGone { .. } => Err(ExecutionError::ClientDisconnected),
+            Self::ReadFailed { error, .. } => {
+                Err(ExecutionError::FragmentBodyError(url.to_string(), error))
+            }
+            Self::TooLarge { limit, .. } => {
+                Err(ExecutionError::FragmentTooLarge(url.to_string(), limit))
+            }
+        }
+    }
+
+    /// Returns the number of bytes written to the sink.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Complete { bytes }
+            | Self::DeadlineExceeded { bytes }
+            | Self::ClientGone { bytes }
+            | Self::ReadFailed { bytes, .. }
+            | Self::TooLarge { bytes, .. } => *bytes,
+        }
+    }
+}
+
+/// Copies a fragment body to `sink` in chunks, checking `budget` before the first chunk and then
+/// every few chunks, and flushing the sink as often, so that a large body reaches the client as
+/// it arrives rather than once it's all been read.
+///
+/// Copying stops between two chunks when the deadline has passed or the client is gone, so the
+/// sink is never left with part of a chunk, and before the chunk that would take the body over the
+/// maximum body size. A failed write to the sink marks the client as gone.
+pub fn copy_fragment_body(
+    mut body: impl Read,
+    sink: &mut impl Write,
+    budget: &CopyBudget,
+) -> CopyOutcome {
+    // Should the shared buffer be in use already, this copy gets one of its own.
+    let mut own = Vec::new();
+    let mut shared = budget.buffer.try_borrow_mut();
+    let buffer = match shared.as_deref_mut() {
+        Ok(buffer) => buffer,
+        Err(_) => &mut own,
+    };
+    buffer.resize(budget.chunk_size, 0);
+    let mut bytes = 0;
+    let mut chunks = 0;
+    loop {
+        if chunks % budget.check_interval == 0 {
+            if budget.is_client_gone() {
+                return CopyOutcome::ClientGone { bytes };
+            }
+            if budget.is_expired() {
+                debug!("deadline passed after copying {bytes} bytes of a fragment");
+                return CopyOutcome::DeadlineExceeded { bytes };
+            }
+        }
+
+        let read = match body.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return CopyOutcome::ReadFailed { bytes, error },
+        };
+        if let Some(limit) = budget.max_body_size.filter(|&limit| bytes + read > limit) {
+            debug!("fragment body is over {limit} bytes after copying {bytes} bytes");
+            return CopyOutcome::TooLarge { bytes, limit };
+        }
+        if let Err(err) = sink.write_all(&buffer[..read]) {
+            debug!("failed to write fragment body, client is gone: {err}");
+            budget.mark_client_gone();
+            return CopyOutcome::ClientGone { bytes };
+        }
+        bytes += read;
+        chunks += 1;
+
+        if chunks % budget.check_interval == 0 {
+            if let Err(err) = sink.flush() {
+                debug!("failed to flush fragment body, client is gone: {err}");
+                budget.mark_client_gone();
+                return CopyOutcome::ClientGone { bytes };
+            }
+        }
+    }
+
+    if let Err(err) = sink.flush() {
+        debug!("failed to flush fragment body, client is gone: {err}");
+        budget.mark_client_gone();
+        return CopyOutcome::ClientGone { bytes };
+    }
+    CopyOutcome::Complete { bytes }
+}
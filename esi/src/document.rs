@@ -1,37 +1,454 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::Result;
-use fastly::{http::request::PendingRequest, Request};
-use quick_xml::Writer;
+use crate::markers::PendingBoundary;
+use crate::parse::GroupErrorPolicy;
+use crate::report::IncludeSlot;
+use crate::request::DeferredRequest;
+use crate::status::StatusPolicy;
+use crate::{
+    ExecutionError, FailurePhase, FragmentKey, KeySpec, Result, FAIL_FAST_HEADER,
+    FORWARDED_HEADERS_HEADER, SRCSET_INDEX_HEADER,
+};
+use fastly::http::request::{PendingRequest, PollResult};
+use fastly::http::{HeaderName, HeaderValue, StatusCode};
+use fastly::{Request, Response};
 
 pub struct Fragment {
     // Metadata of the request
     pub(crate) request: Request,
     // An optional alternate request to send if the original request fails
-    pub(crate) alt: Option<Result<Request>>,
+    pub(crate) alt: Option<Result<DeferredRequest>>,
     // Whether to continue on error
     pub(crate) continue_on_error: bool,
+    // Whether the fragment must succeed before any output is written
+    pub(crate) critical: bool,
     // The pending request, which can be polled to retrieve the response
-    pub(crate) pending_request: PendingRequest,
+    pub(crate) pending_content: FragmentContent,
+    // When the request was dispatched, which its timeout counts from
+    pub(crate) dispatched: Instant,
+    // Where the outcome of the include is recorded
+    pub(crate) slot: IncludeSlot,
+    // Which response statuses count as a success
+    pub(crate) status_policy: Rc<StatusPolicy>,
+    // What's written in place of the fragment if it fails, from the body of the include
+    pub(crate) fallback: Option<Vec<u8>>,
 }
 
-/// `Task` is combining raw data and an include fragment for both `attempt` and `except` arms
-/// the result is written to `output`.
-// #[derive(Default)]
-pub struct Task {
-    pub queue: VecDeque<Element>,
-    pub output: Writer<Vec<u8>>,
-    pub status: PollTaskState,
+/// The result of dispatching a fragment request.
+///
+/// Dispatchers usually return a [`PendingRequest`], but may also provide a response they already
+/// have at hand (for example from a cache), indicate that the fragment has no content and
+/// should be skipped, or fail it straight away, for example when its backend is known to be down.
+pub enum PendingFragmentContent {
+    PendingRequest(PendingRequest),
+    CompletedRequest(Response),
+    NoContent,
+    /// Fails the request without sending it, for the given reason. The include falls back to its
+    /// `alt` URL, its fallback content or `onerror="continue"` as if the request had failed, and
+    /// the reason is reported in a [`crate::Warning::FragmentFailedFast`].
+    ///
+    /// In place of a response, the fragment response processor gets a `503 Service Unavailable`
+    /// with the reason in the [`FAIL_FAST_HEADER`] header, which always counts as a failure.
+    FailFast(String),
+}
+
+impl From<PendingRequest> for PendingFragmentContent {
+    fn from(value: PendingRequest) -> Self {
+        Self::PendingRequest(value)
+    }
+}
+
+impl From<Response> for PendingFragmentContent {
+    fn from(value: Response) -> Self {
+        Self::CompletedRequest(value)
+    }
 }
 
-impl Default for Task {
-    fn default() -> Self {
+impl PendingFragmentContent {
+    /// Block until the fragment response is available.
+    pub(crate) fn wait_for_content(self) -> Result<Response> {
+        match self {
+            Self::PendingRequest(pending_request) => Ok(pending_request.wait()?),
+            Self::CompletedRequest(response) => Ok(response),
+            Self::NoContent => Ok(Response::from_status(StatusCode::NO_CONTENT)),
+            Self::FailFast(reason) => Ok(Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_header(
+                    FAIL_FAST_HEADER,
+                    HeaderValue::try_from(reason)
+                        .unwrap_or_else(|_| HeaderValue::from_static("fail fast")),
+                )),
+        }
+    }
+
+    /// Check whether the fragment response is available without blocking.
+    pub(crate) fn poll(self) -> PollContentResult {
+        match self {
+            Self::PendingRequest(pending_request) => match pending_request.poll() {
+                PollResult::Pending(pending_request) => {
+                    PollContentResult::Pending(Self::PendingRequest(pending_request).into())
+                }
+                PollResult::Done(res) => PollContentResult::ready(res.map_err(Into::into)),
+            },
+            content => PollContentResult::ready(content.wait_for_content()),
+        }
+    }
+
+    /// Block until the fragment response is available, or `deadline` has passed.
+    ///
+    /// The host can't wait on a request with a timeout, so the deadline is checked once the
+    /// request is complete, and a response that comes in after it counts as timed out.
+    fn wait_until(self, deadline: Instant) -> PollContentResult {
+        let content = match self.poll() {
+            PollContentResult::Pending(content) => content,
+            polled => return polled,
+        };
+        if Instant::now() >= deadline {
+            return PollContentResult::TimedOut;
+        }
+        let res = content.wait_for_content();
+        if Instant::now() >= deadline {
+            return PollContentResult::TimedOut;
+        }
+        PollContentResult::ready(res)
+    }
+}
+
+/// The content of a fragment, as tracked by the processor.
+pub(crate) enum FragmentContent {
+    /// The content returned by the dispatcher.
+    Dispatched(Box<PendingFragmentContent>),
+    /// A pending request that has been handed to the caller of
+    /// [`crate::ProcessingSession::poll_progress`] to wait on.
+    Lent,
+    /// A request the caller waited on, which failed.
+    Failed(ExecutionError),
+    /// A request in an `esi:except` arm, which isn't dispatched until the arm is reached.
+    Deferred,
+    /// A request shared with identical includes, see
+    /// [`crate::Configuration::fragment_deduplication`].
+    Shared(Rc<SharedFragment>),
+}
+
+impl From<PendingFragmentContent> for FragmentContent {
+    fn from(value: PendingFragmentContent) -> Self {
+        Self::Dispatched(Box::new(value))
+    }
+}
+
+impl FragmentContent {
+    /// Block until the fragment response is available.
+    pub(crate) fn wait_for_content(self) -> Result<Response> {
+        match self {
+            Self::Dispatched(content) => (*content).wait_for_content(),
+            Self::Lent => unreachable!("lent fragment requests are resumed before waiting"),
+            Self::Failed(err) => Err(err),
+            Self::Deferred => unreachable!("deferred fragment requests are dispatched first"),
+            Self::Shared(shared) => {
+                match shared.take(|content| PollContentResult::ready(content.wait_for_content())) {
+                    PollContentResult::Ready(res) => *res,
+                    _ => unreachable!("shared requests are waited on"),
+                }
+            }
+        }
+    }
+
+    /// Block until the fragment response is available, or `deadline` has passed, in which case
+    /// [`PollContentResult::TimedOut`] is returned and the request is given up on. Without a
+    /// deadline, this is [`Self::wait_for_content`].
+    pub(crate) fn wait_until(self, deadline: Option<Instant>) -> PollContentResult {
+        let Some(deadline) = deadline else {
+            return PollContentResult::ready(self.wait_for_content());
+        };
+        match self {
+            Self::Dispatched(content) => (*content).wait_until(deadline),
+            Self::Shared(shared) => shared.take(|content| content.wait_until(Some(deadline))),
+            content => content.poll(),
+        }
+    }
+
+    /// Like [`Self::wait_until`], but the requests of the other fragments of the document, which
+    /// `others` visits, are waited on along with this one with [`fastly::http::request::select`].
+    /// The responses that arrive first are kept in place of their requests, see
+    /// [`select_in_place`]. The deadline is checked each time one of the requests completes.
+    pub(crate) fn wait_with_others(
+        self,
+        deadline: Option<Instant>,
+        others: &mut VisitContents,
+    ) -> PollContentResult {
+        let timed_out = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let mut content = self;
+        loop {
+            match content.poll() {
+                PollContentResult::Pending(pending) => content = pending,
+                ready => return ready,
+            }
+            if timed_out() {
+                return content.time_out();
+            }
+            if !select_in_place(&mut |f| {
+                f(&mut content);
+                others(f);
+            }) {
+                return content.wait_until(deadline);
+            }
+            if timed_out() {
+                return content.time_out();
+            }
+        }
+    }
+
+    // Gives up on the request once its deadline has passed, for the includes that share it too.
+    fn time_out(self) -> PollContentResult {
+        match self {
+            Self::Shared(shared) => shared.take(|_| PollContentResult::TimedOut),
+            _ => PollContentResult::TimedOut,
+        }
+    }
+
+    // Takes the pending request out of the content, leaving it lent until a response or the
+    // request is kept in its place with `keep`.
+    fn take_pending_request(&mut self) -> Option<PendingRequest> {
+        match self {
+            Self::Dispatched(_) => self.take_dispatched_request(),
+            Self::Shared(shared) => match &mut *shared.state.borrow_mut() {
+                SharedState::Pending(content) => content.take_pending_request(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Takes the request out of content returned by the dispatcher if it's still pending, leaving
+    /// the content lent. Unlike [`Self::take_pending_request`], shared requests aren't taken.
+    pub(crate) fn take_dispatched_request(&mut self) -> Option<PendingRequest> {
+        if !matches!(
+            self,
+            Self::Dispatched(content) if matches!(**content, PendingFragmentContent::PendingRequest(_))
+        ) {
+            return None;
+        }
+        let Self::Dispatched(content) = std::mem::replace(self, Self::Lent) else {
+            unreachable!("content was matched as dispatched");
+        };
+        let PendingFragmentContent::PendingRequest(pending_request) = *content else {
+            unreachable!("content was matched as a pending request");
+        };
+        Some(pending_request)
+    }
+
+    // Puts `content` in the place of the request that `take_pending_request` took.
+    fn keep(&mut self, content: Self) {
+        match self {
+            Self::Shared(shared) => {
+                if let SharedState::Pending(pending) = &mut *shared.state.borrow_mut() {
+                    pending.keep(content);
+                }
+            }
+            _ => *self = content,
+        }
+    }
+
+    /// Check whether the fragment response is available without blocking.
+    pub(crate) fn poll(self) -> PollContentResult {
+        match self {
+            Self::Dispatched(content) => (*content).poll(),
+            Self::Lent => PollContentResult::Pending(Self::Lent),
+            Self::Failed(err) => PollContentResult::ready(Err(err)),
+            Self::Deferred => PollContentResult::Pending(Self::Deferred),
+            Self::Shared(shared) => shared.take(Self::poll),
+        }
+    }
+}
+
+/// A fragment request made once for several identical includes, each of which gets a copy of its
+/// response.
+pub(crate) struct SharedFragment {
+    // The URL of the request, for the error the includes after the first get when it fails
+    url: String,
+    state: RefCell<SharedState>,
+}
+
+enum SharedState {
+    // The content returned by the dispatcher, until one of the includes gets the response
+    Pending(FragmentContent),
+    // The response, with its body buffered once it's first read so that it can be copied
+    Completed(Box<Response>),
+    // The request failed, with the error given to the include that got it first
+    Failed(String),
+}
+
+impl SharedFragment {
+    pub(crate) fn new(url: String, content: FragmentContent) -> Rc<Self> {
+        Rc::new(Self {
+            url,
+            state: RefCell::new(SharedState::Pending(content)),
+        })
+    }
+
+    // Gets a copy of the response once `wait`, which polls or waits on the shared request, has it.
+    fn take(
+        self: Rc<Self>,
+        wait: impl FnOnce(FragmentContent) -> PollContentResult,
+    ) -> PollContentResult {
+        let mut state = self.state.borrow_mut();
+        if let SharedState::Pending(content) = &mut *state {
+            let content = std::mem::replace(content, FragmentContent::Lent);
+            match wait(content) {
+                PollContentResult::Pending(content) => {
+                    *state = SharedState::Pending(content);
+                    drop(state);
+                    return PollContentResult::Pending(FragmentContent::Shared(self));
+                }
+                PollContentResult::Ready(res) => match *res {
+                    Ok(response) => *state = SharedState::Completed(Box::new(response)),
+                    Err(err) => {
+                        *state = SharedState::Failed(err.to_string());
+                        return PollContentResult::ready(Err(err));
+                    }
+                },
+                // The request is given up on, for the includes that share it too.
+                PollContentResult::TimedOut => {
+                    *state = SharedState::Failed("timed out".to_string());
+                    return PollContentResult::TimedOut;
+                }
+            }
+        }
+        PollContentResult::ready(match &mut *state {
+            SharedState::Completed(response) => Ok(response.clone_with_body()),
+            SharedState::Failed(reason) => Err(ExecutionError::SharedRequestFailed(
+                self.url.clone(),
+                reason.clone(),
+            )),
+            SharedState::Pending(_) => unreachable!("pending content was polled"),
+        })
+    }
+}
+
+/// The fragment requests of a document that are shared by identical includes, by key.
+pub(crate) struct SharedFragments {
+    spec: KeySpec,
+    fragments: RefCell<HashMap<FragmentKey, Rc<SharedFragment>>>,
+}
+
+impl SharedFragments {
+    // Requests are told apart by the headers in `spec`, and also by which headers they forward
+    // and which `srcset` URL they're for, as those are set per include.
+    pub(crate) fn new(spec: &KeySpec) -> Self {
+        let spec = [FORWARDED_HEADERS_HEADER, SRCSET_INDEX_HEADER]
+            .into_iter()
+            .fold(spec.clone(), |spec, name| {
+                spec.with_header(HeaderName::from_bytes(name.as_bytes()).expect("valid header"))
+            });
         Self {
-            queue: VecDeque::new(),
-            output: Writer::new(Vec::new()),
-            status: PollTaskState::default(),
+            spec,
+            fragments: RefCell::default(),
         }
     }
+
+    pub(crate) fn key(&self, request: &Request) -> FragmentKey {
+        FragmentKey::compute(request, &self.spec)
+    }
+
+    pub(crate) fn get(&self, key: &FragmentKey) -> Option<Rc<SharedFragment>> {
+        self.fragments.borrow().get(key).cloned()
+    }
+
+    pub(crate) fn insert(&self, key: FragmentKey, fragment: Rc<SharedFragment>) {
+        self.fragments.borrow_mut().insert(key, fragment);
+    }
+}
+
+/// Calls a function on the content of every include in part of the document, see
+/// [`for_each_content`].
+pub(crate) type VisitContents<'a> = dyn FnMut(&mut dyn FnMut(&mut FragmentContent)) + 'a;
+
+/// Waits on the pending requests of the contents that `visit` visits together, with
+/// [`fastly::http::request::select`], until one of them completes. Its response, or error, is
+/// kept in place of the request, and the other requests are put back. Returns whether there were
+/// any requests to wait on.
+///
+/// `visit` has to visit the same contents in the same order each time it's called.
+pub(crate) fn select_in_place(visit: &mut VisitContents) -> bool {
+    let mut indices = Vec::new();
+    let mut requests = Vec::new();
+    let mut index = 0;
+    visit(&mut |content| {
+        if let Some(pending_request) = content.take_pending_request() {
+            indices.push(index);
+            requests.push(pending_request);
+        }
+        index += 1;
+    });
+    if requests.is_empty() {
+        return false;
+    }
+
+    // `select` gives the requests that are still pending back in any order.
+    let ids: Vec<_> = requests.iter().map(request_id).collect();
+    let (res, remaining) = fastly::http::request::select(requests);
+    let mut contents: Vec<Option<FragmentContent>> = ids.iter().map(|_| None).collect();
+    for pending_request in remaining {
+        let i = ids
+            .iter()
+            .position(|&id| id == request_id(&pending_request))
+            .expect("selected requests are given back");
+        contents[i] = Some(PendingFragmentContent::PendingRequest(pending_request).into());
+    }
+    let completed = contents
+        .iter()
+        .position(Option::is_none)
+        .expect("one selected request completes");
+    contents[completed] = Some(match res {
+        Ok(response) => PendingFragmentContent::CompletedRequest(response).into(),
+        Err(err) => FragmentContent::Failed(err.into()),
+    });
+
+    let mut contents = indices.into_iter().zip(contents).peekable();
+    let mut index = 0;
+    visit(&mut |content| {
+        if let Some((_, Some(kept))) = contents.next_if(|&(i, _)| i == index) {
+            content.keep(kept);
+        }
+        index += 1;
+    });
+    true
+}
+
+// Tells a pending request apart from the others while `select` moves them around, by the address
+// of its URL, which stays on the heap for as long as the request does.
+fn request_id(pending_request: &PendingRequest) -> *const u8 {
+    pending_request.sent_req().get_url_str().as_ptr()
+}
+
+/// The result of polling a [`FragmentContent`] without blocking, or of waiting on it.
+pub(crate) enum PollContentResult {
+    Pending(FragmentContent),
+    Ready(Box<Result<Response>>),
+    /// The deadline of [`FragmentContent::wait_until`] passed before the response was available.
+    TimedOut,
+}
+
+impl PollContentResult {
+    fn ready(res: Result<Response>) -> Self {
+        Self::Ready(Box::new(res))
+    }
+}
+
+/// `Task` is combining raw data and an include fragment for each arm of a `try` block
+/// the result is written to `output`.
+///
+/// Like the rest of the output, `output` only ever holds bytes: source events are serialized
+/// when they're queued, and fragment bodies are copied as they are.
+#[derive(Default)]
+pub struct Task {
+    pub queue: VecDeque<Element>,
+    pub output: Vec<u8>,
+    pub status: PollTaskState,
+    // The fragment boundaries in `output` with `MarkerStyle::Callback`, reported once it's written
+    pub(crate) boundaries: Vec<PendingBoundary>,
 }
 
 impl Task {
@@ -43,31 +460,104 @@ impl Task {
 /// A section of the pending response, either raw XML data or a pending fragment request.
 pub enum Element {
     Raw(Vec<u8>),
-    Include(Fragment),
+    Include(Box<Fragment>),
+    /// The arms of an `esi:try` block in the order they're tried, the last of which is always
+    /// the `esi:except` arm, empty if there was none.
     Try {
-        except_task: Task,
-        attempt_task: Task,
+        arms: Vec<Task>,
+    },
+    Group {
+        name: String,
+        onerror: GroupErrorPolicy,
+        task: Task,
+    },
+}
+
+/// Why a fragment that couldn't be written failed.
+#[derive(Clone, Debug)]
+pub enum FragmentFailure {
+    /// The response has a status that doesn't count as a success.
+    Status {
+        status: u16,
+        phase: FailurePhase,
+        body_snippet: Option<String>,
     },
+    /// Reading the body of a successful response failed partway through.
+    BodyError(Rc<std::io::Error>),
+    /// No response came within [`crate::Configuration::fragment_timeout`]. Holds how long it was
+    /// waited for.
+    Timeout(Duration),
+    /// The body is over [`crate::Configuration::max_fragment_size`], which it holds.
+    TooLarge(usize),
+}
+
+impl FragmentFailure {
+    // Turns the failure of the fragment at `url` into the error that ends processing.
+    pub(crate) fn into_error(self, url: &str) -> ExecutionError {
+        match self {
+            Self::Status {
+                status,
+                phase,
+                body_snippet,
+            } => ExecutionError::FragmentFailed {
+                url: url.to_string(),
+                status,
+                phase,
+                body_snippet,
+            },
+            Self::BodyError(err) => ExecutionError::FragmentBodyError(
+                url.to_string(),
+                std::io::Error::new(err.kind(), err.to_string()),
+            ),
+            Self::Timeout(elapsed) => ExecutionError::FragmentTimeout(url.to_string(), elapsed),
+            Self::TooLarge(limit) => ExecutionError::FragmentTooLarge(url.to_string(), limit),
+        }
+    }
 }
 
 // #[derive(PartialEq, Clone)]
+#[derive(Default)]
 pub enum PollTaskState {
-    Failed(Request, u16),
+    Failed(Box<Request>, FragmentFailure),
+    #[default]
     Pending,
     Succeeded,
 }
 impl Clone for PollTaskState {
     fn clone(&self) -> Self {
         match self {
-            Self::Failed(req, res) => Self::Failed(req.clone_without_body(), *res),
+            Self::Failed(req, failure) => {
+                Self::Failed(Box::new(req.clone_without_body()), failure.clone())
+            }
             Self::Pending => Self::Pending,
             Self::Succeeded => Self::Succeeded,
         }
     }
 }
-impl Default for PollTaskState {
-    fn default() -> Self {
-        Self::Pending
+
+impl Element {
+    /// Calls `f` on the content of every include in the element, at any depth.
+    pub(crate) fn for_each_content(&mut self, f: &mut dyn FnMut(&mut FragmentContent)) {
+        match self {
+            Self::Raw(_) => {}
+            Self::Include(fragment) => f(&mut fragment.pending_content),
+            Self::Try { arms } => {
+                for arm in arms {
+                    for_each_content(&mut arm.queue, f);
+                }
+            }
+            Self::Group { task, .. } => for_each_content(&mut task.queue, f),
+        }
+    }
+}
+
+/// Calls `f` on the content of every include in a queue, see [`Element::for_each_content`].
+pub(crate) fn for_each_content(
+    elements: &mut VecDeque<Element>,
+    f: &mut dyn FnMut(&mut FragmentContent),
+) {
+    for element in elements {
+        element.for_each_content(f);
     }
 }
 
@@ -75,11 +565,12 @@ impl std::fmt::Debug for Element {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Raw(_) => write!(f, "Raw"),
-            Self::Include(Fragment { alt: Some(_), .. }) => {
+            Self::Include(fragment) if fragment.alt.is_some() => {
                 write!(f, "Incldude Fragment(with alt)")
             }
-            Self::Include(Fragment { .. }) => write!(f, "Include Fragment"),
+            Self::Include(_) => write!(f, "Include Fragment"),
             Self::Try { .. } => write!(f, "Try"),
+            Self::Group { name, .. } => write!(f, "Group {name}"),
         }
     }
 }
@@ -1,19 +1,52 @@
+use std::fmt;
+use std::time::Duration;
 use thiserror::Error;
 
 use fastly::http::request::SendError;
 
 /// Describes an error encountered during ESI parsing or execution.
 #[derive(Error, Debug)]
-#[allow(clippy::large_enum_variant)]
 pub enum ExecutionError {
-    /// Invalid XML was encountered during parsing.
-    #[error("xml parsing error: {0}")]
-    XMLError(#[from] quick_xml::Error),
+    /// Invalid XML was encountered during parsing. Holds the line and column, counting from 1, at
+    /// which the markup starts in the source document, when it's known. Columns count bytes.
+    #[error(
+        "xml parsing error{}: {0}",
+        .1.map(|(line, column)| format!(" at line {line}, column {column}")).unwrap_or_default()
+    )]
+    XMLError(#[source] quick_xml::Error, Option<(usize, usize)>),
 
     /// The ESI document contains a tag with a missing paraemter.
     #[error("tag `{0}` is missing required parameter `{1}`")]
     MissingRequiredParameter(String, String),
 
+    /// The ESI document contains a tag with two parameters that can't be used together.
+    #[error("tag `{0}` can't have both `{1}` and `{2}` parameters")]
+    ConflictingParameters(String, String, String),
+
+    /// The ESI document contains a tag with an invalid parameter value.
+    #[error("tag `{0}` has an invalid `{1}` parameter: `{2}`")]
+    InvalidAttributeValue(String, String, String),
+
+    /// The ESI document contains a tag with an attribute that's empty, or has no value, where one
+    /// is required. Contains the tag, the attribute and the position of the tag in the document.
+    #[error("tag `{0}` at position {2} has an empty `{1}` parameter")]
+    EmptyAttribute(String, String, usize),
+
+    /// The ESI document contains a tag with an attribute longer than
+    /// [`crate::Configuration::max_attribute_length`]. Contains the tag, the attribute, the
+    /// position of the tag in the document and the maximum length.
+    #[error("tag `{0}` at position {2} has a `{1}` parameter longer than {3} bytes")]
+    AttributeTooLarge(String, String, usize, usize),
+
+    /// A list of status codes, such as an `accept-status` attribute, could not be parsed.
+    #[error("invalid status codes: `{0}`")]
+    InvalidStatusCodes(String),
+
+    /// An include in an `esi:group` with `onerror="fail"` failed. Contains the group name, and
+    /// the URL and status of the fragment.
+    #[error("esi:group `{0}` failed: fragment `{1}` returned status {2}")]
+    GroupFailed(String, String, u16),
+
     /// The ESI document contains an opening tag where it doesn't belong.
     #[error("unexpected `{0}` opening tag")]
     UnexpectedOpeningTag(String),
@@ -26,17 +59,188 @@ pub enum ExecutionError {
     #[error("invalid request URL provided: `{0}`")]
     InvalidRequestUrl(String),
 
+    /// A header added to a fragment request by an include's `add-header` attribute has a name, or
+    /// a value once evaluated, that can't be sent. Contains the name of the header.
+    #[error("invalid request header: `{0}`")]
+    InvalidRequestHeader(String),
+
+    /// A fragment URL uses plain HTTP, which [`crate::InsecureFragmentPolicy::Reject`] forbids.
+    #[error("insecure fragment URL: `{0}`")]
+    InsecureFragmentUrl(String),
+
+    /// The [`crate::SchemeHandler`] registered for the scheme of a fragment URL couldn't resolve
+    /// it. Contains the URL and the reason the handler gave. Like any other failed include, this
+    /// falls back to the `alt` URL or is skipped with `onerror="continue"`.
+    #[error("fragment URL `{0}` couldn't be resolved: {1}")]
+    SchemeHandlerFailed(String, String),
+
+    /// A fragment includes a document it was itself included from, directly or through other
+    /// fragments, with [`crate::Configuration::max_include_depth`] set. Contains the chain of URLs
+    /// from the first document to the repeated one.
+    #[error("include cycle: {}", .0.join(" -> "))]
+    IncludeCycle(Vec<String>),
+
+    /// A fragment is included from further down than [`crate::Configuration::max_include_depth`]
+    /// allows. Contains the fragment URL and the maximum depth.
+    #[error("fragment `{0}` is included deeper than the maximum of {1}")]
+    IncludeTooDeep(String, usize),
+
+    /// A `data:` URI in the ESI template could not be decoded.
+    #[error("invalid data URI: {0}")]
+    InvalidDataUri(String),
+
     /// An error occurred when sending a fragment request to a backend.
     #[error("error sending request: {0}")]
-    RequestError(#[from] SendError),
+    RequestError(Box<SendError>),
+
+    /// A fragment request shared by identical includes, with
+    /// [`crate::Configuration::fragment_deduplication`], failed with an error that was given to
+    /// the first of them. The others get this error instead, holding the fragment URL and the
+    /// original error message.
+    #[error("shared request for fragment `{0}` failed: {1}")]
+    SharedRequestFailed(String, String),
+
+    /// A fragment response has a status that doesn't count as a success, and neither an `alt`,
+    /// fallback content nor `onerror="continue"` applied. `phase` tells whether it's the response
+    /// to the `src` or the `alt` URL, and `body_snippet` holds the start of its body, up to
+    /// [`crate::Configuration::error_body_snippet`] bytes.
+    #[error(
+        "fragment `{url}` failed with status {status} ({phase}){}",
+        .body_snippet.as_ref().map(|snippet| format!(", body: {snippet:?}")).unwrap_or_default()
+    )]
+    FragmentFailed {
+        url: String,
+        status: u16,
+        phase: FailurePhase,
+        body_snippet: Option<String>,
+    },
+
+    /// No response to a fragment request came within [`crate::Configuration::fragment_timeout`],
+    /// and neither an `alt`, fallback content nor `onerror="continue"` applied. Contains the
+    /// fragment URL and how long its response was waited for.
+    #[error("fragment `{0}` timed out after {}ms", .1.as_millis())]
+    FragmentTimeout(String, Duration),
+
+    /// The body of a fragment response is over [`crate::Configuration::max_fragment_size`], and
+    /// neither its `alt`, its fallback content nor `onerror="continue"` applied. Contains the
+    /// fragment URL and the limit.
+    #[error("body of fragment `{0}` is over the limit of {1} bytes")]
+    FragmentTooLarge(String, usize),
+
+    /// A document has more includes than [`crate::Configuration::max_fragments`]. Contains the
+    /// limit.
+    #[error("document has more than {0} includes")]
+    TooManyFragments(usize),
+
+    /// A fragment marked as critical failed during a validation pass, before any output was written.
+    #[error("critical fragment `{0}` failed: {1}")]
+    CriticalFragmentFailed(String, Box<ExecutionError>),
+
+    /// A [`crate::ProcessingSession`] was used out of order, for example polled again before the
+    /// fragment request it handed out was resumed.
+    #[error("invalid processing session state: {0}")]
+    InvalidSessionState(String),
 
-    /// An ESI fragment request returned an unexpected HTTP status code.
-    #[error("received unexpected status code for fragment `{0}`: {1}")]
-    UnexpectedStatus(String, u16),
+    /// A tag in the ESI namespace that isn't supported, with
+    /// [`crate::Configuration::strict_mode`] set. Contains the tag.
+    #[error("unknown ESI tag `{0}`")]
+    UnknownEsiTag(String),
+
+    /// An `esi:remove` block or `esi:include` body starts and ends in different `esi:attempt` or
+    /// `esi:except` arms. Contains the tag and its position in the document.
+    #[error("`{0}` at position {1} crosses an esi:attempt or esi:except boundary")]
+    TagCrossesArmBoundary(String, usize),
+
+    /// The source document is longer than [`crate::Configuration::max_document_size`] bytes, and
+    /// [`crate::OversizedDocumentPolicy::Fail`] is set. Contains the maximum size.
+    #[error("document is larger than the maximum of {0} bytes")]
+    DocumentTooLarge(usize),
+
+    /// More than [`crate::Configuration::max_events`] events of the source document were parsed,
+    /// and [`crate::OversizedDocumentPolicy::Fail`] is set. Contains the maximum number of events.
+    #[error("document has more than the maximum of {0} events")]
+    EventBudgetExceeded(usize),
+
+    /// Processing went on past [`crate::Configuration::deadline`], and
+    /// [`crate::OversizedDocumentPolicy::Fail`] is set.
+    #[error("processing went past the deadline")]
+    DeadlineExceeded,
+
+    /// Writing to the output had already failed, usually because the client went away, when
+    /// processing was about to write to it again, or failed while a fragment body was being copied
+    /// to it. See [`Self::WriterError`] for the failed write itself.
+    #[error("client disconnected")]
+    ClientDisconnected,
+
+    /// Writing to the output failed, usually because the client went away. Holds the error the
+    /// writer returned, for the application to log or to tell a disconnect apart from other
+    /// failures.
+    #[error("failed to write output: {0}")]
+    WriterError(#[from] std::io::Error),
+
+    /// The body of a fragment response could not be read, and neither its `alt`, its fallback
+    /// content nor `onerror="continue"` applied. Contains the fragment URL.
+    #[error("failed to read body of fragment `{0}`: {1}")]
+    FragmentBodyError(String, #[source] std::io::Error),
+
+    /// A template relies on a feature outside the ESI 1.0 specification, with
+    /// [`crate::ComplianceLevel::Enforce`]. Holds the code of the deviation, what it's about and its
+    /// position in the document.
+    #[error("`{1}` at position {2} isn't part of ESI 1.0 ({0})")]
+    SpecDeviation(String, String, usize),
+
+    /// A feature came after the client response started, with
+    /// [`crate::Configuration::strict_prestream`]. Holds the feature and its position in the
+    /// document. See [`crate::Warning::TooLate`].
+    #[error("`{0}` at position {1} came after the response started")]
+    TooLate(String, usize),
 
     /// This error is returned when the parser encounters an unexpected end of document.
     #[error("unexpected end of document")]
     UnexpectedEndOfDocument,
 }
 
+impl From<quick_xml::Error> for ExecutionError {
+    fn from(err: quick_xml::Error) -> Self {
+        Self::XMLError(err, None)
+    }
+}
+
+impl From<SendError> for ExecutionError {
+    fn from(err: SendError) -> Self {
+        Self::RequestError(Box::new(err))
+    }
+}
+
+impl ExecutionError {
+    /// The URL and status of a [`Self::FragmentFailed`] error, which used to be reported as
+    /// `UnexpectedStatus(url, status)`.
+    #[deprecated(note = "match on `ExecutionError::FragmentFailed` instead")]
+    pub fn unexpected_status(&self) -> Option<(&str, u16)> {
+        match self {
+            Self::FragmentFailed { url, status, .. } => Some((url, *status)),
+            _ => None,
+        }
+    }
+}
+
+/// Which request of an include failed, in [`ExecutionError::FragmentFailed`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailurePhase {
+    /// The request for the `src` URL, of an include without an `alt`.
+    Primary,
+    /// The request for the `alt` URL, after the one for the `src` URL failed too.
+    Alt,
+}
+
+impl fmt::Display for FailurePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Primary => "primary",
+            Self::Alt => "alt",
+        })
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ExecutionError>;
@@ -0,0 +1,96 @@
+use std::io::Write;
+
+/// Hashes the composed output as it's written, for [`crate::Configuration::compute_etag`].
+///
+/// The hash is a 64-bit FNV-1a, or SHA-256 with the `sha256` feature. Either way it only depends
+/// on the bytes of the output, so identical renders get identical ETags.
+#[derive(Clone)]
+pub(crate) struct OutputHasher {
+    #[cfg(not(feature = "sha256"))]
+    state: u64,
+    #[cfg(feature = "sha256")]
+    state: sha2::Sha256,
+}
+
+impl OutputHasher {
+    #[cfg(not(feature = "sha256"))]
+    pub fn new() -> Self {
+        Self {
+            state: crate::expression::FNV1A_OFFSET_BASIS,
+        }
+    }
+
+    #[cfg(feature = "sha256")]
+    pub fn new() -> Self {
+        use sha2::Digest;
+        Self {
+            state: sha2::Sha256::new(),
+        }
+    }
+
+    #[cfg(not(feature = "sha256"))]
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.state = crate::expression::fnv1a_update(self.state, bytes);
+    }
+
+    #[cfg(feature = "sha256")]
+    pub fn update(&mut self, bytes: &[u8]) {
+        use sha2::Digest;
+        self.state.update(bytes);
+    }
+
+    /// The strong ETag of everything written so far, quoted as it's sent in the header.
+    #[cfg(not(feature = "sha256"))]
+    pub fn etag(&self) -> String {
+        format!("\"{:016x}\"", self.state)
+    }
+
+    /// The strong ETag of everything written so far, quoted as it's sent in the header.
+    #[cfg(feature = "sha256")]
+    pub fn etag(&self) -> String {
+        use sha2::Digest;
+        let digest = self.state.clone().finalize();
+        let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+        format!("\"{hex}\"")
+    }
+}
+
+/// A writer that hashes what's written through it, when there's a hasher.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Option<OutputHasher>,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, hasher: Option<OutputHasher>) -> Self {
+        Self { inner, hasher }
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.hasher.as_ref().map(OutputHasher::etag)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether the value of an `If-None-Match` header matches `etag`. Following RFC 9110, a
+/// weak comparison is used, and `*` matches any.
+pub(crate) fn if_none_match(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    header
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
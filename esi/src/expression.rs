@@ -0,0 +1,652 @@
+use crate::geo::{GeoData, GeoResolver};
+use crate::timings::CallbackTimer;
+use crate::warning::Warnings;
+use crate::{ComplianceLevel, Result, Warning};
+use fastly::http::header;
+use fastly::Request;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use std::cell::OnceCell;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// The request that ESI variables are evaluated for, along with what's looked up about its
+/// client, which is kept for the rest of the document.
+pub(crate) struct EvaluationRequest {
+    request: Rc<Request>,
+    geo_resolver: GeoResolver,
+    geo: OnceCell<Option<GeoData>>,
+}
+
+impl EvaluationRequest {
+    pub(crate) fn new(request: Rc<Request>, geo_resolver: GeoResolver) -> Self {
+        Self {
+            request,
+            geo_resolver,
+            geo: OnceCell::new(),
+        }
+    }
+
+    // The client's geolocation, looked up the first time it's needed
+    fn geo(&self) -> Option<&GeoData> {
+        self.geo
+            .get_or_init(|| self.geo_resolver.lookup(&self.request))
+            .as_ref()
+    }
+}
+
+impl Deref for EvaluationRequest {
+    type Target = Request;
+
+    fn deref(&self) -> &Request {
+        &self.request
+    }
+}
+
+/// Replaces every ESI variable reference and function call in `input` with its value for
+/// `request`.
+///
+/// References take the form `$(NAME)`, `$(NAME{key})` for a single entry of a list or dictionary
+/// variable, and `$(NAME|default)` for a fallback used when the variable is missing or empty. The
+/// default can be quoted with single quotes. A single query parameter, as in
+/// `$(QUERY_STRING{q})`, is percent-decoded. Unknown variables evaluate to an empty string, and
+/// anything that isn't a well-formed reference is left as it is, raising a
+/// [`Warning::ExpressionError`].
+///
+/// Function calls take the form `$name(arg, ...)`, see [`call_function`] for the functions.
+/// Arguments are evaluated the same way, unless they're quoted with single quotes.
+pub(crate) fn process_symbols(
+    input: &str,
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+) -> String {
+    substitute_symbols(input, request, warnings, &str::to_string, false)
+}
+
+/// Replaces the ESI variable references and function calls in the content of an `<esi:vars>`
+/// block like [`process_symbols`], passing each value through `escape`, since it's written into
+/// the markup around it. References to unknown variables without a default are left as they're
+/// written, instead of evaluating to an empty string.
+pub(crate) fn process_vars_block(
+    input: &str,
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+    escape: &dyn Fn(&str) -> String,
+) -> String {
+    substitute_symbols(input, request, warnings, escape, true)
+}
+
+fn substitute_symbols(
+    input: &str,
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+    escape: &dyn Fn(&str) -> String,
+    keep_unknown: bool,
+) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(reference) = after.strip_prefix('(') {
+            match reference
+                .find(')')
+                .and_then(|end| parse_reference(&reference[..end]).map(|variable| (variable, end)))
+            {
+                Some((variable, end)) => {
+                    if !SPEC_VARIABLES.contains(&variable.name) {
+                        warnings.deviation(warnings.position(), "variable", variable.name);
+                    }
+                    match evaluate(&variable, request) {
+                        Some(value) => output.push_str(&escape(&value)),
+                        None if keep_unknown && variable.default.is_none() => {
+                            output.push_str(&rest[start..start + 2 + end + 1]);
+                        }
+                        None => output.push_str(&escape(variable.default.unwrap_or_default())),
+                    }
+                    rest = &reference[end + 1..];
+                }
+                None => {
+                    let end = reference
+                        .find(')')
+                        .map_or(rest.len(), |end| start + 2 + end + 1);
+                    warn_expression(warnings, &rest[start..end]);
+                    output.push_str("$(");
+                    rest = reference;
+                }
+            }
+        } else if let Some((name, call)) = split_function_call(after) {
+            match split_arguments(call) {
+                Some((args, len)) => {
+                    warnings.deviation(warnings.position(), "function", format!("${name}"));
+                    let expression = &rest[start..start + 1 + name.len() + len];
+                    let args: Vec<String> = args
+                        .into_iter()
+                        .map(|arg| evaluate_argument(arg, request, warnings))
+                        .collect();
+                    match call_function(name, &args) {
+                        Some(value) => output.push_str(&escape(&value)),
+                        None => warn_expression(warnings, expression),
+                    }
+                    rest = &call[len..];
+                }
+                None => {
+                    warn_expression(warnings, &rest[start..]);
+                    output.push('$');
+                    rest = after;
+                }
+            }
+        } else {
+            output.push('$');
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+// The variables defined by ESI 1.0
+const SPEC_VARIABLES: [&str; 6] = [
+    "HTTP_ACCEPT_LANGUAGE",
+    "HTTP_COOKIE",
+    "HTTP_HOST",
+    "HTTP_REFERER",
+    "HTTP_USER_AGENT",
+    "QUERY_STRING",
+];
+
+// The request headers whose values are never written into debug comments
+const REDACTED_HEADERS: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// Replaces the values of the `Authorization`, `Proxy-Authorization` and `Cookie` headers of
+/// `request` in `value` with `[redacted]`.
+pub(crate) fn redact_secrets(mut value: String, request: &Request) -> String {
+    for name in &REDACTED_HEADERS {
+        for secret in request.get_header_all_str(name) {
+            if !secret.is_empty() {
+                value = value.replace(secret, "[redacted]");
+            }
+        }
+    }
+    value
+}
+
+/// The length of the longest value that [`redact_secrets`] replaces.
+pub(crate) fn longest_secret(request: &Request) -> usize {
+    REDACTED_HEADERS
+        .iter()
+        .flat_map(|name| request.get_header_all_str(name))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Evaluates the `text` of an `<esi:comment>` tag for a debug comment, returning the content of the
+/// comment, e.g. ` debug: raw='$(HTTP_HOST)' value='example.com' `.
+///
+/// Expressions that can't be evaluated are listed in the comment as well as raising warnings. The
+/// values of the `Authorization`, `Proxy-Authorization` and `Cookie` headers are redacted from
+/// the value, though single cookies such as `$(HTTP_COOKIE{id})` aren't. The content never
+/// contains `--`, which would end or invalidate the comment.
+pub(crate) fn debug_comment(
+    text: &str,
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+) -> String {
+    let evaluation_warnings = Warnings::new(
+        usize::MAX,
+        None,
+        ComplianceLevel::Off,
+        CallbackTimer::default(),
+    );
+    evaluation_warnings.set_position(warnings.position());
+    let value = redact_secrets(
+        process_symbols(text, request, &evaluation_warnings),
+        request,
+    );
+
+    let mut errors = Vec::new();
+    for warning in evaluation_warnings.take().0 {
+        if let Warning::ExpressionError { expression, .. } = &warning {
+            errors.push(expression.clone());
+        }
+        warnings.push(warning);
+    }
+
+    let comment = if errors.is_empty() {
+        format!(" debug: raw='{text}' value='{value}' ")
+    } else {
+        format!(
+            " debug: raw='{text}' value='{value}' errors='{}' ",
+            errors.join(", ")
+        )
+    };
+    escape_comment(&comment)
+}
+
+// Separates the dashes of any `--` in the content of a comment with a space.
+fn escape_comment(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for c in content.chars() {
+        if c == '-' && escaped.ends_with('-') {
+            escaped.push(' ');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes `value` to be written as the content of a JSON string.
+pub(crate) fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn warn_expression(warnings: &Warnings, expression: &str) {
+    warnings.push(Warning::ExpressionError {
+        position: warnings.position(),
+        expression: expression.to_string(),
+    });
+}
+
+// The names of the supported functions
+const FUNCTIONS: &[&str] = &[
+    "bucket",
+    "pick",
+    "index",
+    "rindex",
+    "substr",
+    "len",
+    "lower",
+    "upper",
+    "replace",
+    "url_encode",
+    "url_decode",
+    "html_encode",
+];
+
+// Splits `name(...)` into the name of a supported function and the rest, starting with the
+// opening parenthesis.
+fn split_function_call(input: &str) -> Option<(&str, &str)> {
+    let len = input
+        .find(|c: char| !c.is_ascii_lowercase() && c != '_')
+        .unwrap_or(input.len());
+    let (name, call) = input.split_at(len);
+    (FUNCTIONS.contains(&name) && call.starts_with('(')).then_some((name, call))
+}
+
+// Splits the parenthesized argument list at the start of `call` at its top-level commas,
+// returning the arguments and the length of the list. Commas and parentheses in quoted
+// arguments and nested calls are skipped over.
+fn split_arguments(call: &str) -> Option<(Vec<&str>, usize)> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut arg_start = 1;
+
+    for (i, c) in call.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(&call[arg_start..i]);
+                    return Some((args, i + 1));
+                }
+            }
+            ',' if depth == 1 => {
+                args.push(&call[arg_start..i]);
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Evaluates the `test` of an `<esi:when>` tag for `request`.
+///
+/// A test compares two operands with `==`, `!=` or `has`, which is true when the left operand
+/// contains the right one, e.g. `$(HTTP_COOKIE{group})=='beta'`. Operands are evaluated like the
+/// arguments of functions, so they're taken literally when quoted with single quotes. A test
+/// without an operator is true when its value is neither empty nor `false`, such as
+/// `$(HTTP_ACCEPT_LANGUAGE{en})`.
+pub(crate) fn evaluate_test(test: &str, request: &EvaluationRequest, warnings: &Warnings) -> bool {
+    let operand = |operand| evaluate_argument(operand, request, warnings);
+    match split_comparison(test) {
+        Some((left, Comparison::Equal, right)) => operand(left) == operand(right),
+        Some((left, Comparison::NotEqual, right)) => operand(left) != operand(right),
+        Some((left, Comparison::Has, right)) => {
+            warnings.deviation(warnings.position(), "test-operator", "has");
+            operand(left).contains(&operand(right))
+        }
+        None => {
+            let value = operand(test);
+            !value.is_empty() && value != "false"
+        }
+    }
+}
+
+// The operators of a test
+enum Comparison {
+    Equal,
+    NotEqual,
+    Has,
+}
+
+// Splits a test at its operator. Operators inside quotes or the parentheses of a variable
+// reference or function call don't count.
+fn split_comparison(test: &str) -> Option<(&str, Comparison, &str)> {
+    let bytes = test.as_bytes();
+    let mut quoted = false;
+    let mut depth = 0usize;
+    for (i, &byte) in bytes.iter().enumerate() {
+        match byte {
+            b'\'' => quoted = !quoted,
+            _ if quoted => {}
+            b'(' => depth += 1,
+            b')' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {}
+            b'=' | b'!' if bytes.get(i + 1) == Some(&b'=') => {
+                let comparison = if byte == b'=' {
+                    Comparison::Equal
+                } else {
+                    Comparison::NotEqual
+                };
+                return Some((&test[..i], comparison, &test[i + 2..]));
+            }
+            b' ' if bytes[i..].starts_with(b" has ") => {
+                return Some((&test[..i], Comparison::Has, &test[i + 5..]));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn evaluate_argument(arg: &str, request: &EvaluationRequest, warnings: &Warnings) -> String {
+    let arg = arg.trim();
+    match arg
+        .strip_prefix('\'')
+        .and_then(|arg| arg.strip_suffix('\''))
+    {
+        Some(literal) => literal.to_string(),
+        None => process_symbols(arg, request, warnings),
+    }
+}
+
+/// Calls a built-in function with its evaluated arguments, returning `None` if they're invalid.
+///
+/// - `$bucket(input, n)` assigns `input` to one of `n` buckets, numbered from 0. The bucket is the
+///   64-bit FNV-1a hash of the UTF-8 bytes of `input`, modulo `n`, so a given input always gets
+///   the same bucket. An empty input, such as a missing cookie, always gets bucket 0. `n` has to
+///   be a positive integer.
+/// - `$pick(index, a, b, ...)` evaluates to the argument after `index`, counting from 0, so
+///   `$pick(1, a, b)` is `b`. `index` has to be one of the arguments.
+/// - `$index(string, substring)` and `$rindex(string, substring)` evaluate to the position of
+///   the first and last occurrence of `substring` in `string`, in characters counting from 0, or
+///   to `-1` if there's none, so `$index('banana', 'a')` is `1` and `$rindex('banana', 'a')` is
+///   `5`. `substring` can't be empty.
+/// - `$substr(string, offset)` and `$substr(string, offset, length)` evaluate to the part of
+///   `string` that starts at `offset` characters, or that many from the end if it's negative, and
+///   is `length` characters long, or stops that many before the end if it's negative. Without a
+///   `length`, it goes to the end. Offsets and lengths past either end are clamped to it.
+/// - `$len(string)` evaluates to the length of `string` in characters.
+/// - `$lower(string)` and `$upper(string)` evaluate to `string` in lowercase and uppercase.
+/// - `$replace(string, from, to)` replaces every occurrence of `from` in `string` with `to`, and
+///   `$replace(string, from, to, count)` only the first `count`. `from` can't be empty.
+/// - `$url_encode(string)` percent-encodes the UTF-8 bytes of `string`, apart from letters,
+///   digits and `-._~`, so that it can be spliced into a URL, and `$url_decode(string)` decodes
+///   percent-encoded bytes. `+` is left as it is. Bytes that don't make valid UTF-8 once decoded
+///   become U+FFFD.
+/// - `$html_encode(string)` escapes `&`, `<`, `>`, `"` and `'` for HTML, as `&amp;`, `&lt;`,
+///   `&gt;`, `&quot;` and `&apos;`.
+fn call_function(name: &str, args: &[String]) -> Option<String> {
+    match (name, args) {
+        ("bucket", [input, buckets]) => {
+            let buckets = buckets.trim().parse::<u64>().ok().filter(|&n| n > 0)?;
+            if input.is_empty() {
+                return Some("0".to_string());
+            }
+            Some((fnv1a(input.as_bytes()) % buckets).to_string())
+        }
+        ("pick", [index, choices @ ..]) => {
+            let index = index.trim().parse::<usize>().ok()?;
+            choices.get(index).cloned()
+        }
+        ("index", [string, substring]) if !substring.is_empty() => {
+            Some(char_position(string, string.find(substring.as_str())))
+        }
+        ("rindex", [string, substring]) if !substring.is_empty() => {
+            Some(char_position(string, string.rfind(substring.as_str())))
+        }
+        ("substr", [string, offset, length @ ..]) if length.len() <= 1 => {
+            let chars: Vec<char> = string.chars().collect();
+            let len = chars.len() as i64;
+            let clamp = |position: i64| position.clamp(0, len) as usize;
+            let offset = offset.trim().parse::<i64>().ok()?;
+            let start = clamp(if offset < 0 { len + offset } else { offset });
+            let end = match length.first() {
+                None => chars.len(),
+                Some(length) => match length.trim().parse::<i64>().ok()? {
+                    length if length < 0 => clamp(len + length),
+                    length => clamp((start as i64).saturating_add(length)),
+                },
+            };
+            Some(chars[start..end.max(start)].iter().collect())
+        }
+        ("url_encode", [string]) => {
+            Some(percent_encoding::utf8_percent_encode(string, URL_ENCODE_SET).to_string())
+        }
+        ("url_decode", [string]) => Some(
+            percent_encoding::percent_decode_str(string)
+                .decode_utf8_lossy()
+                .into_owned(),
+        ),
+        ("html_encode", [string]) => Some(quick_xml::escape::escape(string.as_str()).into_owned()),
+        ("len", [string]) => Some(string.chars().count().to_string()),
+        ("lower", [string]) => Some(string.to_lowercase()),
+        ("upper", [string]) => Some(string.to_uppercase()),
+        ("replace", [string, from, to]) if !from.is_empty() => {
+            Some(string.replace(from.as_str(), to))
+        }
+        ("replace", [string, from, to, count]) if !from.is_empty() => {
+            let count = count.trim().parse::<usize>().ok()?;
+            Some(string.replacen(from.as_str(), to, count))
+        }
+        _ => None,
+    }
+}
+
+// The characters `$url_encode` encodes: all but those that are unreserved in URLs
+const URL_ENCODE_SET: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// Turns a byte offset into `string` into a position in characters, or `-1` for none.
+fn char_position(string: &str, offset: Option<usize>) -> String {
+    match offset {
+        Some(offset) => string[..offset].chars().count().to_string(),
+        None => "-1".to_string(),
+    }
+}
+
+// The 64-bit FNV-1a hash, which is simple enough to be kept stable across releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_update(FNV1A_OFFSET_BASIS, bytes)
+}
+
+pub(crate) const FNV1A_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+// Carries on the FNV-1a hash `hash` of some bytes with more of them.
+pub(crate) fn fnv1a_update(hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+// A parsed `$(NAME{key}|default)` variable reference
+struct VariableReference<'a> {
+    name: &'a str,
+    key: Option<&'a str>,
+    default: Option<&'a str>,
+}
+
+fn parse_reference(reference: &str) -> Option<VariableReference<'_>> {
+    let (reference, default) = match reference.split_once('|') {
+        Some((reference, default)) => {
+            let default = default
+                .strip_prefix('\'')
+                .and_then(|default| default.strip_suffix('\''))
+                .unwrap_or(default);
+            (reference, Some(default))
+        }
+        None => (reference, None),
+    };
+    let (name, key) = match reference.split_once('{') {
+        Some((name, key)) => (name, Some(key.strip_suffix('}')?)),
+        None => (reference, None),
+    };
+
+    let is_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    is_name.then_some(VariableReference { name, key, default })
+}
+
+// Evaluates a variable reference, or returns `None` for a variable that isn't known.
+fn evaluate(reference: &VariableReference, request: &EvaluationRequest) -> Option<String> {
+    let value = match (reference.name, reference.key) {
+        ("REQUEST_METHOD", None) => Some(request.get_method_str().to_string()),
+        ("REQUEST_PATH", None) => Some(request.get_path().to_string()),
+        ("QUERY_STRING", None) => request.get_query_str().map(str::to_string),
+        // Parameters are percent-decoded, so that they're escaped for where they're written
+        ("QUERY_STRING", Some(key)) => request.get_query_parameter(key).map(|value| {
+            percent_encoding::percent_decode_str(value)
+                .decode_utf8_lossy()
+                .into_owned()
+        }),
+        ("HTTP_HOST", None) => request
+            .get_header_str(header::HOST)
+            .or_else(|| request.get_url().host_str())
+            .map(str::to_string),
+        ("HTTP_COOKIE", Some(key)) => request.get_header_str(header::COOKIE).and_then(|cookies| {
+            cookies.split(';').find_map(|cookie| {
+                let (name, value) = cookie.trim().split_once('=')?;
+                (name == key).then(|| value.to_string())
+            })
+        }),
+        // Whether the client accepts the given language
+        ("HTTP_ACCEPT_LANGUAGE", Some(key)) => {
+            let accepted =
+                request
+                    .get_header_str(header::ACCEPT_LANGUAGE)
+                    .is_some_and(|languages| {
+                        languages.split(',').any(|language| {
+                            let language = language.split(';').next().unwrap_or_default().trim();
+                            language.eq_ignore_ascii_case(key)
+                        })
+                    });
+            Some(accepted.to_string())
+        }
+        // The client's geolocation, or all of it as `key=value` pairs without a key
+        ("GEO", key) => request.geo().and_then(|geo| match key {
+            Some(key) => geo.get(key),
+            None => Some(geo.to_string()),
+        }),
+        (name, None) if name.starts_with("HTTP_") => {
+            let name = name["HTTP_".len()..].to_ascii_lowercase().replace('_', "-");
+            request.get_header_str(name.as_str()).map(str::to_string)
+        }
+        _ => return None,
+    };
+
+    Some(match value {
+        Some(value) if !value.is_empty() => value,
+        _ => reference.default.unwrap_or_default().to_string(),
+    })
+}
+
+/// Evaluates the templated attributes of a non-ESI element.
+///
+/// An attribute named `{namespace}:attr-<name>` is replaced by a plain `<name>` attribute whose
+/// value is the result of [`process_symbols`], or dropped if the result is empty. Events without
+/// templated attributes are returned as they are.
+pub(crate) fn evaluate_attribute_templates<'a>(
+    event: XmlEvent<'a>,
+    prefix: &[u8],
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+) -> Result<XmlEvent<'a>> {
+    match event {
+        XmlEvent::Start(e) if has_templated_attribute(&e, prefix) => Ok(XmlEvent::Start(
+            rewrite_templated_attributes(&e, prefix, request, warnings)?,
+        )),
+        XmlEvent::Empty(e) if has_templated_attribute(&e, prefix) => Ok(XmlEvent::Empty(
+            rewrite_templated_attributes(&e, prefix, request, warnings)?,
+        )),
+        event => Ok(event),
+    }
+}
+
+fn has_templated_attribute(e: &BytesStart, prefix: &[u8]) -> bool {
+    e.attributes()
+        .with_checks(false)
+        .flatten()
+        .any(|attr| attr.key.as_ref().starts_with(prefix))
+}
+
+fn rewrite_templated_attributes(
+    e: &BytesStart,
+    prefix: &[u8],
+    request: &EvaluationRequest,
+    warnings: &Warnings,
+) -> Result<BytesStart<'static>> {
+    let mut rewritten = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+
+    for attr in e.attributes().with_checks(false) {
+        let attr = attr.map_err(quick_xml::Error::from)?;
+        match attr.key.as_ref().strip_prefix(prefix) {
+            Some(name) => {
+                warnings.deviation(
+                    warnings.position(),
+                    "attribute-template",
+                    String::from_utf8_lossy(attr.key.as_ref()),
+                );
+                let value = attr.unescape_value_with(resolve_predefined_entity)?;
+                let value = process_symbols(&value, request, warnings);
+                if !value.is_empty() {
+                    // Attributes built from strings have their values escaped.
+                    rewritten
+                        .push_attribute((String::from_utf8_lossy(name).as_ref(), value.as_str()));
+                }
+            }
+            None => rewritten.push_attribute(attr),
+        }
+    }
+
+    Ok(rewritten)
+}
@@ -0,0 +1,141 @@
+//! How an include whose fragment fails is handled, when more than one way of handling it is
+//! available. The first of these that applies decides, in this order:
+//!
+//! 1. `accept-status`: a status it lists, or any 2xx status, isn't a failure unless failed fast
+//! 2. `alt`: the `alt` URL is requested, and if that fails too, the steps after this one apply
+//! 3. fallback content: the content of an `esi:include` that isn't self-closing is written
+//! 4. `onerror="continue"`: the include is skipped, and queued for the retry pass if there is one
+//! 5. `esi:try` and `esi:group`: the enclosing arm or group fails, and handles it as a whole
+//! 6. document: processing fails with `ExecutionError::FragmentFailed`
+//!
+//! Includes whose URL is rejected before it's requested, for being invalid or for example for
+//! using plain HTTP with
+//! [`InsecureFragmentPolicy::Reject`](crate::InsecureFragmentPolicy::Reject), go through the same
+//! steps, except that they're never retried, and that they fail processing with the error they
+//! were rejected with, even inside an `esi:try` arm or `esi:group` block.
+//!
+//! A request that the dispatcher returns an error for is handled the same way, except that
+//! fallback content isn't written for it: its `alt` is requested, and otherwise
+//! `onerror="continue"` skips the include, or processing fails with the error.
+//!
+//! A request that gets no response within
+//! [`Configuration::fragment_timeout`](crate::Configuration::fragment_timeout) is handled like a
+//! failed response, except that it fails processing with `ExecutionError::FragmentTimeout`.
+//!
+//! [`PRECEDENCE`] lists the steps, and [`resolve_fragment_failure`] applies them.
+
+/// The steps of the order in which failures are handled, each as a name and a description.
+pub const PRECEDENCE: [(&str, &str); 6] = [
+    (
+        "`accept-status`",
+        "a status it lists, or any 2xx status, isn't a failure unless failed fast",
+    ),
+    (
+        "`alt`",
+        "the `alt` URL is requested, and if that fails too, the steps after this one apply",
+    ),
+    (
+        "fallback content",
+        "the content of an `esi:include` that isn't self-closing is written",
+    ),
+    (
+        "`onerror=\"continue\"`",
+        "the include is skipped, and queued for the retry pass if there is one",
+    ),
+    (
+        "`esi:try` and `esi:group`",
+        "the enclosing arm or group fails, and handles it as a whole",
+    ),
+    (
+        "document",
+        "processing fails with `ExecutionError::FragmentFailed`",
+    ),
+];
+
+/// The ways of handling a failure that are available to an include, see
+/// [`resolve_fragment_failure`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FailureMechanisms {
+    /// Whether the status of the response is accepted, for being 2xx or listed in
+    /// `accept-status`, and the dispatcher didn't fail the request fast.
+    pub status_accepted: bool,
+    /// Whether the include has an `alt` URL that hasn't been tried yet.
+    pub alt: bool,
+    /// Whether the include has fallback content.
+    pub fallback: bool,
+    /// Whether the include has `onerror="continue"`.
+    pub continue_on_error: bool,
+    /// Whether the include can be retried, for there being a
+    /// [`Configuration::retry_pass`](crate::Configuration::retry_pass) and the include being
+    /// written straight to the output.
+    pub retry_pass: bool,
+    /// Whether the include is inside an `esi:try` arm or `esi:group` block.
+    pub in_block: bool,
+}
+
+/// How a fragment is handled, as decided by [`resolve_fragment_failure`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureResolution {
+    /// The response isn't a failure, and its body is written.
+    Accepted,
+    /// The `alt` URL is requested.
+    Alt,
+    /// The fallback content of the include is written.
+    Fallback,
+    /// The include is skipped.
+    Skip,
+    /// The include is skipped, and queued for the retry pass, which leaves it out once it has as
+    /// many includes as it retries.
+    Retry,
+    /// The enclosing `esi:try` arm or `esi:group` block fails.
+    FailBlock,
+    /// Processing fails.
+    FailDocument,
+}
+
+impl FailureResolution {
+    /// Whether the failure is passed on, to the enclosing block or to the caller.
+    pub fn fails(self) -> bool {
+        matches!(self, Self::FailBlock | Self::FailDocument)
+    }
+}
+
+/// Decides how a fragment is handled from the mechanisms available to its include, in the order
+/// of [`PRECEDENCE`].
+///
+/// ```
+/// use esi::failure::{resolve_fragment_failure, FailureMechanisms, FailureResolution};
+///
+/// let mechanisms = FailureMechanisms {
+///     fallback: true,
+///     continue_on_error: true,
+///     ..FailureMechanisms::default()
+/// };
+/// assert_eq!(resolve_fragment_failure(&mechanisms), FailureResolution::Fallback);
+/// ```
+pub fn resolve_fragment_failure(mechanisms: &FailureMechanisms) -> FailureResolution {
+    let FailureMechanisms {
+        status_accepted,
+        alt,
+        fallback,
+        continue_on_error,
+        retry_pass,
+        in_block,
+    } = *mechanisms;
+
+    if status_accepted {
+        FailureResolution::Accepted
+    } else if alt {
+        FailureResolution::Alt
+    } else if fallback {
+        FailureResolution::Fallback
+    } else if continue_on_error && retry_pass {
+        FailureResolution::Retry
+    } else if continue_on_error {
+        FailureResolution::Skip
+    } else if in_block {
+        FailureResolution::FailBlock
+    } else {
+        FailureResolution::FailDocument
+    }
+}
@@ -0,0 +1,68 @@
+use crate::OversizedDocumentPolicy;
+
+/// The limit a document was cut short at, with [`OversizedDocumentPolicy::Truncate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Limit {
+    Size,
+    Events,
+    Deadline,
+}
+
+/// What the output of a document ran into by the time everything that was going to be written
+/// to it has been, which decides how it ends. See [`Self::ending`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputConditions {
+    /// Processing went past [`crate::Configuration::deadline`] before the document was complete.
+    pub deadline_hit: bool,
+    /// The source document is larger than [`crate::Configuration::max_document_size`].
+    pub size_limit_hit: bool,
+    /// The source document has more events than [`crate::Configuration::max_events`].
+    pub event_limit_hit: bool,
+    /// Processing failed, usually for a fragment with nothing to fall back to.
+    pub fragment_failed: bool,
+    /// Writing to the output failed, usually because the client went away.
+    pub client_disconnected: bool,
+    /// A [`crate::RetryPass`] is configured.
+    pub retry_pass: bool,
+}
+
+/// The one way the output of a document ends. Nothing is written after it, whatever else
+/// happened while processing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEnding {
+    /// The document is complete. With `retries`, the content of the includes skipped with
+    /// `onerror="continue"` that succeed when they're retried is appended.
+    Complete { retries: bool },
+    /// The document was cut short, and the marker of [`OversizedDocumentPolicy::Truncate`] ends
+    /// the output.
+    Truncated,
+    /// Processing fails, and nothing more is written by the processor, so the application can
+    /// end the output with content of its own. See
+    /// [`crate::ProcessingOutcome::recovery_write_allowed`].
+    Failed,
+    /// The output can't be written to anymore, and processing fails with
+    /// [`crate::ExecutionError::WriterError`] or [`crate::ExecutionError::ClientDisconnected`],
+    /// unless it had already failed for another reason.
+    Disconnected,
+}
+
+impl OutputConditions {
+    /// Decides how the output ends, with `policy` applied to the limits. A broken output takes
+    /// precedence over anything else, since nothing can be written to it, and a failure over a
+    /// limit, since a truncation marker would claim that the content before it is what the
+    /// document says. Includes are only retried for a complete document.
+    pub fn ending(&self, policy: &OversizedDocumentPolicy) -> OutputEnding {
+        let limit_hit = self.deadline_hit || self.size_limit_hit || self.event_limit_hit;
+        if self.client_disconnected {
+            OutputEnding::Disconnected
+        } else if self.fragment_failed || (limit_hit && *policy == OversizedDocumentPolicy::Fail) {
+            OutputEnding::Failed
+        } else if limit_hit {
+            OutputEnding::Truncated
+        } else {
+            OutputEnding::Complete {
+                retries: self.retry_pass,
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::rc::Rc;
+
+use fastly::Request;
+
+// The keys of the GEO variable, in the order `$(GEO)` lists them
+const KEYS: [&str; 8] = [
+    "country_code",
+    "country_code3",
+    "country_name",
+    "region",
+    "city",
+    "lat",
+    "long",
+    "postal_code",
+];
+
+/// The geolocation of a client, which the `$(GEO{key})` variable reads from.
+///
+/// It's looked up with [`crate::Configuration::geo_resolver`], [`fastly::geo::geo_lookup`] by
+/// default, from the original request once per document, when a `$(GEO)` variable is first
+/// evaluated. It's only available when the
+/// request given to [`crate::Processor::new`] is the client request. Without it, `$(GEO{key})`
+/// evaluates to its default, as in `$(GEO{country_code}|US)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeoData {
+    /// The two-letter ISO 3166-1 code of the country, e.g. `US`.
+    pub country_code: String,
+    /// The three-letter ISO 3166-1 code of the country, e.g. `USA`.
+    pub country_code3: String,
+    /// The name of the country, e.g. `United States of America`.
+    pub country_name: String,
+    /// The ISO 3166-2 code of the country subdivision, e.g. `CA`, or empty if it's unknown.
+    pub region: String,
+    /// The name of the city, e.g. `san francisco`.
+    pub city: String,
+    /// The latitude, in degrees.
+    pub latitude: f64,
+    /// The longitude, in degrees.
+    pub longitude: f64,
+    /// The postal code, e.g. `94107`.
+    pub postal_code: String,
+}
+
+impl GeoData {
+    /// Looks up the geolocation of the client that sent `request`, or returns `None` if
+    /// `request` isn't the client request or there's no data for its address.
+    pub fn lookup(request: &Request) -> Option<Self> {
+        let geo = fastly::geo::geo_lookup(request.get_client_ip_addr()?)?;
+
+        Some(Self {
+            country_code: geo.country_code().to_string(),
+            country_code3: geo.country_code3().to_string(),
+            country_name: geo.country_name().to_string(),
+            region: geo.region().unwrap_or_default().to_string(),
+            city: geo.city().to_string(),
+            latitude: geo.latitude(),
+            longitude: geo.longitude(),
+            postal_code: geo.postal_code().to_string(),
+        })
+    }
+
+    /// Returns the value of `key`, matched ignoring ASCII case, or `None` for a key that isn't
+    /// one of `country_code`, `country_code3`, `country_name`, `region`, `city`, `lat`, `long`
+    /// and `postal_code`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let key = key.to_ascii_lowercase();
+        let value = match key.as_str() {
+            "country_code" => self.country_code.clone(),
+            "country_code3" => self.country_code3.clone(),
+            "country_name" => self.country_name.clone(),
+            "region" => self.region.clone(),
+            "city" => self.city.clone(),
+            "lat" => self.latitude.to_string(),
+            "long" => self.longitude.to_string(),
+            "postal_code" => self.postal_code.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Lists every key as `key=value`, separated with `; `, which is what `$(GEO)` evaluates to.
+impl fmt::Display for GeoData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in KEYS.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{key}={}", self.get(key).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the processor looks up the client's [`GeoData`] for the `$(GEO)` variable. The host's
+/// geolocation data by default, or any function of the original request, so that tests can
+/// control the location.
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// let resolver = esi::GeoResolver::new(|_| {
+///     Some(esi::GeoData {
+///         country_code: "US".to_string(),
+///         ..Default::default()
+///     })
+/// });
+/// let config = esi::Configuration::default().with_geo_resolver(resolver);
+/// ```
+#[derive(Clone, Default)]
+pub struct GeoResolver(Option<Rc<GeoLookup>>);
+
+type GeoLookup = dyn Fn(&Request) -> Option<GeoData>;
+
+impl GeoResolver {
+    pub fn new(lookup: impl Fn(&Request) -> Option<GeoData> + 'static) -> Self {
+        Self(Some(Rc::new(lookup)))
+    }
+
+    /// The host's geolocation data, which is the default.
+    pub fn host() -> Self {
+        Self(None)
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn lookup(&self, request: &Request) -> Option<GeoData> {
+        match &self.0 {
+            Some(lookup) => lookup(request),
+            None => GeoData::lookup(request),
+        }
+    }
+}
+
+impl fmt::Debug for GeoResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.is_host() {
+            "GeoResolver::host"
+        } else {
+            "GeoResolver"
+        })
+    }
+}
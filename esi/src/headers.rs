@@ -0,0 +1,58 @@
+use fastly::http::{header, HeaderName};
+use fastly::Response;
+
+/// How the body of a client response relates to the body of the source document it's built from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseBody {
+    /// The document was processed, so the body is composed anew and its length and encoding are
+    /// only known once it's been written.
+    Processed,
+    /// The document is sent byte for byte as it was received, for example because it contains no
+    /// ESI tags. See [`crate::may_contain_esi`].
+    Passthrough,
+}
+
+impl ResponseBody {
+    /// The headers of the source response that describe its body, and that no longer apply to a
+    /// body of this kind. They're never copied to the client response.
+    pub fn denied_headers(self) -> &'static [HeaderName] {
+        match self {
+            Self::Processed => &[
+                header::CONTENT_LENGTH,
+                header::CONTENT_ENCODING,
+                header::TRANSFER_ENCODING,
+            ],
+            // The framing of the body is decided when it's sent, whatever it is.
+            Self::Passthrough => &[header::TRANSFER_ENCODING],
+        }
+    }
+}
+
+/// Copies the headers of the source document response `from` to the client response `to`, apart
+/// from those that describe a body it won't have. Headers already set on `to` are replaced.
+///
+/// ```rust,no_run
+/// # let source = fastly::Response::new();
+/// let mut client_response = fastly::Response::new();
+/// esi::propagate_headers(&source, &mut client_response, esi::ResponseBody::Processed);
+/// ```
+pub fn propagate_headers(from: &Response, to: &mut Response, body: ResponseBody) {
+    let denied = body.denied_headers();
+    for name in from.get_header_names() {
+        if denied.contains(name) {
+            continue;
+        }
+        to.remove_header(name);
+        for value in from.get_header_all(name) {
+            to.append_header(name, value);
+        }
+    }
+}
+
+// Removes the headers that describe the body of a response, before a processed document is sent
+// as its body.
+pub(crate) fn remove_body_headers(response: &mut Response) {
+    for name in ResponseBody::Processed.denied_headers() {
+        response.remove_header(name);
+    }
+}
@@ -0,0 +1,82 @@
+use fastly::http::HeaderName;
+use fastly::Request;
+use std::fmt;
+
+/// The parts of a fragment request, beyond its method and URL, that can change the response it
+/// gets, and so take part in its [`FragmentKey`].
+///
+/// By default, no headers do. Add the headers that differ between requests for the same URL, such
+/// as a `Cookie` header that's forwarded to some fragments but not to others.
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// let spec = esi::KeySpec::default().with_header(fastly::http::header::COOKIE);
+/// let config = esi::Configuration::default().with_fragment_key(spec);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeySpec {
+    // Kept sorted and without duplicates, so that the order headers are added in doesn't matter
+    headers: Vec<HeaderName>,
+}
+
+impl KeySpec {
+    /// Makes the values of the header `name` part of the key.
+    pub fn with_header(mut self, name: HeaderName) -> Self {
+        if let Err(i) = self
+            .headers
+            .binary_search_by(|header| header.as_str().cmp(name.as_str()))
+        {
+            self.headers.insert(i, name);
+        }
+        self
+    }
+
+    /// The headers that are part of the key, in order of name.
+    pub fn headers(&self) -> &[HeaderName] {
+        &self.headers
+    }
+}
+
+/// The identity of a fragment request: requests with the same key get the same response.
+///
+/// Fragment requests are deduplicated by key. The key is a string made of the method and URL of
+/// the request, followed by the values of the headers listed in the [`KeySpec`], so that it can be
+/// logged as it is.
+///
+/// ```
+/// let spec = esi::KeySpec::default().with_header(fastly::http::header::COOKIE);
+/// let request = fastly::Request::get("https://example.com/a").with_header("cookie", "uid=1");
+/// assert_eq!(
+///     esi::FragmentKey::compute(&request, &spec).as_str(),
+///     "GET https://example.com/a\ncookie: uid=1"
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FragmentKey(String);
+
+impl FragmentKey {
+    /// Computes the key of `request`. A header that's missing is told apart from one that's
+    /// empty, and a header sent several times is keyed by all of its values, in order.
+    pub fn compute(request: &Request, spec: &KeySpec) -> Self {
+        let mut key = format!("{} {}", request.get_method_str(), request.get_url_str());
+        for name in &spec.headers {
+            key.push('\n');
+            key.push_str(name.as_str());
+            for (i, value) in request.get_header_all(name).enumerate() {
+                key.push_str(if i == 0 { ": " } else { ", " });
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        Self(key)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for FragmentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
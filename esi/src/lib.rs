@@ -1,563 +1,2735 @@
 #![doc = include_str!("../../README.md")]
 
+pub mod attributes;
+mod buffers;
+mod chain;
+mod clock;
+#[cfg(feature = "compat")]
+pub mod compat;
 mod config;
+mod context;
+mod copy;
 mod document;
 mod error;
+mod etag;
+mod expression;
+pub mod failure;
+mod finalize;
+mod geo;
+mod headers;
+mod key;
+mod markers;
+pub mod metrics;
+mod output;
 mod parse;
+mod prefetch;
+mod probe;
+mod report;
+mod request;
+mod retry;
+mod scheduling;
+mod scheme;
+mod scratch;
+#[cfg(feature = "serde")]
+mod serialize;
+mod session;
+mod source;
+mod status;
+mod surrogate;
+mod timings;
+mod utf8;
+mod validate;
+mod warning;
 
-use document::{PollTaskState, Task};
-use fastly::http::request::PendingRequest;
-use fastly::http::{header, Method, StatusCode, Url};
+use attributes::{AcceptStatus, AddHeader, Critical, ForwardHeaders, Headers, NoStore, Raw};
+use buffers::BufferAccountant;
+use chain::IncludeChain;
+use document::{
+    for_each_content, FragmentContent, FragmentFailure, PollContentResult, PollTaskState,
+    SharedFragment, SharedFragments, Task, VisitContents,
+};
+use etag::{if_none_match, HashingWriter, OutputHasher};
+use expression::{
+    debug_comment, escape_json, evaluate_attribute_templates, evaluate_test, process_symbols,
+    process_vars_block, EvaluationRequest,
+};
+use failure::{resolve_fragment_failure, FailureMechanisms, FailureResolution};
+use fastly::http::{header, HeaderName, HeaderValue, Method, StatusCode};
 use fastly::{mime, Body, Request, Response};
-use log::{debug, error, trace};
+use finalize::Limit;
+use headers::remove_body_headers;
+use log::{debug, error, log_enabled, trace, Level};
+use markers::{FragmentMarkers, PendingBoundary};
+use output::{
+    BufferedResponse, ClientResponseWriter, CountingWriter, OutputCursor, PendingClientResponse,
+};
+use parse::{parse_tags_until, ParseOptions};
+use report::{IncludeLog, IncludeSlot};
+use request::{build_fragment_request, decode_data_uri, DeferredRequest, UrlPolicy};
+use retry::RetryQueue;
+use scheduling::{EventBudget, Scheduler};
+use source::{check_blank, record_source, SourceLines, SourceStart};
+use status::StatusPolicy;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::time::Instant;
+use timings::{CallbackTimer, PhaseHandler, PhaseRecorder};
+use utf8::Utf8Body;
+use validate::OutputValidator;
+use warning::Warnings;
 
-pub use crate::document::{Element, Fragment};
+pub use crate::clock::Clock;
+pub use crate::context::FragmentContext;
+pub use crate::copy::{copy_fragment_body, CopyBudget, CopyOutcome};
+pub use crate::document::{Element, Fragment, PendingFragmentContent};
 pub use crate::error::Result;
-pub use crate::parse::{parse_tags, Event, Include, Tag, Tag::Try};
+pub use crate::finalize::{OutputConditions, OutputEnding};
+pub use crate::geo::{GeoData, GeoResolver};
+pub use crate::headers::{propagate_headers, ResponseBody};
+pub use crate::key::{FragmentKey, KeySpec};
+pub use crate::markers::{BoundarySink, FragmentBoundary};
+pub use crate::parse::{
+    parse_tags, Event, EventStream, GroupErrorPolicy, Include, Tag, Tag::Try, TryArm, TryArmKind,
+};
+pub use crate::prefetch::FragmentCache;
+pub use crate::probe::{may_contain_esi, NamespaceProbe};
+pub use crate::report::{
+    AbandonedFragment, BytesWritten, IncludeOutcome, IncludeReport, PrefetchReport,
+    ProcessingOutcome, ProcessingReport,
+};
+pub use crate::scheduling::{SchedulingStats, EVENT_BUDGET_CHECK_INTERVAL};
+pub use crate::scheme::{SchemeHandler, SchemeTarget};
+pub use crate::scratch::ProcessorScratch;
+pub use crate::session::{ProcessingSession, Progress};
+pub use crate::status::StatusCodes;
+pub use crate::surrogate::{SURROGATE_CAPABILITY_HEADER, SURROGATE_CONTROL_HEADER};
+pub use crate::timings::{CallbackKind, CallbackTime, CallbackTimings, Phase, PhaseTimings};
+pub use crate::warning::Warning;
 
-pub use crate::config::Configuration;
-pub use crate::error::ExecutionError;
+pub use crate::config::{
+    ComplianceLevel, ConfigDescription, Configuration, DebugHeader, ForwardHeadersOverride,
+    HeaderForwarding, InsecureFragmentPolicy, MarkerStyle, OutputMode, OversizedDocumentPolicy,
+    ReaderOptions, RetryPass, Scheduling, Utf8Policy,
+};
+pub use crate::error::{ExecutionError, FailurePhase};
 
 // re-export quick_xml Reader and Writer
 pub use quick_xml::{Reader, Writer};
 
-type FragmentRequestDispatcher = dyn Fn(Request) -> Result<Option<PendingRequest>>;
+/// The header set on the requests for an include taken from a `srcset` attribute, holding the
+/// position of its URL in the attribute, starting from 0. Requests for the include's `alt` URL
+/// have it too.
+pub const SRCSET_INDEX_HEADER: &str = "X-ESI-Srcset-Index";
+
+/// The header set on fragment requests that only some headers of the original request were
+/// copied to, listing the names of those copied, in lowercase and separated by spaces. See
+/// [`HeaderForwarding`].
+pub const FORWARDED_HEADERS_HEADER: &str = "X-ESI-Forwarded-Headers";
+
+/// The header set on the requests made by a [`RetryPass`], holding the number of the slot that
+/// the content of the retried include is written to.
+pub const RETRY_HEADER: &str = "X-ESI-Retry";
+
+/// The header set on fragment requests with [`Configuration::max_include_depth`], holding the
+/// URLs of the documents the fragment is included from, starting with the first, followed by its
+/// own, separated by spaces. A processor whose original request already has it carries on from
+/// that chain, so it should be removed from client requests.
+pub const INCLUDE_CHAIN_HEADER: &str = "X-ESI-Include-Chain";
+
+/// The header set on the `503 Service Unavailable` that stands in for the response to a fragment
+/// request failed with [`PendingFragmentContent::FailFast`], holding the reason. Responses with it
+/// always count as a failure.
+pub const FAIL_FAST_HEADER: &str = "X-ESI-Fail-Fast";
+
+/// The header set on fragment requests whose URL was resolved by a [`SchemeHandler`] that named
+/// a backend for it, holding the name of that backend.
+pub const BACKEND_HINT_HEADER: &str = "X-ESI-Backend";
+
+// How many parsed events go by between progress summaries in the debug log
+const EVENT_SUMMARY_INTERVAL: usize = 1024;
+
+/// Written after the part of a fragment body that was streamed to the client before reading the
+/// rest of it failed, or before it went over [`Configuration::max_fragment_size`], since what was
+/// written can't be taken back. Inside `esi:try` and `esi:group` blocks, the partial body is
+/// dropped instead.
+pub const TRUNCATED_FRAGMENT_MARKER: &str = "<!-- esi: fragment body truncated -->";
+
+type FragmentRequestDispatcher<'a> = dyn Fn(Request) -> Result<PendingFragmentContent> + 'a;
 
 type FragmentResponseProcessor = dyn Fn(&mut Request, Response) -> Result<Response>;
 
+type FragmentContextProcessor<'a> =
+    dyn Fn(&FragmentContext, &mut Request, Response) -> Result<Response> + 'a;
+
+type WarningHandler = dyn Fn(&Warning);
+
 /// An instance of the ESI processor with a given configuration.
+///
+/// Fragments are written in document order. While one is waited on, the other pending requests
+/// of the document are waited on along with it, so responses that arrive early are kept until
+/// their fragment is reached. Fragment bodies are copied to the output in chunks as they're
+/// received, flushing it every few chunks, and only the content of `esi:try` and `esi:group`
+/// blocks is held in memory until the block is written.
 pub struct Processor {
     // The original client request metadata, if any.
     original_request_metadata: Option<Request>,
     // The configuration for the processor.
     configuration: Configuration,
+    // The number of bytes written to the output so far, and whether writing to it has failed.
+    outcome: ProcessingOutcome,
+    // Called with every warning as it's raised
+    on_warning: Option<Rc<WarningHandler>>,
+    // Called with every phase of processing as it's reached
+    on_phase: Option<Rc<PhaseHandler>>,
+    // Where the queue and buffers are taken from and given back to
+    scratch: ProcessorScratch,
 }
 
 impl Processor {
-    pub const fn new(
-        original_request_metadata: Option<Request>,
-        configuration: Configuration,
-    ) -> Self {
+    pub fn new(original_request_metadata: Option<Request>, configuration: Configuration) -> Self {
         Self {
             original_request_metadata,
             configuration,
+            outcome: ProcessingOutcome::default(),
+            on_warning: None,
+            on_phase: None,
+            scratch: ProcessorScratch::default(),
+        }
+    }
+
+    /// Reuses the queue and buffers in `scratch` for processing, instead of allocating them. See
+    /// [`ProcessorScratch`].
+    #[must_use]
+    pub fn with_scratch(mut self, scratch: &ProcessorScratch) -> Self {
+        self.scratch = scratch.clone();
+        self
+    }
+
+    /// Calls `on_warning` with every [`Warning`] raised while processing, as soon as it's raised.
+    ///
+    /// Unlike [`ProcessingReport::warnings`], this isn't limited by
+    /// [`Configuration::max_warnings`].
+    #[must_use]
+    pub fn on_warning(mut self, on_warning: impl Fn(&Warning) + 'static) -> Self {
+        self.on_warning = Some(Rc::new(on_warning));
+        self
+    }
+
+    /// Calls `on_phase` with every [`Phase`] of processing as it's reached, for example to send
+    /// early hints once the first fragment is dispatched. When each was reached is in
+    /// [`ProcessingReport::phases`].
+    #[must_use]
+    pub fn on_phase(mut self, on_phase: impl Fn(Phase) + 'static) -> Self {
+        self.on_phase = Some(Rc::new(on_phase));
+        self
+    }
+
+    /// Returns a handle to the number of bytes this processor has written to its output.
+    ///
+    /// The handle can be moved into the fragment callbacks to make decisions based on whether
+    /// any content has been sent yet.
+    pub fn bytes_written(&self) -> BytesWritten {
+        self.outcome.counter().clone()
+    }
+
+    /// Returns a handle to the state this processor leaves its output in, which says whether a
+    /// caller-provided writer can still be written to after processing fails.
+    pub fn outcome(&self) -> ProcessingOutcome {
+        self.outcome.clone()
+    }
+
+    /// Returns a reader for `body` that parses it with [`Configuration::reader_options`], the same
+    /// way [`Self::process_response`] does, for passing to [`Self::process_document`].
+    pub fn reader_with_config(&self, body: Body) -> Reader<Body> {
+        reader_from_body(body, &self.configuration.reader_options)
+    }
+
+    /// Process a response body as an ESI document. Consumes the response body.
+    ///
+    /// The client response headers are sent when the first non-whitespace content is written, so
+    /// an `<esi:response>` tag before that point can still set the status and headers. If an
+    /// error occurs before then, nothing has been sent to the client.
+    ///
+    /// Headers that describe the source body, such as `Content-Length`, are removed from the
+    /// client response before it's sent, however they were set. See [`ResponseBody::Processed`].
+    pub fn process_response(
+        self,
+        src_document: &mut Response,
+        client_response_metadata: Option<Response>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<ProcessingReport> {
+        self.process_response_with_context(
+            src_document,
+            client_response_metadata,
+            dispatch_fragment_request,
+            ignore_context(process_fragment_response).as_deref(),
+        )
+    }
+
+    /// Like [`Self::process_response`], except that the fragment response processor is also
+    /// given the [`FragmentContext`] of each fragment: where it comes from in the document, and
+    /// whether it's for an `alt` URL or a retry.
+    pub fn process_response_with_context(
+        self,
+        src_document: &mut Response,
+        client_response_metadata: Option<Response>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+    ) -> Result<ProcessingReport> {
+        // Send the response headers to the client and open an output stream once there's content.
+        let (report, output_writer) = self.process_into_client_response(
+            src_document,
+            client_response_metadata,
+            Response::stream_to_client,
+            false,
+            dispatch_fragment_request,
+            process_fragment_response,
+        )?;
+        output_writer.finish()?;
+        Ok(report)
+    }
+
+    /// Process a response body as an ESI document, returning the client response with the whole
+    /// processed document as its body instead of streaming it. Consumes the response body.
+    ///
+    /// Like with [`Processor::process_response`], an `<esi:response>` tag before any
+    /// non-whitespace content sets the status and headers of the client response. If
+    /// [`Configuration::debug_header`] is set, the outcome of the includes is added in an
+    /// `X-ESI-Debug` header.
+    ///
+    /// With [`Configuration::compute_etag`], the `ETag` header is set to the hash of the body, and
+    /// if the original request has an `If-None-Match` header that matches it, a `200 OK`
+    /// response is turned into a `304 Not Modified` one without a body.
+    ///
+    /// A response with an empty body is given a `Content-Length: 0` header.
+    pub fn process_to_response(
+        self,
+        src_document: &mut Response,
+        client_response_metadata: Option<Response>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<(Response, ProcessingReport)> {
+        let debug_header = self.configuration.debug_header;
+        let conditional = self
+            .original_request_metadata
+            .as_ref()
+            .and_then(|req| req.get_header_str(header::IF_NONE_MATCH))
+            .map(str::to_string);
+        let (report, BufferedResponse(mut resp)) = self.process_into_client_response(
+            src_document,
+            client_response_metadata,
+            BufferedResponse,
+            true,
+            dispatch_fragment_request,
+            ignore_context(process_fragment_response).as_deref(),
+        )?;
+
+        if let Some(etag) = &report.etag {
+            resp.set_header(header::ETAG, etag);
+            if resp.get_status() == StatusCode::OK
+                && conditional.is_some_and(|conditional| if_none_match(&conditional, etag))
+            {
+                debug!("composed document matches {etag}, not modified");
+                resp.set_status(StatusCode::NOT_MODIFIED);
+                resp.take_body();
+            }
+        }
+
+        if let Some(debug_header) = debug_header {
+            let value = match debug_header {
+                DebugHeader::Summary => report.to_debug_header(),
+                DebugHeader::Verbose => report.to_verbose_debug_header(),
+            };
+            resp.set_header(ProcessingReport::DEBUG_HEADER_NAME, value);
+        }
+        if report.bytes_written == 0 && resp.get_status() != StatusCode::NOT_MODIFIED {
+            resp.set_header(header::CONTENT_LENGTH, "0");
+        }
+        Ok((resp, report))
+    }
+
+    /// Process a response body as an ESI document, returning the processed document as a body
+    /// without sending anything to the client, so that it can be post-processed first, for
+    /// example minified or stored in a cache. Consumes the response body.
+    ///
+    /// The document is processed like with [`Processor::process_to_response`], except that there
+    /// is no client response: an `<esi:response>` tag has nothing to set and raises a
+    /// [`Warning::TooLate`], and [`Configuration::debug_header`] doesn't apply. With
+    /// [`Configuration::compute_etag`], the hash of the body is in [`ProcessingReport::etag`].
+    pub fn process_to_body(
+        self,
+        src_document: &mut Response,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<(Body, ProcessingReport)> {
+        let mut xml_writer = Writer::new(Vec::new());
+        let reader = self.reader_with_config(src_document.take_body());
+        let report = self
+            .process(
+                reader,
+                &mut xml_writer,
+                dispatch_fragment_request,
+                ignore_context(process_fragment_response).as_deref(),
+                false,
+                true,
+                None,
+            )
+            .inspect_err(|err| error!("error processing ESI document: {}", err))?;
+        Ok((Body::from(xml_writer.into_inner()), report))
+    }
+
+    fn process_into_client_response<W: Write>(
+        self,
+        src_document: &mut Response,
+        client_response_metadata: Option<Response>,
+        open_body: impl FnOnce(Response) -> W,
+        buffered: bool,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+    ) -> Result<(ProcessingReport, W)> {
+        // Create a response to send the headers to the client. `Surrogate-Control` is meant for
+        // the processor, so it isn't passed on.
+        let mut resp = client_response_metadata.unwrap_or_else(|| {
+            Response::from_status(StatusCode::OK).with_content_type(mime::TEXT_HTML)
+        });
+        resp.remove_header(SURROGATE_CONTROL_HEADER);
+
+        if !self.is_processing_requested(src_document) {
+            debug!("processing not requested by Surrogate-Control, passing the document through");
+            let mut output = open_body(resp);
+            let bytes_written = std::io::copy(&mut src_document.take_body(), &mut output)?;
+            let report = ProcessingReport {
+                bytes_written: usize::try_from(bytes_written).unwrap_or(usize::MAX),
+                ..ProcessingReport::default()
+            };
+            return Ok((report, output));
+        }
+        let client_response = Rc::new(RefCell::new(Some(resp)));
+
+        // Set up an XML writer to write to the client response body once it's opened. Whatever
+        // headers the client response was given, the body is the processed document, so any that
+        // describe another body are dropped when it's opened.
+        let mut xml_writer = Writer::new(ClientResponseWriter::new(
+            client_response.clone(),
+            |mut resp| {
+                remove_body_headers(&mut resp);
+                open_body(resp)
+            },
+        ));
+
+        let reader = self.reader_with_config(src_document.take_body());
+        match self.process(
+            reader,
+            &mut xml_writer,
+            dispatch_fragment_request,
+            process_fragment_response,
+            false,
+            buffered,
+            Some(client_response),
+        ) {
+            Ok(report) => Ok((report, xml_writer.into_inner().into_body()?)),
+            Err(err) => {
+                error!("error processing ESI document: {}", err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Process an ESI document from a [`quick_xml::Reader`].
+    ///
+    /// If processing fails, `output_writer` is flushed and left at the boundary of an event, with
+    /// the content before it written. The application can then finish it off with content of its
+    /// own, such as an error message, if [`ProcessingOutcome::recovery_write_allowed`] on
+    /// [`Processor::outcome`] says the writer still works. Nothing else is written to it by the
+    /// processor.
+    pub fn process_document(
+        self,
+        src_document: Reader<impl BufRead>,
+        output_writer: &mut Writer<impl Write>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<ProcessingReport> {
+        self.process_document_with_context(
+            src_document,
+            output_writer,
+            dispatch_fragment_request,
+            ignore_context(process_fragment_response).as_deref(),
+        )
+    }
+
+    /// Like [`Self::process_document`], except that the fragment response processor is also
+    /// given the [`FragmentContext`] of each fragment: where it comes from in the document, and
+    /// whether it's for an `alt` URL or a retry.
+    pub fn process_document_with_context(
+        self,
+        src_document: Reader<impl BufRead>,
+        output_writer: &mut Writer<impl Write>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+    ) -> Result<ProcessingReport> {
+        self.process(
+            src_document,
+            output_writer,
+            dispatch_fragment_request,
+            process_fragment_response,
+            false,
+            false,
+            None,
+        )
+    }
+
+    /// Process an ESI document from a [`quick_xml::Reader`], making sure that every include
+    /// marked with `critical="true"` succeeds before anything is written to `output_writer`.
+    ///
+    /// The whole document is parsed and all fragments are dispatched up front. If a critical
+    /// fragment fails (after trying its `alt`, if any), [`ExecutionError::CriticalFragmentFailed`]
+    /// is returned and the writer is left untouched, so the application can fall back to other
+    /// content such as a previously cached page. Critical includes ignore `onerror="continue"`.
+    /// Only includes outside of `esi:try` blocks can be critical.
+    pub fn process_with_validation(
+        self,
+        src_document: Reader<impl BufRead>,
+        output_writer: &mut Writer<impl Write>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentResponseProcessor>,
+    ) -> Result<ProcessingReport> {
+        self.process(
+            src_document,
+            output_writer,
+            dispatch_fragment_request,
+            ignore_context(process_fragment_response).as_deref(),
+            true,
+            true,
+            None,
+        )
+    }
+
+    /// Start processing an ESI document from a [`quick_xml::Reader`] one step at a time, for
+    /// applications that wait on fragment requests themselves. See [`ProcessingSession`].
+    pub fn session<'a, R: BufRead>(
+        self,
+        src_document: Reader<R>,
+        dispatch_fragment_request: Option<&'a FragmentRequestDispatcher>,
+        process_fragment_response: Option<&'a FragmentResponseProcessor>,
+    ) -> ProcessingSession<'a, R> {
+        let mut ctx = self.fragment_request_context(dispatch_fragment_request);
+        // A session's output is written as it's produced.
+        ctx.cursor = OutputCursor::new(None, true);
+        if self.configuration.compute_etag {
+            // The session can't fail yet, so in strict mode the error is raised as it starts.
+            if let Err(err) =
+                ctx.check_prestream(ctx.cursor.require_complete_output("compute_etag", 0))
+            {
+                ctx.warnings.fail_later(err);
+            }
+        }
+        ProcessingSession::new(
+            src_document,
+            self.configuration.namespace,
+            ctx,
+            ignore_context(process_fragment_response),
+            self.outcome,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process(
+        self,
+        src_document: Reader<impl BufRead>,
+        output_writer: &mut Writer<impl Write>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+        validate_critical: bool,
+        buffered: bool,
+        client_response: Option<PendingClientResponse>,
+    ) -> Result<ProcessingReport> {
+        let started = Instant::now();
+
+        // A document that's empty or only whitespace is copied as it is, without setting up
+        // anything to process it.
+        let src_document = match check_blank(src_document) {
+            Ok(SourceStart::Content(src_document)) => src_document,
+            Ok(SourceStart::Blank(whitespace)) => {
+                let result = self.copy_blank(&whitespace, output_writer, buffered, started);
+                metrics::document(&result, started.elapsed());
+                return result;
+            }
+            Err(err) => {
+                let result = Err(quick_xml::Error::from(err).into());
+                metrics::document(&result, started.elapsed());
+                return result;
+            }
+        };
+
+        let outcome = self.outcome.clone();
+        let scratch = self.scratch.clone();
+        let poll_abandoned = self.configuration.poll_abandoned;
+
+        // Set up the queue of document elements to be sent to the client.
+        let mut document = DocumentState::new(&scratch);
+        let result = self.run(
+            &mut document,
+            src_document,
+            output_writer,
+            dispatch_fragment_request,
+            process_fragment_response,
+            validate_critical,
+            buffered,
+            client_response,
+            started,
+        );
+        if result.is_err() {
+            // Whatever is still queued was dispatched, but will never be written.
+            outcome.record_abandoned(document.abandon(poll_abandoned));
+            // Leave the output at a clean boundary for whatever the caller writes next.
+            let _ = CountingWriter::new(output_writer.get_mut(), outcome).flush();
+        }
+        document.release(&scratch);
+        metrics::document(&result, started.elapsed());
+        result
+    }
+
+    // Writes a blank document to the output. None of the callbacks are called, and the report
+    // only has the bytes written, and the ETag if the output is buffered and one is wanted.
+    fn copy_blank(
+        self,
+        whitespace: &[u8],
+        output_writer: &mut Writer<impl Write>,
+        buffered: bool,
+        started: Instant,
+    ) -> Result<ProcessingReport> {
+        debug!("document is blank, copying {} bytes", whitespace.len());
+        let hasher = (self.configuration.compute_etag && buffered).then(OutputHasher::new);
+        let mut output = HashingWriter::new(
+            CountingWriter::new(output_writer.get_mut(), self.outcome.clone()),
+            hasher,
+        );
+        output.write_all(whitespace).and_then(|()| output.flush())?;
+
+        Ok(ProcessingReport {
+            bytes_written: self.outcome.bytes_written(),
+            etag: output.etag(),
+            elapsed: started.elapsed(),
+            config_hash: self.configuration.describe().hash(),
+            ..ProcessingReport::default()
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        self,
+        document: &mut DocumentState,
+        src_document: Reader<impl BufRead>,
+        output_writer: &mut Writer<impl Write>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+        validate_critical: bool,
+        buffered: bool,
+        client_response: Option<PendingClientResponse>,
+        started: Instant,
+    ) -> Result<ProcessingReport> {
+        let mut ctx = self.fragment_request_context(dispatch_fragment_request);
+        // The debug header is only added to a client response that's sent once it's complete.
+        if self.configuration.debug_header.is_some() && client_response.is_some() && !buffered {
+            ctx.check_prestream(
+                OutputCursor::new(None, true).require_complete_output("debug_header", 0),
+            )?;
+        }
+        ctx.cursor = OutputCursor::new(client_response, !buffered);
+        let (mut src_document, source) = record_source(
+            src_document,
+            ctx.parse_options.raw_passthrough,
+            ctx.parse_options.lines.clone(),
+        );
+
+        // Only output that's complete before it's sent can be given an ETag.
+        let hasher = match self.configuration.compute_etag
+            && ctx.check_prestream(ctx.cursor.require_complete_output("compute_etag", 0))?
+        {
+            true => Some(OutputHasher::new()),
+            false => None,
+        };
+
+        // Count everything written to the output, whether it's source content or a fragment.
+        let output = &mut HashingWriter::new(
+            CountingWriter::new(output_writer.get_mut(), self.outcome.clone())
+                .with_phases(ctx.phases.clone())
+                .with_validator(ctx.validator.clone()),
+            hasher,
+        );
+
+        // Begin parsing the source document
+        let parsed = parse_tags_until(
+            &self.configuration.namespace,
+            &mut src_document,
+            &mut |event| {
+                document.handle_event(
+                    event,
+                    &ctx,
+                    output,
+                    process_fragment_response,
+                    validate_critical,
+                )
+            },
+            &|| false,
+            &ctx.warnings,
+            ctx.parse_options.clone(),
+            source.as_ref(),
+        );
+        ctx.phases.reach(Phase::ParseComplete);
+        let (mut limit, mut failure) = match ctx.cut_short(parsed) {
+            Ok(limit) => (limit, None),
+            Err(err) => (None, Some(err)),
+        };
+        debug!("parsed {} events", document.event_count);
+
+        let mut abandoned = Vec::new();
+        if validate_critical && failure.is_none() {
+            failure = resolve_critical_fragments(
+                &mut document.elements,
+                ctx.dispatch_fragment_request,
+                process_fragment_response,
+                &ctx.copy_budget,
+                &ctx.scratch,
+                &ctx.buffers,
+            )
+            .err();
+        }
+
+        // Wait for any pending requests to complete, then end the output, which may first queue
+        // the retries of the includes that were skipped.
+        let mut retrying = false;
+        loop {
+            if failure.is_none() && !document.elements.is_empty() {
+                let polled = poll_queue(
+                    &mut document.elements,
+                    output,
+                    ctx.dispatch_fragment_request,
+                    process_fragment_response,
+                    &ctx.copy_budget,
+                    &ctx.scratch,
+                    &ctx.buffers,
+                    true,
+                );
+                match ctx.cut_short(polled) {
+                    Ok(None) => {}
+                    Ok(Some(cut)) => {
+                        // Out of time, so whatever is still queued is dropped. The document was
+                        // already complete if it's the retries.
+                        abandoned = document.abandon(ctx.poll_abandoned);
+                        if !retrying {
+                            limit = Some(cut);
+                        }
+                    }
+                    Err(err) => failure = Some(err),
+                }
+                continue;
+            }
+            retrying = ctx.finalize_output(
+                limit,
+                failure.take(),
+                &self.outcome,
+                &mut document.elements,
+                output,
+            )?;
+            if !retrying {
+                break;
+            }
+        }
+
+        if let Some(validator) = &ctx.validator {
+            validator.finish();
+        }
+        let phases = ctx.phases.finish();
+        let callbacks = ctx.warnings.callback_timings();
+        let (warnings, warnings_dropped) = ctx.warnings.take();
+        Ok(ProcessingReport {
+            bytes_written: self.outcome.bytes_written(),
+            includes: ctx.include_log.take(),
+            warnings,
+            warnings_dropped,
+            truncated: limit.is_some(),
+            etag: output.etag(),
+            peak_buffered_bytes: ctx.buffers.peak_buffered_bytes(),
+            peak_queue_len: ctx.buffers.peak_queue_len(),
+            scheduling: document.scheduler.stats(),
+            events_parsed: ctx.parse_options.events.parsed(),
+            phases,
+            callbacks,
+            elapsed: started.elapsed(),
+            config_hash: ctx.config_hash,
+            abandoned,
+        })
+    }
+
+    fn fragment_request_context<'a>(
+        &self,
+        dispatch_fragment_request: Option<&'a FragmentRequestDispatcher>,
+    ) -> FragmentRequestContext<'a> {
+        // If there is a source request to mimic, copy its metadata, otherwise use a default request.
+        let original_request_metadata =
+            Rc::new(self.original_request_metadata.as_ref().map_or_else(
+                || Request::new(Method::GET, "http://localhost"),
+                Request::clone_without_body,
+            ));
+
+        let include_chain = self
+            .configuration
+            .max_include_depth
+            .map(|max_depth| IncludeChain::new(&original_request_metadata, max_depth));
+
+        let warnings = Warnings::new(
+            self.configuration.max_warnings,
+            self.on_warning.clone(),
+            self.configuration.spec_compliance,
+            CallbackTimer::new(
+                self.configuration.clock.clone(),
+                self.configuration.callback_overrun_threshold,
+            ),
+        );
+        let on_phase = self.on_phase.clone().map(|on_phase| {
+            let warnings = warnings.clone();
+            Rc::new(move |phase| {
+                warnings.time_callback(CallbackKind::PhaseHandler, warnings.position(), || {
+                    on_phase(phase);
+                });
+            }) as Rc<PhaseHandler>
+        });
+        let phases = PhaseRecorder::start(self.configuration.clock.clone(), on_phase);
+        let fragment_markers = self.configuration.fragment_markers.clone().map(|style| {
+            FragmentMarkers::new(match style {
+                MarkerStyle::Callback(sink) => MarkerStyle::Callback(sink.timed(&warnings)),
+                style => style,
+            })
+        });
+        let validator = self
+            .configuration
+            .debug_validate_output
+            .then(|| OutputValidator::new(&self.configuration.namespace, warnings.clone()));
+
+        FragmentRequestContext {
+            variables: EvaluationRequest::new(
+                Rc::clone(&original_request_metadata),
+                self.configuration.geo_resolver.clone(),
+            ),
+            original_request_metadata,
+            url_policy: UrlPolicy::from_configuration(&self.configuration),
+            config_hash: self.configuration.describe().hash(),
+            // Use the provided fragment request dispatcher, or fall back to the default
+            dispatch_fragment_request: dispatch_fragment_request
+                .unwrap_or(&default_dispatch_fragment_request),
+            trace_events: self.configuration.trace_events && log_enabled!(Level::Debug),
+            namespace: self.configuration.namespace.clone(),
+            attribute_template_prefix: format!("{}:attr-", self.configuration.namespace)
+                .into_bytes(),
+            cursor: OutputCursor::default(),
+            strict_prestream: self.configuration.strict_prestream,
+            include_log: IncludeLog::new(phases.clone(), warnings.clone()),
+            phases,
+            validator,
+            status_policy: Rc::new(StatusPolicy {
+                accept: self.configuration.accept_status.clone(),
+                empty_body: self.configuration.empty_body_status.clone(),
+                utf8: self.configuration.validate_fragment_utf8,
+                error_body_snippet: self.configuration.error_body_snippet,
+                timeout: self.configuration.fragment_timeout,
+            }),
+            warnings,
+            parse_options: ParseOptions {
+                max_document_size: self.configuration.max_document_size,
+                events: EventBudget::new(self.configuration.max_events),
+                max_attribute_length: Some(self.configuration.max_attribute_length),
+                process_in_comments: self.configuration.process_in_comments,
+                debug_comments: self.configuration.debug_comments,
+                unescape_urls: self.configuration.is_escaped,
+                raw_passthrough: self.configuration.output_mode == OutputMode::RawPassthrough,
+                strict_markup: self.configuration.reader_options.strict,
+                strict_tags: self.configuration.strict_mode,
+                lines: Some(SourceLines::default()),
+            },
+            oversized_document: self.configuration.oversized_document.clone(),
+            copy_budget: {
+                let budget = match self.configuration.deadline {
+                    Some(deadline) => CopyBudget::new().with_deadline(Instant::now() + deadline),
+                    None => CopyBudget::new(),
+                };
+                match self.configuration.max_fragment_size {
+                    Some(max) => budget.with_max_body_size(max),
+                    None => budget,
+                }
+            },
+            poll_abandoned: self.configuration.poll_abandoned,
+            scheduling: self.configuration.scheduling,
+            retry_pass: self.configuration.retry_pass,
+            retries: RetryQueue::default(),
+            fragment_markers,
+            include_chain,
+            scratch: self.scratch.clone(),
+            buffers: BufferAccountant::default(),
+            arm: RefCell::default(),
+            next_index: Cell::default(),
+            max_fragments: self.configuration.max_fragments,
+            shared_fragments: self
+                .configuration
+                .fragment_deduplication
+                .then(|| SharedFragments::new(&self.configuration.fragment_key)),
+        }
+    }
+}
+
+// Adapts a fragment response processor to the form that's given the context of the fragment,
+// which it ignores.
+fn ignore_context(
+    process_fragment_response: Option<&FragmentResponseProcessor>,
+) -> Option<Box<FragmentContextProcessor<'_>>> {
+    process_fragment_response.map(|process| {
+        Box::new(move |_: &FragmentContext, req: &mut Request, resp| process(req, resp)) as Box<_>
+    })
+}
+
+// The default fragment request dispatcher, which sends requests to a backend named after the host
+fn default_dispatch_fragment_request(req: Request) -> Result<PendingFragmentContent> {
+    debug!("no dispatch method configured, defaulting to hostname");
+    let backend = match req.get_url().host() {
+        Some(host) => host.to_string(),
+        None => {
+            return Err(ExecutionError::InvalidRequestUrl(
+                req.get_url_str().to_string(),
+            ))
+        }
+    };
+    let pending_req = req.send_async(backend)?;
+    Ok(PendingFragmentContent::PendingRequest(pending_req))
+}
+
+// The queue of document elements waiting to be sent to the client, as the document is parsed
+struct DocumentState {
+    elements: VecDeque<Element>,
+    // Per-event logging is only done when tracing, otherwise we log a summary every so often
+    // and whenever we switch between streaming and buffering.
+    event_count: usize,
+    buffering: bool,
+    // Where each streamed event is serialized before it's written out
+    event_bytes: Vec<u8>,
+    // When the fragments that complete while parsing are written out
+    scheduler: Scheduler,
+}
+
+impl DocumentState {
+    fn new(scratch: &ProcessorScratch) -> Self {
+        Self {
+            elements: scratch.take_queue(),
+            event_count: 0,
+            buffering: false,
+            event_bytes: scratch.buffer(),
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    // Empties the queue, returning the fragments in it that were dispatched but never resolved,
+    // including those in `esi:try` arms and `esi:group` blocks. With `poll`, each is polled once
+    // more without blocking, so that those that have completed are reported with their status.
+    fn abandon(&mut self, poll: bool) -> Vec<AbandonedFragment> {
+        let mut abandoned = Vec::new();
+        abandon_elements(&mut self.elements, poll, &mut abandoned);
+        if !abandoned.is_empty() {
+            debug!("abandoned {} pending fragments", abandoned.len());
+        }
+        abandoned
+    }
+
+    // Gives the queue and buffer back to `scratch` for the next document.
+    fn release(&mut self, scratch: &ProcessorScratch) {
+        scratch.reclaim_queue(std::mem::take(&mut self.elements));
+        scratch.recycle(std::mem::take(&mut self.event_bytes));
+    }
+
+    // Handles a top-level event from the source document, either dispatching the fragment
+    // requests it needs, streaming it to the output, or queueing it behind pending fragments.
+    fn handle_event(
+        &mut self,
+        event: Event,
+        ctx: &FragmentRequestContext,
+        output: &mut impl Write,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+        validate_critical: bool,
+    ) -> Result<()> {
+        ctx.warnings.check_compliance()?;
+        self.event_count += 1;
+        self.scheduler.event_parsed();
+        if ctx.trace_events {
+            debug!("got {:?}", event);
+        } else if self.event_count.is_multiple_of(EVENT_SUMMARY_INTERVAL) {
+            debug!(
+                "parsed {} events, {} elements queued",
+                self.event_count,
+                self.elements.len()
+            );
+        }
+        match event {
+            Event::ESI(Tag::Include(include)) => {
+                let critical = include.get::<Critical>().is_some_and(|critical| critical.0);
+                if let Some(mut element) = ctx.dispatch_include(include, false)? {
+                    if let Element::Include(fragment) = &mut element {
+                        fragment.critical = critical;
+                    }
+                    self.elements.push_back(element);
+                }
+            }
+            Event::ESI(Tag::Try { arms }) => {
+                let arms = parse_arms(arms, ctx, false)?;
+
+                // push the elements
+                self.elements.push_back(Element::Try { arms });
+            }
+            Event::ESI(Tag::Group {
+                name,
+                onerror,
+                events,
+            }) => {
+                let task = parse_task(events, ctx, "group", false)?;
+                self.elements.push_back(Element::Group {
+                    name,
+                    onerror,
+                    task,
+                });
+            }
+            Event::ESI(Tag::Choose {
+                when_branches,
+                otherwise_events,
+            }) => {
+                for event in ctx.choose(when_branches, otherwise_events) {
+                    self.handle_event(
+                        event,
+                        ctx,
+                        output,
+                        process_fragment_response,
+                        validate_critical,
+                    )?;
+                }
+            }
+            Event::ESI(Tag::ResponseDirective { status, headers }) => {
+                ctx.apply_response_directive(status, headers)?;
+            }
+            Event::ESI(Tag::DebugComment { text }) => {
+                let comment = ctx.debug_comment(&text);
+                self.write_xml(
+                    comment,
+                    ctx,
+                    output,
+                    process_fragment_response,
+                    validate_critical,
+                )?;
+            }
+            Event::ESI(Tag::VarsValue { name, raw }) => {
+                let value = ctx.vars_value(&name, raw);
+                self.write_xml(
+                    value,
+                    ctx,
+                    output,
+                    process_fragment_response,
+                    validate_critical,
+                )?;
+            }
+            Event::ESI(Tag::Vars { text }) => {
+                let content = ctx.vars_block(&text);
+                self.write_xml(
+                    content,
+                    ctx,
+                    output,
+                    process_fragment_response,
+                    validate_critical,
+                )?;
+            }
+            Event::XML(event) => {
+                let event = ctx.evaluate_attribute_templates(event)?;
+                self.write_xml(
+                    event,
+                    ctx,
+                    output,
+                    process_fragment_response,
+                    validate_critical,
+                )?;
+            }
+        }
+        ctx.buffers.queue_len(self.elements.len());
+        // Deviations found while evaluating the event fail it before the next one is parsed.
+        ctx.warnings.check_compliance()
+    }
+
+    // Streams a source XML event to the output, or queues it behind pending fragments.
+    fn write_xml(
+        &mut self,
+        event: quick_xml::events::Event,
+        ctx: &FragmentRequestContext,
+        output: &mut impl Write,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+        validate_critical: bool,
+    ) -> Result<()> {
+        // Write out anything that has completed in the meantime, so that we can
+        // resume streaming directly to the client as soon as possible.
+        if !validate_critical {
+            self.scheduler.drain_completed(
+                &mut self.elements,
+                ctx,
+                output,
+                process_fragment_response,
+            )?;
+        }
+
+        if self.elements.is_empty() && !validate_critical {
+            if self.buffering || ctx.trace_events {
+                debug!("nothing waiting so streaming directly to client");
+                self.buffering = false;
+            }
+            // Serialize the event on its own first, so that a failed write can't leave
+            // part of it in the output.
+            self.event_bytes.clear();
+            write_event(&mut self.event_bytes, event)?;
+            output
+                .write_all(&self.event_bytes)
+                .and_then(|()| output.flush())?;
+        } else {
+            if !self.buffering {
+                debug!("waiting on queued elements, buffering content");
+                self.buffering = true;
+            }
+            log_queue_len(ctx.trace_events, "buffer", self.elements.len());
+            queue_raw_event(&mut self.elements, event, &ctx.scratch, &ctx.buffers)?;
+        }
+        Ok(())
+    }
+}
+
+// Shared state used to turn parsed include tags into dispatched fragment requests
+struct FragmentRequestContext<'a> {
+    // The request metadata that fragment requests are based on, shared with the `alt` requests
+    // that haven't been built yet
+    original_request_metadata: Rc<Request>,
+    // The same request, which ESI variables are evaluated for
+    variables: EvaluationRequest,
+    // The rules for building fragment request URLs
+    url_policy: UrlPolicy,
+    // The hash of the description of the configuration, for the report
+    config_hash: u64,
+    dispatch_fragment_request: &'a FragmentRequestDispatcher<'a>,
+    // Whether every event should be logged
+    trace_events: bool,
+    // The ESI namespace, e.g. `esi`
+    namespace: String,
+    // The prefix of attributes whose values are evaluated, e.g. `esi:attr-`
+    attribute_template_prefix: Vec<u8>,
+    // How far the output has got, for the features that have to come before the client
+    // response starts, such as `esi:response`
+    cursor: OutputCursor,
+    // Whether those features fail processing when they come too late
+    strict_prestream: bool,
+    // The outcomes of the includes resolved so far
+    include_log: IncludeLog,
+    // When each phase of processing was reached
+    phases: PhaseRecorder,
+    // What the output is checked with as it's written, if it is
+    validator: Option<OutputValidator>,
+    // Which fragment response statuses count as a success, unless an include overrides it
+    status_policy: Rc<StatusPolicy>,
+    // The warnings raised so far
+    warnings: Warnings,
+    parse_options: ParseOptions,
+    // What to do when the source document is over the size or event limit, or the deadline has
+    // passed
+    oversized_document: OversizedDocumentPolicy,
+    // The deadline and client state that writing to the output is checked against
+    copy_budget: CopyBudget,
+    // Whether fragments left pending when processing stops are polled once more
+    poll_abandoned: bool,
+    // How often the queue is checked for completed fragments while parsing
+    scheduling: Scheduling,
+    // Whether skipped includes are retried at the end of the document, and which were skipped
+    retry_pass: Option<RetryPass>,
+    retries: RetryQueue,
+    // How the content written for each include is marked, if it is
+    fragment_markers: Option<FragmentMarkers>,
+    // The documents this one was included from, when include chains are tracked
+    include_chain: Option<IncludeChain>,
+    // Where the queue and buffers are taken from and given back to
+    scratch: ProcessorScratch,
+    // How much content is buffered, and the most there has been
+    buffers: BufferAccountant,
+    // The kind of the `esi:try` arm whose includes are being dispatched, if any
+    arm: RefCell<Option<TryArmKind>>,
+    // The index of the next include, see `FragmentContext::index`
+    next_index: Cell<usize>,
+    // The most includes the document can have
+    max_fragments: Option<usize>,
+    // The requests sent so far, when identical requests are shared by their includes
+    shared_fragments: Option<SharedFragments>,
+}
+
+impl FragmentRequestContext<'_> {
+    // Dispatches the retries of the includes skipped so far, if there's a retry pass. See
+    // `RetryQueue::dispatch`.
+    fn dispatch_retries(&self) -> VecDeque<Element> {
+        let Some(pass) = self.retry_pass else {
+            return VecDeque::new();
+        };
+        self.retries.dispatch(
+            pass,
+            self.copy_budget.remaining(),
+            &self.include_log,
+            self.dispatch_fragment_request,
+            &self.scratch,
+        )
+    }
+
+    // Checks how parsing or polling ended. A document over the size or event limit or past the
+    // deadline is cut short instead of failing if the policy allows it, returning the limit it hit.
+    fn cut_short(&self, result: Result<()>) -> Result<Option<Limit>> {
+        match (result, &self.oversized_document) {
+            (
+                Err(ExecutionError::DocumentTooLarge(max_document_size)),
+                OversizedDocumentPolicy::Truncate { .. },
+            ) => {
+                debug!("document is larger than {max_document_size} bytes, truncating");
+                Ok(Some(Limit::Size))
+            }
+            (
+                Err(ExecutionError::EventBudgetExceeded(max_events)),
+                OversizedDocumentPolicy::Truncate { .. },
+            ) => {
+                debug!("document has more than {max_events} events, truncating");
+                Ok(Some(Limit::Events))
+            }
+            (Err(ExecutionError::DeadlineExceeded), OversizedDocumentPolicy::Truncate { .. }) => {
+                debug!("deadline passed, truncating");
+                Ok(Some(Limit::Deadline))
+            }
+            (result, _) => result.map(|()| None),
+        }
+    }
+
+    // Ends the output of a document once everything queued has been written, or processing has
+    // failed, in the one way `OutputConditions::ending` decides: the truncation marker is only
+    // written for a document that was cut short, and the retries of the skipped includes only
+    // for a complete one, while a failure writes nothing and is returned. Returns whether
+    // retries were queued, in which case it's called again once they've been written.
+    fn finalize_output(
+        &self,
+        limit: Option<Limit>,
+        failure: Option<ExecutionError>,
+        outcome: &ProcessingOutcome,
+        elements: &mut VecDeque<Element>,
+        output: &mut impl Write,
+    ) -> Result<bool> {
+        let conditions = OutputConditions {
+            deadline_hit: limit == Some(Limit::Deadline)
+                || matches!(failure, Some(ExecutionError::DeadlineExceeded)),
+            size_limit_hit: limit == Some(Limit::Size)
+                || matches!(failure, Some(ExecutionError::DocumentTooLarge(_))),
+            event_limit_hit: limit == Some(Limit::Events)
+                || matches!(failure, Some(ExecutionError::EventBudgetExceeded(_))),
+            fragment_failed: failure.is_some(),
+            client_disconnected: !outcome.recovery_write_allowed()
+                || matches!(
+                    failure,
+                    Some(ExecutionError::ClientDisconnected | ExecutionError::WriterError(_))
+                ),
+            retry_pass: self.retry_pass.is_some(),
+        };
+        let ending = conditions.ending(&self.oversized_document);
+        debug!("ending the output: {ending:?}");
+
+        match ending {
+            OutputEnding::Complete { retries } => {
+                let queued = if retries {
+                    self.dispatch_retries()
+                } else {
+                    VecDeque::new()
+                };
+                let retrying = !queued.is_empty();
+                elements.extend(queued);
+                Ok(retrying)
+            }
+            OutputEnding::Truncated => {
+                if let OversizedDocumentPolicy::Truncate { marker } = &self.oversized_document {
+                    // The client may have gone away without anything having been written since.
+                    output
+                        .write_all(marker.as_bytes())
+                        .and_then(|()| output.flush())?;
+                }
+                Ok(false)
+            }
+            // A limit only fails the document with an error already, under the `Fail` policy.
+            OutputEnding::Failed => Err(failure.expect("a failed document has an error")),
+            OutputEnding::Disconnected => {
+                Err(failure.unwrap_or(ExecutionError::ClientDisconnected))
+            }
+        }
+    }
+
+    // Raises a feature that came after the client response started, as a warning or, in strict
+    // mode, an error. Returns whether the feature can be used.
+    fn check_prestream(&self, check: std::result::Result<(), Warning>) -> Result<bool> {
+        match check {
+            Ok(()) => Ok(true),
+            Err(Warning::TooLate { position, feature }) if self.strict_prestream => {
+                Err(ExecutionError::TooLate(feature, position))
+            }
+            Err(warning) => {
+                self.warnings.push(warning);
+                Ok(false)
+            }
+        }
+    }
+
+    // Applies an `esi:response` tag to the client response, unless its headers have been sent.
+    fn apply_response_directive(
+        &self,
+        status: Option<u16>,
+        headers: Vec<(String, String)>,
+    ) -> Result<()> {
+        let tag = format!("{}:response", self.namespace);
+        let position = self.warnings.position();
+        if !self.check_prestream(self.cursor.require_prestream(&tag, position))? {
+            return Ok(());
+        }
+
+        self.cursor.update_response(|resp| {
+            if let Some(status) = status {
+                resp.set_status(status);
+            }
+            for (name, value) in headers {
+                match (
+                    HeaderName::try_from(name.as_str()),
+                    HeaderValue::try_from(value.as_str()),
+                ) {
+                    (Ok(name), Ok(value)) => resp.set_header(name, value),
+                    _ => self.warnings.push(Warning::InvalidAttribute {
+                        position,
+                        tag: tag.clone(),
+                        attribute: format!("header-{name}"),
+                        value,
+                    }),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Evaluates the text of an `esi:comment` tag into a debug comment.
+    fn debug_comment(&self, text: &str) -> quick_xml::events::Event<'static> {
+        let comment = debug_comment(text, &self.variables, &self.warnings);
+        quick_xml::events::Event::Comment(quick_xml::events::BytesText::from_escaped(comment))
+    }
+
+    // Returns the events of the first `esi:when` branch whose test is true, or of the
+    // `esi:otherwise` branch if none is. The other branches are dropped without being processed.
+    fn choose<'e>(
+        &self,
+        when_branches: Vec<(String, Vec<Event<'e>>)>,
+        otherwise_events: Vec<Event<'e>>,
+    ) -> Vec<Event<'e>> {
+        when_branches
+            .into_iter()
+            .find(|(test, _)| evaluate_test(test, &self.variables, &self.warnings))
+            .map_or(otherwise_events, |(_, events)| events)
+    }
+
+    // Evaluates the name of a self-closing `esi:vars` tag into text, escaped for the document
+    // unless the tag is `raw`.
+    fn vars_value(&self, name: &str, raw: bool) -> quick_xml::events::Event<'static> {
+        let value = process_symbols(name, &self.variables, &self.warnings);
+        let value = if raw {
+            value
+        } else {
+            self.escape_value(&value)
+        };
+        quick_xml::events::Event::Text(quick_xml::events::BytesText::from_escaped(value))
+    }
+
+    // Evaluates the content of an `esi:vars` block, escaping the values in it for the document.
+    fn vars_block(&self, text: &str) -> quick_xml::events::Event<'static> {
+        let content = process_vars_block(text, &self.variables, &self.warnings, &|value| {
+            self.escape_value(value)
+        });
+        quick_xml::events::Event::Text(quick_xml::events::BytesText::from_escaped(content))
+    }
+
+    // Escapes a value to be written into the document. Documents that aren't escaped are taken to
+    // be JSON.
+    fn escape_value(&self, value: &str) -> String {
+        if self.parse_options.unescape_urls {
+            quick_xml::escape::escape(value).into_owned()
+        } else {
+            escape_json(value)
+        }
+    }
+
+    fn evaluate_attribute_templates<'e>(
+        &self,
+        event: quick_xml::events::Event<'e>,
+    ) -> Result<quick_xml::events::Event<'e>> {
+        evaluate_attribute_templates(
+            event,
+            &self.attribute_template_prefix,
+            &self.variables,
+            &self.warnings,
+        )
+    }
+
+    // Builds the request for an include URL, once any expressions in it have been evaluated, if
+    // they are.
+    fn build_request(
+        &self,
+        url: &str,
+        forward_headers: Option<&[HeaderName]>,
+    ) -> Result<DeferredRequest> {
+        let url = if self.url_policy.evaluate {
+            process_symbols(url, &self.variables, &self.warnings)
+        } else {
+            url.to_string()
+        };
+        let request = build_fragment_request(
+            &self.original_request_metadata,
+            &url,
+            &self.url_policy,
+            forward_headers,
+            &self.warnings,
+        )?;
+        match &self.include_chain {
+            Some(chain) => chain.extend(request),
+            None => Ok(request),
+        }
+    }
+
+    // Evaluates the values of an include's `add-header` attribute like its URLs, and checks that
+    // the headers can be sent.
+    fn added_headers(
+        &self,
+        headers: &[(String, String)],
+    ) -> Result<Vec<(HeaderName, HeaderValue)>> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.url_policy.evaluate {
+                    process_symbols(value, &self.variables, &self.warnings)
+                } else {
+                    value.clone()
+                };
+                let invalid = || ExecutionError::InvalidRequestHeader(name.clone());
+                Ok((
+                    HeaderName::try_from(name.as_str()).map_err(|_| invalid())?,
+                    HeaderValue::try_from(value).map_err(|_| invalid())?,
+                ))
+            })
+            .collect()
+    }
+
+    // Requests the fragment of an include, or queues it to be requested if `defer` is set. The
+    // include's fallback content goes with the fragment.
+    fn dispatch_include(&self, include: Include, defer: bool) -> Result<Option<Element>> {
+        Ok(match self.dispatch_include_urls(&include, defer)? {
+            Some(Element::Include(mut fragment)) => {
+                fragment.fallback = include.fallback;
+                Some(Element::Include(fragment))
+            }
+            element => element,
+        })
+    }
+
+    // Requests the URLs of an include. If they're rejected before they can be requested, the
+    // include is resolved straight away, and it's the fallback content that's queued if any.
+    fn dispatch_include_urls(&self, include: &Include, defer: bool) -> Result<Option<Element>> {
+        let Include {
+            src,
+            alt,
+            continue_on_error,
+            srcset_index,
+            ..
+        } = include;
+        let continue_on_error = *continue_on_error;
+        let accept_status = include.get::<AcceptStatus>().map(|codes| codes.0.clone());
+        let forward_headers = include
+            .get::<ForwardHeaders>()
+            .map(|names| names.0.as_slice())
+            .or_else(|| include.get::<Headers>().map(|names| names.0.as_slice()));
+        let method = include
+            .get::<attributes::Method>()
+            .map(|method| method.0.clone());
+        let no_store = include.get::<NoStore>().is_some_and(|no_store| no_store.0);
+        let raw = include.get::<Raw>().is_some_and(|raw| raw.0);
+        let added_headers = match include.get::<AddHeader>() {
+            Some(headers) => self.added_headers(&headers.0)?,
+            None => Vec::new(),
+        };
+        let retries = self.retry_pass.map(|_| self.retries.clone());
+        let index = self.next_index.get();
+        if let Some(max) = self.max_fragments.filter(|&max| index >= max) {
+            return Err(ExecutionError::TooManyFragments(max));
+        }
+        self.next_index.set(index + 1);
+        let marker = self.fragment_markers.as_ref().map(FragmentMarkers::next);
+        let context = Rc::new(FragmentContext {
+            src: src.clone(),
+            is_alt: false,
+            is_retry: false,
+            arm: self.arm.borrow().clone(),
+            index,
+        });
+        let slot =
+            IncludeSlot::new(self.include_log.clone(), retries, marker, context).with_raw(raw);
+        // A raw body is copied as it is, whatever the configured UTF-8 policy.
+        let status_policy = match (accept_status, raw) {
+            (None, false) => self.status_policy.clone(),
+            (accept, raw) => Rc::new(StatusPolicy {
+                accept: accept.unwrap_or_else(|| self.status_policy.accept.clone()),
+                empty_body: self.status_policy.empty_body.clone(),
+                utf8: match raw {
+                    true => Utf8Policy::Passthrough,
+                    false => self.status_policy.utf8,
+                },
+                error_body_snippet: self.status_policy.error_body_snippet,
+                timeout: self.status_policy.timeout,
+            }),
+        };
+        // Includes taken from a `srcset` tell the dispatcher which of its URLs they are for. The
+        // `alt` request forwards the same headers as the `src` one, with the same method and
+        // cache override.
+        let build_request = |url: &str| {
+            let mut req = self.build_request(url, forward_headers)?;
+            if let Some(index) = srcset_index {
+                req = req.with_header(SRCSET_INDEX_HEADER, index.to_string());
+            }
+            if let Some(method) = &method {
+                req = req.with_method(method.clone());
+            }
+            if no_store {
+                req = req.with_pass();
+            }
+            Ok(req.with_added_headers(added_headers.clone()))
+        };
+        // Expressions in `src` and `alt` are each evaluated once, here, in document order, so that
+        // nothing after this sees a different URL. Only the `alt` request is built lazily.
+        let alt_req = alt.as_deref().map(build_request);
+
+        let req = match build_request(src).map(DeferredRequest::build) {
+            Ok(req) => req,
+            // A rejected `src` is handled like a failed request, except that it's never retried
+            // and fails processing with its own error.
+            Err(mut err) => {
+                let Some(url) = rejected_url(&err) else {
+                    return Err(err);
+                };
+                debug!("fragment URL {url} rejected: {err}");
+                slot.rejected(url);
+                let mut mechanisms = FailureMechanisms {
+                    alt: alt_req.is_some(),
+                    fallback: include.fallback.is_some(),
+                    continue_on_error,
+                    ..FailureMechanisms::default()
+                };
+                if let (FailureResolution::Alt, Some(alt_req)) =
+                    (resolve_fragment_failure(&mechanisms), alt_req)
+                {
+                    match usable_alt(alt_req, &slot)? {
+                        Ok(alt_req) => {
+                            return Ok(self
+                                .send(
+                                    alt_req,
+                                    None,
+                                    continue_on_error,
+                                    slot.alt(),
+                                    status_policy,
+                                    defer,
+                                )?
+                                .map(|fragment| Element::Include(Box::new(fragment))))
+                        }
+                        Err(alt_err) => {
+                            err = alt_err;
+                            mechanisms.alt = false;
+                        }
+                    }
+                }
+                return match (resolve_fragment_failure(&mechanisms), &include.fallback) {
+                    (FailureResolution::Fallback, Some(fallback)) => {
+                        self.buffers.grow(fallback.len());
+                        Ok(Some(Element::Raw(fallback.clone())))
+                    }
+                    (FailureResolution::Skip | FailureResolution::Retry, _) => Ok(None),
+                    _ => Err(err),
+                };
+            }
+        };
+
+        // The requests of includes that add headers of their own aren't shared, as their key
+        // doesn't tell them apart.
+        let sent = match added_headers.is_empty() {
+            true => self.send_src(req, alt_req, continue_on_error, slot, status_policy, defer)?,
+            false => self.send(req, alt_req, continue_on_error, slot, status_policy, defer)?,
+        };
+        Ok(sent.map(|fragment| Element::Include(Box::new(fragment))))
+    }
+
+    // Sends the request for the `src` of an include like `send`, unless an identical request was
+    // already sent for another include and requests are shared, in which case its response is.
+    // Requests that fail fast, and those for `data:` URIs, aren't shared.
+    fn send_src(
+        &self,
+        request: Request,
+        alt: Option<Result<DeferredRequest>>,
+        continue_on_error: bool,
+        slot: IncludeSlot,
+        status_policy: Rc<StatusPolicy>,
+        defer: bool,
+    ) -> Result<Option<Fragment>> {
+        let Some(shared) = self
+            .shared_fragments
+            .as_ref()
+            .filter(|_| !defer && request.get_url().scheme() != "data")
+        else {
+            return self.send(request, alt, continue_on_error, slot, status_policy, defer);
+        };
+        let key = shared.key(&request);
+        if let Some(fragment) = shared.get(&key) {
+            debug!("sharing the request for fragment {key}");
+            return Ok(Some(Fragment {
+                request,
+                alt,
+                continue_on_error,
+                critical: false,
+                pending_content: FragmentContent::Shared(fragment),
+                slot,
+                status_policy,
+                dispatched: Instant::now(),
+                fallback: None,
+            }));
+        }
+
+        let url = request.get_url_str().to_string();
+        let mut sent = self.send(request, alt, continue_on_error, slot, status_policy, false)?;
+        // When the request couldn't be dispatched, the fragment is the one for the `alt` URL.
+        if let Some(fragment) = sent
+            .as_mut()
+            .filter(|fragment| shared.key(&fragment.request) == key)
+        {
+            if matches!(
+                &fragment.pending_content,
+                FragmentContent::Dispatched(content) if matches!(
+                    **content,
+                    PendingFragmentContent::PendingRequest(_)
+                        | PendingFragmentContent::CompletedRequest(_)
+                )
+            ) {
+                let content =
+                    std::mem::replace(&mut fragment.pending_content, FragmentContent::Lent);
+                let shared_fragment = SharedFragment::new(url, content);
+                shared.insert(key, Rc::clone(&shared_fragment));
+                fragment.pending_content = FragmentContent::Shared(shared_fragment);
+            }
+        }
+        Ok(sent)
+    }
+
+    // Sends a fragment request, or only queues it to be sent by `dispatch_deferred` if `defer`
+    // is set.
+    fn send(
+        &self,
+        request: Request,
+        alt: Option<Result<DeferredRequest>>,
+        continue_on_error: bool,
+        slot: IncludeSlot,
+        status_policy: Rc<StatusPolicy>,
+        defer: bool,
+    ) -> Result<Option<Fragment>> {
+        if !defer {
+            return send_fragment_request(
+                request,
+                alt,
+                continue_on_error,
+                self.dispatch_fragment_request,
+                slot,
+                status_policy,
+            );
+        }
+        Ok(Some(Fragment {
+            request,
+            alt,
+            continue_on_error,
+            critical: false,
+            pending_content: FragmentContent::Deferred,
+            slot,
+            status_policy,
+            dispatched: Instant::now(),
+            fallback: None,
+        }))
+    }
+}
+
+// Dispatches the includes of each arm of an `esi:try` block. A block without an `esi:except` arm
+// gets an empty one, so that it renders nothing when the other arms fail.
+// The requests of an `esi:except` arm are deferred until it's reached, like those of every arm of
+// a try that's in a deferred arm itself.
+fn parse_arms(arms: Vec<TryArm>, ctx: &FragmentRequestContext, defer: bool) -> Result<Vec<Task>> {
+    let has_except = arms.iter().any(|arm| arm.kind == TryArmKind::Except);
+    let mut tasks = arms
+        .into_iter()
+        .map(|arm| {
+            // The includes of the arm, and of the blocks in it, are in this arm.
+            let outer = ctx.arm.replace(Some(arm.kind.clone()));
+            let task = parse_task(
+                arm.events,
+                ctx,
+                "try",
+                defer || arm.kind == TryArmKind::Except,
+            );
+            ctx.arm.replace(outer);
+            task
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !has_except {
+        tasks.push(parse_task(Vec::new(), ctx, "try", defer)?);
+    }
+    Ok(tasks)
+}
+
+// Dispatches the includes of an `esi:try` arm or `esi:group` block, the name of which is given by
+// `block`, and queues the rest of its content. With `defer`, the includes are only queued.
+fn parse_task(
+    events: Vec<Event>,
+    ctx: &FragmentRequestContext,
+    block: &str,
+    defer: bool,
+) -> Result<Task> {
+    let mut task = Task::new();
+    task.output = ctx.scratch.buffer();
+    queue_task_events(events, &mut task, ctx, block, defer)?;
+    Ok(task)
+}
+
+// Adds the events of an `esi:try` arm or `esi:group` block to its task, like `parse_task`.
+fn queue_task_events(
+    events: Vec<Event>,
+    task: &mut Task,
+    ctx: &FragmentRequestContext,
+    block: &str,
+    defer: bool,
+) -> Result<()> {
+    for event in events {
+        match event {
+            Event::ESI(Tag::Include(include)) => {
+                if let Some(element) = ctx.dispatch_include(include, defer)? {
+                    // build up task list with fragments
+                    task.queue.push_back(element);
+                }
+            }
+            Event::ESI(Tag::Try { arms }) => {
+                // nested try blocks are polled along with the rest of the task
+                task.queue.push_back(Element::Try {
+                    arms: parse_arms(arms, ctx, defer)?,
+                });
+            }
+            Event::ESI(Tag::Group {
+                name,
+                onerror,
+                events,
+            }) => {
+                task.queue.push_back(Element::Group {
+                    name,
+                    onerror,
+                    task: parse_task(events, ctx, "group", defer)?,
+                });
+            }
+            Event::ESI(Tag::Choose {
+                when_branches,
+                otherwise_events,
+            }) => {
+                let events = ctx.choose(when_branches, otherwise_events);
+                queue_task_events(events, task, ctx, block, defer)?;
+            }
+            Event::ESI(Tag::DebugComment { text }) => {
+                queue_raw_event(
+                    &mut task.queue,
+                    ctx.debug_comment(&text),
+                    &ctx.scratch,
+                    &ctx.buffers,
+                )?;
+            }
+            Event::ESI(Tag::VarsValue { name, raw }) => {
+                queue_raw_event(
+                    &mut task.queue,
+                    ctx.vars_value(&name, raw),
+                    &ctx.scratch,
+                    &ctx.buffers,
+                )?;
+            }
+            Event::ESI(Tag::Vars { text }) => {
+                queue_raw_event(
+                    &mut task.queue,
+                    ctx.vars_block(&text),
+                    &ctx.scratch,
+                    &ctx.buffers,
+                )?;
+            }
+            Event::ESI(Tag::ResponseDirective { .. }) => {
+                ctx.warnings.push(Warning::IgnoredTag {
+                    position: ctx.warnings.position(),
+                    tag: format!("{}:response", ctx.namespace),
+                    reason: format!("it's inside an esi:{block} block"),
+                });
+            }
+            Event::XML(event) => {
+                if ctx.trace_events {
+                    debug!("XML event inside esi:{block} -- {event:?}");
+                }
+                let event = ctx.evaluate_attribute_templates(event)?;
+                log_queue_len(ctx.trace_events, "task's buffer", task.queue.len());
+                queue_raw_event(&mut task.queue, event, &ctx.scratch, &ctx.buffers)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn send_fragment_request(
+    req: Request,
+    alt: Option<Result<DeferredRequest>>,
+    continue_on_error: bool,
+    dispatch_request: &FragmentRequestDispatcher,
+    slot: IncludeSlot,
+    status_policy: Rc<StatusPolicy>,
+) -> Result<Option<Fragment>> {
+    debug!("Requesting ESI fragment: {}", req.get_url());
+
+    let request = req.clone_without_body();
+
+    // `data:` URIs are decoded locally instead of being dispatched.
+    if req.get_url().scheme() == "data" {
+        let data_uri = match decode_data_uri(req.get_url()) {
+            Ok(data_uri) => data_uri,
+            Err(err) => {
+                if let Some(alt) = alt {
+                    debug!("invalid data URI, trying alt: {}", err);
+                    return send_alt_request(
+                        alt,
+                        continue_on_error,
+                        dispatch_request,
+                        &slot,
+                        status_policy,
+                    );
+                }
+                slot.failed(&request);
+                if continue_on_error {
+                    debug!("invalid data URI, continuing: {}", err);
+                    return Ok(None);
+                }
+                return Err(err);
+            }
+        };
+        let response = Response::from_body(data_uri.data)
+            .with_header(header::CONTENT_TYPE, data_uri.media_type);
+
+        return Ok(Some(Fragment {
+            request,
+            alt,
+            continue_on_error,
+            critical: false,
+            pending_content: PendingFragmentContent::CompletedRequest(response).into(),
+            slot,
+            status_policy,
+            dispatched: Instant::now(),
+            fallback: None,
+        }));
+    }
+
+    slot.dispatched();
+    let pending_content =
+        match slot.time_callback(CallbackKind::Dispatcher, || dispatch_request(req)) {
+            Ok(PendingFragmentContent::NoContent) => {
+                debug!("No pending request returned, skipping");
+                slot.succeeded(&request);
+                return Ok(None);
+            }
+            Ok(PendingFragmentContent::FailFast(reason)) => {
+                debug!("fragment request failed fast: {reason}");
+                slot.failed_fast(&request, &reason);
+                PendingFragmentContent::FailFast(reason).into()
+            }
+            Ok(pending_content) => pending_content.into(),
+            Err(err) => {
+                error!("Failed to dispatch request: {:?}", err);
+                let Some(alt) = alt else {
+                    slot.errored(&request);
+                    if continue_on_error {
+                        debug!("failed to dispatch request, continuing: {}", err);
+                        return Ok(None);
+                    }
+                    return Err(err);
+                };
+                slot.errored_trying_alt();
+                return send_alt_request(
+                    alt,
+                    continue_on_error,
+                    dispatch_request,
+                    &slot,
+                    status_policy,
+                );
+            }
+        };
+
+    Ok(Some(Fragment {
+        request,
+        alt,
+        continue_on_error,
+        critical: false,
+        pending_content,
+        slot,
+        status_policy,
+        dispatched: Instant::now(),
+        fallback: None,
+    }))
+}
+
+// Sends the `alt` request of an include whose own request couldn't be made. When the alt can't be
+// requested either, this fails with its error, unless the include continues on error.
+fn send_alt_request(
+    alt: Result<DeferredRequest>,
+    continue_on_error: bool,
+    dispatch_request: &FragmentRequestDispatcher,
+    slot: &IncludeSlot,
+    status_policy: Rc<StatusPolicy>,
+) -> Result<Option<Fragment>> {
+    let sent = match usable_alt(alt, slot)? {
+        Ok(alt_request) => send_fragment_request(
+            alt_request,
+            None,
+            continue_on_error,
+            dispatch_request,
+            slot.alt(),
+            status_policy,
+        ),
+        Err(err) => Err(err),
+    };
+    match sent {
+        Err(err) if continue_on_error => {
+            debug!("alt request failed too, continuing: {err}");
+            Ok(None)
+        }
+        sent => sent,
+    }
+}
+
+// Unwraps the request for an `alt` URL, or the error it was rejected with, which is handled like
+// a failed `alt` request.
+fn usable_alt(
+    alt: Result<DeferredRequest>,
+    slot: &IncludeSlot,
+) -> Result<std::result::Result<Request, ExecutionError>> {
+    match alt {
+        Ok(alt) => Ok(Ok(alt.build())),
+        Err(err) => {
+            let Some(url) = rejected_url(&err) else {
+                return Err(err);
+            };
+            debug!("alt URL {url} rejected: {err}");
+            slot.alt().rejected(url);
+            Ok(Err(err))
+        }
+    }
+}
+
+// The URL of an include that was rejected before it could be requested, which is handled like a
+// failed request, or `None` for other errors.
+fn rejected_url(err: &ExecutionError) -> Option<&str> {
+    match err {
+        ExecutionError::InvalidRequestUrl(url)
+        | ExecutionError::InsecureFragmentUrl(url)
+        | ExecutionError::IncludeTooDeep(url, _)
+        | ExecutionError::SchemeHandlerFailed(url, _) => Some(url),
+        ExecutionError::IncludeCycle(chain) => chain.last().map(String::as_str),
+        _ => None,
+    }
+}
+
+// Wait for every critical fragment in the queue and replace it with its output, so that a
+// failure can be reported before anything has been written to the client.
+fn resolve_critical_fragments(
+    elements: &mut VecDeque<Element>,
+    dispatch_fragment_request: &FragmentRequestDispatcher,
+    process_fragment_response: Option<&FragmentContextProcessor>,
+    budget: &CopyBudget,
+    scratch: &ProcessorScratch,
+    buffers: &BufferAccountant,
+) -> Result<()> {
+    for element in elements.iter_mut() {
+        if !matches!(element, Element::Include(fragment) if fragment.critical) {
+            continue;
+        }
+        let Element::Include(mut fragment) = std::mem::replace(element, Element::Raw(Vec::new()))
+        else {
+            unreachable!("element was matched as an include");
+        };
+
+        debug!(
+            "waiting for critical fragment {}",
+            fragment.request.get_url()
+        );
+        // A critical fragment can't be skipped.
+        fragment.continue_on_error = false;
+        let url = fragment.request.get_url_str().to_string();
+
+        let mut queue = VecDeque::from([Element::Include(fragment)]);
+        let mut output = Vec::new();
+        while !queue.is_empty() {
+            poll_queue(
+                &mut queue,
+                &mut output,
+                dispatch_fragment_request,
+                process_fragment_response,
+                budget,
+                scratch,
+                buffers,
+                true,
+            )
+            .map_err(|err| ExecutionError::CriticalFragmentFailed(url.clone(), Box::new(err)))?;
         }
-    }
 
-    /// Process a response body as an ESI document. Consumes the response body.
-    pub fn process_response(
-        self,
-        src_document: &mut Response,
-        client_response_metadata: Option<Response>,
-        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
-        process_fragment_response: Option<&FragmentResponseProcessor>,
-    ) -> Result<()> {
-        // Create a response to send the headers to the client
-        let resp = client_response_metadata.unwrap_or_else(|| {
-            Response::from_status(StatusCode::OK).with_content_type(mime::TEXT_HTML)
-        });
+        buffers.grow(output.len());
+        *element = Element::Raw(output);
+    }
 
-        // Send the response headers to the client and open an output stream
-        let output_writer = resp.stream_to_client();
+    Ok(())
+}
 
-        // Set up an XML writer to write directly to the client output stream.
-        let mut xml_writer = Writer::new(output_writer);
+// Logs the length of a buffer that content is being pushed to. Unless tracing, this only happens
+// when the length reaches a power of two, so that growth is visible without logging every event.
+fn log_queue_len(trace_events: bool, buffer: &str, len: usize) {
+    if trace_events || len.is_power_of_two() {
+        debug!("pushing content to {}, len: {}", buffer, len);
+    }
+}
 
-        match self.process_document(
-            reader_from_body(src_document.take_body()),
-            &mut xml_writer,
-            dispatch_fragment_request,
-            process_fragment_response,
-        ) {
-            Ok(()) => {
-                xml_writer.into_inner().finish().unwrap();
-                Ok(())
+// Drains a queue into `abandoned`, see `DocumentState::abandon`.
+fn abandon_elements(
+    elements: &mut VecDeque<Element>,
+    poll: bool,
+    abandoned: &mut Vec<AbandonedFragment>,
+) {
+    for element in elements.drain(..) {
+        match element {
+            Element::Raw(_) => {}
+            // Deferred requests were never dispatched.
+            Element::Include(fragment)
+                if matches!(fragment.pending_content, FragmentContent::Deferred) => {}
+            Element::Include(fragment) => {
+                let status = match fragment.pending_content {
+                    content if poll => match content.poll() {
+                        PollContentResult::Ready(res) => (*res).ok().map(|res| res.get_status()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                abandoned.push(fragment.slot.abandoned(&fragment.request, status));
             }
-            Err(err) => {
-                error!("error processing ESI document: {}", err);
-                Err(err)
+            Element::Try { arms } => {
+                for mut arm in arms {
+                    abandon_elements(&mut arm.queue, poll, abandoned);
+                }
+            }
+            Element::Group { mut task, .. } => {
+                abandon_elements(&mut task.queue, poll, abandoned);
             }
         }
     }
+}
 
-    /// Process an ESI document from a [`quick_xml::Reader`].
-    pub fn process_document(
-        self,
-        mut src_document: Reader<impl BufRead>,
-        output_writer: &mut Writer<impl Write>,
-        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
-        process_fragment_response: Option<&FragmentResponseProcessor>,
-    ) -> Result<()> {
-        // Set up fragment request dispatcher. Use what's provided or use a default
-        let dispatch_fragment_request = dispatch_fragment_request.unwrap_or({
-            &|req| {
-                debug!("no dispatch method configured, defaulting to hostname");
-                let backend = req
-                    .get_url()
-                    .host()
-                    .unwrap_or_else(|| panic!("no host in request: {}", req.get_url()))
-                    .to_string();
-                let pending_req = req.send_async(backend)?;
-                Ok(Some(pending_req))
-            }
-        });
+// Serializes a source XML event to the output.
+//
+// All output, whether it's streamed to the client or buffered in the queue or a task, is plain
+// bytes. Source events are serialized by a writer that only lives for the one event, so no writer
+// keeps state (such as element nesting) across the fragment bodies written in between.
+fn write_event(output: &mut impl Write, event: quick_xml::events::Event) -> Result<()> {
+    Writer::new(output).write_event(event)?;
+    Ok(())
+}
 
-        // Set up the queue of document elements to be sent to the client.
-        let mut elements: VecDeque<Element> = VecDeque::new();
+// Queues a source XML event to be written once the elements ahead of it are done. Consecutive
+// events share a single buffer, rather than each taking up an element in the queue.
+fn queue_raw_event(
+    queue: &mut VecDeque<Element>,
+    event: quick_xml::events::Event,
+    scratch: &ProcessorScratch,
+    buffers: &BufferAccountant,
+) -> Result<()> {
+    if let Some(Element::Raw(raw)) = queue.back_mut() {
+        let len = raw.len();
+        write_event(raw, event)?;
+        buffers.grow(raw.len() - len);
+    } else {
+        let mut raw = scratch.buffer();
+        write_event(&mut raw, event)?;
+        buffers.grow(raw.len());
+        queue.push_back(Element::Raw(raw));
+    }
+    Ok(())
+}
 
-        // If there is a source request to mimic, copy its metadata, otherwise use a default request.
-        let original_request_metadata = self.original_request_metadata.as_ref().map_or_else(
-            || Request::new(Method::GET, "http://localhost"),
-            Request::clone_without_body,
-        );
+// Where `poll_elements` writes what it polls: straight to the output, or into the buffer of an
+// `esi:try` arm or `esi:group`, which is only written once the whole task has succeeded.
+trait Sink: Write {
+    // The length of the task buffer, which a fragment body that can't be read in full is
+    // truncated back to. `None` when writing straight to the output, where it has been sent.
+    fn task_len(&self) -> Option<usize>;
 
-        let is_escaped = self.configuration.is_escaped;
-        // Begin parsing the source document
-        parse_tags(
-            &self.configuration.namespace,
-            &mut src_document,
-            &mut |event| {
-                debug!("got {:?}", event);
-                match event {
-                    Event::ESI(Tag::Include {
-                        src,
-                        alt,
-                        continue_on_error,
-                    }) => {
-                        let req = build_fragment_request(
-                            original_request_metadata.clone_without_body(),
-                            &src,
-                            is_escaped,
-                        );
-                        let alt_req = alt.map(|alt| {
-                            build_fragment_request(
-                                original_request_metadata.clone_without_body(),
-                                &alt,
-                                is_escaped,
-                            )
-                        });
+    fn truncate(&mut self, len: usize);
 
-                        if let Some(fragment) = send_fragment_request(
-                            req?,
-                            alt_req,
-                            continue_on_error,
-                            dispatch_fragment_request,
-                        )? {
-                            elements.push_back(Element::Include(fragment));
-                        }
-                    }
-                    Event::ESI(Tag::Try {
-                        attempt_events,
-                        except_events,
-                    }) => {
-                        let attempt_task = parse_task(
-                            attempt_events,
-                            is_escaped,
-                            &original_request_metadata,
-                            dispatch_fragment_request,
-                        )?;
-                        let except_task = parse_task(
-                            except_events,
-                            is_escaped,
-                            &original_request_metadata,
-                            dispatch_fragment_request,
-                        )?;
-
-                        // push the elements
-                        elements.push_back(Element::Try {
-                            attempt_task,
-                            except_task,
-                        });
-                    }
-                    Event::XML(event) => {
-                        if elements.is_empty() {
-                            debug!("nothing waiting so streaming directly to client");
-                            output_writer.write_event(event)?;
-                            output_writer
-                                .get_mut()
-                                .flush()
-                                .expect("failed to flush output");
-                        } else {
-                            debug!("pushing content to buffer, len: {}", elements.len());
-                            let mut vec = Vec::new();
-                            let mut writer = Writer::new(&mut vec);
-                            writer.write_event(event)?;
-                            elements.push_back(Element::Raw(vec));
-                        }
-                    }
-                }
-                Ok(())
-            },
-        )?;
+    // Reports a fragment boundary to `sink` once the output reaches it.
+    fn boundary(&mut self, sink: &BoundarySink, boundary: FragmentBoundary);
+}
 
-        // Wait for any pending requests to complete
-        loop {
-            if elements.is_empty() {
-                break;
-            }
+// The buffer of a task, as a `Sink`.
+struct TaskOutput<'a> {
+    output: &'a mut Vec<u8>,
+    boundaries: &'a mut Vec<PendingBoundary>,
+    buffers: &'a BufferAccountant,
+}
 
-            poll_elements(
-                &mut elements,
-                output_writer,
-                dispatch_fragment_request,
-                process_fragment_response,
-            )?;
-        }
+impl Write for TaskOutput<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffers.grow(buf.len());
+        self.output.write(buf)
+    }
 
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
 }
 
-fn parse_task(
-    events: Vec<Event>,
-    is_escaped: bool,
-    original_request_metadata: &Request,
-    dispatch_fragment_request: &FragmentRequestDispatcher,
-) -> Result<Task> {
-    let mut task = Task::new();
-    for event in events {
-        if let Event::ESI(Tag::Include {
-            ref src,
-            ref alt,
-            ref continue_on_error,
-        }) = event
-        {
-            let req = build_fragment_request(
-                original_request_metadata.clone_without_body(),
-                src,
-                is_escaped,
-            );
-            let alt_req = alt.clone().map(|alt| {
-                build_fragment_request(
-                    original_request_metadata.clone_without_body(),
-                    &alt,
-                    is_escaped,
-                )
-            });
-
-            if let Some(fragment) =
-                send_fragment_request(req?, alt_req, *continue_on_error, dispatch_fragment_request)?
-            {
-                // build up task list with fragments
-                task.queue.push_back(Element::Include(fragment));
-            }
-        }
-        if let Event::XML(event) = event {
-            debug!("XML event inside esi:try -- {event:?}");
-            debug!(
-                "pushing non-ESI content to task's buffer, len: {}",
-                task.queue.len()
-            );
-            let mut vec = Vec::new();
-            let mut writer = Writer::new(&mut vec);
-            writer.write_event(event)?;
-            task.queue.push_back(Element::Raw(vec));
-        }
+impl Sink for TaskOutput<'_> {
+    fn task_len(&self) -> Option<usize> {
+        Some(self.output.len())
     }
-    Ok(task)
-}
 
-fn build_fragment_request(mut request: Request, url: &str, is_escaped: bool) -> Result<Request> {
-    let escaped_url = if is_escaped {
-        match quick_xml::escape::unescape(url) {
-            Ok(url) => url.to_string(),
-            Err(err) => {
-                return Err(ExecutionError::InvalidRequestUrl(err.to_string()));
-            }
+    fn truncate(&mut self, len: usize) {
+        self.buffers.shrink(self.output.len().saturating_sub(len));
+        self.output.truncate(len);
+        // The start of the fragment being dropped is at `len` itself.
+        while self.boundaries.last().is_some_and(|last| {
+            last.offset > len
+                || (last.offset == len && matches!(last.boundary, FragmentBoundary::Start { .. }))
+        }) {
+            self.boundaries.pop();
         }
-    } else {
-        url.to_string()
-    };
+    }
 
-    if escaped_url.starts_with('/') {
-        match Url::parse(
-            format!("{}://0.0.0.0{}", request.get_url().scheme(), escaped_url).as_str(),
-        ) {
-            Ok(u) => {
-                request.get_url_mut().set_path(u.path());
-                request.get_url_mut().set_query(u.query());
-            }
-            Err(_err) => {
-                return Err(ExecutionError::InvalidRequestUrl(escaped_url));
-            }
-        };
-    } else {
-        request.set_url(match Url::parse(&escaped_url) {
-            Ok(url) => url,
-            Err(_err) => {
-                return Err(ExecutionError::InvalidRequestUrl(escaped_url));
-            }
+    fn boundary(&mut self, sink: &BoundarySink, boundary: FragmentBoundary) {
+        self.boundaries.push(PendingBoundary {
+            offset: self.output.len(),
+            sink: sink.clone(),
+            boundary,
         });
     }
+}
 
-    let hostname = request.get_url().host().expect("no host").to_string();
+// The output of the document, as a `Sink`.
+struct Direct<'a, W>(&'a mut W);
 
-    request.set_header(header::HOST, &hostname);
+impl<W: Write> Write for Direct<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
 
-    Ok(request)
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
 }
 
-fn send_fragment_request(
-    req: Request,
-    alt: Option<Result<Request>>,
-    continue_on_error: bool,
-    dispatch_request: &FragmentRequestDispatcher,
-) -> Result<Option<Fragment>> {
-    debug!("Requesting ESI fragment: {}", req.get_url());
+impl<W: Write> Sink for Direct<'_, W> {
+    fn task_len(&self) -> Option<usize> {
+        None
+    }
 
-    let request = req.clone_without_body();
+    fn truncate(&mut self, _len: usize) {}
 
-    let pending_request = match dispatch_request(req) {
-        Ok(Some(req)) => req,
-        Ok(None) => {
-            debug!("No pending request returned, skipping");
-            return Ok(None);
-        }
-        Err(err) => {
-            error!("Failed to dispatch request: {:?}", err);
-            return Err(err);
-        }
-    };
+    fn boundary(&mut self, sink: &BoundarySink, boundary: FragmentBoundary) {
+        sink.call(&boundary);
+    }
+}
 
-    Ok(Some(Fragment {
-        request,
-        alt,
-        continue_on_error,
-        pending_request,
-    }))
+// Polls the queue of the document, where a fragment that fails with nothing to fall back to
+// fails processing. See `poll_elements`.
+#[allow(clippy::too_many_arguments)]
+fn poll_queue(
+    elements: &mut VecDeque<Element>,
+    output: &mut impl Write,
+    dispatch_fragment_request: &FragmentRequestDispatcher,
+    process_fragment_response: Option<&FragmentContextProcessor>,
+    budget: &CopyBudget,
+    scratch: &ProcessorScratch,
+    buffers: &BufferAccountant,
+    wait: bool,
+) -> Result<()> {
+    match poll_elements(
+        elements,
+        &mut Direct(output),
+        dispatch_fragment_request,
+        process_fragment_response,
+        budget,
+        scratch,
+        buffers,
+        wait,
+        &mut |_| {},
+    )? {
+        PollTaskState::Failed(req, failure) => Err(failure.into_error(req.get_url_str())),
+        PollTaskState::Pending | PollTaskState::Succeeded => Ok(()),
+    }
 }
 
 // This function is responsible for polling pending requests and writing their
-// responses to the client output stream. It also handles any queued source
-// content that needs to be written to the client output stream.
-#[allow(clippy::cognitive_complexity)]
+// responses to `output`. It also handles any queued source content that needs
+// to be written. The same logic applies to the document queue and, through
+// `poll_tasks`, to the queues of `esi:try` arms and `esi:group` blocks at any
+// depth.
+// When `wait` is false, it stops at the first element that isn't ready yet
+// instead of blocking on it. Returns `Failed` when a fragment fails with nothing
+// to fall back to, and `Pending` when it stops before the end of the queue.
+// While it waits on a fragment, the requests further down the queue are waited
+// on along with it, as are those of the queues around it, which `around` visits,
+// so that their responses are kept as they arrive. Output is still written in
+// document order.
+#[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
 fn poll_elements(
     elements: &mut VecDeque<Element>,
-    output_writer: &mut Writer<impl Write>,
+    output: &mut impl Sink,
     dispatch_fragment_request: &FragmentRequestDispatcher,
-    process_fragment_response: Option<&FragmentResponseProcessor>,
-) -> Result<()> {
+    process_fragment_response: Option<&FragmentContextProcessor>,
+    budget: &CopyBudget,
+    scratch: &ProcessorScratch,
+    buffers: &BufferAccountant,
+    wait: bool,
+    around: &mut VisitContents,
+) -> Result<PollTaskState> {
     while let Some(element) = elements.pop_front() {
+        // Nothing more is written once the deadline has passed or the client is gone.
+        budget.check()?;
         match element {
             Element::Raw(raw) => {
-                debug!("writing previously queued other content");
-                output_writer.get_mut().write_all(&raw).unwrap();
+                trace!("writing previously queued other content");
+                output.write_all(&raw)?;
+                buffers.release(scratch, raw);
             }
-            Element::Include(Fragment {
-                mut request,
-                alt,
-                continue_on_error,
-                pending_request,
-            }) => {
-                match pending_request.wait() {
-                    Ok(res) => {
+            Element::Include(fragment) => {
+                let Fragment {
+                    mut request,
+                    alt,
+                    continue_on_error,
+                    critical,
+                    pending_content,
+                    dispatched,
+                    slot,
+                    status_policy,
+                    fallback,
+                } = *fragment;
+                // The response isn't waited for past the timeout, if there is one.
+                let deadline = status_policy.timeout.map(|timeout| dispatched + timeout);
+                let polled = if wait {
+                    pending_content.wait_with_others(deadline, &mut |f| {
+                        for_each_content(elements, f);
+                        around(f);
+                    })
+                } else {
+                    pending_content.poll()
+                };
+                let res = match polled {
+                    PollContentResult::Ready(res) => Some(*res),
+                    PollContentResult::Pending(_)
+                        if deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                    {
+                        debug!("fragment request timed out: {}", request.get_url_str());
+                        None
+                    }
+                    PollContentResult::TimedOut => {
+                        debug!("fragment request timed out: {}", request.get_url_str());
+                        None
+                    }
+                    PollContentResult::Pending(pending_content) => {
+                        elements.push_front(Element::Include(Box::new(Fragment {
+                            request,
+                            alt,
+                            continue_on_error,
+                            critical,
+                            pending_content,
+                            dispatched,
+                            slot,
+                            status_policy,
+                            fallback,
+                        })));
+                        return Ok(PollTaskState::Pending);
+                    }
+                };
+
+                // How a failure of the fragment is handled, in the order of
+                // `failure::PRECEDENCE`.
+                let in_block = output.task_len().is_some();
+                let mechanisms = FailureMechanisms {
+                    status_accepted: false,
+                    alt: alt.is_some(),
+                    fallback: fallback.is_some(),
+                    continue_on_error,
+                    retry_pass: slot.can_retry() && !in_block,
+                    in_block,
+                };
+
+                let failure = match res {
+                    // A request that timed out is handled like a failed response.
+                    None => FragmentFailure::Timeout(dispatched.elapsed()),
+                    Some(Ok(res)) => {
                         // Let the app process the response if needed.
                         let res = if let Some(process_response) = process_fragment_response {
-                            process_response(&mut request, res)?
+                            slot.time_callback(CallbackKind::ResponseProcessor, || {
+                                process_response(&slot.fragment_context(), &mut request, res)
+                            })?
                         } else {
                             res
                         };
 
                         // Request has completed, check the status code.
-                        if res.get_status().is_success() {
-                            // Response status is success, write the response body to the output stream.
-                            output_writer
-                                .get_mut()
-                                .write_all(&res.into_body_bytes())
-                                .unwrap();
-                            output_writer
-                                .get_mut()
-                                .flush()
-                                .expect("failed to flush output");
-                        } else {
-                            // Response status is NOT success, either continue, fallback to an alt, or fail.
-                            if let Some(request) = alt {
-                                debug!("request poll DONE ERROR, trying alt");
-                                if let Some(fragment) = send_fragment_request(
-                                    request?,
-                                    None,
-                                    continue_on_error,
-                                    dispatch_fragment_request,
-                                )? {
-                                    // push the request back to front with ALT as the request
-                                    elements.push_front(Element::Include(fragment));
-                                    break;
-                                }
-                                debug!("guest returned None, continuing");
-                                continue;
-                            } else if continue_on_error {
-                                debug!("request poll DONE ERROR, NO ALT, continuing");
+                        slot.responded(res.get_status());
+                        let status_accepted = status_policy.is_success(res.get_status())
+                            && !res.contains_header(FAIL_FAST_HEADER);
+                        match resolve_fragment_failure(&FailureMechanisms {
+                            status_accepted,
+                            ..mechanisms
+                        }) {
+                            FailureResolution::Accepted
+                                if !status_policy.emits_body(res.get_status()) =>
+                            {
+                                slot.succeeded(&request);
                                 continue;
                             }
-                            debug!("request poll DONE ERROR, NO ALT, failing");
-                            return Err(ExecutionError::UnexpectedStatus(
-                                request.get_url_str().to_string(),
-                                res.get_status().into(),
-                            ));
+                            FailureResolution::Accepted => {
+                                trace!(
+                                    "Poll is success, {} - {}",
+                                    request.get_url_str(),
+                                    res.get_status()
+                                );
+                                // Response status is success, write the response body to the output.
+                                let start = output.task_len();
+                                slot.mark_start(&request, output)?;
+                                let body = Utf8Body::new(res, status_policy.utf8);
+                                let (bytes, failure) =
+                                    match copy_fragment_body(body, output, budget) {
+                                        CopyOutcome::ReadFailed { bytes, error } => {
+                                            debug!(
+                                                "fragment body failed after {bytes} bytes: {error}"
+                                            );
+                                            (bytes, FragmentFailure::BodyError(Rc::new(error)))
+                                        }
+                                        CopyOutcome::TooLarge { bytes, limit } => {
+                                            (bytes, FragmentFailure::TooLarge(limit))
+                                        }
+                                        outcome => {
+                                            outcome.into_result(request.get_url_str())?;
+                                            slot.mark_end(output)?;
+                                            slot.succeeded(&request);
+                                            continue;
+                                        }
+                                    };
+                                match start {
+                                    // Nothing has been written from a task yet, so a body that
+                                    // can't be copied in full is dropped, as if the fragment had
+                                    // failed outright.
+                                    Some(start) => output.truncate(start),
+                                    None => {
+                                        if bytes > 0 {
+                                            output_handler(
+                                                output,
+                                                TRUNCATED_FRAGMENT_MARKER.as_bytes(),
+                                            )?;
+                                        }
+                                        slot.mark_end(output)?;
+                                    }
+                                }
+                                failure
+                            }
+                            resolution => FragmentFailure::Status {
+                                status: res.get_status().into(),
+                                phase: if slot.is_alt() {
+                                    FailurePhase::Alt
+                                } else {
+                                    FailurePhase::Primary
+                                },
+                                // The body is only kept for a failure that's raised as an error.
+                                body_snippet: if resolution.fails() {
+                                    status_policy.body_snippet(res, &request)
+                                } else {
+                                    None
+                                },
+                            },
+                        }
+                    }
+                    Some(Err(err)) => {
+                        slot.errored(&request);
+                        return Err(err);
+                    }
+                };
+
+                // Response failed, either fall back to the alt, the fallback content or
+                // `onerror`, or fail.
+                let mut resolution = resolve_fragment_failure(&mechanisms);
+                // Whether the outcome of the include has been recorded already
+                let mut recorded = false;
+                if let (FailureResolution::Alt, Some(alt_request)) = (resolution, alt) {
+                    debug!("request poll DONE ERROR, trying alt");
+                    match usable_alt(alt_request, &slot)? {
+                        Ok(alt_request) => {
+                            if let Some(mut fragment) = send_fragment_request(
+                                alt_request,
+                                None,
+                                continue_on_error,
+                                dispatch_fragment_request,
+                                slot.alt(),
+                                status_policy,
+                            )? {
+                                // push the request back to front with ALT as the request
+                                fragment.fallback = fallback;
+                                elements.push_front(Element::Include(Box::new(fragment)));
+                                return Ok(PollTaskState::Pending);
+                            }
+                            debug!("guest returned None, continuing");
+                            continue;
+                        }
+                        // A rejected alt URL is handled like a failed alt request,
+                        // except that it fails processing with its own error.
+                        Err(err) => {
+                            resolution = resolve_fragment_failure(&FailureMechanisms {
+                                alt: false,
+                                ..mechanisms
+                            });
+                            if resolution.fails() {
+                                return Err(err);
+                            }
+                            recorded = true;
+                        }
+                    }
+                }
+                match (resolution, fallback) {
+                    (FailureResolution::Fallback, Some(fallback)) => {
+                        debug!("request poll DONE ERROR, NO ALT, writing fallback");
+                        if !recorded {
+                            slot.succeeded(&request);
+                        }
+                        slot.mark_start(&request, output)?;
+                        output.write_all(&fallback)?;
+                        slot.mark_end(output)?;
+                    }
+                    (FailureResolution::Skip | FailureResolution::Retry, _) => {
+                        debug!("request poll DONE ERROR, NO ALT, continuing");
+                        if !recorded {
+                            slot.failed(&request);
+                        }
+                        if resolution == FailureResolution::Retry {
+                            if let Some(number) = slot.queue_retry(&request, &status_policy) {
+                                slot.mark_start(&request, output)?;
+                                output.write_all(retry::placeholder(number).as_bytes())?;
+                                slot.mark_end(output)?;
+                            }
+                        }
+                    }
+                    _ => {
+                        debug!("request poll DONE ERROR, NO ALT, failing");
+                        slot.failed(&request);
+                        return Ok(PollTaskState::Failed(Box::new(request), failure));
+                    }
+                }
+                continue;
+            }
+
+            Element::Try { arms } if !wait => {
+                elements.push_front(Element::Try { arms });
+                return Ok(PollTaskState::Pending);
+            }
+
+            Element::Try { mut arms } => {
+                // Walk the arms in order, up to the first that succeeds. A try without an except
+                // arm has an empty one last, so when every other arm fails it writes nothing and
+                // processing carries on.
+                let mut first_failure = None;
+                let mut pending = false;
+                let mut written = None;
+                for (i, arm) in arms.iter_mut().enumerate() {
+                    // The requests of an `esi:except` arm are only sent once it's reached. They
+                    // are pending until the next poll, rather than waited on straight away.
+                    if dispatch_deferred(&mut arm.queue, dispatch_fragment_request)? {
+                        debug!("esi:try arm {i} reached, dispatched its requests");
+                        pending = true;
+                        break;
+                    }
+                    match poll_tasks(
+                        arm,
+                        dispatch_fragment_request,
+                        process_fragment_response,
+                        budget,
+                        scratch,
+                        buffers,
+                        &mut |f| {
+                            for_each_content(elements, f);
+                            around(f);
+                        },
+                    )? {
+                        PollTaskState::Succeeded => {
+                            written = Some(i);
+                            break;
+                        }
+                        PollTaskState::Failed(req, failure) => {
+                            debug!(
+                                "esi:try arm {i} failed on {}, trying the next one",
+                                req.get_url_str()
+                            );
+                            first_failure.get_or_insert((req, failure));
+                        }
+                        PollTaskState::Pending => {
+                            pending = true;
+                            break;
+                        }
+                    }
+                }
+
+                match (written, first_failure) {
+                    (Some(i), _) => {
+                        write_task(output, &arms[i])?;
+                        for arm in arms {
+                            buffers.release(scratch, arm.output);
+                        }
+                    }
+                    (None, Some((req, failure))) if !pending => {
+                        // every arm failed
+                        for arm in arms {
+                            buffers.release(scratch, arm.output);
                         }
+                        return Ok(PollTaskState::Failed(req, failure));
+                    }
+                    _ => {
+                        // Request are still pending, re-add it to the front of the queue and wait for the next poll.
+                        elements.push_front(Element::Try { arms });
+                        return Ok(PollTaskState::Pending);
                     }
-                    Err(err) => return Err(ExecutionError::RequestError(err)),
                 }
             }
 
-            Element::Try {
-                mut attempt_task,
-                mut except_task,
+            Element::Group {
+                name,
+                onerror,
+                task,
+            } if !wait => {
+                elements.push_front(Element::Group {
+                    name,
+                    onerror,
+                    task,
+                });
+                return Ok(PollTaskState::Pending);
+            }
+
+            Element::Group {
+                name,
+                onerror,
+                mut task,
             } => {
-                let attempt_state = poll_tasks(
-                    &mut attempt_task,
-                    dispatch_fragment_request,
-                    process_fragment_response,
-                )?;
-                let except_state = poll_tasks(
-                    &mut except_task,
+                match poll_tasks(
+                    &mut task,
                     dispatch_fragment_request,
                     process_fragment_response,
-                )?;
-
-                match (attempt_state, except_state) {
-                    (PollTaskState::Succeeded, _) => {
-                        output_handler(output_writer, &attempt_task.output.into_inner());
-                        continue;
+                    budget,
+                    scratch,
+                    buffers,
+                    &mut |f| {
+                        for_each_content(elements, f);
+                        around(f);
+                    },
+                )? {
+                    PollTaskState::Succeeded => {
+                        write_task(output, &task)?;
+                        buffers.release(scratch, task.output);
                     }
-                    (PollTaskState::Failed(_, _), PollTaskState::Succeeded) => {
-                        output_handler(output_writer, &except_task.output.into_inner());
-                        continue;
+                    // A group that fails inside a task fails the task, so that an `esi:try` arm
+                    // can fall back to the next one.
+                    PollTaskState::Failed(req, failure)
+                        if onerror == GroupErrorPolicy::Fail && output.task_len().is_some() =>
+                    {
+                        buffers.release(scratch, task.output);
+                        return Ok(PollTaskState::Failed(req, failure));
                     }
-                    (PollTaskState::Failed(req, res), PollTaskState::Failed(_req, _res)) => {
-                        // both tasks failed
-                        return Err(ExecutionError::UnexpectedStatus(
-                            req.get_url_str().to_string(),
-                            res,
-                        ));
+                    PollTaskState::Failed(req, failure) => {
+                        let content = failed_group_content(&name, &onerror, &req, failure)?;
+                        output_handler(output, content)?;
+                        buffers.release(scratch, task.output);
                     }
-                    (PollTaskState::Pending, _) | (_, PollTaskState::Pending) => {
-                        // Request are still pending, re-add it to the front of the queue and wait for the next poll.
-                        elements.push_front(Element::Try {
-                            attempt_task,
-                            except_task,
+                    PollTaskState::Pending => {
+                        elements.push_front(Element::Group {
+                            name,
+                            onerror,
+                            task,
                         });
-                        break;
+                        return Ok(PollTaskState::Pending);
                     }
                 }
             }
         }
     }
 
-    Ok(())
+    Ok(PollTaskState::Succeeded)
 }
 
+// Polls the queue of an `esi:try` arm or `esi:group` block into its buffer, waiting on each
+// fragment. Once the task has failed, it's never polled again.
 fn poll_tasks(
     task: &mut Task,
     dispatch_fragment_request: &FragmentRequestDispatcher,
-    process_fragment_response: Option<&FragmentResponseProcessor>,
+    process_fragment_response: Option<&FragmentContextProcessor>,
+    budget: &CopyBudget,
+    scratch: &ProcessorScratch,
+    buffers: &BufferAccountant,
+    around: &mut VisitContents,
 ) -> Result<PollTaskState> {
     // return the Failed status if it's already known
     if let PollTaskState::Failed(_, _) = &task.status {
         debug!("The task has previously failed, returning failed status");
         return Ok(task.status.clone());
     }
-    // loop over elements of the task
-    while let Some(element) = task.queue.pop_front() {
-        let (mut request, alt, continue_on_error, pending_request) = match element {
-            Element::Include(Fragment {
-                request,
-                alt,
-                continue_on_error,
-                pending_request,
-            }) => (request, alt, continue_on_error, pending_request),
-            Element::Raw(raw) => {
-                task.output.get_mut().extend_from_slice(&raw);
-                continue;
-            }
-            Element::Try {
-                attempt_task,
-                except_task,
-            } => {
-                let mut nested_try = VecDeque::from(vec![Element::Try {
-                    attempt_task,
-                    except_task,
-                }]);
-
-                poll_elements(
-                    &mut nested_try,
-                    &mut task.output,
-                    dispatch_fragment_request,
-                    process_fragment_response,
-                )?;
-
-                continue;
-            }
-        };
-
-        match pending_request.wait() {
-            Ok(res) => {
-                let res = if let Some(process_response) = process_fragment_response {
-                    process_response(&mut request, res)?
-                } else {
-                    res
-                };
+    let state = poll_elements(
+        &mut task.queue,
+        &mut TaskOutput {
+            output: &mut task.output,
+            boundaries: &mut task.boundaries,
+            buffers,
+        },
+        dispatch_fragment_request,
+        process_fragment_response,
+        budget,
+        scratch,
+        buffers,
+        true,
+        around,
+    )?;
+    if let PollTaskState::Failed(_, _) = &state {
+        task.status = state.clone();
+    }
+    Ok(state)
+}
 
-                if res.get_status().is_success() {
-                    trace!(
-                        "Poll is success, {} - {}",
-                        request.get_url_str(),
-                        res.get_status()
-                    );
-                    task.output
-                        .get_mut()
-                        .extend_from_slice(&res.into_body_bytes());
-                    continue;
-                }
-                // Response status is NOT success, either continue, fallback to an alt, or fail.
-                if let Some(req) = alt {
-                    debug!("request poll DONE ERROR, trying alt");
-                    if let Some(fragment) = send_fragment_request(
-                        req?,
-                        None,
-                        continue_on_error,
-                        dispatch_fragment_request,
-                    )? {
-                        // push the request back to front with ALT as the request
-                        task.queue.push_front(Element::Include(fragment));
-                        return Ok(PollTaskState::Pending);
-                    }
-                    debug!("guest returned None, continuing");
-                    continue;
+// Dispatches the requests in a queue that were deferred until its `esi:except` arm was reached,
+// along with those of the groups in it and the arms of its `esi:try` blocks other than their
+// own `esi:except` arms. Returns whether any were dispatched.
+fn dispatch_deferred(
+    elements: &mut VecDeque<Element>,
+    dispatch_fragment_request: &FragmentRequestDispatcher,
+) -> Result<bool> {
+    let mut dispatched = false;
+    for element in std::mem::take(elements) {
+        match element {
+            Element::Include(fragment)
+                if matches!(fragment.pending_content, FragmentContent::Deferred) =>
+            {
+                let Fragment {
+                    request,
+                    alt,
+                    continue_on_error,
+                    slot,
+                    status_policy,
+                    fallback,
+                    ..
+                } = *fragment;
+                dispatched = true;
+                if let Some(mut fragment) = send_fragment_request(
+                    request,
+                    alt,
+                    continue_on_error,
+                    dispatch_fragment_request,
+                    slot.restarted(),
+                    status_policy,
+                )? {
+                    fragment.fallback = fallback;
+                    elements.push_back(Element::Include(Box::new(fragment)));
                 }
-                if continue_on_error {
-                    debug!("request poll DONE ERROR, NO ALT, continuing");
-                    continue;
+            }
+            Element::Try { mut arms } => {
+                let except = arms.len() - 1;
+                for arm in &mut arms[..except] {
+                    dispatched |= dispatch_deferred(&mut arm.queue, dispatch_fragment_request)?;
                 }
-                debug!("request poll DONE ERROR, NO ALT, failing");
-                task.status = PollTaskState::Failed(request, res.get_status().into());
-                return Ok(task.status.clone());
+                elements.push_back(Element::Try { arms });
+            }
+            Element::Group {
+                name,
+                onerror,
+                mut task,
+            } => {
+                dispatched |= dispatch_deferred(&mut task.queue, dispatch_fragment_request)?;
+                elements.push_back(Element::Group {
+                    name,
+                    onerror,
+                    task,
+                });
             }
-            Err(err) => return Err(ExecutionError::RequestError(err)),
+            element => elements.push_back(element),
         }
     }
-    // no more elements, return success
-    Ok(PollTaskState::Succeeded)
+    Ok(dispatched)
+}
+
+// Returns what's written in place of an `esi:group` with a failed include, or the error it fails
+// with.
+fn failed_group_content<'a>(
+    name: &str,
+    onerror: &'a GroupErrorPolicy,
+    req: &Request,
+    failure: FragmentFailure,
+) -> Result<&'a [u8]> {
+    debug!(
+        "esi:group {name} failed on {}, applying {onerror:?}",
+        req.get_url_str()
+    );
+    match onerror {
+        GroupErrorPolicy::Omit => Ok(&[]),
+        GroupErrorPolicy::Placeholder(placeholder) => Ok(placeholder.as_bytes()),
+        GroupErrorPolicy::Fail => match failure {
+            FragmentFailure::Status { status, .. } => Err(ExecutionError::GroupFailed(
+                name.to_string(),
+                req.get_url_str().to_string(),
+                status,
+            )),
+            failure => Err(failure.into_error(req.get_url_str())),
+        },
+    }
 }
 
-// Helper function to create an XML reader from a body.
-fn reader_from_body(body: Body) -> Reader<Body> {
+// Helper function to create an XML reader from a body, parsing it with `options`.
+fn reader_from_body(body: Body, options: &ReaderOptions) -> Reader<Body> {
     let mut reader = Reader::from_reader(body);
 
-    // TODO: make this configurable
     let config = reader.config_mut();
-    config.check_end_names = false;
+    config.check_end_names = options.check_end_names;
+    config.trim_text(options.trim_text);
+    config.expand_empty_elements = options.expand_empty_elements;
 
     reader
 }
 
+// Writes the buffer of a task that succeeded, reporting the fragment boundaries in it as they're
+// reached.
+fn write_task(output: &mut impl Sink, task: &Task) -> std::io::Result<()> {
+    let mut written = 0;
+    for pending in &task.boundaries {
+        output.write_all(&task.output[written..pending.offset])?;
+        written = pending.offset;
+        output.boundary(&pending.sink, pending.boundary.clone());
+    }
+    output_handler(output, &task.output[written..])
+}
+
 // helper function to drive output to a response stream
-fn output_handler(output_writer: &mut Writer<impl Write>, buffer: &[u8]) {
-    output_writer.get_mut().write_all(buffer).unwrap();
-    output_writer
-        .get_mut()
-        .flush()
-        .expect("failed to flush output");
+fn output_handler(output: &mut impl Write, buffer: &[u8]) -> std::io::Result<()> {
+    output.write_all(buffer)?;
+    output.flush()
 }
@@ -0,0 +1,138 @@
+use crate::report::redact;
+use crate::warning::Warnings;
+use crate::{CallbackKind, MarkerStyle, Sink};
+use fastly::Request;
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+/// One end of the content written for an include, as marked with
+/// [`crate::Configuration::with_fragment_markers`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FragmentBoundary {
+    /// The content of the include starts, from the response to `src`, which is redacted like
+    /// [`crate::IncludeReport::url`].
+    Start { slot: usize, src: String },
+    /// The content of the include ends.
+    End { slot: usize },
+}
+
+impl fmt::Display for FragmentBoundary {
+    /// Writes the boundary as an HTML comment, e.g. `<!--esi-frag end slot=2 -->`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // A URL can't end the comment early, but could still hold `--`.
+            Self::Start { slot, src } => write!(
+                f,
+                "<!--esi-frag start slot={slot} src={} -->",
+                src.replace("--", "-%2D")
+            ),
+            Self::End { slot } => write!(f, "<!--esi-frag end slot={slot} -->"),
+        }
+    }
+}
+
+/// A function called with each [`FragmentBoundary`] as the output reaches it, with
+/// [`MarkerStyle::Callback`].
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// let sink = esi::BoundarySink::new(|boundary| println!("{boundary:?}"));
+/// let config = esi::Configuration::default()
+///     .with_fragment_markers(esi::MarkerStyle::Callback(sink));
+/// ```
+#[derive(Clone)]
+pub struct BoundarySink(Rc<dyn Fn(&FragmentBoundary)>);
+
+impl BoundarySink {
+    pub fn new(sink: impl Fn(&FragmentBoundary) + 'static) -> Self {
+        Self(Rc::new(sink))
+    }
+
+    pub(crate) fn call(&self, boundary: &FragmentBoundary) {
+        (self.0)(boundary);
+    }
+
+    /// The same sink, with each call timed in `warnings`.
+    pub(crate) fn timed(self, warnings: &Warnings) -> Self {
+        let warnings = warnings.clone();
+        Self::new(move |boundary| {
+            warnings.time_callback(CallbackKind::BoundarySink, warnings.position(), || {
+                self.call(boundary);
+            });
+        })
+    }
+}
+
+impl fmt::Debug for BoundarySink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BoundarySink")
+    }
+}
+
+/// A boundary reached in the buffer of a task, which is reported once the buffer is written.
+pub(crate) struct PendingBoundary {
+    // The length of the buffer when the boundary was reached
+    pub offset: usize,
+    pub sink: BoundarySink,
+    pub boundary: FragmentBoundary,
+}
+
+/// Numbers the includes of a document in the order they're reached, for their markers.
+#[derive(Debug)]
+pub(crate) struct FragmentMarkers {
+    style: MarkerStyle,
+    next_slot: Cell<usize>,
+}
+
+impl FragmentMarkers {
+    pub fn new(style: MarkerStyle) -> Self {
+        Self {
+            style,
+            next_slot: Cell::new(0),
+        }
+    }
+
+    /// The marker for the next include.
+    pub fn next(&self) -> SlotMarker {
+        let slot = self.next_slot.get();
+        self.next_slot.set(slot + 1);
+        SlotMarker {
+            style: self.style.clone(),
+            slot,
+        }
+    }
+}
+
+/// Marks the boundaries of the content written for one include.
+#[derive(Clone, Debug)]
+pub(crate) struct SlotMarker {
+    style: MarkerStyle,
+    slot: usize,
+}
+
+impl SlotMarker {
+    pub fn start(&self, request: &Request, output: &mut impl Sink) -> std::io::Result<()> {
+        self.mark(
+            FragmentBoundary::Start {
+                slot: self.slot,
+                src: redact(request.get_url()),
+            },
+            output,
+        )
+    }
+
+    pub fn end(&self, output: &mut impl Sink) -> std::io::Result<()> {
+        self.mark(FragmentBoundary::End { slot: self.slot }, output)
+    }
+
+    fn mark(&self, boundary: FragmentBoundary, output: &mut impl Sink) -> std::io::Result<()> {
+        match &self.style {
+            MarkerStyle::HtmlComment => output.write_all(boundary.to_string().as_bytes()),
+            MarkerStyle::Callback(sink) => {
+                output.boundary(sink, boundary);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! The names of the metrics recorded through the [`metrics`](https://docs.rs/metrics) facade when
+//! the `metrics` feature is enabled, which flow into whichever recorder the application installs.
+//!
+//! Names and labels are part of the public API, and don't change between releases. Labels only
+//! take a handful of values each:
+//!
+//! - `result`: `ok` or `error`
+//! - `outcome`: `ok`, `alt` or `failed`, as in [`crate::IncludeOutcome`]
+//! - `status_class`: `1xx` to `5xx`, or `other`
+//! - `alt_used`: `true` for the request made for an include's `alt` URL, otherwise `false`
+
+/// Counter of documents processed, labelled with `result`.
+pub const DOCUMENTS: &str = "esi_documents_total";
+/// Histogram of the time taken to process a document in seconds, labelled with `result`.
+pub const DOCUMENT_DURATION: &str = "esi_document_duration_seconds";
+/// Counter of bytes written to the output by documents that were processed successfully.
+pub const BYTES_WRITTEN: &str = "esi_bytes_written_total";
+/// Gauge of the most bytes of content buffered at once by the last document processed
+/// successfully, see [`crate::ProcessingReport::peak_buffered_bytes`].
+pub const PEAK_BUFFERED_BYTES: &str = "esi_peak_buffered_bytes";
+/// Gauge of the most elements queued at once by the last document processed successfully, see
+/// [`crate::ProcessingReport::peak_queue_len`].
+pub const PEAK_QUEUE_LEN: &str = "esi_peak_queue_len";
+/// Counter of fragment requests handed to the dispatcher, labelled with `alt_used`.
+pub const FRAGMENT_REQUESTS: &str = "esi_fragment_requests_total";
+/// Counter of fragment responses received, labelled with `status_class` and `alt_used`.
+pub const FRAGMENT_RESPONSES: &str = "esi_fragment_responses_total";
+/// Counter of fragment requests that failed without a response, either when being dispatched
+/// or while waiting for the response, labelled with `alt_used`.
+pub const FRAGMENT_ERRORS: &str = "esi_fragment_errors_total";
+/// Counter of fragment requests that the dispatcher failed without sending them, with
+/// [`crate::PendingFragmentContent::FailFast`], labelled with `alt_used`. Their reasons are in
+/// the warnings rather than a label, to keep the number of series down.
+pub const FRAGMENTS_FAILED_FAST: &str = "esi_fragments_failed_fast_total";
+/// Counter of fragment requests that were still pending when processing stopped, either because
+/// it failed or because the output was cut short, labelled with `alt_used`.
+pub const FRAGMENTS_ABANDONED: &str = "esi_fragments_abandoned_total";
+/// Counter of includes resolved, labelled with `outcome`.
+pub const INCLUDES: &str = "esi_includes_total";
+/// Histogram of the time from dispatching an include to resolving it in seconds, including any
+/// `alt` request, labelled with `outcome`.
+pub const INCLUDE_DURATION: &str = "esi_include_duration_seconds";
+/// Counter of includes handled by [`crate::Processor::prefetch`], labelled with `outcome`:
+/// `warmed`, `failed` or `skipped`, as in [`crate::PrefetchReport`].
+pub const PREFETCHED_FRAGMENTS: &str = "esi_prefetched_fragments_total";
+
+#[cfg(feature = "metrics")]
+mod record {
+    use super::*;
+    use crate::{IncludeOutcome, PrefetchReport, ProcessingReport, Result};
+    use fastly::http::StatusCode;
+    use std::time::Duration;
+
+    fn alt_used(is_alt: bool) -> &'static str {
+        if is_alt {
+            "true"
+        } else {
+            "false"
+        }
+    }
+
+    pub(crate) fn document(result: &Result<ProcessingReport>, elapsed: Duration) {
+        let label = if result.is_ok() { "ok" } else { "error" };
+        ::metrics::counter!(DOCUMENTS, "result" => label).increment(1);
+        ::metrics::histogram!(DOCUMENT_DURATION, "result" => label).record(elapsed);
+        if let Ok(report) = result {
+            ::metrics::counter!(BYTES_WRITTEN).increment(report.bytes_written as u64);
+            ::metrics::gauge!(PEAK_BUFFERED_BYTES).set(report.peak_buffered_bytes as f64);
+            ::metrics::gauge!(PEAK_QUEUE_LEN).set(report.peak_queue_len as f64);
+        }
+    }
+
+    pub(crate) fn fragment_request(is_alt: bool) {
+        ::metrics::counter!(FRAGMENT_REQUESTS, "alt_used" => alt_used(is_alt)).increment(1);
+    }
+
+    pub(crate) fn fragment_response(status: StatusCode, is_alt: bool) {
+        let status_class = match status.as_u16() {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            500..=599 => "5xx",
+            _ => "other",
+        };
+        ::metrics::counter!(
+            FRAGMENT_RESPONSES,
+            "status_class" => status_class,
+            "alt_used" => alt_used(is_alt)
+        )
+        .increment(1);
+    }
+
+    pub(crate) fn fragment_error(is_alt: bool) {
+        ::metrics::counter!(FRAGMENT_ERRORS, "alt_used" => alt_used(is_alt)).increment(1);
+    }
+
+    pub(crate) fn fragment_failed_fast(is_alt: bool) {
+        ::metrics::counter!(FRAGMENTS_FAILED_FAST, "alt_used" => alt_used(is_alt)).increment(1);
+    }
+
+    pub(crate) fn fragment_abandoned(is_alt: bool) {
+        ::metrics::counter!(FRAGMENTS_ABANDONED, "alt_used" => alt_used(is_alt)).increment(1);
+    }
+
+    pub(crate) fn include(outcome: IncludeOutcome, elapsed: Duration) {
+        let label = match outcome {
+            IncludeOutcome::Ok => "ok",
+            IncludeOutcome::Alt => "alt",
+            IncludeOutcome::Failed => "failed",
+            IncludeOutcome::Retried => "retried",
+        };
+        ::metrics::counter!(INCLUDES, "outcome" => label).increment(1);
+        ::metrics::histogram!(INCLUDE_DURATION, "outcome" => label).record(elapsed);
+    }
+
+    pub(crate) fn prefetch(report: &PrefetchReport) {
+        for (label, count) in [
+            ("warmed", report.warmed),
+            ("failed", report.failed),
+            ("skipped", report.skipped),
+        ] {
+            ::metrics::counter!(PREFETCHED_FRAGMENTS, "outcome" => label).increment(count as u64);
+        }
+    }
+}
+
+// Without the feature, recording compiles down to nothing.
+#[cfg(not(feature = "metrics"))]
+mod record {
+    use crate::{IncludeOutcome, PrefetchReport, ProcessingReport, Result};
+    use fastly::http::StatusCode;
+    use std::time::Duration;
+
+    pub(crate) fn document(_: &Result<ProcessingReport>, _: Duration) {}
+    pub(crate) fn fragment_request(_: bool) {}
+    pub(crate) fn fragment_response(_: StatusCode, _: bool) {}
+    pub(crate) fn fragment_error(_: bool) {}
+    pub(crate) fn fragment_failed_fast(_: bool) {}
+    pub(crate) fn fragment_abandoned(_: bool) {}
+    pub(crate) fn include(_: IncludeOutcome, _: Duration) {}
+    pub(crate) fn prefetch(_: &PrefetchReport) {}
+}
+
+pub(crate) use record::*;
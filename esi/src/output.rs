@@ -0,0 +1,207 @@
+use crate::report::ProcessingOutcome;
+use crate::timings::PhaseRecorder;
+use crate::validate::OutputValidator;
+use crate::{Phase, Warning};
+use fastly::Response;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// A writer that keeps track of how many bytes have been written through it, and whether writing
+/// has failed.
+pub(crate) struct CountingWriter<W: Write> {
+    inner: W,
+    outcome: ProcessingOutcome,
+    // Where the first byte written is recorded, if it is
+    phases: Option<PhaseRecorder>,
+    // What the bytes written are checked with, if they are
+    validator: Option<OutputValidator>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W, outcome: ProcessingOutcome) -> Self {
+        Self {
+            inner,
+            outcome,
+            phases: None,
+            validator: None,
+        }
+    }
+
+    /// Records [`Phase::FirstOutputByte`] in `phases` when the first byte is written.
+    pub fn with_phases(mut self, phases: PhaseRecorder) -> Self {
+        self.phases = Some(phases);
+        self
+    }
+
+    /// Checks every byte written with `validator`, if there's one.
+    pub fn with_validator(mut self, validator: Option<OutputValidator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    // Records a failure, unless it's an interruption that the write is retried after.
+    fn failed(&self, err: &std::io::Error) {
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            self.outcome.output_failed();
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf).inspect_err(|err| self.failed(err))?;
+        self.outcome.counter().add(written);
+        if let Some(validator) = &self.validator {
+            validator.check(&buf[..written]);
+        }
+        if written > 0 {
+            if let Some(phases) = &self.phases {
+                phases.reach(Phase::FirstOutputByte);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().inspect_err(|err| self.failed(err))
+    }
+}
+
+/// The client response, until its headers have been sent.
+pub(crate) type PendingClientResponse = Rc<RefCell<Option<Response>>>;
+
+/// How far the output has got, for the features that have to come before the client response
+/// starts. Each of them is checked with [`Self::require_prestream`] or
+/// [`Self::require_complete_output`], so that those that come too late are all reported the same
+/// way, as a [`Warning::TooLate`].
+#[derive(Clone, Default)]
+pub(crate) struct OutputCursor {
+    // The client response, until its status and headers are sent, if the processor owns it
+    client_response: Option<PendingClientResponse>,
+    // Whether the output is sent as it's written, rather than once it's complete
+    streaming: bool,
+}
+
+impl OutputCursor {
+    pub fn new(client_response: Option<PendingClientResponse>, streaming: bool) -> Self {
+        Self {
+            client_response,
+            streaming,
+        }
+    }
+
+    /// Whether the status and headers of the client response have been sent, which is once the
+    /// first non-whitespace byte is written, or there's no client response to set them on.
+    pub fn has_streamed(&self) -> bool {
+        self.client_response
+            .as_ref()
+            .is_none_or(|response| response.borrow().is_none())
+    }
+
+    /// Fails with a [`Warning::TooLate`] for `feature` at `position` if the client response has
+    /// started, for features that change its status or headers.
+    pub fn require_prestream(&self, feature: &str, position: usize) -> Result<(), Warning> {
+        if self.has_streamed() {
+            return Err(Warning::TooLate {
+                position,
+                feature: feature.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails with a [`Warning::TooLate`] for `feature` at `position` if the output is streamed,
+    /// for features that depend on the whole output, which is then only known once the client
+    /// response has started.
+    pub fn require_complete_output(&self, feature: &str, position: usize) -> Result<(), Warning> {
+        if self.streaming {
+            return Err(Warning::TooLate {
+                position,
+                feature: feature.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Calls `update` with the client response, unless it has started.
+    pub fn update_response(&self, update: impl FnOnce(&mut Response)) {
+        if let Some(response) = &self.client_response {
+            if let Some(response) = response.borrow_mut().as_mut() {
+                update(response);
+            }
+        }
+    }
+}
+
+/// A writer for the client response body, holding back the response headers until the first
+/// non-whitespace byte is written so that the document can still change them.
+pub(crate) struct ClientResponseWriter<W: Write, F: FnOnce(Response) -> W> {
+    pending: PendingClientResponse,
+    // Sends the response headers and opens the body
+    open: Option<F>,
+    // Whitespace written before the headers were sent
+    whitespace: Vec<u8>,
+    body: Option<W>,
+}
+
+impl<W: Write, F: FnOnce(Response) -> W> ClientResponseWriter<W, F> {
+    pub fn new(pending: PendingClientResponse, open: F) -> Self {
+        Self {
+            pending,
+            open: Some(open),
+            whitespace: Vec::new(),
+            body: None,
+        }
+    }
+
+    /// Sends the response headers if that hasn't been done yet, and returns the body.
+    pub fn into_body(mut self) -> std::io::Result<W> {
+        self.body()?;
+        Ok(self.body.expect("body was just opened"))
+    }
+
+    fn body(&mut self) -> std::io::Result<&mut W> {
+        if let (None, Some(open)) = (&self.body, self.open.take()) {
+            let response = self
+                .pending
+                .borrow_mut()
+                .take()
+                .expect("client response was already sent");
+            let mut body = open(response);
+            body.write_all(&std::mem::take(&mut self.whitespace))?;
+            self.body = Some(body);
+        }
+        Ok(self.body.as_mut().expect("body was just opened"))
+    }
+}
+
+impl<W: Write, F: FnOnce(Response) -> W> Write for ClientResponseWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.body.is_none() && buf.iter().all(u8::is_ascii_whitespace) {
+            self.whitespace.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        self.body()?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.body.as_mut() {
+            Some(body) => body.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A response whose body is written in full before it's returned rather than streamed.
+pub(crate) struct BufferedResponse(pub Response);
+
+impl Write for BufferedResponse {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.get_body_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,10 +1,23 @@
-use crate::{ExecutionError, Result};
-use log::debug;
-use quick_xml::events::{BytesStart, Event as XmlEvent};
+use crate::attributes::{
+    AttributeMap, FromAttribute, Parsed, KNOWN_ATTRIBUTES, REPEATABLE_ATTRIBUTES,
+};
+use crate::request::unescape_url;
+use crate::scheduling::EventBudget;
+use crate::source::{SourceLines, SourceWindow};
+use crate::warning::Warnings;
+use crate::{ExecutionError, Result, Warning};
+use log::{debug, log_enabled, Level};
+use quick_xml::errors::IllFormedError;
+use quick_xml::escape::{resolve_predefined_entity, unescape_with};
+use quick_xml::events::{BytesStart, BytesText, Event as XmlEvent};
 use quick_xml::name::QName;
 use quick_xml::Reader;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::io::BufRead;
 use std::ops::Deref;
+use std::rc::Rc;
 
 // State carrier of Try branch
 #[derive(Debug, PartialEq)]
@@ -14,25 +27,171 @@ enum TryTagArms {
     Except,
 }
 
-/// Representation of an ESI tag from a source response.
-#[derive(Debug)]
+/// An `<esi:include>` tag, or one of the URLs of its `srcset`.
+///
+/// Attributes other than `src`, `alt` and `onerror` are parsed into the types in
+/// [`crate::attributes`], and are looked up with [`Include::get`], so that new ones can be added
+/// without changing the fields.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Include {
     pub src: String,
     pub alt: Option<String>,
     pub continue_on_error: bool,
+    /// The position of `src` in the `srcset` attribute it was taken from, if any. An include
+    /// with a `srcset` of several URLs is parsed as one include for each of them, in order.
+    pub srcset_index: Option<usize>,
+    /// Attributes in the ESI namespace, such as `esi:backend="catalog"`, by name without the
+    /// namespace prefix. These carry private metadata for the application, and are never
+    /// written to the output.
+    pub extensions: Vec<(String, String)>,
+    /// The content of an include that isn't self-closing, written in its place if the
+    /// fragment and its `alt` both fail. It's kept as it's written, without the ESI tags in
+    /// it, and is `None` for a self-closing include or one with only whitespace inside.
+    pub fallback: Option<Vec<u8>>,
+    // The attributes parsed into their own types
+    attributes: AttributeMap,
 }
 
+impl Include {
+    /// Creates an include of `src`, without any other attributes.
+    pub fn new(src: impl Into<String>) -> Self {
+        Self {
+            src: src.into(),
+            alt: None,
+            continue_on_error: false,
+            srcset_index: None,
+            extensions: Vec::new(),
+            fallback: None,
+            attributes: AttributeMap::default(),
+        }
+    }
+
+    /// Sets the URL requested if the `src` one fails.
+    pub fn with_alt(mut self, alt: impl Into<String>) -> Self {
+        self.alt = Some(alt.into());
+        self
+    }
+
+    /// Sets whether the include is left out if it fails, as with `onerror="continue"`.
+    pub fn with_continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// Sets an attribute, replacing any value it had.
+    pub fn with_attribute<T: FromAttribute>(mut self, value: T) -> Self {
+        self.attributes.insert(T::NAME, Rc::new(value));
+        self
+    }
+
+    /// The value of an attribute, if it was set.
+    pub fn get<T: FromAttribute>(&self) -> Option<&T> {
+        self.attributes.get()
+    }
+}
+
+/// A parsed ESI tag.
+///
+/// The URLs of includes, the `test` of `<esi:when>` branches, the content and `name` of
+/// `<esi:vars>` and attributes prefixed with `esi:attr-` hold expressions, which are evaluated for
+/// the original request:
+///
+/// - `$(NAME)`, `$(NAME{key})` and `$(NAME|default)` are variables: `REQUEST_METHOD`,
+///   `REQUEST_PATH`, `QUERY_STRING`, `HTTP_HOST`, `HTTP_COOKIE{name}`,
+///   `HTTP_ACCEPT_LANGUAGE{language}`, `GEO{key}` for the client's [`crate::GeoData`], and any
+///   other request header as `HTTP_<NAME>`, e.g. `$(HTTP_USER_AGENT)`.
+/// - `$name(arg, ...)` calls one of the functions `$bucket`, `$pick`, `$index`, `$rindex`,
+///   `$substr`, `$len`, `$lower`, `$upper`, `$replace`, `$url_encode`, `$url_decode` and
+///   `$html_encode`, which nest, e.g. `$lower($substr($(HTTP_USER_AGENT), 0, 10))`.
+///
+/// An attribute such as `<html esi:attr-lang="$(QUERY_STRING{lang})">` is written as
+/// `<html lang="en">`, and left out if it evaluates to an empty string. The URLs of an include
+/// are evaluated once, when it's reached in document order, so failing over to `alt` or retrying
+/// a skipped include reuses them; see [`crate::Configuration::vars_in_attributes`] to turn that
+/// off.
 #[derive(Debug)]
 pub enum Tag<'a> {
-    Include {
-        src: String,
-        alt: Option<String>,
-        continue_on_error: bool,
-    },
+    Include(Include),
+    /// An `<esi:try>` block, with its arms in the order they're tried: every `<esi:attempt>` and
+    /// `<esi:retry>` arm as written, then any `<esi:except>` arm. The first arm whose includes all
+    /// succeed is written. The `<esi:except>` arm is optional: without one, a try whose arms all
+    /// fail renders nothing, like `onerror="continue"` on an include.
     Try {
-        attempt_events: Vec<Event<'a>>,
-        except_events: Vec<Event<'a>>,
+        arms: Vec<TryArm<'a>>,
+    },
+    /// An `<esi:group>` block, whose content is only written once every include in it has
+    /// completed, and only if none of them failed. Otherwise `onerror` decides what's written in
+    /// place of the whole block. Includes with `onerror="continue"` don't fail the group.
+    Group {
+        name: String,
+        onerror: GroupErrorPolicy,
+        events: Vec<Event<'a>>,
+    },
+    /// An `<esi:choose>` block, with the `test` and events of each `<esi:when>` branch in order,
+    /// and the events of the `<esi:otherwise>` branch, if there is one. Only the first branch whose
+    /// test is true is processed, or the `<esi:otherwise>` branch if none is; the includes of the
+    /// others are never requested.
+    Choose {
+        when_branches: Vec<(String, Vec<Event<'a>>)>,
+        otherwise_events: Vec<Event<'a>>,
+    },
+    /// The `text` of an `<esi:comment>` tag, with debug comments on. It's evaluated like an
+    /// include URL and written as an HTML comment with both the text and its value.
+    DebugComment {
+        text: String,
+    },
+    /// The `name` of a self-closing `<esi:vars>` tag, an expression evaluated like an include URL
+    /// and written in place of the tag as text. The value is escaped for HTML, or as the content
+    /// of a JSON string with [`crate::Configuration::is_escaped`] off, unless the tag has
+    /// `raw="true"`.
+    VarsValue {
+        name: String,
+        raw: bool,
     },
+    /// The content of an `<esi:vars>` block, written in place of the block with the variables and
+    /// functions in it evaluated. Markup in it is kept as it is, and the values are escaped like
+    /// those of [`Self::VarsValue`]. Unknown variables without a default are written as they
+    /// are, and ESI tags in the block are left out, raising a warning.
+    Vars {
+        text: String,
+    },
+    /// An `<esi:response>` tag, setting the status and headers of the client response. Each
+    /// `header-<name>` attribute becomes a `<name>` header.
+    ResponseDirective {
+        status: Option<u16>,
+        headers: Vec<(String, String)>,
+    },
+}
+
+/// An arm of an `<esi:try>` block.
+#[derive(Debug)]
+pub struct TryArm<'a> {
+    pub kind: TryArmKind,
+    pub events: Vec<Event<'a>>,
+}
+
+/// The tag an arm of an `<esi:try>` block was written with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TryArmKind {
+    Attempt,
+    Except,
+    /// Another arm tried in order like an attempt, such as `retry` for `<esi:retry>`, by name
+    /// without the namespace prefix.
+    Custom(String),
+}
+
+/// What's written in place of an `<esi:group>` block when one of its includes fails, from its
+/// `onerror` attribute.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum GroupErrorPolicy {
+    /// Leave the block out, with `onerror="omit"` or no `onerror` attribute.
+    #[default]
+    Omit,
+    /// Write the `placeholder` attribute instead, with `onerror="placeholder"`.
+    Placeholder(String),
+    /// Fail like an include without `onerror="continue"` would, with `onerror="fail"`.
+    Fail,
 }
 
 /// Representation of either XML data or a parsed ESI tag.
@@ -43,146 +202,660 @@ pub enum Event<'e> {
     ESI(Tag<'e>),
 }
 
-// #[derive(Debug)]
+// The kinds of ESI tags the parser handles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EsiTagKind {
+    Include,
+    Comment,
+    Remove,
+    Try,
+    Attempt,
+    Retry,
+    Except,
+    Group,
+    Response,
+    Vars,
+    Choose,
+    When,
+    Otherwise,
+}
+
+// Recognizes the ESI tags of a namespace. Only element names are ever matched, never text,
+// comments, CDATA or attribute values.
 struct EsiTags {
-    include: Vec<u8>,
-    comment: Vec<u8>,
-    remove: Vec<u8>,
-    tryy: Vec<u8>,
-    attempt: Vec<u8>,
-    except: Vec<u8>,
+    // The namespace followed by a colon, e.g. `esi:`
+    prefix: Vec<u8>,
 }
 impl EsiTags {
     fn init(namespace: &str) -> Self {
         Self {
-            include: format!("{namespace}:include",).into_bytes(),
-            comment: format!("{namespace}:comment",).into_bytes(),
-            remove: format!("{namespace}:remove",).into_bytes(),
-            tryy: format!("{namespace}:try",).into_bytes(),
-            attempt: format!("{namespace}:attempt",).into_bytes(),
-            except: format!("{namespace}:except",).into_bytes(),
+            prefix: format!("{namespace}:").into_bytes(),
+        }
+    }
+
+    // Returns the kind of ESI tag with the given name, if it is one. The namespace prefix is
+    // checked first, so most non-ESI tags are ruled out with a single comparison.
+    fn classify(&self, name: &QName) -> Option<EsiTagKind> {
+        match name.as_ref().strip_prefix(self.prefix.as_slice())? {
+            b"include" => Some(EsiTagKind::Include),
+            b"comment" => Some(EsiTagKind::Comment),
+            b"remove" => Some(EsiTagKind::Remove),
+            b"try" => Some(EsiTagKind::Try),
+            b"attempt" => Some(EsiTagKind::Attempt),
+            b"retry" => Some(EsiTagKind::Retry),
+            b"except" => Some(EsiTagKind::Except),
+            b"group" => Some(EsiTagKind::Group),
+            b"response" => Some(EsiTagKind::Response),
+            b"vars" => Some(EsiTagKind::Vars),
+            b"choose" => Some(EsiTagKind::Choose),
+            b"when" => Some(EsiTagKind::When),
+            b"otherwise" => Some(EsiTagKind::Otherwise),
+            _ => None,
+        }
+    }
+
+    // Returns the kind of ESI tag an event opens or closes, if any.
+    fn classify_event(&self, event: &XmlEvent) -> Option<EsiTagKind> {
+        match event {
+            XmlEvent::Start(e) | XmlEvent::Empty(e) => self.classify(&e.name()),
+            XmlEvent::End(e) => self.classify(&e.name()),
+            _ => None,
+        }
+    }
+
+    // Returns the name of a tag in the ESI namespace that isn't supported, if the event opens or
+    // closes one.
+    fn unknown_tag(&self, event: &XmlEvent) -> Option<String> {
+        let name = match event {
+            XmlEvent::Start(e) | XmlEvent::Empty(e) => e.name(),
+            XmlEvent::End(e) => e.name(),
+            _ => return None,
+        };
+        (name.as_ref().starts_with(&self.prefix) && self.classify(&name).is_none())
+            .then(|| String::from_utf8_lossy(name.as_ref()).into_owned())
+    }
+}
+
+// A block whose content is skipped: an `esi:remove` block, or the body of an `esi:include`
+struct SuppressedBlock {
+    // The name of the tag that ends the block
+    end_tag: Vec<u8>,
+    // The position in the document where the block starts
+    position: usize,
+    // How many tags of the same name as the block's are open inside it, such as nested
+    // `esi:remove` blocks
+    nested: usize,
+    // How many `esi:attempt`, `esi:except`, `esi:when` and `esi:otherwise` arms have been opened
+    // and not closed in the block
+    open_arms: usize,
+    // The content of the block so far, if it's kept as the fallback of an `esi:include`
+    capture: Option<Vec<u8>>,
+}
+
+// Parser state shared across the recursion into `esi:attempt` and `esi:except` arms
+struct ParserState {
+    // The depth of nested `esi:try` tags
+    depth: usize,
+    current_arm: Option<TryTagArms>,
+    suppressed: Option<SuppressedBlock>,
+    // The depth of the `esi:group` being parsed, if any. Groups can't be nested.
+    group_depth: Option<usize>,
+    warnings: Warnings,
+    options: ParseOptions,
+    // The source bytes of the events, if content is written out exactly as it was read
+    source: Option<SourceWindow>,
+}
+
+/// Options for how the parser reads documents.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ParseOptions {
+    // The most bytes of the document that are parsed
+    pub max_document_size: Option<usize>,
+    // The events parsed so far, and the most that are
+    pub events: EventBudget,
+    // The longest attribute value of an `esi:include` tag
+    pub max_attribute_length: Option<usize>,
+    // Whether the content of `esi:comment` blocks is parsed rather than skipped
+    pub process_in_comments: bool,
+    // Whether the `text` of `esi:comment` tags is kept as a debug comment
+    pub debug_comments: bool,
+    // Whether include URLs are XML-escaped, and are unescaped as they're parsed
+    pub unescape_urls: bool,
+    // Whether content is written out exactly as it was read, from the source bytes
+    pub raw_passthrough: bool,
+    // Whether markup that can't be parsed is an error, rather than a warning
+    pub strict_markup: bool,
+    // Whether tags in the ESI namespace that aren't supported are an error, rather than a warning
+    pub strict_tags: bool,
+    // How far the reader has read, if it counts lines
+    pub lines: Option<SourceLines>,
+}
+
+impl ParserState {
+    fn suppress(&mut self, end_tag: &[u8], position: usize) {
+        self.suppressed = Some(SuppressedBlock {
+            end_tag: end_tag.to_vec(),
+            position,
+            nested: 0,
+            open_arms: 0,
+            capture: None,
+        });
+    }
+
+    // Suppresses the body of an `esi:include` like `suppress`, keeping its content.
+    fn capture(&mut self, end_tag: &[u8], position: usize) {
+        self.suppress(end_tag, position);
+        if let Some(block) = self.suppressed.as_mut() {
+            block.capture = Some(Vec::new());
+        }
+    }
+}
+
+// Skips an event inside a suppressed block, ending the block at its closing tag. A block has to
+// be closed within the same `esi:attempt` or `esi:except` arm it was opened in. Every ESI tag in
+// the block is skipped along with the rest of its content, raising a warning. Other content is
+// kept if the block captures it, and is returned once a capturing block ends.
+fn skip_suppressed_event(
+    state: &mut ParserState,
+    event: &XmlEvent,
+    position: usize,
+    tag: &EsiTags,
+) -> Result<Option<Vec<u8>>> {
+    let Some(block) = state.suppressed.as_mut() else {
+        return Ok(None);
+    };
+    let is_esi = match event {
+        XmlEvent::Start(e) | XmlEvent::Empty(e) => {
+            let is_esi = e.name().as_ref().starts_with(&tag.prefix);
+            if is_esi {
+                state.warnings.push(Warning::IgnoredTag {
+                    position,
+                    tag: String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+                    reason: format!("it's inside {}", String::from_utf8_lossy(&block.end_tag)),
+                });
+            }
+            is_esi
+        }
+        XmlEvent::End(e) => e.name().as_ref().starts_with(&tag.prefix),
+        _ => false,
+    };
+    if let Some(capture) = block.capture.as_mut() {
+        if !is_esi && !matches!(event, XmlEvent::Eof) {
+            quick_xml::Writer::new(capture).write_event(event)?;
+        }
+    }
+    let is_arm = matches!(
+        tag.classify_event(event),
+        Some(
+            EsiTagKind::Attempt
+                | EsiTagKind::Retry
+                | EsiTagKind::Except
+                | EsiTagKind::When
+                | EsiTagKind::Otherwise
+        )
+    );
+    let crosses_boundary = |block: &SuppressedBlock| {
+        Err(ExecutionError::TagCrossesArmBoundary(
+            String::from_utf8_lossy(&block.end_tag).to_string(),
+            block.position,
+        ))
+    };
+
+    match event {
+        XmlEvent::Start(e) if e.name() == QName(&block.end_tag) => {
+            block.nested += 1;
+        }
+        XmlEvent::End(e) if e.name() == QName(&block.end_tag) && block.nested > 0 => {
+            block.nested -= 1;
+        }
+        XmlEvent::End(e) if e.name() == QName(&block.end_tag) => {
+            if block.open_arms > 0 {
+                return crosses_boundary(block);
+            }
+            return Ok(state.suppressed.take().and_then(|block| block.capture));
+        }
+        XmlEvent::Start(_) if is_arm => {
+            block.open_arms += 1;
+        }
+        XmlEvent::End(_) if is_arm => {
+            if block.open_arms == 0 {
+                return crosses_boundary(block);
+            }
+            block.open_arms -= 1;
         }
+        XmlEvent::Eof => return Err(ExecutionError::UnexpectedEndOfDocument),
+        _ => {}
     }
+
+    Ok(None)
 }
 
 fn do_parse<'a, R>(
     reader: &mut Reader<R>,
     callback: &mut dyn FnMut(Event<'a>) -> Result<()>,
     task: &mut Vec<Event<'a>>,
-    depth: &mut usize,
-    current_arm: &mut Option<TryTagArms>,
+    state: &mut ParserState,
     tag: &EsiTags,
+    pause: &dyn Fn() -> bool,
 ) -> Result<()>
 where
     R: BufRead,
 {
-    let mut is_remove_tag = false;
-    let mut open_include = false;
-
-    let attempt_events = &mut Vec::new();
-    let except_events = &mut Vec::new();
+    let arms = &mut Vec::new();
+    // The includes of an open `esi:include` tag, held until its body is read, and its position
+    let mut open_includes = Vec::new();
+    let mut open_include_position = 0;
+    // Whether the block being captured is the content of an `esi:vars` tag, not an include body
+    let mut open_vars = false;
+    // The position of the `esi:try` tag whose arms are being parsed
+    let mut try_position = 0;
+    // The branches of the `esi:choose` tag being parsed, if one is open
+    let mut when_branches = Vec::new();
+    let mut otherwise_events = None;
+    let mut in_choose = false;
 
     let mut buffer = Vec::new();
     // Parse tags and build events vec
     loop {
-        match reader.read_event_into(&mut buffer) {
+        state.warnings.check_compliance()?;
+        let position = reader.buffer_position();
+        // Where the next event starts, in case it's malformed. The parser may have read a byte of
+        // it already.
+        let location = state
+            .options
+            .lines
+            .as_ref()
+            .and_then(|lines| lines.location(position));
+        let event = reader.read_event_into(&mut buffer);
+        // The event as it was written in the source, if that's what's written out
+        let raw = state
+            .source
+            .as_ref()
+            .and_then(|source| source.take(position, reader.buffer_position()));
+        // Only events that end within the size limit are processed.
+        if let Some(max_document_size) = state.options.max_document_size {
+            if reader.buffer_position() > max_document_size {
+                return Err(ExecutionError::DocumentTooLarge(max_document_size));
+            }
+        }
+        state.options.events.event_parsed()?;
+        let event = match event {
+            Ok(event) => event,
+            Err(err) if state.options.strict_markup => {
+                return Err(ExecutionError::XMLError(err, location));
+            }
+            // A closing ESI tag that doesn't match the open element is misplaced, which isn't
+            // skipped like other malformed markup.
+            Err(quick_xml::Error::IllFormed(
+                IllFormedError::UnmatchedEndTag(found)
+                | IllFormedError::MismatchedEndTag { found, .. },
+            )) if found.as_bytes().starts_with(&tag.prefix) => {
+                return Err(ExecutionError::UnexpectedClosingTag(found));
+            }
+            Err(err) => {
+                state.warnings.push(Warning::MalformedMarkup {
+                    position,
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        // Issues found while processing a top-level event are reported at its position.
+        if state.depth == 0 {
+            state.warnings.set_position(position);
+        }
+
+        // Skip the content of <esi:remove>, <esi:comment>, <esi:vars> and open <esi:include>
+        // tags, the same way at any depth
+        if state.suppressed.is_some() {
+            match skip_suppressed_event(state, &event, position, tag)? {
+                Some(body) if open_vars => {
+                    open_vars = false;
+                    let text = String::from_utf8_lossy(&body).into_owned();
+                    emit_tag(Tag::Vars { text }, callback, task, state.depth)?;
+                }
+                Some(body) => {
+                    let fallback = (!body.iter().all(u8::is_ascii_whitespace)).then_some(body);
+                    if fallback.is_some() && !open_includes.is_empty() {
+                        let tag = format!("{}include", String::from_utf8_lossy(&tag.prefix));
+                        state
+                            .warnings
+                            .deviation(open_include_position, "include-body", tag);
+                    }
+                    for mut include in open_includes.drain(..) {
+                        if let Tag::Include(include) = &mut include {
+                            include.fallback.clone_from(&fallback);
+                        }
+                        emit_tag(include, callback, task, state.depth)?;
+                    }
+                }
+                None => {}
+            }
+            continue;
+        }
+
+        match (tag.classify_event(&event), event) {
             // Handle <esi:remove> tags
-            Ok(XmlEvent::Start(e)) if e.name() == QName(&tag.remove) => {
-                is_remove_tag = true;
+            (Some(EsiTagKind::Remove), XmlEvent::Start(e)) => {
+                state.suppress(e.name().into_inner(), position);
             }
 
-            Ok(XmlEvent::End(e)) if e.name() == QName(&tag.remove) => {
-                if !is_remove_tag {
-                    return unexpected_closing_tag_error(&e);
+            (Some(EsiTagKind::Remove), XmlEvent::End(e)) => {
+                return unexpected_closing_tag_error(&e);
+            }
+
+            // Handle <esi:include> tags. The content of one that isn't self-closing is its
+            // fallback, so it's only handled once its closing tag is reached.
+            (Some(EsiTagKind::Include), XmlEvent::Empty(e)) => {
+                for include in parse_include(&e, position, tag, state)? {
+                    emit_tag(include, callback, task, state.depth)?;
                 }
+            }
 
-                is_remove_tag = false;
+            (Some(EsiTagKind::Include), XmlEvent::Start(e)) => {
+                open_includes = parse_include(&e, position, tag, state)?;
+                open_include_position = position;
+                state.capture(e.name().into_inner(), position);
             }
-            _ if is_remove_tag => continue,
 
-            // Handle <esi:include> tags, and ignore the contents if they are not self-closing
-            Ok(XmlEvent::Empty(e)) if e.name().into_inner().starts_with(&tag.include) => {
-                include_tag_handler(&e, callback, task, *depth)?;
+            (Some(EsiTagKind::Include), XmlEvent::End(e)) => {
+                return unexpected_closing_tag_error(&e);
             }
 
-            Ok(XmlEvent::Start(e)) if e.name().into_inner().starts_with(&tag.include) => {
-                open_include = true;
-                include_tag_handler(&e, callback, task, *depth)?;
+            // Handle <esi:response> tags, ignoring their contents if they are not self-closing
+            (Some(EsiTagKind::Response), XmlEvent::Empty(e)) => {
+                tag_deviation(&e, position, state);
+                response_tag_handler(&e, callback, task, state.depth)?;
             }
 
-            Ok(XmlEvent::End(e)) if e.name().into_inner().starts_with(&tag.include) => {
-                if !open_include {
-                    return unexpected_closing_tag_error(&e);
-                }
+            (Some(EsiTagKind::Response), XmlEvent::Start(e)) => {
+                tag_deviation(&e, position, state);
+                response_tag_handler(&e, callback, task, state.depth)?;
+                state.suppress(e.name().into_inner(), position);
+            }
+
+            (Some(EsiTagKind::Response), XmlEvent::End(e)) => {
+                return unexpected_closing_tag_error(&e);
+            }
 
-                open_include = false;
+            // Ignore <esi:comment> tags, along with their content unless it's to be processed.
+            // With debug comments on, their text is kept.
+            (Some(EsiTagKind::Comment), XmlEvent::Empty(e)) => {
+                comment_tag_handler(&e, callback, task, state)?;
             }
 
-            _ if open_include => continue,
+            (Some(EsiTagKind::Comment), XmlEvent::Start(e))
+                if state.options.process_in_comments =>
+            {
+                comment_tag_handler(&e, callback, task, state)?;
+            }
 
-            // Ignore <esi:comment> tags
-            Ok(XmlEvent::Empty(e)) if e.name().into_inner().starts_with(&tag.comment) => continue,
+            (Some(EsiTagKind::Comment), XmlEvent::End(_)) if state.options.process_in_comments => {
+                continue
+            }
+
+            (Some(EsiTagKind::Comment), XmlEvent::Start(e)) => {
+                comment_tag_handler(&e, callback, task, state)?;
+                state.suppress(e.name().into_inner(), position);
+            }
+
+            (Some(EsiTagKind::Comment), XmlEvent::End(e)) => {
+                return unexpected_closing_tag_error(&e);
+            }
+
+            // Replace self-closing <esi:vars> tags with the value of their name
+            (Some(EsiTagKind::Vars), XmlEvent::Empty(e)) => {
+                vars_tag_handler(&e, position, callback, task, state)?;
+            }
+
+            // Keep the content of <esi:vars> blocks, to be evaluated once the block ends
+            (Some(EsiTagKind::Vars), XmlEvent::Start(e)) => {
+                open_vars = true;
+                state.capture(e.name().into_inner(), position);
+            }
+
+            (Some(EsiTagKind::Vars), XmlEvent::End(e)) => {
+                return unexpected_closing_tag_error(&e);
+            }
 
             // Handle <esi:try> tags
-            Ok(XmlEvent::Start(ref e)) if e.name() == QName(&tag.tryy) => {
-                *current_arm = Some(TryTagArms::Try);
-                *depth += 1;
+            (Some(EsiTagKind::Try), XmlEvent::Start(_)) => {
+                try_position = position;
+                state.current_arm = Some(TryTagArms::Try);
+                state.depth += 1;
                 continue;
             }
 
-            // Handle <esi:attempt> and <esi:except> tags in recursion
-            Ok(XmlEvent::Start(ref e))
-                if e.name() == QName(&tag.attempt) || e.name() == QName(&tag.except) =>
-            {
-                if *current_arm != Some(TryTagArms::Try) {
-                    return unexpected_opening_tag_error(e);
+            // Handle <esi:attempt>, <esi:retry> and <esi:except> tags in recursion
+            (
+                Some(kind @ (EsiTagKind::Attempt | EsiTagKind::Retry | EsiTagKind::Except)),
+                XmlEvent::Start(e),
+            ) => {
+                if state.current_arm != Some(TryTagArms::Try) {
+                    return unexpected_opening_tag_error(&e);
                 }
-                if e.name() == QName(&tag.attempt) {
-                    *current_arm = Some(TryTagArms::Attempt);
-                    do_parse(reader, callback, attempt_events, depth, current_arm, tag)?;
-                } else if e.name() == QName(&tag.except) {
-                    *current_arm = Some(TryTagArms::Except);
-                    do_parse(reader, callback, except_events, depth, current_arm, tag)?;
+                let kind = match kind {
+                    EsiTagKind::Except => {
+                        state.current_arm = Some(TryTagArms::Except);
+                        TryArmKind::Except
+                    }
+                    EsiTagKind::Retry => {
+                        tag_deviation(&e, position, state);
+                        state.current_arm = Some(TryTagArms::Attempt);
+                        TryArmKind::Custom("retry".to_string())
+                    }
+                    _ => {
+                        state.current_arm = Some(TryTagArms::Attempt);
+                        TryArmKind::Attempt
+                    }
+                };
+                let mut events = Vec::new();
+                do_parse(reader, callback, &mut events, state, tag, pause)?;
+                arms.push(TryArm { kind, events });
+            }
+
+            (Some(EsiTagKind::Try), XmlEvent::End(e)) => {
+                state.current_arm = None;
+                if state.depth == 0 || state.group_depth == Some(state.depth) {
+                    return unexpected_closing_tag_error(&e);
+                }
+                let attempts = arms
+                    .iter()
+                    .filter(|arm| arm.kind != TryArmKind::Except)
+                    .count();
+                let name = String::from_utf8_lossy(e.name().into_inner()).into_owned();
+                if attempts == arms.len() {
+                    state
+                        .warnings
+                        .deviation(try_position, "missing-except", name.clone());
+                }
+                if attempts > 1 {
+                    state
+                        .warnings
+                        .deviation(try_position, "multiple-attempts", name);
                 }
+                try_end_handler(state.depth, task, arms, callback)?;
+                state.depth -= 1;
+                continue;
             }
 
-            Ok(XmlEvent::End(ref e)) if e.name() == QName(&tag.tryy) => {
-                *current_arm = None;
-                if *depth == 0 {
-                    return unexpected_closing_tag_error(e);
+            (
+                Some(EsiTagKind::Attempt | EsiTagKind::Retry | EsiTagKind::Except),
+                XmlEvent::End(e),
+            ) => {
+                state.current_arm = Some(TryTagArms::Try);
+                if state.depth == 0 || state.group_depth == Some(state.depth) {
+                    return unexpected_closing_tag_error(&e);
                 }
-                try_end_handler(*depth, task, attempt_events, except_events, callback)?;
-                *depth -= 1;
+                return Ok(());
+            }
+
+            // Handle <esi:choose> tags, whose branches are parsed in recursion like the arms of
+            // <esi:try> tags
+            (Some(EsiTagKind::Choose), XmlEvent::Start(_)) => {
+                in_choose = true;
+                state.depth += 1;
                 continue;
             }
 
-            Ok(XmlEvent::End(ref e))
-                if e.name() == QName(&tag.attempt) || e.name() == QName(&tag.except) =>
-            {
-                *current_arm = Some(TryTagArms::Try);
-                if *depth == 0 {
-                    return unexpected_closing_tag_error(e);
+            (Some(EsiTagKind::Choose), XmlEvent::End(e)) => {
+                if !in_choose {
+                    return unexpected_closing_tag_error(&e);
+                }
+                in_choose = false;
+                state.depth -= 1;
+                let choose = Tag::Choose {
+                    when_branches: std::mem::take(&mut when_branches),
+                    otherwise_events: otherwise_events.take().unwrap_or_default(),
+                };
+                emit_tag(choose, callback, task, state.depth)?;
+                continue;
+            }
+
+            (Some(EsiTagKind::When), XmlEvent::Start(e)) => {
+                // Every <esi:when> branch comes before the <esi:otherwise> branch
+                if !in_choose || otherwise_events.is_some() {
+                    return unexpected_opening_tag_error(&e);
+                }
+                let test = when_test(&e)?;
+                let mut events = Vec::new();
+                do_parse(reader, callback, &mut events, state, tag, pause)?;
+                when_branches.push((test, events));
+            }
+
+            (Some(EsiTagKind::Otherwise), XmlEvent::Start(e)) => {
+                if !in_choose || otherwise_events.is_some() {
+                    return unexpected_opening_tag_error(&e);
+                }
+                let mut events = Vec::new();
+                do_parse(reader, callback, &mut events, state, tag, pause)?;
+                otherwise_events = Some(events);
+            }
+
+            (Some(EsiTagKind::When | EsiTagKind::Otherwise), XmlEvent::End(e)) => {
+                if state.depth == 0 || in_choose {
+                    return unexpected_closing_tag_error(&e);
+                }
+                return Ok(());
+            }
+
+            // Self-closing branches are empty, and so is a self-closing <esi:choose> block.
+            (Some(EsiTagKind::Choose), XmlEvent::Empty(_)) => {}
+
+            (Some(EsiTagKind::When), XmlEvent::Empty(e)) => {
+                if !in_choose || otherwise_events.is_some() {
+                    return unexpected_opening_tag_error(&e.name().into_inner());
+                }
+                when_branches.push((when_test(&e)?, Vec::new()));
+            }
+
+            (Some(EsiTagKind::Otherwise), XmlEvent::Empty(e)) => {
+                if !in_choose || otherwise_events.is_some() {
+                    return unexpected_opening_tag_error(&e.name().into_inner());
+                }
+                otherwise_events = Some(Vec::new());
+            }
+
+            (_, XmlEvent::Eof) if in_choose => {
+                return Err(ExecutionError::UnexpectedEndOfDocument);
+            }
+
+            // Handle <esi:group> tags in recursion, like the arms of <esi:try> tags
+            (Some(EsiTagKind::Group), XmlEvent::Start(e)) => {
+                if state.group_depth.is_some() {
+                    return unexpected_opening_tag_error(&e.name().into_inner());
+                }
+                tag_deviation(&e, position, state);
+                let (name, onerror) = parse_group(&e, position, &state.warnings)?;
+                state.depth += 1;
+                state.group_depth = Some(state.depth);
+                let mut events = Vec::new();
+                do_parse(reader, callback, &mut events, state, tag, pause)?;
+                state.group_depth = None;
+                state.depth -= 1;
+
+                let group = Event::ESI(Tag::Group {
+                    name,
+                    onerror,
+                    events,
+                });
+                if state.depth == 0 {
+                    callback(group)?;
+                } else {
+                    task.push(group);
+                }
+            }
+
+            (Some(EsiTagKind::Group), XmlEvent::End(e)) => {
+                if state.group_depth != Some(state.depth) {
+                    return unexpected_closing_tag_error(&e);
                 }
                 return Ok(());
             }
 
-            Ok(XmlEvent::Eof) => {
+            (_, XmlEvent::Eof) if state.group_depth.is_some() => {
+                return Err(ExecutionError::UnexpectedEndOfDocument);
+            }
+
+            (_, XmlEvent::Eof) => {
                 debug!("End of document");
+                state.warnings.check_compliance()?;
+                state.options.events.check()?;
                 break;
             }
-            Ok(e) => {
-                if *depth == 0 {
-                    callback(Event::XML(e.into_owned()))?;
+            (_, e) => {
+                // Tags in the ESI namespace that aren't supported are left out, keeping their
+                // content, so that ESI markup doesn't reach the client.
+                if let Some(name) = tag.unknown_tag(&e) {
+                    if state.options.strict_tags {
+                        return Err(ExecutionError::UnknownEsiTag(name));
+                    }
+                    if !matches!(e, XmlEvent::End(_)) {
+                        state.warnings.push(Warning::UnknownTag {
+                            position,
+                            tag: name,
+                        });
+                    }
+                    continue;
+                }
+                let e = match raw {
+                    Some(raw) => passthrough(e, raw, tag),
+                    None => e.into_owned(),
+                };
+                if state.depth == 0 {
+                    callback(Event::XML(e))?;
+                    // Top-level content is the only place where there's no parsing state to
+                    // keep, so it's where parsing can be resumed later.
+                    if pause() {
+                        return Ok(());
+                    }
                 } else {
-                    task.push(Event::XML(e.into_owned()));
+                    task.push(Event::XML(e));
                 }
             }
-            _ => {}
         }
     }
     Ok(())
 }
 
+// Turns a source event into text holding its bytes as they were read, which is written out
+// unchanged. Tags with attributes in the ESI namespace are kept as they are, to be evaluated, as
+// is anything that isn't valid UTF-8, since text can't hold it.
+fn passthrough(event: XmlEvent, raw: Vec<u8>, tag: &EsiTags) -> XmlEvent<'static> {
+    let evaluated = match &event {
+        XmlEvent::Start(e) | XmlEvent::Empty(e) => e
+            .attributes()
+            .with_checks(false)
+            .flatten()
+            .any(|attr| attr.key.as_ref().starts_with(&tag.prefix)),
+        _ => false,
+    };
+    match String::from_utf8(raw) {
+        Ok(raw) if !evaluated => XmlEvent::Text(BytesText::from_escaped(raw)),
+        _ => event.into_owned(),
+    }
+}
+
 /// Parses the ESI document from the given `reader` and calls the `callback` closure upon each successfully parsed ESI tag.
 pub fn parse_tags<'a, R>(
     namespace: &str,
@@ -194,99 +867,657 @@ where
 {
     debug!("Parsing document...");
 
+    for event in EventStream::new(namespace, reader) {
+        callback(event?)?;
+    }
+
+    Ok(())
+}
+
+/// An iterator over the events of an ESI document, parsed as they're pulled, for code that drives
+/// parsing itself rather than handing [`parse_tags`] a callback.
+///
+/// Events are owned, so they can be kept while the next ones are pulled. An `<esi:try>`,
+/// `<esi:group>` or `<esi:choose>` block is yielded once it's complete, with the events inside it,
+/// as [`parse_tags`] reports it. Iteration ends after the first error.
+///
+/// ## Usage Example
+/// ```rust
+/// let mut reader = esi::Reader::from_str(r#"<p>a</p><esi:include src="/b"/>"#);
+/// for event in esi::EventStream::new("esi", &mut reader) {
+///     if let esi::Event::ESI(esi::Tag::Include(include)) = event? {
+///         assert_eq!(include.src, "/b");
+///     }
+/// }
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub struct EventStream<'r, R> {
+    reader: &'r mut Reader<R>,
+    tags: EsiTags,
+    state: ParserState,
+    // Events that have been parsed but not yielded yet
+    queue: VecDeque<Event<'static>>,
+    // The error parsing stopped with, yielded after the events parsed before it
+    error: Option<ExecutionError>,
+    // Whether the end of the document or an error has been reached
+    done: bool,
+}
+
+impl<'r, R: BufRead> EventStream<'r, R> {
+    /// Starts parsing the ESI document from `reader`, with tags in the given namespace, such as
+    /// `esi`.
+    pub fn new(namespace: &str, reader: &'r mut Reader<R>) -> Self {
+        Self {
+            reader,
+            tags: EsiTags::init(namespace),
+            state: ParserState {
+                depth: 0,
+                current_arm: None,
+                suppressed: None,
+                group_depth: None,
+                warnings: Warnings::default(),
+                options: ParseOptions::default(),
+                source: None,
+            },
+            queue: VecDeque::new(),
+            error: None,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for EventStream<'_, R> {
+    type Item = Result<Event<'static>>;
+
+    // Parsing pauses after each top-level XML event, which is where there's no parsing state to
+    // keep, and so where it can be resumed. The ESI tags parsed before it are queued along with it.
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.queue.is_empty() && !self.done {
+            let paused = Cell::new(false);
+            let queue = &mut self.queue;
+            let parsed = do_parse(
+                self.reader,
+                &mut |event| {
+                    queue.push_back(event);
+                    Ok(())
+                },
+                &mut Vec::new(),
+                &mut self.state,
+                &self.tags,
+                &|| {
+                    paused.set(true);
+                    true
+                },
+            );
+            match parsed {
+                Ok(()) => self.done = !paused.get(),
+                Err(err) => {
+                    self.error = Some(err);
+                    self.done = true;
+                }
+            }
+        }
+        match self.queue.pop_front() {
+            Some(event) => Some(Ok(event)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+// Parses the ESI document like `parse_tags`, but stops after any top-level XML event for which
+// `pause` returns true. Calling it again with the same reader picks up where it left off.
+pub(crate) fn parse_tags_until<'a, R>(
+    namespace: &str,
+    reader: &mut Reader<R>,
+    callback: &mut dyn FnMut(Event<'a>) -> Result<()>,
+    pause: &dyn Fn() -> bool,
+    warnings: &Warnings,
+    options: ParseOptions,
+    source: Option<&SourceWindow>,
+) -> Result<()>
+where
+    R: BufRead,
+{
     // Initialize the ESI tags
     let tags = EsiTags::init(namespace);
-    // set the initial depth of nested tags
-    let mut depth = 0;
     let mut root = Vec::new();
 
-    let mut current_arm: Option<TryTagArms> = None;
+    let mut state = ParserState {
+        // set the initial depth of nested tags
+        depth: 0,
+        current_arm: None,
+        suppressed: None,
+        group_depth: None,
+        warnings: warnings.clone(),
+        options,
+        source: source.cloned(),
+    };
 
-    do_parse(
-        reader,
-        callback,
-        &mut root,
-        &mut depth,
-        &mut current_arm,
-        &tags,
-    )?;
-    debug!("Root: {:?}", root);
+    do_parse(reader, callback, &mut root, &mut state, &tags, pause)?;
+    if log_enabled!(Level::Debug) {
+        debug!("Root: {:?}", root);
+    }
 
     Ok(())
 }
 
-fn parse_include<'a>(elem: &BytesStart) -> Result<Tag<'a>> {
-    let src = match elem
-        .attributes()
-        .flatten()
-        .find(|attr| attr.key.into_inner() == b"src")
-    {
-        Some(attr) => String::from_utf8(attr.value.to_vec()).unwrap(),
-        None => {
-            return Err(ExecutionError::MissingRequiredParameter(
-                String::from_utf8(elem.name().into_inner().to_vec()).unwrap(),
-                "src".to_string(),
+// The attributes of an `esi:include` tag, borrowed from the tag while it's being parsed
+#[derive(Default)]
+struct IncludeBuilder<'e> {
+    src: Option<Cow<'e, [u8]>>,
+    srcset: Option<Cow<'e, [u8]>>,
+    alt: Option<Cow<'e, [u8]>>,
+    onerror: Option<Cow<'e, [u8]>>,
+    // The attributes in `KNOWN_ATTRIBUTES`, in that order
+    known: [Option<Cow<'e, [u8]>>; KNOWN_ATTRIBUTES.len()],
+    // Attributes in the ESI namespace, by name without the prefix
+    extensions: Vec<(&'e [u8], Cow<'e, [u8]>)>,
+    // The names of attributes that were repeated
+    duplicates: Vec<&'e [u8]>,
+}
+
+impl<'e> IncludeBuilder<'e> {
+    // Collects the attributes in a single pass. Only the first of any repeated attribute is kept,
+    // apart from those with the namespace `prefix`, which are all kept, and those in
+    // `REPEATABLE_ATTRIBUTES`, whose values are joined. An attribute without a
+    // value, like `alt` in `<esi:include alt src="/a"/>`, is collected as empty.
+    fn from_element(elem: &'e BytesStart, prefix: &[u8]) -> Self {
+        let mut builder = Self::default();
+        for attr in elem.html_attributes().with_checks(false).flatten() {
+            if let Some(name) = attr.key.into_inner().strip_prefix(prefix) {
+                builder.extensions.push((name, attr.value));
+                continue;
+            }
+            let known = KNOWN_ATTRIBUTES
+                .iter()
+                .position(|(name, _)| name.as_bytes() == attr.key.into_inner());
+            let field = match (attr.key.into_inner(), known) {
+                (b"src", _) => &mut builder.src,
+                (b"srcset", _) => &mut builder.srcset,
+                (b"alt", _) => &mut builder.alt,
+                (b"onerror", _) => &mut builder.onerror,
+                (_, Some(index)) => &mut builder.known[index],
+                _ => continue,
+            };
+            let repeatable = known
+                .is_some_and(|index| REPEATABLE_ATTRIBUTES.contains(&KNOWN_ATTRIBUTES[index].0));
+            match field {
+                Some(value) if repeatable => {
+                    let mut joined = value.to_vec();
+                    joined.push(b'|');
+                    joined.extend_from_slice(&attr.value);
+                    *value = Cow::Owned(joined);
+                }
+                Some(_) => builder.duplicates.push(attr.key.into_inner()),
+                None => *field = Some(attr.value),
+            }
+        }
+        builder
+    }
+
+    // The names and values of the attributes in `KNOWN_ATTRIBUTES`
+    fn known_attributes(&self) -> impl Iterator<Item = (&'static str, &Option<Cow<'e, [u8]>>)> {
+        KNOWN_ATTRIBUTES
+            .iter()
+            .map(|(name, _)| *name)
+            .zip(&self.known)
+    }
+
+    // Checks the attribute values before anything is taken from them: repeated attributes are
+    // warned about, and empty URLs or values over `max_length` bytes are rejected.
+    fn validate(
+        &self,
+        elem: &BytesStart,
+        position: usize,
+        warnings: &Warnings,
+        max_length: Option<usize>,
+    ) -> Result<()> {
+        let tag_name = || String::from_utf8_lossy(elem.name().into_inner()).into_owned();
+        for name in &self.duplicates {
+            warnings.push(Warning::DuplicateAttribute {
+                position,
+                tag: tag_name(),
+                attribute: String::from_utf8_lossy(name).into_owned(),
+            });
+        }
+
+        let extensions = std::iter::once(("srcset", &self.srcset)).chain(self.known_attributes());
+        for (name, _) in extensions.filter(|(_, value)| value.is_some()) {
+            warnings.deviation(position, "include-attribute", name);
+        }
+        let namespace = elem.name().prefix().map(|prefix| prefix.into_inner());
+        for (name, _) in &self.extensions {
+            let name = String::from_utf8_lossy(name);
+            let name = match namespace {
+                Some(namespace) => format!("{}:{name}", String::from_utf8_lossy(namespace)),
+                None => name.into_owned(),
+            };
+            warnings.deviation(position, "include-attribute", name);
+        }
+
+        let urls = [("src", &self.src), ("alt", &self.alt)];
+        if let Some((name, _)) = urls.iter().find(|(_, value)| {
+            value
+                .as_ref()
+                .is_some_and(|value| value.trim_ascii().is_empty())
+        }) {
+            return Err(ExecutionError::EmptyAttribute(
+                tag_name(),
+                name.to_string(),
+                position,
             ));
         }
+
+        let Some(max_length) = max_length else {
+            return Ok(());
+        };
+        let values = [
+            ("src", &self.src),
+            ("srcset", &self.srcset),
+            ("alt", &self.alt),
+            ("onerror", &self.onerror),
+        ];
+        let too_large = values
+            .into_iter()
+            .chain(self.known_attributes())
+            .filter_map(|(name, value)| Some((Cow::Borrowed(name), value.as_ref()?)))
+            .chain(
+                self.extensions
+                    .iter()
+                    .map(|(name, value)| (String::from_utf8_lossy(name), value)),
+            )
+            .find(|(_, value)| value.len() > max_length);
+        if let Some((name, _)) = too_large {
+            return Err(ExecutionError::AttributeTooLarge(
+                tag_name(),
+                name.into_owned(),
+                position,
+                max_length,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // URLs are unescaped here, once, if `unescape_urls` is set, and are otherwise kept as they're
+    // written. A `srcset` builds one include for each of its URLs, sharing the other attributes.
+    fn build<'a>(
+        self,
+        elem: &BytesStart,
+        position: usize,
+        warnings: &Warnings,
+        options: &ParseOptions,
+    ) -> Result<Vec<Tag<'a>>> {
+        self.validate(elem, position, warnings, options.max_attribute_length)?;
+        let tag_name = || String::from_utf8_lossy(elem.name().into_inner()).into_owned();
+        // Flags with a value other than the expected ones are treated as unset.
+        let flag = |name: &str, value: Option<Cow<[u8]>>, expected: &[&[u8]], set: &[u8]| {
+            let value = value?;
+            if !expected.contains(&value.as_ref()) {
+                warnings.push(Warning::InvalidAttribute {
+                    position,
+                    tag: tag_name(),
+                    attribute: name.to_string(),
+                    value: String::from_utf8_lossy(&value).into_owned(),
+                });
+            }
+            Some(value.as_ref() == set)
+        };
+        let url = |url: &str| {
+            if options.unescape_urls {
+                unescape_url(url).into_owned()
+            } else {
+                url.to_string()
+            }
+        };
+
+        let srcs: Vec<(String, Option<usize>)> = match (self.src, self.srcset) {
+            (Some(_), Some(_)) => {
+                return Err(ExecutionError::ConflictingParameters(
+                    tag_name(),
+                    "src".to_string(),
+                    "srcset".to_string(),
+                ));
+            }
+            (Some(src), None) => vec![(url(&String::from_utf8_lossy(&src)), None)],
+            (None, Some(srcset)) => {
+                let srcset = String::from_utf8_lossy(&srcset);
+                let srcs: Vec<_> = split_srcset(&srcset)
+                    .enumerate()
+                    .map(|(i, src)| (url(src), Some(i)))
+                    .collect();
+                if srcs.is_empty() {
+                    return Err(ExecutionError::InvalidAttributeValue(
+                        tag_name(),
+                        "srcset".to_string(),
+                        srcset.into_owned(),
+                    ));
+                }
+                srcs
+            }
+            (None, None) => {
+                return Err(ExecutionError::MissingRequiredParameter(
+                    tag_name(),
+                    "src".to_string(),
+                ));
+            }
+        };
+
+        let alt = self.alt.map(|alt| url(&String::from_utf8_lossy(&alt)));
+        let continue_on_error =
+            flag("onerror", self.onerror, &[b"continue"], b"continue").unwrap_or(false);
+        let mut attributes = AttributeMap::default();
+        for ((name, parse), value) in KNOWN_ATTRIBUTES.iter().zip(self.known) {
+            let Some(value) = value else {
+                continue;
+            };
+            let value = String::from_utf8_lossy(&value);
+            let unescaped = unescape_with(&value, resolve_predefined_entity)
+                .unwrap_or(Cow::Borrowed(value.as_ref()));
+            match parse(&unescaped) {
+                Parsed::Valid(parsed) => attributes.insert(name, parsed),
+                Parsed::Warn(parsed) => {
+                    warnings.push(Warning::InvalidAttribute {
+                        position,
+                        tag: tag_name(),
+                        attribute: name.to_string(),
+                        value: value.into_owned(),
+                    });
+                    attributes.insert(name, parsed);
+                }
+                Parsed::Invalid => {
+                    return Err(ExecutionError::InvalidAttributeValue(
+                        tag_name(),
+                        name.to_string(),
+                        value.into_owned(),
+                    ));
+                }
+            }
+        }
+        let extensions: Vec<(String, String)> = self
+            .extensions
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value);
+                let value = unescape_with(&value, resolve_predefined_entity)
+                    .map_or_else(|_| value.to_string(), Cow::into_owned);
+                (String::from_utf8_lossy(name).into_owned(), value)
+            })
+            .collect();
+
+        Ok(srcs
+            .into_iter()
+            .map(|(src, srcset_index)| {
+                Tag::Include(Include {
+                    src,
+                    alt: alt.clone(),
+                    continue_on_error,
+                    srcset_index,
+                    extensions: extensions.clone(),
+                    fallback: None,
+                    attributes: attributes.clone(),
+                })
+            })
+            .collect())
+    }
+}
+
+// Splits a `srcset` attribute into its URLs. Whitespace inside the parentheses of a variable or
+// function call, such as `$pick(1, 'a', 'b')`, doesn't separate URLs.
+fn split_srcset(srcset: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0usize;
+    let mut quoted = false;
+    srcset
+        .split(move |c: char| {
+            match c {
+                '\'' if depth > 0 => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            depth == 0 && c.is_ascii_whitespace()
+        })
+        .filter(|src| !src.is_empty())
+}
+
+fn parse_include<'a>(
+    elem: &BytesStart,
+    position: usize,
+    tag: &EsiTags,
+    state: &ParserState,
+) -> Result<Vec<Tag<'a>>> {
+    IncludeBuilder::from_element(elem, &tag.prefix).build(
+        elem,
+        position,
+        &state.warnings,
+        &state.options,
+    )
+}
+
+// Parses the required `name` of an `esi:group` tag, and what to do if one of its includes fails.
+// An unknown `onerror` value is treated as unset.
+fn parse_group(
+    elem: &BytesStart,
+    position: usize,
+    warnings: &Warnings,
+) -> Result<(String, GroupErrorPolicy)> {
+    let tag_name = || String::from_utf8_lossy(elem.name().into_inner()).into_owned();
+    let mut name = None;
+    let mut onerror = None;
+    let mut placeholder = None;
+    for attr in elem.attributes().flatten() {
+        let field = match attr.key.into_inner() {
+            b"name" => &mut name,
+            b"onerror" => &mut onerror,
+            b"placeholder" => &mut placeholder,
+            _ => continue,
+        };
+        field.get_or_insert(attr.value);
+    }
+
+    let Some(name) = name else {
+        return Err(ExecutionError::MissingRequiredParameter(
+            tag_name(),
+            "name".to_string(),
+        ));
+    };
+    let policy = match onerror.as_deref() {
+        None | Some(b"omit") => GroupErrorPolicy::Omit,
+        Some(b"fail") => GroupErrorPolicy::Fail,
+        Some(b"placeholder") => {
+            let Some(placeholder) = placeholder else {
+                return Err(ExecutionError::MissingRequiredParameter(
+                    tag_name(),
+                    "placeholder".to_string(),
+                ));
+            };
+            let placeholder = String::from_utf8_lossy(&placeholder);
+            GroupErrorPolicy::Placeholder(
+                unescape_with(&placeholder, resolve_predefined_entity)
+                    .map_or_else(|_| placeholder.to_string(), Cow::into_owned),
+            )
+        }
+        Some(value) => {
+            warnings.push(Warning::InvalidAttribute {
+                position,
+                tag: tag_name(),
+                attribute: "onerror".to_string(),
+                value: String::from_utf8_lossy(value).into_owned(),
+            });
+            GroupErrorPolicy::Omit
+        }
     };
 
-    let alt = elem
-        .attributes()
-        .flatten()
-        .find(|attr| attr.key.into_inner() == b"alt")
-        .map(|attr| String::from_utf8(attr.value.to_vec()).unwrap());
+    Ok((String::from_utf8_lossy(&name).into_owned(), policy))
+}
 
-    let continue_on_error = elem
-        .attributes()
-        .flatten()
-        .find(|attr| attr.key.into_inner() == b"onerror")
-        .is_some_and(|attr| &attr.value.to_vec() == b"continue");
+fn parse_response<'a>(elem: &BytesStart) -> Result<Tag<'a>> {
+    let mut status = None;
+    let mut headers = Vec::new();
 
-    Ok(Tag::Include {
-        src,
-        alt,
-        continue_on_error,
-    })
+    for attr in elem.attributes().flatten() {
+        let value = String::from_utf8_lossy(&attr.value).into_owned();
+        if attr.key.into_inner() == b"status" {
+            match value.parse::<u16>() {
+                Ok(code) if (100..=999).contains(&code) => status = Some(code),
+                _ => {
+                    return Err(ExecutionError::InvalidAttributeValue(
+                        String::from_utf8_lossy(elem.name().into_inner()).into_owned(),
+                        "status".to_string(),
+                        value,
+                    ));
+                }
+            }
+        } else if let Some(name) = attr.key.into_inner().strip_prefix(b"header-") {
+            headers.push((String::from_utf8_lossy(name).into_owned(), value));
+        }
+    }
+
+    Ok(Tag::ResponseDirective { status, headers })
 }
 
 // Helper function to handle the end of a <esi:try> tag
+// The <esi:except> arms are moved after the others, keeping their order
 // If the depth is 1, the `callback` closure is called with the `Tag::Try` event
 // Otherwise, a new `Tag::Try` event is pushed to the `task` vector
 fn try_end_handler<'a>(
     depth: usize,
     task: &mut Vec<Event<'a>>,
-    attempt_events: &mut Vec<Event<'a>>,
-    except_events: &mut Vec<Event<'a>>,
+    arms: &mut Vec<TryArm<'a>>,
     callback: &mut dyn FnMut(Event<'a>) -> Result<()>,
 ) -> Result<()> {
+    let mut arms = std::mem::take(arms);
+    arms.sort_by_key(|arm| arm.kind == TryArmKind::Except);
     if depth == 1 {
-        callback(Event::ESI(Tag::Try {
-            attempt_events: std::mem::take(attempt_events),
-            except_events: std::mem::take(except_events),
-        }))?;
+        callback(Event::ESI(Tag::Try { arms }))?;
     } else {
-        task.push(Event::ESI(Tag::Try {
-            attempt_events: std::mem::take(attempt_events),
-            except_events: std::mem::take(except_events),
-        }));
+        task.push(Event::ESI(Tag::Try { arms }));
     }
 
     Ok(())
 }
 
-// Helper function to handle <esi:include> tags
-// If the depth is 0, the `callback` closure is called with the `Tag::Include` event
-// Otherwise, a new `Tag::Include` event is pushed to the `task` vector
-fn include_tag_handler<'e>(
+// Reads the `test` attribute of an `<esi:when>` tag, as it's written.
+fn when_test(elem: &BytesStart) -> Result<String> {
+    elem.attributes()
+        .flatten()
+        .find(|attr| attr.key.into_inner() == b"test")
+        .map(|attr| String::from_utf8_lossy(&attr.value).into_owned())
+        .ok_or_else(|| {
+            ExecutionError::MissingRequiredParameter(
+                String::from_utf8_lossy(elem.name().into_inner()).into_owned(),
+                "test".to_string(),
+            )
+        })
+}
+
+// Helper function to emit a parsed tag
+// If the depth is 0, the `callback` closure is called with the tag's event
+// Otherwise, the event is pushed to the `task` vector
+fn emit_tag<'e>(
+    tag: Tag<'e>,
+    callback: &mut dyn FnMut(Event<'e>) -> Result<()>,
+    task: &mut Vec<Event<'e>>,
+    depth: usize,
+) -> Result<()> {
+    if depth == 0 {
+        callback(Event::ESI(tag))?;
+    } else {
+        task.push(Event::ESI(tag));
+    }
+
+    Ok(())
+}
+
+// Reports a tag outside ESI 1.0, such as `esi:group`
+fn tag_deviation(elem: &BytesStart, position: usize, state: &ParserState) {
+    let name = String::from_utf8_lossy(elem.name().into_inner()).into_owned();
+    state.warnings.deviation(position, "tag", name);
+}
+
+// Helper function to handle <esi:comment> tags
+// With debug comments on, the `text` attribute of the tag, if any, is emitted to be evaluated
+fn comment_tag_handler<'e>(
+    elem: &BytesStart,
+    callback: &mut dyn FnMut(Event<'e>) -> Result<()>,
+    task: &mut Vec<Event<'e>>,
+    state: &ParserState,
+) -> Result<()> {
+    if !state.options.debug_comments {
+        return Ok(());
+    }
+    let Some(text) = elem
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key.into_inner() == b"text")
+    else {
+        return Ok(());
+    };
+    let text = String::from_utf8_lossy(&text.value);
+    let text = unescape_with(&text, resolve_predefined_entity)
+        .map_or_else(|_| text.to_string(), Cow::into_owned);
+
+    emit_tag(Tag::DebugComment { text }, callback, task, state.depth)
+}
+
+// The name is unescaped like an include URL, if `unescape_urls` is set.
+fn vars_tag_handler<'e>(
+    elem: &BytesStart,
+    position: usize,
+    callback: &mut dyn FnMut(Event<'e>) -> Result<()>,
+    task: &mut Vec<Event<'e>>,
+    state: &ParserState,
+) -> Result<()> {
+    let tag_name = || String::from_utf8_lossy(elem.name().into_inner()).into_owned();
+    let mut name = None;
+    let mut raw = false;
+    for attr in elem.attributes().flatten() {
+        match attr.key.into_inner() {
+            b"name" => name = Some(String::from_utf8_lossy(&attr.value).into_owned()),
+            b"raw" => {
+                if !matches!(attr.value.as_ref(), b"true" | b"false") {
+                    state.warnings.push(Warning::InvalidAttribute {
+                        position,
+                        tag: tag_name(),
+                        attribute: "raw".to_string(),
+                        value: String::from_utf8_lossy(&attr.value).into_owned(),
+                    });
+                }
+                raw = attr.value.as_ref() == b"true";
+            }
+            _ => {}
+        }
+    }
+    let Some(name) = name else {
+        return Err(ExecutionError::MissingRequiredParameter(
+            tag_name(),
+            "name".to_string(),
+        ));
+    };
+    // ESI 1.0 only has the block form, without attributes.
+    state.warnings.deviation(position, "vars-attribute", "name");
+    if raw {
+        state.warnings.deviation(position, "vars-attribute", "raw");
+    }
+    let name = if state.options.unescape_urls {
+        unescape_url(&name).into_owned()
+    } else {
+        name
+    };
+
+    emit_tag(Tag::VarsValue { name, raw }, callback, task, state.depth)
+}
+
+fn response_tag_handler<'e>(
     elem: &BytesStart,
     callback: &mut dyn FnMut(Event<'e>) -> Result<()>,
     task: &mut Vec<Event<'e>>,
     depth: usize,
 ) -> Result<()> {
     if depth == 0 {
-        callback(Event::ESI(parse_include(elem)?))?;
+        callback(Event::ESI(parse_response(elem)?))?;
     } else {
-        task.push(Event::ESI(parse_include(elem)?));
+        task.push(Event::ESI(parse_response(elem)?));
     }
 
     Ok(())
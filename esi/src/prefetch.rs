@@ -0,0 +1,170 @@
+use crate::attributes::ForwardHeaders;
+use crate::parse::{parse_tags_until, ParseOptions, TryArmKind};
+use crate::request::DeferredRequest;
+use crate::warning::Warnings;
+use crate::{metrics, CallbackKind, Event, FragmentKey, PrefetchReport, Processor, Result, Tag};
+use crate::{FragmentRequestDispatcher, PendingFragmentContent};
+use fastly::http::HeaderName;
+use fastly::Request;
+use log::debug;
+use quick_xml::Reader;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+
+/// A store for fragment bodies, filled by [`Processor::prefetch`].
+pub trait FragmentCache {
+    /// Stores the body of a successful fragment response.
+    fn insert(&mut self, request: &Request, body: Vec<u8>);
+}
+
+/// Stores fragment bodies by URL.
+impl FragmentCache for HashMap<String, Vec<u8>> {
+    fn insert(&mut self, request: &Request, body: Vec<u8>) {
+        self.insert(request.get_url_str().to_string(), body);
+    }
+}
+
+impl Processor {
+    /// Fetch every fragment included by an ESI document and store the bodies in `cache`, without
+    /// producing any output.
+    ///
+    /// This is intended for warming fragment caches ahead of traffic. Fragment requests are based
+    /// on the request given to [`Processor::new`], and are all dispatched before any is waited on.
+    /// Includes in `esi:attempt` arms are fetched, and those in `esi:except` arms are too if
+    /// [`Configuration::prefetch_except`] is set. Each fragment is fetched once, as identified by
+    /// its [`FragmentKey`], and `alt` fallbacks aren't fetched.
+    pub fn prefetch(
+        self,
+        mut src_document: Reader<impl BufRead>,
+        dispatch_fragment_request: Option<&FragmentRequestDispatcher>,
+        cache: &mut dyn FragmentCache,
+    ) -> Result<PrefetchReport> {
+        let ctx = self.fragment_request_context(dispatch_fragment_request);
+        let mut report = PrefetchReport::default();
+
+        let mut srcs = Vec::new();
+        let prefetch_except = self.configuration.prefetch_except;
+        parse_tags_until(
+            &self.configuration.namespace,
+            &mut src_document,
+            &mut |event| {
+                collect_include_srcs(event, prefetch_except, &mut srcs);
+                Ok(())
+            },
+            &|| false,
+            &Warnings::default(),
+            ParseOptions {
+                unescape_urls: self.configuration.is_escaped,
+                max_attribute_length: Some(self.configuration.max_attribute_length),
+                ..ParseOptions::default()
+            },
+            None,
+        )?;
+
+        // Dispatch everything up front, so that the requests run concurrently.
+        let mut seen = HashSet::new();
+        let mut pending = Vec::new();
+        for (src, forward_headers) in srcs {
+            let request = match ctx
+                .build_request(&src, forward_headers.as_deref())
+                .map(DeferredRequest::build)
+            {
+                Ok(request) => request,
+                Err(err) => {
+                    debug!("failed to build prefetch request for {src}: {err}");
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            if request.get_url().scheme() == "data"
+                || !seen.insert(FragmentKey::compute(
+                    &request,
+                    &self.configuration.fragment_key,
+                ))
+            {
+                report.skipped += 1;
+                continue;
+            }
+
+            let dispatched = ctx.warnings.time_callback(
+                CallbackKind::Dispatcher,
+                ctx.warnings.position(),
+                || (ctx.dispatch_fragment_request)(request.clone_without_body()),
+            );
+            match dispatched {
+                Ok(PendingFragmentContent::NoContent) => report.skipped += 1,
+                Ok(PendingFragmentContent::FailFast(reason)) => {
+                    debug!("prefetch request failed fast: {reason}");
+                    report.failed += 1;
+                }
+                Ok(content) => pending.push((request, content)),
+                Err(err) => {
+                    debug!("failed to dispatch prefetch request: {err}");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        for (request, content) in pending {
+            match content.wait_for_content() {
+                Ok(response) if response.get_status().is_success() => {
+                    cache.insert(&request, response.into_body_bytes());
+                    report.warmed += 1;
+                }
+                Ok(response) => {
+                    debug!(
+                        "prefetch of {} returned {}",
+                        request.get_url_str(),
+                        response.get_status()
+                    );
+                    report.failed += 1;
+                }
+                Err(err) => {
+                    debug!("prefetch of {} failed: {err}", request.get_url_str());
+                    report.failed += 1;
+                }
+            }
+        }
+
+        metrics::prefetch(&report);
+        Ok(report)
+    }
+}
+
+// The `src` of an include, and the headers it forwards if they're overridden
+type IncludeSrc = (String, Option<Vec<HeaderName>>);
+
+// Collects the `src` of every include in an event, in document order.
+fn collect_include_srcs(event: Event, prefetch_except: bool, srcs: &mut Vec<IncludeSrc>) {
+    match event {
+        Event::ESI(Tag::Include(include)) => {
+            let forward_headers = include.get::<ForwardHeaders>().map(|names| names.0.clone());
+            srcs.push((include.src, forward_headers));
+        }
+        Event::ESI(Tag::Try { arms }) => {
+            for arm in arms {
+                if arm.kind == TryArmKind::Except && !prefetch_except {
+                    continue;
+                }
+                for event in arm.events {
+                    collect_include_srcs(event, prefetch_except, srcs);
+                }
+            }
+        }
+        Event::ESI(Tag::Group { events, .. }) => {
+            for event in events {
+                collect_include_srcs(event, prefetch_except, srcs);
+            }
+        }
+        // Which branch of an `esi:choose` is taken isn't known until it's processed, so none of
+        // them are prefetched.
+        Event::ESI(
+            Tag::Choose { .. }
+            | Tag::ResponseDirective { .. }
+            | Tag::DebugComment { .. }
+            | Tag::VarsValue { .. }
+            | Tag::Vars { .. },
+        )
+        | Event::XML(_) => {}
+    }
+}
@@ -0,0 +1,74 @@
+/// Looks for ESI tags in a document as it arrives in chunks, so that documents without any can be
+/// served as they are instead of being processed.
+///
+/// Only element names count: the probe looks for `<` directly followed by the namespace and a
+/// colon, like `<esi:`, so the names of ESI tags in text or in the attribute values of other
+/// elements aren't mistaken for tags. A match that straddles two chunks is found all the same.
+///
+/// The probe never misses a tag the processor would handle, but it can report one that the
+/// processor leaves alone, such as `<esi:include/>` in a comment or CDATA section.
+///
+/// ```
+/// let mut probe = esi::NamespaceProbe::new("esi");
+/// assert!(!probe.feed(b"<p>about esi:include</p><"));
+/// assert!(probe.feed(b"esi:include src=\"/a\"/>"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct NamespaceProbe {
+    // `<` followed by the namespace and a colon
+    pattern: Vec<u8>,
+    // The end of the input so far, too short to hold the pattern, kept to match across chunks
+    tail: Vec<u8>,
+    found: bool,
+}
+
+impl NamespaceProbe {
+    pub fn new(namespace: &str) -> Self {
+        Self {
+            pattern: format!("<{namespace}:").into_bytes(),
+            tail: Vec::new(),
+            found: false,
+        }
+    }
+
+    /// Looks for an ESI tag in the next chunk of the document, returning whether one has been
+    /// found so far.
+    pub fn feed(&mut self, chunk: &[u8]) -> bool {
+        if self.found {
+            return true;
+        }
+
+        // A match that starts in the tail ends within the first bytes of the chunk.
+        let keep = self.pattern.len() - 1;
+        self.tail.extend_from_slice(&chunk[..chunk.len().min(keep)]);
+        self.found = contains(&self.tail, &self.pattern) || contains(chunk, &self.pattern);
+
+        if chunk.len() >= keep {
+            self.tail.clear();
+            self.tail.extend_from_slice(&chunk[chunk.len() - keep..]);
+        } else {
+            let excess = self.tail.len().saturating_sub(keep);
+            self.tail.drain(..excess);
+        }
+        self.found
+    }
+
+    /// Whether an ESI tag has been found so far.
+    pub fn found(&self) -> bool {
+        self.found
+    }
+}
+
+/// Returns whether `document` may contain tags in the ESI `namespace`. See [`NamespaceProbe`].
+pub fn may_contain_esi(document: &[u8], namespace: &str) -> bool {
+    NamespaceProbe::new(namespace).feed(document)
+}
+
+// Whether `pattern`, which starts with `<`, appears in `haystack`.
+fn contains(haystack: &[u8], pattern: &[u8]) -> bool {
+    haystack
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b == b'<')
+        .any(|(i, _)| haystack[i..].starts_with(pattern))
+}
@@ -0,0 +1,497 @@
+use crate::context::FragmentContext;
+use crate::markers::SlotMarker;
+use crate::retry::RetryQueue;
+use crate::status::StatusPolicy;
+use crate::timings::PhaseRecorder;
+use crate::warning::Warnings;
+use crate::{
+    metrics, CallbackKind, CallbackTimings, Phase, PhaseTimings, SchedulingStats, Sink, Warning,
+};
+use fastly::http::{StatusCode, Url};
+use fastly::Request;
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A summary of what happened while processing an ESI document.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProcessingReport {
+    /// The number of bytes written to the output, including both source content and fragment bodies.
+    pub bytes_written: usize,
+    /// How each include was resolved, in the order they completed.
+    pub includes: Vec<IncludeReport>,
+    /// The recoverable issues found in the document, up to [`crate::Configuration::max_warnings`].
+    pub warnings: Vec<Warning>,
+    /// The number of warnings left out of [`Self::warnings`] because there were too many.
+    pub warnings_dropped: usize,
+    /// Whether the output was cut short, for the source document being over
+    /// [`crate::Configuration::max_document_size`] or [`crate::Configuration::max_events`], or
+    /// processing going past
+    /// [`crate::Configuration::deadline`].
+    pub truncated: bool,
+    /// How long processing took.
+    pub elapsed: Duration,
+    /// The hash of the description of the configuration the document was processed with, see
+    /// [`crate::ConfigDescription::hash`].
+    pub config_hash: u64,
+    /// The fragments that were still pending when the output was cut short at the
+    /// [`crate::Configuration::deadline`]. When processing fails, they're in
+    /// [`ProcessingOutcome::abandoned`] instead.
+    pub abandoned: Vec<AbandonedFragment>,
+    /// The strong ETag of the output, quoted as it's sent in the header, with
+    /// [`crate::Configuration::compute_etag`]. `None` when the output was streamed.
+    pub etag: Option<String>,
+    /// The most bytes of content held in memory at once while waiting on fragments: queued
+    /// source content, and the buffers of `esi:try` arms and `esi:group` blocks at any depth.
+    /// Fragment bodies written straight to the output aren't buffered.
+    pub peak_buffered_bytes: usize,
+    /// The most elements queued in the document at once, where an `esi:try` or `esi:group`
+    /// block counts as one.
+    pub peak_queue_len: usize,
+    /// How parsing was interleaved with writing out completed fragments.
+    pub scheduling: SchedulingStats,
+    /// The number of events of the source document that were parsed, at any depth, as counted
+    /// against [`crate::Configuration::max_events`].
+    pub events_parsed: usize,
+    /// When each phase of processing was reached, measured from the start.
+    pub phases: PhaseTimings,
+    /// The time spent in each kind of callback, such as the dispatcher and the response
+    /// processor, which is included in [`Self::elapsed`].
+    pub callbacks: CallbackTimings,
+}
+
+impl ProcessingReport {
+    /// The name of the header that [`Self::to_debug_header`] is meant to be sent in.
+    pub const DEBUG_HEADER_NAME: &'static str = "X-ESI-Debug";
+
+    /// Summarizes how the includes were resolved, e.g. `slots=7 ok=5 alt=1 err=1 ms=143`. With
+    /// a [`crate::Configuration::retry_pass`], includes that succeeded when they were retried are
+    /// counted after the others, e.g. `slots=7 ok=4 alt=1 err=1 retried=1 ms=143`.
+    pub fn to_debug_header(&self) -> String {
+        let count = |outcome| {
+            self.includes
+                .iter()
+                .filter(|include| include.outcome == outcome)
+                .count()
+        };
+        let retried = match count(IncludeOutcome::Retried) {
+            0 => String::new(),
+            retried => format!(" retried={retried}"),
+        };
+        format!(
+            "slots={} ok={} alt={} err={}{retried} ms={}",
+            self.includes.len(),
+            count(IncludeOutcome::Ok),
+            count(IncludeOutcome::Alt),
+            count(IncludeOutcome::Failed),
+            self.elapsed.as_millis()
+        )
+    }
+
+    /// Like [`Self::to_debug_header`], followed by the outcome and URL of every include, e.g.
+    /// `slots=2 ok=1 alt=1 err=0 ms=12; ok http://example.com/a, alt http://example.com/b`.
+    ///
+    /// URLs are redacted: their user info, query and fragment identifier are left out.
+    pub fn to_verbose_debug_header(&self) -> String {
+        let mut header = self.to_debug_header();
+        for (i, include) in self.includes.iter().enumerate() {
+            let separator = if i == 0 { "; " } else { ", " };
+            let outcome = match include.outcome {
+                IncludeOutcome::Ok => "ok",
+                IncludeOutcome::Alt => "alt",
+                IncludeOutcome::Failed => "err",
+                IncludeOutcome::Retried => "retried",
+            };
+            let _ = write!(header, "{separator}{outcome} {}", include.url);
+        }
+        header
+    }
+}
+
+/// How an include was resolved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IncludeOutcome {
+    /// The `src` request succeeded, or the dispatcher skipped it.
+    Ok,
+    /// The `src` request failed and the `alt` request was used instead.
+    Alt,
+    /// The include failed, and was either skipped with `onerror="continue"` or failed processing.
+    Failed,
+    /// The include was skipped with `onerror="continue"`, and succeeded when it was retried at
+    /// the end of the document. See [`crate::Configuration::with_retry_pass`].
+    Retried,
+}
+
+/// The outcome of a single include.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct IncludeReport {
+    /// The redacted URL of the last request made for the include.
+    pub url: String,
+    pub outcome: IncludeOutcome,
+    /// Whether the include's body was copied byte for byte with `raw="true"`, skipping
+    /// [`crate::Configuration::validate_fragment_utf8`].
+    pub raw: bool,
+}
+
+/// A fragment request that was dispatched but never resolved, because processing stopped first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AbandonedFragment {
+    /// The redacted URL of the request, like [`IncludeReport::url`].
+    pub url: String,
+    /// Whether the request was for the include's `alt` URL.
+    pub alt: bool,
+    /// When the include was dispatched, to match the request with the logs of the origin.
+    pub dispatched_at: SystemTime,
+    /// The status of the response, if the request turned out to have completed when it was
+    /// polled one last time, with [`crate::Configuration::poll_abandoned`].
+    pub status: Option<u16>,
+}
+
+/// The outcomes of the includes of a document, shared by every fragment as it's resolved.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IncludeLog {
+    reports: Rc<RefCell<Vec<IncludeReport>>>,
+    // Where the first dispatch and the completion of each include are timed
+    phases: PhaseRecorder,
+    // Where failures reported by the dispatcher are raised
+    warnings: Warnings,
+}
+
+impl IncludeLog {
+    pub fn new(phases: PhaseRecorder, warnings: Warnings) -> Self {
+        Self {
+            reports: Rc::default(),
+            phases,
+            warnings,
+        }
+    }
+
+    pub fn take(&self) -> Vec<IncludeReport> {
+        self.reports.take()
+    }
+}
+
+/// Where the outcome of a fragment gets recorded, and whether it's the fallback for a failed `src`.
+#[derive(Clone, Debug)]
+pub(crate) struct IncludeSlot {
+    log: IncludeLog,
+    is_alt: bool,
+    // When the include was dispatched
+    started: Instant,
+    // Where the include is queued to be retried if it's skipped, when there's a retry pass
+    retries: Option<RetryQueue>,
+    // The position in the log of the outcome that a retry replaces
+    retry_of: Option<usize>,
+    // How the content written for the include is marked, if it is
+    marker: Option<SlotMarker>,
+    // The position of the include in the source document
+    position: usize,
+    // Whether the include has `raw="true"`
+    raw: bool,
+    // Where the include comes from in the document, for the fragment response processor
+    context: Rc<FragmentContext>,
+}
+
+impl IncludeSlot {
+    pub fn new(
+        log: IncludeLog,
+        retries: Option<RetryQueue>,
+        marker: Option<SlotMarker>,
+        context: Rc<FragmentContext>,
+    ) -> Self {
+        Self {
+            is_alt: false,
+            started: Instant::now(),
+            retries,
+            retry_of: None,
+            position: log.warnings.position(),
+            log,
+            marker,
+            raw: false,
+            context,
+        }
+    }
+
+    /// The slot for an include whose body is copied byte for byte.
+    pub fn with_raw(self, raw: bool) -> Self {
+        Self { raw, ..self }
+    }
+
+    /// The slot for the retry of the include whose outcome is at `index` in the log.
+    pub fn retry(
+        log: IncludeLog,
+        index: usize,
+        marker: Option<SlotMarker>,
+        context: Rc<FragmentContext>,
+    ) -> Self {
+        Self {
+            retry_of: Some(index),
+            ..Self::new(log, None, marker, context)
+        }
+    }
+
+    pub fn is_alt(&self) -> bool {
+        self.is_alt
+    }
+
+    /// Where the request for this slot comes from, for the fragment response processor.
+    pub fn fragment_context(&self) -> FragmentContext {
+        FragmentContext {
+            is_alt: self.is_alt,
+            is_retry: self.retry_of.is_some(),
+            ..(*self.context).clone()
+        }
+    }
+
+    /// The slot for the `alt` request of this fragment.
+    pub fn alt(&self) -> Self {
+        Self {
+            is_alt: true,
+            ..self.clone()
+        }
+    }
+
+    /// The slot for a request that was deferred, as it's dispatched.
+    pub fn restarted(self) -> Self {
+        Self {
+            started: Instant::now(),
+            ..self
+        }
+    }
+
+    /// Records that the request for this slot is being dispatched.
+    pub fn dispatched(&self) {
+        self.log.phases.reach(Phase::FirstDispatch);
+        metrics::fragment_request(self.is_alt);
+    }
+
+    /// Calls `call`, a callback of the given kind for this slot, timing it.
+    pub fn time_callback<T>(&self, kind: CallbackKind, call: impl FnOnce() -> T) -> T {
+        self.log.warnings.time_callback(kind, self.position, call)
+    }
+
+    /// Records that a response was received for this slot, whether or not it counts as a success.
+    pub fn responded(&self, status: StatusCode) {
+        metrics::fragment_response(status, self.is_alt);
+    }
+
+    /// Records that the dispatcher failed the request for this slot without sending it.
+    pub fn failed_fast(&self, request: &Request, reason: &str) {
+        metrics::fragment_failed_fast(self.is_alt);
+        self.log.warnings.push(Warning::FragmentFailedFast {
+            position: self.position,
+            url: redact(request.get_url()),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Records that the request for this slot failed without a response.
+    pub fn errored(&self, request: &Request) {
+        metrics::fragment_error(self.is_alt);
+        self.failed(request);
+    }
+
+    /// Records that the request for this slot failed without a response, and its `alt` is tried
+    /// instead, which records the outcome of the include.
+    pub fn errored_trying_alt(&self) {
+        metrics::fragment_error(self.is_alt);
+    }
+
+    /// Records that the request for this slot succeeded.
+    pub fn succeeded(&self, request: &Request) {
+        let outcome = if self.retry_of.is_some() {
+            IncludeOutcome::Retried
+        } else if self.is_alt {
+            IncludeOutcome::Alt
+        } else {
+            IncludeOutcome::Ok
+        };
+        self.record(request.get_url(), outcome);
+    }
+
+    /// Records that the request for this slot failed, and there is nothing to fall back to.
+    pub fn failed(&self, request: &Request) {
+        self.record(request.get_url(), IncludeOutcome::Failed);
+    }
+
+    /// Whether the include can be queued to be retried, for there being a retry pass.
+    pub fn can_retry(&self) -> bool {
+        self.retries.is_some()
+    }
+
+    /// Queues the include to be retried, after it failed and was skipped. Returns the number of
+    /// the slot its content is written to, or `None` without a retry pass.
+    pub fn queue_retry(
+        &self,
+        request: &Request,
+        status_policy: &Rc<StatusPolicy>,
+    ) -> Option<usize> {
+        let retries = self.retries.as_ref()?;
+        // The include has just been recorded as failed.
+        let index = self.log.reports.borrow().len().checked_sub(1)?;
+        Some(retries.push(
+            request,
+            status_policy,
+            index,
+            self.marker.clone(),
+            self.context.clone(),
+        ))
+    }
+
+    /// Marks the start of the content written for this slot, from `request`.
+    pub fn mark_start(&self, request: &Request, output: &mut impl Sink) -> std::io::Result<()> {
+        match &self.marker {
+            Some(marker) => marker.start(request, output),
+            None => Ok(()),
+        }
+    }
+
+    /// Marks the end of the content written for this slot.
+    pub fn mark_end(&self, output: &mut impl Sink) -> std::io::Result<()> {
+        match &self.marker {
+            Some(marker) => marker.end(output),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that the request for this slot was abandoned, along with its response status if
+    /// it's known.
+    pub fn abandoned(&self, request: &Request, status: Option<StatusCode>) -> AbandonedFragment {
+        metrics::fragment_abandoned(self.is_alt);
+        let pending = self.started.elapsed();
+        AbandonedFragment {
+            url: redact(request.get_url()),
+            alt: self.is_alt,
+            dispatched_at: SystemTime::now()
+                .checked_sub(pending)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            status: status.map(|status| status.as_u16()),
+        }
+    }
+
+    /// Records that the URL for this slot was rejected before a request could be made.
+    pub fn rejected(&self, url: &str) {
+        if let Ok(url) = Url::parse(url) {
+            self.record(&url, IncludeOutcome::Failed);
+        }
+    }
+
+    fn record(&self, url: &Url, outcome: IncludeOutcome) {
+        metrics::include(outcome, self.started.elapsed());
+        self.log.phases.fragment_completed();
+        let report = IncludeReport {
+            url: redact(url),
+            outcome,
+            raw: self.raw,
+        };
+        let mut log = self.log.reports.borrow_mut();
+        match self.retry_of.and_then(|index| log.get_mut(index)) {
+            // The retry goes through the include's status policy, so it's as raw as it was.
+            Some(retried) => {
+                *retried = IncludeReport {
+                    raw: retried.raw,
+                    ..report
+                }
+            }
+            None => log.push(report),
+        }
+    }
+}
+
+// Leaves the user info, query and fragment identifier out of a URL, for reports.
+pub(crate) fn redact(url: &Url) -> String {
+    if url.scheme() == "data" {
+        // The content of a data URI is in its path, which could be long.
+        return "data:".to_string();
+    }
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// A live view of the number of bytes a [`Processor`](crate::Processor) has written to its output.
+///
+/// This can be obtained with [`Processor::bytes_written`](crate::Processor::bytes_written) before
+/// processing starts and moved into callbacks, which then see the count at the moment they are
+/// invoked. It remains readable after processing fails.
+#[derive(Clone, Debug, Default)]
+pub struct BytesWritten(Rc<Cell<usize>>);
+
+impl BytesWritten {
+    /// Returns the number of bytes written so far.
+    pub fn get(&self) -> usize {
+        self.0.get()
+    }
+
+    pub(crate) fn add(&self, bytes: usize) {
+        self.0.set(self.0.get() + bytes);
+    }
+}
+
+/// A live view of the state processing left the output in, for deciding how to recover from an
+/// error.
+///
+/// This can be obtained with [`Processor::outcome`](crate::Processor::outcome) before processing
+/// starts. When processing fails, the output has been flushed and ends at the boundary of an
+/// event, so a caller-provided writer can be followed by a recovery write such as an error message,
+/// as long as [`Self::recovery_write_allowed`] says that the output still works.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessingOutcome {
+    bytes_written: BytesWritten,
+    // Whether writing to or flushing the output failed
+    output_failed: Rc<Cell<bool>>,
+    // The fragments still pending when processing failed
+    abandoned: Rc<RefCell<Vec<AbandonedFragment>>>,
+}
+
+impl ProcessingOutcome {
+    /// Returns the number of bytes written to the output so far.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written.get()
+    }
+
+    /// Returns whether more content can be written to the output, which is the case unless
+    /// writing to or flushing it has failed, for example because the client went away.
+    pub fn recovery_write_allowed(&self) -> bool {
+        !self.output_failed.get()
+    }
+
+    /// Returns the fragments that were dispatched but still pending when processing failed,
+    /// including those in `esi:try` arms and `esi:group` blocks. Their requests are cancelled.
+    pub fn abandoned(&self) -> Vec<AbandonedFragment> {
+        self.abandoned.borrow().clone()
+    }
+
+    pub(crate) fn counter(&self) -> &BytesWritten {
+        &self.bytes_written
+    }
+
+    pub(crate) fn output_failed(&self) {
+        self.output_failed.set(true);
+    }
+
+    pub(crate) fn record_abandoned(&self, abandoned: Vec<AbandonedFragment>) {
+        self.abandoned.borrow_mut().extend(abandoned);
+    }
+}
+
+/// A summary of the fragments fetched by [`Processor::prefetch`](crate::Processor::prefetch).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PrefetchReport {
+    /// The number of fragments that were fetched successfully and added to the cache.
+    pub warmed: usize,
+    /// The number of fragments that could not be requested or didn't return a successful response.
+    pub failed: usize,
+    /// The number of includes that weren't fetched, because their URL was already fetched, the
+    /// dispatcher returned no content, or their content is inline in a `data:` URI.
+    pub skipped: usize,
+}
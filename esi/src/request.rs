@@ -0,0 +1,517 @@
+use crate::scheme::scheme_of;
+use crate::warning::Warnings;
+use crate::{
+    CallbackKind, Configuration, ExecutionError, ForwardHeadersOverride, HeaderForwarding,
+    InsecureFragmentPolicy, Result, SchemeHandler, Warning, BACKEND_HINT_HEADER,
+    FORWARDED_HEADERS_HEADER,
+};
+use base64::Engine;
+use fastly::http::{header, HeaderName, HeaderValue, Method, Url};
+use fastly::Request;
+use std::borrow::Cow;
+use std::rc::Rc;
+
+/// The rules applied when turning an include's `src` or `alt` attribute into a fragment request.
+///
+/// A single policy is derived from the [`Configuration`] when processing starts and is shared by
+/// every include in the document, including those nested in `esi:try` arms and `alt` fallbacks.
+#[derive(Clone, Debug)]
+pub(crate) struct UrlPolicy {
+    // Whether URLs with characters that need percent-encoding are rejected rather than encoded
+    pub strict: bool,
+    // Whether variables and functions in URLs are evaluated
+    pub evaluate: bool,
+    // Whether relative URLs keep the original request's Host header
+    pub preserve_host: bool,
+    // What to do with URLs that use plain HTTP
+    pub insecure: InsecureFragmentPolicy,
+    // Which headers of the original request are copied, unless an include says otherwise
+    pub forwarding: HeaderForwarding,
+    // How an include's `forward-headers` attribute combines with `forwarding`
+    pub forward_override: ForwardHeadersOverride,
+    // The handlers of custom schemes, by lowercase scheme
+    pub scheme_handlers: Vec<(String, SchemeHandler)>,
+}
+
+impl UrlPolicy {
+    pub fn from_configuration(configuration: &Configuration) -> Self {
+        Self {
+            strict: configuration.strict_urls,
+            evaluate: configuration.vars_in_attributes,
+            preserve_host: configuration.preserve_host,
+            insecure: configuration.upgrade_insecure_fragments,
+            forwarding: configuration.header_forwarding.clone(),
+            forward_override: configuration.forward_headers_override,
+            scheme_handlers: configuration.scheme_handlers.clone(),
+        }
+    }
+
+    fn scheme_handler(&self, scheme: &str) -> Option<&SchemeHandler> {
+        self.scheme_handlers
+            .iter()
+            .find(|(registered, _)| registered.eq_ignore_ascii_case(scheme))
+            .map(|(_, handler)| handler)
+    }
+
+    // Returns the headers of the original request to copy to the requests for an include with the
+    // given `forward-headers`, or `None` to copy all of them.
+    fn forwarded_headers(&self, include: Option<&[HeaderName]>) -> Option<Vec<HeaderName>> {
+        let allowed = match &self.forwarding {
+            HeaderForwarding::All => None,
+            HeaderForwarding::None => Some(Vec::new()),
+            HeaderForwarding::Allowlist(names) => Some(names.clone()),
+        };
+        match (include, self.forward_override) {
+            (None, _) => allowed,
+            (Some([]), _) => Some(Vec::new()),
+            (Some(names), ForwardHeadersOverride::Replace) => Some(names.to_vec()),
+            (Some(names), ForwardHeadersOverride::Extend) => allowed.map(|mut allowed| {
+                for name in names {
+                    if !allowed.contains(name) {
+                        allowed.push(name.clone());
+                    }
+                }
+                allowed
+            }),
+        }
+    }
+}
+
+/// A fragment request whose URL has been checked against the [`UrlPolicy`], but that hasn't been
+/// built yet. Building it copies the headers of the original request, so requests that may never be
+/// sent, like those for `alt` URLs, are kept in this form until they're needed.
+pub(crate) struct DeferredRequest {
+    // The request metadata the fragment request is based on
+    original: Rc<Request>,
+    url: Url,
+    // The Host header to send, unless the URL is a `data:` URI
+    host: Option<String>,
+    // The only headers of the original request to copy, if not all of them
+    forwarded: Option<Vec<HeaderName>>,
+    // Headers set on top of those of the original request
+    extra_headers: Vec<(&'static str, String)>,
+    // Headers added by the include, after those of the original request
+    added_headers: Vec<(HeaderName, HeaderValue)>,
+    // The method to send, if not that of the original request
+    method: Option<Method>,
+    // Whether the request bypasses the cache
+    pass: bool,
+}
+
+impl DeferredRequest {
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.extra_headers.push((name, value));
+        self
+    }
+
+    pub fn with_added_headers(mut self, headers: Vec<(HeaderName, HeaderValue)>) -> Self {
+        self.added_headers = headers;
+        self
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    // Sends the request straight to the backend, without looking it up in the cache or storing
+    // its response there.
+    pub fn with_pass(mut self) -> Self {
+        self.pass = true;
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn build(self) -> Request {
+        let mut request = self.original.clone_without_body();
+        request.set_url(self.url);
+        if let Some(forwarded) = self.forwarded {
+            let names: Vec<HeaderName> = request.get_header_names().cloned().collect();
+            for name in names {
+                if name != header::HOST && !forwarded.contains(&name) {
+                    request.remove_header(name);
+                }
+            }
+            // Tell the dispatcher which of the headers it was allowed were there to copy.
+            let copied: Vec<&str> = forwarded
+                .iter()
+                .filter(|name| request.contains_header(*name))
+                .map(HeaderName::as_str)
+                .collect();
+            request.set_header(FORWARDED_HEADERS_HEADER, copied.join(" "));
+        }
+        if let Some(host) = self.host {
+            // Drop every existing Host header, whatever its case, before setting the one to send.
+            request.remove_header(header::HOST);
+            request.set_header(header::HOST, host);
+        }
+        for (name, value) in self.extra_headers {
+            request.set_header(name, value);
+        }
+        for (name, value) in self.added_headers {
+            request.append_header(name, value);
+        }
+        if let Some(method) = self.method {
+            request.set_method(method);
+        }
+        if self.pass {
+            request.set_pass(true);
+        }
+        request
+    }
+}
+
+/// Builds the request for a fragment from the original request metadata. Nothing is copied from
+/// the original request until the request is built, so a URL that's rejected costs no more than
+/// checking it.
+///
+/// The fragment request always carries exactly one Host header. It's taken from the final fragment
+/// URL, including any non-default port, whether the URL was relative or absolute. With
+/// [`UrlPolicy::preserve_host`], relative URLs keep the first Host header of the original request
+/// instead, if it had one, and the port of the URL is the one in that header.
+///
+/// Relative URLs are resolved against the original request URL without its userinfo. A path
+/// that doesn't start with `/`, like `fragments/header.html?x=1` or `../up/foo`, is resolved
+/// against the directory of the original request's path, as a browser would. Any fragment
+/// identifier they have is dropped with a [`Warning::UrlNormalized`], as fragment identifiers
+/// aren't sent to servers.
+///
+/// The other headers of the original request are copied as [`UrlPolicy::forwarding`] allows, or
+/// as the include's `forward-headers` attribute says if it has one. The headers of its
+/// `add-header` attribute are added to those with [`DeferredRequest::with_added_headers`].
+///
+/// URLs that have to be percent-encoded or upgraded to HTTPS raise a [`Warning::UrlNormalized`].
+///
+/// URLs with a scheme that has a [`SchemeHandler`] skip all of that, and are requested where the
+/// handler says, with the headers of the original request copied as usual.
+pub(crate) fn build_fragment_request(
+    original: &Rc<Request>,
+    url: &str,
+    policy: &UrlPolicy,
+    forward_headers: Option<&[HeaderName]>,
+    warnings: &Warnings,
+) -> Result<DeferredRequest> {
+    if let Some(handler) = scheme_of(url).and_then(|scheme| policy.scheme_handler(scheme)) {
+        return handled_request(original, url, handler, policy, forward_headers, warnings);
+    }
+
+    let encoded_url = encode_unsafe_characters(url, policy)?;
+    if encoded_url != url {
+        warnings.push(Warning::UrlNormalized {
+            position: warnings.position(),
+            url: url.to_string(),
+            normalized: encoded_url.clone(),
+        });
+    }
+    let escaped_url = encoded_url;
+
+    let is_relative = scheme_of(&escaped_url).is_none();
+    let mut url = if is_relative {
+        resolve_relative_url(original, &escaped_url, policy, warnings)?
+    } else {
+        match Url::parse(&escaped_url) {
+            Ok(url) => url,
+            Err(_err) => {
+                return Err(ExecutionError::InvalidRequestUrl(escaped_url));
+            }
+        }
+    };
+
+    // Resolved against an original request with a custom scheme, a relative URL has it too.
+    if let Some(handler) = policy.scheme_handler(url.scheme()) {
+        return handled_request(
+            original,
+            url.as_str(),
+            handler,
+            policy,
+            forward_headers,
+            warnings,
+        );
+    }
+
+    // `data:` URIs are resolved locally and never sent anywhere, so they don't need a host.
+    if url.scheme() == "data" {
+        warnings.deviation(warnings.position(), "data-uri", url.as_str());
+        return Ok(DeferredRequest {
+            original: Rc::clone(original),
+            url,
+            host: None,
+            forwarded: None,
+            extra_headers: Vec::new(),
+            added_headers: Vec::new(),
+            method: None,
+            pass: false,
+        });
+    }
+
+    if url.scheme() == "http" {
+        match policy.insecure {
+            InsecureFragmentPolicy::Allow => {}
+            InsecureFragmentPolicy::Upgrade => {
+                let insecure = url.to_string();
+                // Switching between special schemes always succeeds.
+                let _ = url.set_scheme("https");
+                warnings.push(Warning::UrlNormalized {
+                    position: warnings.position(),
+                    url: insecure,
+                    normalized: url.to_string(),
+                });
+            }
+            InsecureFragmentPolicy::Reject => {
+                return Err(ExecutionError::InsecureFragmentUrl(url.to_string()));
+            }
+        }
+    }
+
+    let host = match original.get_header_str(header::HOST) {
+        Some(original_host) if is_relative && policy.preserve_host => original_host.to_string(),
+        _ => {
+            // Absolute URLs such as `mailto:x` or `file:///x` have nowhere to be sent.
+            let Some(hostname) = url.host_str() else {
+                return Err(ExecutionError::InvalidRequestUrl(url.to_string()));
+            };
+            match url.port() {
+                Some(port) => format!("{hostname}:{port}"),
+                None => hostname.to_string(),
+            }
+        }
+    };
+
+    Ok(DeferredRequest {
+        original: Rc::clone(original),
+        url,
+        host: Some(host),
+        forwarded: policy.forwarded_headers(forward_headers),
+        extra_headers: Vec::new(),
+        added_headers: Vec::new(),
+        method: None,
+        pass: false,
+    })
+}
+
+// Resolves a relative URL against the URL of the original request. A path starting with `/`
+// replaces the path of the original URL, and any other is joined to it. The userinfo and fragment
+// identifier of the original URL are never copied, and neither is a fragment identifier of the
+// path, which is dropped with a warning. The port is kept, unless the original Host header is
+// preserved, in which case the port is the one in that header, if any.
+fn resolve_relative_url(
+    original: &Request,
+    path: &str,
+    policy: &UrlPolicy,
+    warnings: &Warnings,
+) -> Result<Url> {
+    if path.chars().any(|c| c.is_ascii_control()) {
+        return Err(ExecutionError::InvalidRequestUrl(path.to_string()));
+    }
+
+    let (path_and_query, fragment) = match path.split_once('#') {
+        Some((path_and_query, _)) => (path_and_query, true),
+        None => (path, false),
+    };
+    if fragment {
+        warnings.push(Warning::UrlNormalized {
+            position: warnings.position(),
+            url: path.to_string(),
+            normalized: path_and_query.to_string(),
+        });
+    }
+
+    let mut url = original.get_url().clone();
+    if path_and_query.starts_with('/') {
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path_and_query, None),
+        };
+        url.set_path(path);
+        url.set_query(query);
+    } else {
+        url = url
+            .join(path_and_query)
+            .map_err(|_| ExecutionError::InvalidRequestUrl(path.to_string()))?;
+    }
+    url.set_fragment(None);
+    // Neither fails for URLs with a host, and those without one have no userinfo to clear.
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+
+    if policy.preserve_host {
+        if let Some(original_host) = original.get_header_str(header::HOST) {
+            let port = original_host
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse().ok());
+            let _ = url.set_port(port);
+        }
+    }
+    Ok(url)
+}
+
+// Builds the request for a URL with a custom scheme, from the target its handler resolves it to.
+fn handled_request(
+    original: &Rc<Request>,
+    url: &str,
+    handler: &SchemeHandler,
+    policy: &UrlPolicy,
+    forward_headers: Option<&[HeaderName]>,
+    warnings: &Warnings,
+) -> Result<DeferredRequest> {
+    let target = warnings
+        .time_callback(CallbackKind::SchemeHandler, warnings.position(), || {
+            handler.call(url)
+        })
+        .map_err(|reason| ExecutionError::SchemeHandlerFailed(url.to_string(), reason))?;
+    let Some(hostname) = target.url.host_str() else {
+        return Err(ExecutionError::InvalidRequestUrl(target.url.to_string()));
+    };
+    let host = match target.url.port() {
+        Some(port) => format!("{hostname}:{port}"),
+        None => hostname.to_string(),
+    };
+
+    Ok(DeferredRequest {
+        original: Rc::clone(original),
+        url: target.url,
+        host: Some(host),
+        forwarded: policy.forwarded_headers(forward_headers),
+        extra_headers: target
+            .backend
+            .map(|backend| (BACKEND_HINT_HEADER, backend))
+            .into_iter()
+            .collect(),
+        added_headers: Vec::new(),
+        method: None,
+        pass: false,
+    })
+}
+
+/// Unescapes the XML entity references in a URL attribute.
+///
+/// An `&` that doesn't start a predefined or numeric character reference, as in an unescaped
+/// `?a=1&b=2`, is kept as it is, so URLs written without escaping come through unchanged.
+pub(crate) fn unescape_url(url: &str) -> Cow<'_, str> {
+    if !url.contains('&') {
+        return Cow::Borrowed(url);
+    }
+
+    let mut unescaped = String::with_capacity(url.len());
+    let mut rest = url;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let reference = rest[1..]
+            .find(';')
+            .and_then(|end| Some((resolve_reference(&rest[1..=end])?, end + 2)));
+        match reference {
+            Some((c, len)) => {
+                unescaped.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    Cow::Owned(unescaped)
+}
+
+// Resolves the name of a predefined entity, or a decimal or hexadecimal character reference.
+fn resolve_reference(name: &str) -> Option<char> {
+    let code = match name.strip_prefix('#') {
+        Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok()?,
+        Some(decimal) => decimal.parse().ok()?,
+        None => {
+            return quick_xml::escape::resolve_predefined_entity(name)?
+                .chars()
+                .next()
+        }
+    };
+    char::from_u32(code)
+}
+
+// Characters that aren't allowed in URLs, but show up in hand-written templates.
+const UNSAFE_CHARACTERS: &[char] = &[' ', '"', '<', '>', '`', '{', '}', '|', '^'];
+
+// Percent-encodes unsafe characters and stray `%` signs ahead of the fragment identifier, leaving
+// existing percent-encoded sequences as they are. Fails instead if the policy is strict.
+fn encode_unsafe_characters(url: &str, policy: &UrlPolicy) -> Result<String> {
+    let (before_fragment, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+    let bytes = before_fragment.as_bytes();
+    let is_stray_percent = |i: usize| {
+        bytes[i] == b'%'
+            && !(bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+                && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit))
+    };
+    let needs_encoding =
+        |(i, c): &(usize, char)| UNSAFE_CHARACTERS.contains(c) || is_stray_percent(*i);
+
+    if !before_fragment.char_indices().any(|c| needs_encoding(&c)) {
+        return Ok(url.to_string());
+    }
+    if policy.strict {
+        return Err(ExecutionError::InvalidRequestUrl(url.to_string()));
+    }
+
+    let mut encoded = String::with_capacity(url.len() + 8);
+    for (i, c) in before_fragment.char_indices() {
+        if needs_encoding(&(i, c)) {
+            encoded.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded.push_str(fragment);
+    Ok(encoded)
+}
+
+/// The contents of a `data:` URI, as described in [RFC 2397](https://www.rfc-editor.org/rfc/rfc2397).
+pub(crate) struct DataUri {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Decodes a `data:[<mediatype>][;base64],<data>` URI.
+pub(crate) fn decode_data_uri(url: &Url) -> Result<DataUri> {
+    let invalid = |reason: &str| ExecutionError::InvalidDataUri(format!("{reason}: `{url}`"));
+
+    // Everything after the scheme, without any fragment identifier.
+    let uri = url.as_str();
+    let uri = uri.split_once('#').map_or(uri, |(uri, _fragment)| uri);
+    let Some((metadata, data)) = uri
+        .strip_prefix("data:")
+        .and_then(|uri| uri.split_once(','))
+    else {
+        return Err(invalid("missing data separator"));
+    };
+
+    let (media_type, is_base64) = match metadata.rsplit_once(';') {
+        Some((media_type, encoding)) if encoding.eq_ignore_ascii_case("base64") => {
+            (media_type, true)
+        }
+        _ => (metadata, false),
+    };
+    let media_type = if media_type.is_empty() {
+        "text/plain;charset=US-ASCII".to_string()
+    } else {
+        media_type.to_string()
+    };
+
+    let data: Vec<u8> = percent_encoding::percent_decode_str(data).collect();
+    let data = if is_base64 {
+        // Whitespace is allowed within base64 data URIs, but not by the decoder.
+        let data: Vec<u8> = data
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|err| invalid(&err.to_string()))?
+    } else {
+        data
+    };
+
+    Ok(DataUri { media_type, data })
+}
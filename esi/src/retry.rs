@@ -0,0 +1,121 @@
+use crate::context::FragmentContext;
+use crate::document::{Element, Task};
+use crate::markers::SlotMarker;
+use crate::parse::GroupErrorPolicy;
+use crate::report::{IncludeLog, IncludeSlot};
+use crate::status::StatusPolicy;
+use crate::{
+    send_fragment_request, FragmentRequestDispatcher, ProcessorScratch, RetryPass, RETRY_HEADER,
+};
+use fastly::Request;
+use log::debug;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+// An include that was skipped with `onerror="continue"`
+struct Skipped {
+    request: Request,
+    status_policy: Rc<StatusPolicy>,
+    // The position of its outcome in the include log
+    index: usize,
+    marker: Option<SlotMarker>,
+    context: Rc<FragmentContext>,
+}
+
+/// The includes skipped on the way to the output, in the order they were skipped, to be retried
+/// by a [`RetryPass`]. The number of a slot is its position in the queue.
+#[derive(Clone, Default)]
+pub(crate) struct RetryQueue(Rc<RefCell<Vec<Skipped>>>);
+
+impl std::fmt::Debug for RetryQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RetryQueue({})", self.0.borrow().len())
+    }
+}
+
+impl RetryQueue {
+    pub fn push(
+        &self,
+        request: &Request,
+        status_policy: &Rc<StatusPolicy>,
+        index: usize,
+        marker: Option<SlotMarker>,
+        context: Rc<FragmentContext>,
+    ) -> usize {
+        let mut skipped = self.0.borrow_mut();
+        skipped.push(Skipped {
+            request: request.clone_without_body(),
+            status_policy: status_policy.clone(),
+            index,
+            marker,
+            context,
+        });
+        skipped.len() - 1
+    }
+
+    /// Dispatches the retries of the skipped includes, as long as enough of the deadline is
+    /// `remaining`, and returns the elements that write the content of those that succeed.
+    pub fn dispatch(
+        &self,
+        pass: RetryPass,
+        remaining: Option<Duration>,
+        log: &IncludeLog,
+        dispatch_fragment_request: &FragmentRequestDispatcher,
+        scratch: &ProcessorScratch,
+    ) -> VecDeque<Element> {
+        let skipped = self.0.take();
+        if skipped.is_empty() {
+            return VecDeque::new();
+        }
+        if remaining.is_some_and(|remaining| remaining < pass.min_remaining_budget) {
+            debug!(
+                "not retrying {} skipped includes, {remaining:?} of the deadline left",
+                skipped.len()
+            );
+            return VecDeque::new();
+        }
+
+        let mut elements = VecDeque::new();
+        for (slot, skipped) in skipped.into_iter().enumerate().take(pass.max_retries) {
+            debug!("retrying {} in slot {slot}", skipped.request.get_url_str());
+            let request = skipped.request.with_header(RETRY_HEADER, slot.to_string());
+            let fragment = match send_fragment_request(
+                request,
+                None,
+                false,
+                dispatch_fragment_request,
+                IncludeSlot::retry(log.clone(), skipped.index, skipped.marker, skipped.context),
+                skipped.status_policy,
+            ) {
+                Ok(Some(fragment)) => fragment,
+                Ok(None) => continue,
+                Err(err) => {
+                    debug!("retry for slot {slot} couldn't be dispatched: {err}");
+                    continue;
+                }
+            };
+
+            // The content is written as a group, so that nothing is written if the retry fails.
+            let mut task = Task::new();
+            task.output = scratch.buffer();
+            task.queue.extend([
+                Element::Raw(format!(r#"<template data-esi-slot-content="{slot}">"#).into_bytes()),
+                Element::Include(Box::new(fragment)),
+                Element::Raw(b"</template>".to_vec()),
+            ]);
+            elements.push_back(Element::Group {
+                name: format!("retry {slot}"),
+                onerror: GroupErrorPolicy::Omit,
+                task,
+            });
+        }
+        elements
+    }
+}
+
+// Written in place of a skipped include, for the content of its retry to be moved to.
+pub(crate) fn placeholder(slot: usize) -> String {
+    format!(r#"<template data-esi-slot="{slot}"></template>"#)
+}
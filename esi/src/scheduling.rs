@@ -0,0 +1,153 @@
+use crate::{
+    poll_queue, Element, ExecutionError, FragmentContextProcessor, FragmentRequestContext, Result,
+    Scheduling,
+};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+
+/// How many events of the source document are parsed between two checks against
+/// [`crate::Configuration::max_events`].
+pub const EVENT_BUDGET_CHECK_INTERVAL: usize = 1024;
+
+/// How parsing the document was interleaved with writing out completed fragments, for tuning
+/// [`crate::Configuration::scheduling`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchedulingStats {
+    /// The number of times the queue was checked for completed fragments while the document was
+    /// being parsed.
+    pub drain_checks: usize,
+    /// The number of fragments written out by those checks, where an `esi:try` or `esi:group`
+    /// block counts as one. Fragments written once the whole document is parsed aren't counted.
+    pub drained_fragments: usize,
+    /// The most events parsed between two checks while fragments were pending.
+    pub max_events_between_checks: usize,
+}
+
+/// Decides when the fragments that completed while the document is being parsed are written out.
+/// Both [`crate::Processor::process_document`] and [`crate::ProcessingSession`] parse through
+/// `DocumentState::handle_event`, which checks with the scheduler before each event that
+/// has to be written or queued.
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    // The events parsed since the queue was last checked, or since it was last empty
+    events_since_check: usize,
+    stats: SchedulingStats,
+}
+
+impl Scheduler {
+    pub fn event_parsed(&mut self) {
+        self.events_since_check += 1;
+    }
+
+    /// Writes out the fragments at the head of the queue that have completed, without blocking,
+    /// once enough events have been parsed since the last check, and up to as many as
+    /// [`Scheduling::max_drain_per_check`] allows.
+    pub fn drain_completed(
+        &mut self,
+        elements: &mut VecDeque<Element>,
+        ctx: &FragmentRequestContext,
+        output: &mut impl Write,
+        process_fragment_response: Option<&FragmentContextProcessor>,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            self.events_since_check = 0;
+            return Ok(());
+        }
+        let Scheduling {
+            events_per_drain_check,
+            max_drain_per_check,
+        } = ctx.scheduling;
+        if self.events_since_check < events_per_drain_check {
+            return Ok(());
+        }
+        self.stats.drain_checks += 1;
+        self.stats.max_events_between_checks = self
+            .stats
+            .max_events_between_checks
+            .max(self.events_since_check);
+        self.events_since_check = 0;
+
+        // The head is polled on its own, so that the fragments written can be counted. It's
+        // left at the head of the queue when it's still pending, along with a fallback request
+        // it might have dispatched.
+        let mut drained = 0;
+        while max_drain_per_check.is_none_or(|max| drained < max) {
+            let Some(element) = elements.pop_front() else {
+                break;
+            };
+            let is_fragment = !matches!(element, Element::Raw(_));
+            let mut head = VecDeque::from([element]);
+            poll_queue(
+                &mut head,
+                output,
+                ctx.dispatch_fragment_request,
+                process_fragment_response,
+                &ctx.copy_budget,
+                &ctx.scratch,
+                &ctx.buffers,
+                false,
+            )?;
+            if !head.is_empty() {
+                while let Some(element) = head.pop_back() {
+                    elements.push_front(element);
+                }
+                break;
+            }
+            if is_fragment {
+                drained += 1;
+            }
+        }
+        self.stats.drained_fragments += drained;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> SchedulingStats {
+        self.stats
+    }
+}
+
+/// Counts the events of the source document as they're parsed, at any depth, and stops parsing
+/// once there are more than [`crate::Configuration::max_events`]. The count is only compared
+/// with the limit every [`EVENT_BUDGET_CHECK_INTERVAL`] events, and at the end of the document,
+/// so that the check costs next to nothing per event. Clones share the count, so that it carries
+/// over between the calls a [`crate::ProcessingSession`] parses the document in.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EventBudget {
+    max_events: Option<usize>,
+    parsed: Rc<Cell<usize>>,
+}
+
+impl EventBudget {
+    pub fn new(max_events: Option<usize>) -> Self {
+        Self {
+            max_events,
+            parsed: Rc::default(),
+        }
+    }
+
+    pub fn event_parsed(&self) -> Result<()> {
+        let parsed = self.parsed.get() + 1;
+        self.parsed.set(parsed);
+        if parsed.is_multiple_of(EVENT_BUDGET_CHECK_INTERVAL) {
+            self.check()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Fails once more events have been parsed than the limit allows.
+    pub fn check(&self) -> Result<()> {
+        match self.max_events {
+            Some(max_events) if self.parsed.get() > max_events => {
+                Err(ExecutionError::EventBudgetExceeded(max_events))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn parsed(&self) -> usize {
+        self.parsed.get()
+    }
+}
@@ -0,0 +1,58 @@
+use fastly::http::Url;
+use std::fmt;
+use std::rc::Rc;
+
+/// Where the request for a fragment URL with a custom scheme is sent, as decided by its
+/// [`SchemeHandler`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemeTarget {
+    /// The URL of the fragment request. Its host and port make the Host header.
+    pub url: Url,
+    /// The backend the request should be sent to, passed on to the dispatcher in the
+    /// [`crate::BACKEND_HINT_HEADER`] header.
+    pub backend: Option<String>,
+}
+
+impl SchemeTarget {
+    pub fn new(url: Url) -> Self {
+        Self { url, backend: None }
+    }
+
+    pub fn with_backend(mut self, backend: impl Into<String>) -> Self {
+        self.backend = Some(backend.into());
+        self
+    }
+}
+
+/// A function that turns fragment URLs with a custom scheme, such as `frag://catalog/nav`, into
+/// the [`SchemeTarget`] to request, or the reason they can't be. See
+/// [`crate::Configuration::with_scheme_handler`].
+#[derive(Clone)]
+pub struct SchemeHandler(Rc<ResolveScheme>);
+
+type ResolveScheme = dyn Fn(&str) -> Result<SchemeTarget, String>;
+
+impl SchemeHandler {
+    pub fn new(handler: impl Fn(&str) -> Result<SchemeTarget, String> + 'static) -> Self {
+        Self(Rc::new(handler))
+    }
+
+    pub(crate) fn call(&self, url: &str) -> Result<SchemeTarget, String> {
+        (self.0)(url)
+    }
+}
+
+impl fmt::Debug for SchemeHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SchemeHandler")
+    }
+}
+
+/// The scheme of an absolute URL, as written, or `None` for a relative one.
+pub(crate) fn scheme_of(url: &str) -> Option<&str> {
+    let (scheme, _) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
@@ -0,0 +1,97 @@
+use crate::Element;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// The most buffers kept for reuse, so that one unusually fragmented document doesn't leave them
+// all allocated for good
+const MAX_POOLED_BUFFERS: usize = 32;
+
+/// Space for the queue and buffers used while processing a document, kept so that their
+/// allocations can be reused by the next document.
+///
+/// This is for applications that process documents of the same shape over and over, such as one
+/// per request. Keep a scratch in a thread-local or request-scoped slot and hand it to each
+/// processor with [`crate::Processor::with_scratch`]. The queue of pending elements, the buffers
+/// of queued content and the output buffers of `esi:try` arms are taken from it, and given back
+/// cleared, with their capacity retained, once they're done with. Without a scratch, a processor
+/// starts from empty space every time, with the same output.
+///
+/// Cloning a scratch gives another handle to the same space.
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// thread_local! {
+///     static SCRATCH: esi::ProcessorScratch = esi::ProcessorScratch::with_capacity(16, 4096);
+/// }
+///
+/// let processor = SCRATCH.with(|scratch| {
+///     esi::Processor::new(None, esi::Configuration::default()).with_scratch(scratch)
+/// });
+/// ```
+#[derive(Clone, Default)]
+pub struct ProcessorScratch {
+    space: Rc<RefCell<ScratchSpace>>,
+}
+
+#[derive(Default)]
+struct ScratchSpace {
+    elements: VecDeque<Element>,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl ProcessorScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a scratch with room for `elements` queued elements, and a buffer of `raw_bytes`
+    /// bytes for queued content.
+    pub fn with_capacity(elements: usize, raw_bytes: usize) -> Self {
+        Self {
+            space: Rc::new(RefCell::new(ScratchSpace {
+                elements: VecDeque::with_capacity(elements),
+                buffers: vec![Vec::with_capacity(raw_bytes)],
+            })),
+        }
+    }
+
+    // Takes the element queue, which is always empty.
+    pub(crate) fn take_queue(&self) -> VecDeque<Element> {
+        std::mem::take(&mut self.space.borrow_mut().elements)
+    }
+
+    // Gives back an element queue, dropping whatever is left in it. The larger of the two queues
+    // is kept.
+    pub(crate) fn reclaim_queue(&self, mut elements: VecDeque<Element>) {
+        elements.clear();
+        let mut space = self.space.borrow_mut();
+        if elements.capacity() > space.elements.capacity() {
+            space.elements = elements;
+        }
+    }
+
+    // Takes an empty buffer.
+    pub(crate) fn buffer(&self) -> Vec<u8> {
+        self.space.borrow_mut().buffers.pop().unwrap_or_default()
+    }
+
+    // Gives back a buffer, clearing it.
+    pub(crate) fn recycle(&self, mut buffer: Vec<u8>) {
+        let mut space = self.space.borrow_mut();
+        if buffer.capacity() > 0 && space.buffers.len() < MAX_POOLED_BUFFERS {
+            buffer.clear();
+            space.buffers.push(buffer);
+        }
+    }
+}
+
+impl std::fmt::Debug for ProcessorScratch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let space = self.space.borrow();
+        f.debug_struct("ProcessorScratch")
+            .field("elements", &space.elements.capacity())
+            .field("buffers", &space.buffers.len())
+            .finish()
+    }
+}
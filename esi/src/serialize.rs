@@ -0,0 +1,732 @@
+//! Serialization of the public data types, with the `serde` feature.
+//!
+//! The implementations are written out by hand rather than derived, so that the serialized form
+//! is spelled out here and doesn't change when a field is renamed. Types that wrap `fastly` or
+//! `quick-xml` values serialize a summary of them instead. Types that are written as a string,
+//! such as [`StatusCodes`], can be deserialized too.
+
+use crate::attributes::{AcceptStatus, Critical, ForwardHeaders, Raw};
+use crate::{
+    AbandonedFragment, CallbackKind, CallbackTime, CallbackTimings, ComplianceLevel,
+    ConfigDescription, Configuration, DebugHeader, Event, ForwardHeadersOverride, GroupErrorPolicy,
+    HeaderForwarding, Include, IncludeOutcome, IncludeReport, InsecureFragmentPolicy, KeySpec,
+    MarkerStyle, OutputMode, OversizedDocumentPolicy, PhaseTimings, PrefetchReport,
+    ProcessingReport, ReaderOptions, RetryPass, Scheduling, SchedulingStats, StatusCodes, Tag,
+    TryArm, TryArmKind, Utf8Policy, Warning,
+};
+use fastly::http::HeaderName;
+use quick_xml::events::Event as XmlEvent;
+use serde::de::Error as _;
+use serde::ser::{SerializeMap, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+impl Serialize for StatusCodes {
+    /// Serializes as a string, e.g. `"404,500-599"`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusCodes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for KeySpec {
+    /// Serializes as a list of header names, e.g. `["cookie"]`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.headers().iter().map(|name| name.as_str()))
+    }
+}
+
+// Serializes and deserializes a fieldless enum as one of a fixed set of strings.
+macro_rules! string_enum {
+    ($type:ty { $($variant:path => $name:literal),+ $(,)? }) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(match self {
+                    $($variant => $name,)+
+                })
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let name = String::deserialize(deserializer)?;
+                match name.as_str() {
+                    $($name => Ok($variant),)+
+                    _ => Err(D::Error::unknown_variant(&name, &[$($name),+])),
+                }
+            }
+        }
+    };
+}
+
+string_enum!(InsecureFragmentPolicy {
+    InsecureFragmentPolicy::Allow => "allow",
+    InsecureFragmentPolicy::Upgrade => "upgrade",
+    InsecureFragmentPolicy::Reject => "reject",
+});
+
+string_enum!(ForwardHeadersOverride {
+    ForwardHeadersOverride::Replace => "replace",
+    ForwardHeadersOverride::Extend => "extend",
+});
+
+impl Serialize for HeaderForwarding {
+    /// Serializes as `{"policy": "all"}`, `{"policy": "none"}` or
+    /// `{"policy": "allowlist", "headers": [...]}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::All => map.serialize_entry("policy", "all")?,
+            Self::None => map.serialize_entry("policy", "none")?,
+            Self::Allowlist(names) => {
+                map.serialize_entry("policy", "allowlist")?;
+                map.serialize_entry(
+                    "headers",
+                    &names.iter().map(HeaderName::as_str).collect::<Vec<_>>(),
+                )?;
+            }
+        }
+        map.end()
+    }
+}
+
+string_enum!(Utf8Policy {
+    Utf8Policy::Passthrough => "passthrough",
+    Utf8Policy::Lossy => "lossy",
+    Utf8Policy::Reject => "reject",
+});
+
+string_enum!(OutputMode {
+    OutputMode::Writer => "writer",
+    OutputMode::RawPassthrough => "raw_passthrough",
+});
+
+string_enum!(ComplianceLevel {
+    ComplianceLevel::Off => "off",
+    ComplianceLevel::Warn => "warn",
+    ComplianceLevel::Enforce => "enforce",
+});
+
+string_enum!(DebugHeader {
+    DebugHeader::Summary => "summary",
+    DebugHeader::Verbose => "verbose",
+});
+
+impl Serialize for MarkerStyle {
+    /// Serializes as `"html_comment"` or `"callback"`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            Self::HtmlComment => "html_comment",
+            Self::Callback(_) => "callback",
+        })
+    }
+}
+
+impl Serialize for IncludeOutcome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            Self::Ok => "ok",
+            Self::Alt => "alt",
+            Self::Failed => "failed",
+            Self::Retried => "retried",
+        })
+    }
+}
+
+impl Serialize for RetryPass {
+    /// Serializes as `{"max_retries": 2, "min_remaining_budget_ms": 200}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("RetryPass", 2)?;
+        s.serialize_field("max_retries", &self.max_retries)?;
+        s.serialize_field(
+            "min_remaining_budget_ms",
+            &u64::try_from(self.min_remaining_budget.as_millis()).unwrap_or(u64::MAX),
+        )?;
+        s.end()
+    }
+}
+
+impl Serialize for Scheduling {
+    /// Serializes as `{"events_per_drain_check": 1, "max_drain_per_check": null}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Scheduling", 2)?;
+        s.serialize_field("events_per_drain_check", &self.events_per_drain_check)?;
+        s.serialize_field("max_drain_per_check", &self.max_drain_per_check)?;
+        s.end()
+    }
+}
+
+impl Serialize for ReaderOptions {
+    /// Serializes as `{"check_end_names": false, "trim_text": false, "expand_empty_elements":
+    /// false, "strict": false}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ReaderOptions", 4)?;
+        s.serialize_field("check_end_names", &self.check_end_names)?;
+        s.serialize_field("trim_text", &self.trim_text)?;
+        s.serialize_field("expand_empty_elements", &self.expand_empty_elements)?;
+        s.serialize_field("strict", &self.strict)?;
+        s.end()
+    }
+}
+
+impl Serialize for OversizedDocumentPolicy {
+    /// Serializes as `{"policy": "truncate", "marker": "..."}` or `{"policy": "fail"}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::Truncate { marker } => {
+                map.serialize_entry("policy", "truncate")?;
+                map.serialize_entry("marker", marker)?;
+            }
+            Self::Fail => map.serialize_entry("policy", "fail")?,
+        }
+        map.end()
+    }
+}
+
+impl Serialize for GroupErrorPolicy {
+    /// Serializes as `{"policy": "placeholder", "placeholder": "..."}`, or `{"policy": "omit"}` or
+    /// `{"policy": "fail"}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::Omit => map.serialize_entry("policy", "omit")?,
+            Self::Placeholder(placeholder) => {
+                map.serialize_entry("policy", "placeholder")?;
+                map.serialize_entry("placeholder", placeholder)?;
+            }
+            Self::Fail => map.serialize_entry("policy", "fail")?,
+        }
+        map.end()
+    }
+}
+
+impl Serialize for TryArmKind {
+    /// Serializes as the name of the arm's tag without the namespace prefix, e.g. `"attempt"`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Attempt => serializer.serialize_str("attempt"),
+            Self::Except => serializer.serialize_str("except"),
+            Self::Custom(name) => serializer.serialize_str(name),
+        }
+    }
+}
+
+impl Serialize for TryArm<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("TryArm", 2)?;
+        s.serialize_field("kind", &self.kind)?;
+        s.serialize_field("events", &self.events)?;
+        s.end()
+    }
+}
+
+// A branch of an `esi:choose` block, serialized like a `TryArm`
+struct WhenBranch<'a, 'e> {
+    test: &'a str,
+    events: &'a [Event<'e>],
+}
+
+impl Serialize for WhenBranch<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("WhenBranch", 2)?;
+        s.serialize_field("test", self.test)?;
+        s.serialize_field("events", self.events)?;
+        s.end()
+    }
+}
+
+impl Serialize for Configuration {
+    /// The deadline, the fragment timeout and the callback overrun threshold are serialized in
+    /// whole milliseconds, as `deadline_ms`, `fragment_timeout_ms` and
+    /// `callback_overrun_threshold_ms`, and only the schemes of the scheme handlers are.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Configuration", 45)?;
+        s.serialize_field("namespace", &self.namespace)?;
+        s.serialize_field("is_escaped", &self.is_escaped)?;
+        s.serialize_field("strict_urls", &self.strict_urls)?;
+        s.serialize_field("vars_in_attributes", &self.vars_in_attributes)?;
+        s.serialize_field("preserve_host", &self.preserve_host)?;
+        s.serialize_field(
+            "upgrade_insecure_fragments",
+            &self.upgrade_insecure_fragments,
+        )?;
+        s.serialize_field("header_forwarding", &self.header_forwarding)?;
+        s.serialize_field("forward_headers_override", &self.forward_headers_override)?;
+        s.serialize_field(
+            "scheme_handlers",
+            &self
+                .scheme_handlers
+                .iter()
+                .map(|(scheme, _)| scheme)
+                .collect::<Vec<_>>(),
+        )?;
+        s.serialize_field("accept_status", &self.accept_status)?;
+        s.serialize_field("empty_body_status", &self.empty_body_status)?;
+        s.serialize_field("validate_fragment_utf8", &self.validate_fragment_utf8)?;
+        s.serialize_field("error_body_snippet", &self.error_body_snippet)?;
+        s.serialize_field("prefetch_except", &self.prefetch_except)?;
+        s.serialize_field("fragment_key", &self.fragment_key)?;
+        s.serialize_field("fragment_deduplication", &self.fragment_deduplication)?;
+        s.serialize_field("trace_events", &self.trace_events)?;
+        s.serialize_field("debug_header", &self.debug_header)?;
+        s.serialize_field("compute_etag", &self.compute_etag)?;
+        s.serialize_field("require_surrogate_control", &self.require_surrogate_control)?;
+        s.serialize_field("max_warnings", &self.max_warnings)?;
+        s.serialize_field("max_document_size", &self.max_document_size)?;
+        s.serialize_field("max_events", &self.max_events)?;
+        s.serialize_field("max_attribute_length", &self.max_attribute_length)?;
+        s.serialize_field("max_include_depth", &self.max_include_depth)?;
+        s.serialize_field("max_fragments", &self.max_fragments)?;
+        s.serialize_field("max_fragment_size", &self.max_fragment_size)?;
+        s.serialize_field("oversized_document", &self.oversized_document)?;
+        s.serialize_field("process_in_comments", &self.process_in_comments)?;
+        s.serialize_field("debug_comments", &self.debug_comments)?;
+        s.serialize_field("debug_validate_output", &self.debug_validate_output)?;
+        s.serialize_field("spec_compliance", &self.spec_compliance)?;
+        s.serialize_field("strict_prestream", &self.strict_prestream)?;
+        s.serialize_field("strict_mode", &self.strict_mode)?;
+        s.serialize_field("output_mode", &self.output_mode)?;
+        s.serialize_field("poll_abandoned", &self.poll_abandoned)?;
+        s.serialize_field("retry_pass", &self.retry_pass)?;
+        s.serialize_field("fragment_markers", &self.fragment_markers)?;
+        s.serialize_field(
+            "deadline_ms",
+            &self
+                .deadline
+                .map(|deadline| u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX)),
+        )?;
+        s.serialize_field(
+            "fragment_timeout_ms",
+            &self
+                .fragment_timeout
+                .map(|timeout| u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)),
+        )?;
+        s.serialize_field(
+            "clock",
+            if self.clock.is_system() {
+                "system"
+            } else {
+                "custom"
+            },
+        )?;
+        s.serialize_field(
+            "geo_resolver",
+            if self.geo_resolver.is_host() {
+                "host"
+            } else {
+                "custom"
+            },
+        )?;
+        s.serialize_field(
+            "callback_overrun_threshold_ms",
+            &self
+                .callback_overrun_threshold
+                .map(|threshold| u64::try_from(threshold.as_millis()).unwrap_or(u64::MAX)),
+        )?;
+        s.serialize_field("scheduling", &self.scheduling)?;
+        s.serialize_field("reader_options", &self.reader_options)?;
+        s.end()
+    }
+}
+
+impl Serialize for ConfigDescription {
+    /// Serializes as a map of option names to values, in the order of [`Self::options`].
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.options.len()))?;
+        for (name, value) in &self.options {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for IncludeReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("IncludeReport", 3)?;
+        s.serialize_field("url", &self.url)?;
+        s.serialize_field("outcome", &self.outcome)?;
+        s.serialize_field("raw", &self.raw)?;
+        s.end()
+    }
+}
+
+impl Serialize for ProcessingReport {
+    /// The elapsed time is serialized in whole microseconds, as `elapsed_us`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ProcessingReport", 15)?;
+        s.serialize_field("bytes_written", &self.bytes_written)?;
+        s.serialize_field("includes", &self.includes)?;
+        s.serialize_field("warnings", &self.warnings)?;
+        s.serialize_field("warnings_dropped", &self.warnings_dropped)?;
+        s.serialize_field("truncated", &self.truncated)?;
+        s.serialize_field(
+            "elapsed_us",
+            &u64::try_from(self.elapsed.as_micros()).unwrap_or(u64::MAX),
+        )?;
+        s.serialize_field("config_hash", &format!("{:016x}", self.config_hash))?;
+        s.serialize_field("abandoned", &self.abandoned)?;
+        s.serialize_field("etag", &self.etag)?;
+        s.serialize_field("peak_buffered_bytes", &self.peak_buffered_bytes)?;
+        s.serialize_field("peak_queue_len", &self.peak_queue_len)?;
+        s.serialize_field("scheduling", &self.scheduling)?;
+        s.serialize_field("events_parsed", &self.events_parsed)?;
+        s.serialize_field("phases", &self.phases)?;
+        s.serialize_field("callbacks", &self.callbacks)?;
+        s.end()
+    }
+}
+
+impl Serialize for SchedulingStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("SchedulingStats", 3)?;
+        s.serialize_field("drain_checks", &self.drain_checks)?;
+        s.serialize_field("drained_fragments", &self.drained_fragments)?;
+        s.serialize_field("max_events_between_checks", &self.max_events_between_checks)?;
+        s.end()
+    }
+}
+
+impl Serialize for CallbackTimings {
+    /// Serializes the time of each kind of callback under its [`CallbackKind::name`].
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let kinds = [
+            CallbackKind::Dispatcher,
+            CallbackKind::ResponseProcessor,
+            CallbackKind::SchemeHandler,
+            CallbackKind::WarningHandler,
+            CallbackKind::PhaseHandler,
+            CallbackKind::BoundarySink,
+        ];
+        let mut map = serializer.serialize_map(Some(kinds.len()))?;
+        for kind in kinds {
+            map.serialize_entry(kind.name(), &self.get(kind))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for CallbackTime {
+    /// The total is serialized in whole microseconds, as `total_us`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CallbackTime", 2)?;
+        s.serialize_field("calls", &self.calls)?;
+        s.serialize_field(
+            "total_us",
+            &u64::try_from(self.total.as_micros()).unwrap_or(u64::MAX),
+        )?;
+        s.end()
+    }
+}
+
+impl Serialize for PhaseTimings {
+    /// Each phase is serialized in whole microseconds since the start, e.g. as
+    /// `first_dispatch_us`, or `null` if it wasn't reached.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let micros = |duration: Option<Duration>| {
+            duration.map(|duration| u64::try_from(duration.as_micros()).unwrap_or(u64::MAX))
+        };
+        let mut s = serializer.serialize_struct("PhaseTimings", 5)?;
+        s.serialize_field("first_dispatch_us", &micros(self.first_dispatch))?;
+        s.serialize_field("first_output_byte_us", &micros(self.first_output_byte))?;
+        s.serialize_field("parse_complete_us", &micros(self.parse_complete))?;
+        s.serialize_field(
+            "last_fragment_complete_us",
+            &micros(self.last_fragment_complete),
+        )?;
+        s.serialize_field("finalized_us", &micros(self.finalized))?;
+        s.end()
+    }
+}
+
+impl Serialize for AbandonedFragment {
+    /// The dispatch time is serialized in milliseconds since the Unix epoch, as
+    /// `dispatched_at_ms`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("AbandonedFragment", 4)?;
+        s.serialize_field("url", &self.url)?;
+        s.serialize_field("alt", &self.alt)?;
+        s.serialize_field(
+            "dispatched_at_ms",
+            &self
+                .dispatched_at
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| {
+                    u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+                }),
+        )?;
+        s.serialize_field("status", &self.status)?;
+        s.end()
+    }
+}
+
+impl Serialize for PrefetchReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("PrefetchReport", 3)?;
+        s.serialize_field("warmed", &self.warmed)?;
+        s.serialize_field("failed", &self.failed)?;
+        s.serialize_field("skipped", &self.skipped)?;
+        s.end()
+    }
+}
+
+impl Serialize for Warning {
+    /// Serializes as an object with a `type` field naming the kind of warning, e.g.
+    /// `{"type": "unknown_tag", "position": 12, "tag": "esi:foo"}`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::MalformedMarkup { position, message } => {
+                map.serialize_entry("type", "malformed_markup")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("message", message)?;
+            }
+            Self::UnknownTag { position, tag } => {
+                map.serialize_entry("type", "unknown_tag")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("tag", tag)?;
+            }
+            Self::InvalidAttribute {
+                position,
+                tag,
+                attribute,
+                value,
+            } => {
+                map.serialize_entry("type", "invalid_attribute")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("tag", tag)?;
+                map.serialize_entry("attribute", attribute)?;
+                map.serialize_entry("value", value)?;
+            }
+            Self::DuplicateAttribute {
+                position,
+                tag,
+                attribute,
+            } => {
+                map.serialize_entry("type", "duplicate_attribute")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("tag", tag)?;
+                map.serialize_entry("attribute", attribute)?;
+            }
+            Self::IgnoredTag {
+                position,
+                tag,
+                reason,
+            } => {
+                map.serialize_entry("type", "ignored_tag")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("tag", tag)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            Self::ExpressionError {
+                position,
+                expression,
+            } => {
+                map.serialize_entry("type", "expression_error")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("expression", expression)?;
+            }
+            Self::UrlNormalized {
+                position,
+                url,
+                normalized,
+            } => {
+                map.serialize_entry("type", "url_normalized")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("url", url)?;
+                map.serialize_entry("normalized", normalized)?;
+            }
+            Self::SpecDeviation {
+                position,
+                code,
+                detail,
+            } => {
+                map.serialize_entry("type", "spec_deviation")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("code", code)?;
+                map.serialize_entry("detail", detail)?;
+            }
+            Self::IgnoredOption {
+                position,
+                option,
+                reason,
+            } => {
+                map.serialize_entry("type", "ignored_option")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("option", option)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            Self::FragmentFailedFast {
+                position,
+                url,
+                reason,
+            } => {
+                map.serialize_entry("type", "fragment_failed_fast")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("url", url)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            Self::InvalidOutput { offset, problem } => {
+                map.serialize_entry("type", "invalid_output")?;
+                map.serialize_entry("offset", offset)?;
+                map.serialize_entry("problem", problem)?;
+            }
+            Self::TooLate { position, feature } => {
+                map.serialize_entry("type", "too_late")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("feature", feature)?;
+            }
+            Self::SlowCallback {
+                position,
+                callback,
+                elapsed,
+            } => {
+                map.serialize_entry("type", "slow_callback")?;
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("callback", callback.name())?;
+                map.serialize_entry(
+                    "elapsed_us",
+                    &u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+                )?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Include {
+    /// Serializes the attributes of [`crate::attributes`] as fields of their own, `null` or
+    /// `false` when they aren't set.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(10))?;
+        serialize_include(self, &mut map)?;
+        map.end()
+    }
+}
+
+// Writes the fields of an include to `map`, shared by `Include` and `Tag::Include`.
+fn serialize_include<M: SerializeMap>(include: &Include, map: &mut M) -> Result<(), M::Error> {
+    map.serialize_entry("src", &include.src)?;
+    map.serialize_entry("alt", &include.alt)?;
+    map.serialize_entry("continue_on_error", &include.continue_on_error)?;
+    map.serialize_entry(
+        "critical",
+        &include.get::<Critical>().is_some_and(|critical| critical.0),
+    )?;
+    map.serialize_entry(
+        "accept_status",
+        &include.get::<AcceptStatus>().map(|codes| &codes.0),
+    )?;
+    map.serialize_entry("srcset_index", &include.srcset_index)?;
+    map.serialize_entry("extensions", &include.extensions)?;
+    map.serialize_entry(
+        "fallback",
+        &include.fallback.as_deref().map(String::from_utf8_lossy),
+    )?;
+    map.serialize_entry(
+        "forward_headers",
+        &include
+            .get::<ForwardHeaders>()
+            .map(|names| names.0.iter().map(HeaderName::as_str).collect::<Vec<_>>()),
+    )?;
+    map.serialize_entry("raw", &include.get::<Raw>().is_some_and(|raw| raw.0))
+}
+
+impl Serialize for Tag<'_> {
+    /// Serializes as an object with a `type` field naming the tag. Events in `esi:try` arms,
+    /// `esi:group` blocks and `esi:choose` branches are serialized as [`Event`] summaries.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::Include(include) => {
+                map.serialize_entry("type", "include")?;
+                serialize_include(include, &mut map)?;
+            }
+            Self::Try { arms } => {
+                map.serialize_entry("type", "try")?;
+                map.serialize_entry("arms", arms)?;
+            }
+            Self::Group {
+                name,
+                onerror,
+                events,
+            } => {
+                map.serialize_entry("type", "group")?;
+                map.serialize_entry("name", name)?;
+                map.serialize_entry("onerror", onerror)?;
+                map.serialize_entry("events", events)?;
+            }
+            Self::Choose {
+                when_branches,
+                otherwise_events,
+            } => {
+                map.serialize_entry("type", "choose")?;
+                map.serialize_entry(
+                    "when",
+                    &when_branches
+                        .iter()
+                        .map(|(test, events)| WhenBranch { test, events })
+                        .collect::<Vec<_>>(),
+                )?;
+                map.serialize_entry("otherwise", otherwise_events)?;
+            }
+            Self::DebugComment { text } => {
+                map.serialize_entry("type", "debug_comment")?;
+                map.serialize_entry("text", text)?;
+            }
+            Self::VarsValue { name, raw } => {
+                map.serialize_entry("type", "vars")?;
+                map.serialize_entry("name", name)?;
+                map.serialize_entry("raw", raw)?;
+            }
+            Self::Vars { text } => {
+                map.serialize_entry("type", "vars_block")?;
+                map.serialize_entry("text", text)?;
+            }
+            Self::ResponseDirective { status, headers } => {
+                map.serialize_entry("type", "response")?;
+                map.serialize_entry("status", status)?;
+                map.serialize_entry("headers", headers)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Event<'_> {
+    /// ESI tags are serialized like [`Tag`]. Other markup is summarized as
+    /// `{"type": "xml", "kind": "start", "name": "p"}`, with a `name` only for elements.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let event = match self {
+            Self::ESI(tag) => return tag.serialize(serializer),
+            Self::XML(event) => event,
+        };
+        let (kind, name) = match event {
+            XmlEvent::Start(e) => ("start", Some(e.name())),
+            XmlEvent::End(e) => ("end", Some(e.name())),
+            XmlEvent::Empty(e) => ("empty", Some(e.name())),
+            XmlEvent::Text(_) => ("text", None),
+            XmlEvent::CData(_) => ("cdata", None),
+            XmlEvent::Comment(_) => ("comment", None),
+            XmlEvent::Decl(_) => ("decl", None),
+            XmlEvent::PI(_) => ("pi", None),
+            XmlEvent::DocType(_) => ("doctype", None),
+            XmlEvent::Eof => ("eof", None),
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("type", "xml")?;
+        map.serialize_entry("kind", kind)?;
+        if let Some(name) = name {
+            map.serialize_entry("name", &String::from_utf8_lossy(name.as_ref()))?;
+        }
+        map.end()
+    }
+}
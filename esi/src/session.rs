@@ -0,0 +1,358 @@
+use crate::document::FragmentContent;
+use crate::finalize::Limit;
+use crate::metrics;
+use crate::output::CountingWriter;
+use crate::parse::parse_tags_until;
+use crate::source::{record_source, RecordingReader, SourceWindow};
+use crate::{
+    poll_queue, AbandonedFragment, DocumentState, Element, ExecutionError,
+    FragmentContextProcessor, FragmentRequestContext, PendingFragmentContent, Phase,
+    ProcessingOutcome, ProcessingReport, Result,
+};
+use fastly::http::request::{PendingRequest, PollResult};
+use log::debug;
+use quick_xml::{Reader, Writer};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::time::Instant;
+
+/// The state of a [`ProcessingSession`] after a call to [`ProcessingSession::poll_progress`].
+pub enum Progress {
+    /// The whole document has been written to the output.
+    Complete(ProcessingReport),
+    /// No more progress can be made until this request completes. Wait for it, for example with
+    /// [`fastly::http::request::select`] alongside other requests, then hand the result back to
+    /// [`ProcessingSession::resume`].
+    NeedsWait(PendingRequest),
+}
+
+/// An ESI document being processed step by step, without ever blocking on fragment requests.
+///
+/// This allows applications with their own event loop to drive ESI processing. Each call to
+/// [`poll_progress`](Self::poll_progress) writes out whatever is ready and parses as much of the
+/// document as possible, until it either completes or is waiting on a fragment request. The
+/// output is the same as with [`crate::Processor::process_document`].
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// # use esi::{Configuration, Processor, Progress, Reader, Writer};
+/// # fn f() -> esi::Result<()> {
+/// let processor = Processor::new(None, Configuration::default());
+/// let mut session = processor.session(Reader::from_str("<esi:include src=\"http://example.com/\"/>"), None, None);
+/// let mut output = Writer::new(Vec::new());
+///
+/// let report = loop {
+///     match session.poll_progress(&mut output)? {
+///         Progress::Complete(report) => break report,
+///         Progress::NeedsWait(pending_request) => {
+///             // Do other work, then hand back the request once it's done.
+///             session.resume(fastly::http::request::PollResult::Done(pending_request.wait()))?;
+///         }
+///     }
+/// };
+/// # Ok(())
+/// # }
+/// ```
+pub struct ProcessingSession<'a, R: BufRead> {
+    // The source document, which is parsed incrementally
+    src_document: Reader<RecordingReader<R>>,
+    // The source bytes that content is copied from, if it's written out exactly as it was read
+    source: Option<SourceWindow>,
+    // The XML namespace of ESI tags
+    namespace: String,
+    ctx: FragmentRequestContext<'a>,
+    process_fragment_response: Option<Box<FragmentContextProcessor<'a>>>,
+    document: DocumentState,
+    // Whether the whole source document has been parsed
+    parsed: bool,
+    // The limit the source document was cut short at, for being too large or taking too long
+    limit: Option<Limit>,
+    // The fragments that were dropped from the queue when the output was cut short
+    abandoned: Vec<AbandonedFragment>,
+    // Whether a fragment request has been handed out and not resumed yet
+    lent: bool,
+    // Whether the retries of the skipped includes have been queued, once the rest is written
+    retries_queued: bool,
+    // The number of bytes written to the output so far, and whether writing to it has failed
+    outcome: ProcessingOutcome,
+    // When the session was created, which is when processing is considered to have started
+    started: Instant,
+}
+
+impl<'a, R: BufRead> ProcessingSession<'a, R> {
+    pub(crate) fn new(
+        src_document: Reader<R>,
+        namespace: String,
+        ctx: FragmentRequestContext<'a>,
+        process_fragment_response: Option<Box<FragmentContextProcessor<'a>>>,
+        outcome: ProcessingOutcome,
+    ) -> Self {
+        let document = DocumentState::new(&ctx.scratch);
+        let (src_document, source) = record_source(
+            src_document,
+            ctx.parse_options.raw_passthrough,
+            ctx.parse_options.lines.clone(),
+        );
+        Self {
+            src_document,
+            source,
+            namespace,
+            ctx,
+            process_fragment_response,
+            document,
+            parsed: false,
+            limit: None,
+            abandoned: Vec::new(),
+            lent: false,
+            retries_queued: false,
+            outcome,
+            started: Instant::now(),
+        }
+    }
+
+    /// Makes as much progress as possible without blocking.
+    ///
+    /// Completed fragments are written to `output_writer`, and the document is parsed for as long
+    /// as its content can be streamed. Returns [`Progress::NeedsWait`] with the request at the head
+    /// of the queue when nothing else can be done until it completes.
+    ///
+    /// On error, the output is left flushed at the boundary of an event, like with
+    /// [`crate::Processor::process_document`].
+    pub fn poll_progress(&mut self, output_writer: &mut Writer<impl Write>) -> Result<Progress> {
+        if self.lent {
+            return Err(ExecutionError::InvalidSessionState(
+                "the previous fragment request has not been resumed".to_string(),
+            ));
+        }
+
+        let output = &mut CountingWriter::new(output_writer.get_mut(), self.outcome.clone())
+            .with_phases(self.ctx.phases.clone())
+            .with_validator(self.ctx.validator.clone());
+        let progress = self.advance(output);
+        if progress.is_err() {
+            self.outcome
+                .record_abandoned(self.document.abandon(self.ctx.poll_abandoned));
+            let _ = output.flush();
+        }
+        progress
+    }
+
+    fn advance(&mut self, output: &mut impl Write) -> Result<Progress> {
+        loop {
+            let polled = poll_queue(
+                &mut self.document.elements,
+                output,
+                self.ctx.dispatch_fragment_request,
+                self.process_fragment_response.as_deref(),
+                &self.ctx.copy_budget,
+                &self.ctx.scratch,
+                &self.ctx.buffers,
+                false,
+            );
+            if self.cut_short(polled)? {
+                continue;
+            }
+
+            if let Some(head) = self.document.elements.front_mut() {
+                if let Some(pending_request) = lend_pending_request(head) {
+                    debug!(
+                        "waiting on fragment {}",
+                        pending_request.sent_req().get_url_str()
+                    );
+                    self.lent = true;
+                    return Ok(Progress::NeedsWait(pending_request));
+                }
+
+                // Everything the element needs has completed, so it can be written without
+                // blocking. It might still be requeued if a fallback request was dispatched.
+                let mut head: VecDeque<Element> =
+                    self.document.elements.pop_front().into_iter().collect();
+                let polled = poll_queue(
+                    &mut head,
+                    output,
+                    self.ctx.dispatch_fragment_request,
+                    self.process_fragment_response.as_deref(),
+                    &self.ctx.copy_budget,
+                    &self.ctx.scratch,
+                    &self.ctx.buffers,
+                    true,
+                );
+                if self.cut_short(polled)? {
+                    continue;
+                }
+                if let Some(element) = head.pop_front() {
+                    self.document.elements.push_front(element);
+                }
+                continue;
+            }
+
+            if self.parsed {
+                // A failure has already returned, so the output ends complete or cut short, which
+                // may first queue the retries of the includes that were skipped.
+                if self.ctx.finalize_output(
+                    self.limit,
+                    None,
+                    &self.outcome,
+                    &mut self.document.elements,
+                    output,
+                )? {
+                    self.retries_queued = true;
+                    continue;
+                }
+                debug!("parsed {} events", self.document.event_count);
+                let truncated = self.limit.is_some();
+                if let Some(validator) = &self.ctx.validator {
+                    validator.finish();
+                }
+                let phases = self.ctx.phases.finish();
+                self.document.release(&self.ctx.scratch);
+                let callbacks = self.ctx.warnings.callback_timings();
+                let (warnings, warnings_dropped) = self.ctx.warnings.take();
+                let report = Ok(ProcessingReport {
+                    bytes_written: self.outcome.bytes_written(),
+                    includes: self.ctx.include_log.take(),
+                    warnings,
+                    warnings_dropped,
+                    truncated,
+                    elapsed: self.started.elapsed(),
+                    config_hash: self.ctx.config_hash,
+                    abandoned: std::mem::take(&mut self.abandoned),
+                    etag: None,
+                    peak_buffered_bytes: self.ctx.buffers.peak_buffered_bytes(),
+                    peak_queue_len: self.ctx.buffers.peak_queue_len(),
+                    scheduling: self.document.scheduler.stats(),
+                    events_parsed: self.ctx.parse_options.events.parsed(),
+                    phases,
+                    callbacks,
+                });
+                metrics::document(&report, self.started.elapsed());
+                return report.map(Progress::Complete);
+            }
+
+            // The queue is empty, so parse until content has to be buffered behind a fragment.
+            let buffered = Cell::new(false);
+            let parsed = parse_tags_until(
+                &self.namespace,
+                &mut self.src_document,
+                &mut |event| {
+                    let is_content = matches!(event, crate::Event::XML(_));
+                    self.document.handle_event(
+                        event,
+                        &self.ctx,
+                        output,
+                        self.process_fragment_response.as_deref(),
+                        false,
+                    )?;
+                    buffered.set(is_content && !self.document.elements.is_empty());
+                    Ok(())
+                },
+                &|| buffered.get(),
+                &self.ctx.warnings,
+                self.ctx.parse_options.clone(),
+                self.source.as_ref(),
+            );
+            match self.ctx.cut_short(parsed)? {
+                Some(limit) => {
+                    self.limit = Some(limit);
+                    self.parsed = true;
+                }
+                None => self.parsed = !buffered.get(),
+            }
+            if self.parsed {
+                self.ctx.phases.reach(Phase::ParseComplete);
+            }
+        }
+    }
+
+    // Checks how polling ended, and when the deadline has passed and the policy allows it, drops
+    // whatever is still queued so that the output ends with the truncation marker. The document
+    // is already complete if it's the retries that were dropped, so there's no marker then.
+    fn cut_short(&mut self, polled: Result<()>) -> Result<bool> {
+        let Some(limit) = self.ctx.cut_short(polled)? else {
+            return Ok(false);
+        };
+        if !self.retries_queued {
+            self.limit = Some(limit);
+        }
+        self.abandoned = self.document.abandon(self.ctx.poll_abandoned);
+        self.parsed = true;
+        Ok(true)
+    }
+
+    /// Hands back the request returned in the last [`Progress::NeedsWait`], either still pending
+    /// or with its result.
+    pub fn resume(&mut self, poll_result: PollResult) -> Result<()> {
+        let content = match poll_result {
+            PollResult::Pending(pending_request) => {
+                PendingFragmentContent::PendingRequest(pending_request).into()
+            }
+            PollResult::Done(Ok(response)) => {
+                PendingFragmentContent::CompletedRequest(response).into()
+            }
+            PollResult::Done(Err(err)) => FragmentContent::Failed(err.into()),
+        };
+
+        let unused = match self.document.elements.front_mut() {
+            Some(head) if self.lent => return_lent_content(head, content),
+            _ => Some(content),
+        };
+        if unused.is_some() {
+            return Err(ExecutionError::InvalidSessionState(
+                "no fragment request is waiting to be resumed".to_string(),
+            ));
+        }
+        self.lent = false;
+        Ok(())
+    }
+}
+
+// Polls the fragment requests of an element without blocking, in the order they're written out,
+// and takes the first one that's still pending.
+fn lend_pending_request(element: &mut Element) -> Option<PendingRequest> {
+    match element {
+        Element::Raw(_) => None,
+        Element::Include(fragment) => {
+            let pending_request = fragment.pending_content.take_dispatched_request()?;
+
+            match pending_request.poll() {
+                PollResult::Pending(pending_request) => Some(pending_request),
+                PollResult::Done(res) => {
+                    fragment.pending_content = match res {
+                        Ok(response) => PendingFragmentContent::CompletedRequest(response).into(),
+                        Err(err) => FragmentContent::Failed(err.into()),
+                    };
+                    None
+                }
+            }
+        }
+        Element::Try { arms } => arms
+            .iter_mut()
+            .flat_map(|arm| arm.queue.iter_mut())
+            .find_map(lend_pending_request),
+        Element::Group { task, .. } => task.queue.iter_mut().find_map(lend_pending_request),
+    }
+}
+
+// Puts `content` in place of the lent fragment request, or gives it back if there is none.
+fn return_lent_content(element: &mut Element, content: FragmentContent) -> Option<FragmentContent> {
+    match element {
+        Element::Raw(_) => Some(content),
+        Element::Include(fragment) if matches!(fragment.pending_content, FragmentContent::Lent) => {
+            fragment.pending_content = content;
+            None
+        }
+        Element::Include(_) => Some(content),
+        Element::Try { arms } => arms
+            .iter_mut()
+            .flat_map(|arm| arm.queue.iter_mut())
+            .try_fold(content, |content, element| {
+                return_lent_content(element, content)
+            }),
+        Element::Group { task, .. } => {
+            task.queue.iter_mut().try_fold(content, |content, element| {
+                return_lent_content(element, content)
+            })
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use quick_xml::Reader;
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, Chain, Cursor, Read};
+use std::rc::Rc;
+
+/// The bytes of the source document that the parser has read but not yet handed out with an
+/// event, so that content can be written exactly as it was read.
+///
+/// Only a sliding window of the document is kept: taking the bytes of an event drops everything
+/// before its end.
+#[derive(Clone, Default)]
+pub(crate) struct SourceWindow(Rc<RefCell<Window>>);
+
+#[derive(Default)]
+struct Window {
+    // The position in the document of the first byte kept
+    start: usize,
+    bytes: Vec<u8>,
+}
+
+impl SourceWindow {
+    /// Takes the bytes between two positions of the parser, along with everything before them.
+    /// Returns `None` if they're no longer kept.
+    pub fn take(&self, start: usize, end: usize) -> Option<Vec<u8>> {
+        let mut window = self.0.borrow_mut();
+        let (from, to) = (start.checked_sub(window.start)?, end - window.start);
+        let bytes = window.bytes.get(from..to).map(<[u8]>::to_vec);
+        let to = to.min(window.bytes.len());
+        window.bytes.drain(..to);
+        window.start += to;
+        bytes
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        self.0.borrow_mut().bytes.extend_from_slice(bytes);
+    }
+}
+
+/// How far the parser has read into the source document, by line, so that malformed markup can
+/// be located. Clones share the count.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SourceLines(Rc<Cell<Lines>>);
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Lines {
+    // The line breaks read so far
+    breaks: usize,
+    // The bytes read since the last line break
+    column: usize,
+    // The bytes read so far
+    read: usize,
+}
+
+impl SourceLines {
+    /// The line and column, counting from 1, of the byte at `position` in the document, if it's on
+    /// the line being read and not past it. Columns count bytes.
+    pub fn location(&self, position: usize) -> Option<(usize, usize)> {
+        let lines = self.0.get();
+        let back = lines.read.checked_sub(position)?;
+        (back <= lines.column).then(|| (lines.breaks + 1, lines.column - back + 1))
+    }
+
+    fn advance(&self, bytes: &[u8]) {
+        let mut lines = self.0.get();
+        match bytes.iter().rposition(|&b| b == b'\n') {
+            Some(last) => {
+                lines.breaks += bytes[..=last].iter().filter(|&&b| b == b'\n').count();
+                lines.column = bytes.len() - last - 1;
+            }
+            None => lines.column += bytes.len(),
+        }
+        lines.read += bytes.len();
+        self.0.set(lines);
+    }
+}
+
+/// A source document reader that copies what it reads into a [`SourceWindow`], if it has one,
+/// and counts the lines it reads, if it's given [`SourceLines`].
+pub(crate) struct RecordingReader<R> {
+    inner: R,
+    window: Option<SourceWindow>,
+    lines: Option<SourceLines>,
+}
+
+/// Wraps the reader of a source document so that its bytes can be taken from the returned
+/// window, if `record` is set, and its lines are counted in `lines`. The parser's positions are
+/// counted from where it's wrapped.
+pub(crate) fn record_source<R: BufRead>(
+    reader: Reader<R>,
+    record: bool,
+    lines: Option<SourceLines>,
+) -> (Reader<RecordingReader<R>>, Option<SourceWindow>) {
+    let window = record.then(SourceWindow::default);
+    let config = reader.config().clone();
+    let mut recording = Reader::from_reader(RecordingReader {
+        inner: reader.into_inner(),
+        window: window.clone(),
+        lines,
+    });
+    *recording.config_mut() = config;
+    (recording, window)
+}
+
+/// What the start of a source document holds.
+pub(crate) enum SourceStart<R> {
+    /// The whole document, which is empty or only whitespace.
+    Blank(Vec<u8>),
+    /// A reader for the document from its first byte, as the one it was read from.
+    Content(Reader<Chain<Cursor<Vec<u8>>, R>>),
+}
+
+/// Reads the whitespace at the start of a source document, to find out whether that's all there
+/// is to it. If it isn't, what was read is put back in front of the rest.
+pub(crate) fn check_blank<R: BufRead>(reader: Reader<R>) -> std::io::Result<SourceStart<R>> {
+    let config = reader.config().clone();
+    let mut inner = reader.into_inner();
+    let mut whitespace = Vec::new();
+    loop {
+        let buf = inner.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(SourceStart::Blank(whitespace));
+        }
+        let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let content = blank < buf.len();
+        whitespace.extend_from_slice(&buf[..blank]);
+        inner.consume(blank);
+        if content {
+            break;
+        }
+    }
+
+    let mut content = Reader::from_reader(Cursor::new(whitespace).chain(inner));
+    *content.config_mut() = config;
+    Ok(SourceStart::Content(content))
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(window) = &self.window {
+            window.record(&buf[..read]);
+        }
+        if let Some(lines) = &self.lines {
+            lines.advance(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for RecordingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.window.is_some() || self.lines.is_some() {
+            // The bytes being consumed are still at the front of the buffer, so this doesn't read.
+            if let Ok(buf) = self.inner.fill_buf() {
+                let consumed = &buf[..amt.min(buf.len())];
+                if let Some(window) = &self.window {
+                    window.record(consumed);
+                }
+                if let Some(lines) = &self.lines {
+                    lines.advance(consumed);
+                }
+            }
+        }
+        self.inner.consume(amt);
+    }
+}
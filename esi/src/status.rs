@@ -0,0 +1,135 @@
+use crate::expression::{longest_secret, redact_secrets};
+use crate::{ExecutionError, Utf8Policy};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use std::fmt;
+use std::io::Read;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A set of HTTP status codes, written as a comma-separated list of codes and inclusive ranges,
+/// such as `404,204-206`.
+///
+/// ## Usage Example
+/// ```rust,no_run
+/// let codes: esi::StatusCodes = "404, 204-206".parse().unwrap();
+/// assert!(codes.contains(205));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusCodes(Vec<RangeInclusive<u16>>);
+
+impl StatusCodes {
+    /// Returns whether `status` is in the set.
+    pub fn contains(&self, status: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&status))
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for StatusCodes {
+    type Err = ExecutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ExecutionError::InvalidStatusCodes(s.to_string());
+        let parse_code = |code: &str| match code.trim().parse::<u16>() {
+            Ok(code) if (100..=999).contains(&code) => Ok(code),
+            _ => Err(invalid()),
+        };
+
+        let mut ranges = Vec::new();
+        for item in s.split(',') {
+            if item.trim().is_empty() {
+                return Err(invalid());
+            }
+            let range = match item.split_once('-') {
+                Some((start, end)) => parse_code(start)?..=parse_code(end)?,
+                None => {
+                    let code = parse_code(item)?;
+                    code..=code
+                }
+            };
+            if range.is_empty() {
+                return Err(invalid());
+            }
+            ranges.push(range);
+        }
+
+        Ok(Self(ranges))
+    }
+}
+
+impl fmt::Display for StatusCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decides which fragment response statuses count as a success, how their bodies are checked, and
+/// how long they're waited for.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StatusPolicy {
+    // Statuses treated as a success on top of the 2xx range
+    pub accept: StatusCodes,
+    // Accepted statuses whose body is left out of the output
+    pub empty_body: StatusCodes,
+    // How the bodies of successful responses are checked for valid UTF-8
+    pub utf8: Utf8Policy,
+    // The most bytes of the body of a failed response kept for its error
+    pub error_body_snippet: usize,
+    // How long a response is waited for, from when its request is dispatched
+    pub timeout: Option<Duration>,
+}
+
+impl StatusPolicy {
+    pub fn is_success(&self, status: StatusCode) -> bool {
+        status.is_success() || self.accept.contains(status.as_u16())
+    }
+
+    pub fn emits_body(&self, status: StatusCode) -> bool {
+        !self.empty_body.contains(status.as_u16())
+    }
+
+    /// Reads the start of the body of a failed response to `request`, for its error, with the
+    /// credentials sent in the request redacted.
+    pub fn body_snippet(&self, res: Response, request: &Request) -> Option<String> {
+        if self.error_body_snippet == 0 {
+            return None;
+        }
+        // Read enough past the limit that a secret it cuts through is still redacted whole.
+        let mut body = Vec::new();
+        let read = self
+            .error_body_snippet
+            .saturating_add(longest_secret(request));
+        if let Err(err) = res
+            .into_body()
+            .take(u64::try_from(read).unwrap_or(u64::MAX))
+            .read_to_end(&mut body)
+        {
+            log::debug!("failed to read the body of a failed fragment: {err}");
+        }
+        let mut snippet = redact_secrets(String::from_utf8_lossy(&body).into_owned(), request);
+        if snippet.len() > self.error_body_snippet {
+            let mut end = self.error_body_snippet;
+            while !snippet.is_char_boundary(end) {
+                end -= 1;
+            }
+            snippet.truncate(end);
+        }
+        (!snippet.is_empty()).then_some(snippet)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{Configuration, Processor};
+use fastly::{Request, Response};
+
+/// The header of an origin response that asks surrogates to process it as an ESI document, with
+/// a `content="ESI/1.0"` directive. See [`Processor::should_process`].
+pub const SURROGATE_CONTROL_HEADER: &str = "Surrogate-Control";
+
+/// The header of a request to an origin in which surrogates announce the device tokens they
+/// process ESI documents for, as in `Surrogate-Capability: edge="ESI/1.0"`.
+pub const SURROGATE_CAPABILITY_HEADER: &str = "Surrogate-Capability";
+
+// The capability that both headers name ESI processing with
+const ESI_CAPABILITY: &str = "ESI/1.0";
+
+impl Processor {
+    /// Returns whether `resp` is meant to be processed as an ESI document.
+    ///
+    /// Without [`Configuration::require_surrogate_control`], every response is, and it's left to
+    /// the application to decide, for example by content type. With it, only a response whose
+    /// `Surrogate-Control` header has a `content` directive listing `ESI/1.0` is, such as
+    /// `Surrogate-Control: max-age=300, content="ESI/1.0"`. A directive targeted at a particular
+    /// surrogate, as in `content="ESI/1.0";edge`, is ignored here, since there's no request to
+    /// tell which surrogate this is. [`Processor::process_response`] also honors those targeted
+    /// at a device token that the original request announces in its `Surrogate-Capability`
+    /// header.
+    pub fn should_process(resp: &Response, config: &Configuration) -> bool {
+        !config.require_surrogate_control || requests_esi(resp, &[])
+    }
+
+    // Like `should_process`, also honoring directives targeted at the device tokens that the
+    // original request announces ESI support for.
+    pub(crate) fn is_processing_requested(&self, resp: &Response) -> bool {
+        if !self.configuration.require_surrogate_control {
+            return true;
+        }
+        let tokens = self
+            .original_request_metadata
+            .as_ref()
+            .map(esi_device_tokens)
+            .unwrap_or_default();
+        requests_esi(resp, &tokens)
+    }
+}
+
+// Returns whether a `Surrogate-Control` header of `resp` has a `content` directive that lists
+// `ESI/1.0`, either for every surrogate or targeted at one of `tokens`.
+fn requests_esi(resp: &Response, tokens: &[String]) -> bool {
+    resp.get_header_all(SURROGATE_CONTROL_HEADER)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| {
+            let (directive, target) = match directive.split_once(';') {
+                Some((directive, target)) => (directive, Some(target.trim())),
+                None => (directive, None),
+            };
+            let (name, value) = directive.split_once('=')?;
+            (name.trim().eq_ignore_ascii_case("content")
+                && target.is_none_or(|target| tokens.iter().any(|token| token == target)))
+            .then_some(value)
+        })
+        .any(lists_esi)
+}
+
+// Whether a directive value, quoted or not, lists `ESI/1.0` among its space-separated
+// capabilities.
+fn lists_esi(value: &str) -> bool {
+    value
+        .trim()
+        .trim_matches('"')
+        .split_whitespace()
+        .any(|capability| capability.eq_ignore_ascii_case(ESI_CAPABILITY))
+}
+
+// The device tokens that the `Surrogate-Capability` headers of `req` announce ESI support for.
+fn esi_device_tokens(req: &Request) -> Vec<String> {
+    req.get_header_all(SURROGATE_CAPABILITY_HEADER)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|capability| {
+            let (token, capabilities) = capability.split_once('=')?;
+            lists_esi(capabilities).then(|| token.trim().to_string())
+        })
+        .collect()
+}
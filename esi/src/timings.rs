@@ -0,0 +1,301 @@
+use crate::Clock;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A point reached while processing a document, reported to [`crate::Processor::on_phase`] as
+/// it's reached and timed in [`PhaseTimings`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Processing started.
+    Started,
+    /// The first fragment request was handed to the dispatcher.
+    FirstDispatch,
+    /// The first byte was written to the output.
+    FirstOutputByte,
+    /// The whole source document was parsed, or as much of it as its size limit or deadline
+    /// allowed.
+    ParseComplete,
+    /// The last include was resolved. It's only known to be the last at the end of the document,
+    /// so it's reported then, just before [`Self::Finalized`].
+    LastFragmentComplete,
+    /// The output was ended.
+    Finalized,
+}
+
+/// When each [`Phase`] of processing a document was reached, measured from the start with the
+/// [`crate::Configuration::clock`]. Phases that weren't reached, such as the first dispatch of a
+/// document without includes, are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PhaseTimings {
+    pub first_dispatch: Option<Duration>,
+    pub first_output_byte: Option<Duration>,
+    pub parse_complete: Option<Duration>,
+    pub last_fragment_complete: Option<Duration>,
+    pub finalized: Option<Duration>,
+}
+
+impl PhaseTimings {
+    /// When `phase` was reached. [`Phase::Started`] is always at zero.
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Started => Some(Duration::ZERO),
+            Phase::FirstDispatch => self.first_dispatch,
+            Phase::FirstOutputByte => self.first_output_byte,
+            Phase::ParseComplete => self.parse_complete,
+            Phase::LastFragmentComplete => self.last_fragment_complete,
+            Phase::Finalized => self.finalized,
+        }
+    }
+
+    fn get_mut(&mut self, phase: Phase) -> Option<&mut Option<Duration>> {
+        match phase {
+            Phase::Started => None,
+            Phase::FirstDispatch => Some(&mut self.first_dispatch),
+            Phase::FirstOutputByte => Some(&mut self.first_output_byte),
+            Phase::ParseComplete => Some(&mut self.parse_complete),
+            Phase::LastFragmentComplete => Some(&mut self.last_fragment_complete),
+            Phase::Finalized => Some(&mut self.finalized),
+        }
+    }
+
+    /// Formats the phases that were reached as the value of a `Server-Timing` header, in
+    /// milliseconds, e.g. `esi-dispatch;dur=0.4, esi-ttfb;dur=0.1, esi-parse;dur=1.2,
+    /// esi-fragments;dur=38.0, esi-total;dur=38.5`.
+    pub fn to_server_timing(&self) -> String {
+        let metrics = [
+            ("esi-dispatch", self.first_dispatch),
+            ("esi-ttfb", self.first_output_byte),
+            ("esi-parse", self.parse_complete),
+            ("esi-fragments", self.last_fragment_complete),
+            ("esi-total", self.finalized),
+        ];
+        let mut value = String::new();
+        for (name, duration) in metrics {
+            let Some(duration) = duration else {
+                continue;
+            };
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            let _ = write!(value, "{name};dur={:.1}", duration.as_secs_f64() * 1000.0);
+        }
+        value
+    }
+}
+
+pub(crate) type PhaseHandler = dyn Fn(Phase);
+
+/// Records the [`PhaseTimings`] of a document, shared by everything that reaches a phase.
+#[derive(Clone)]
+pub(crate) struct PhaseRecorder(Rc<PhaseState>);
+
+struct PhaseState {
+    clock: Clock,
+    started: Instant,
+    timings: RefCell<PhaseTimings>,
+    on_phase: Option<Rc<PhaseHandler>>,
+}
+
+impl PhaseRecorder {
+    /// Starts timing a document now, reporting [`Phase::Started`].
+    pub fn start(clock: Clock, on_phase: Option<Rc<PhaseHandler>>) -> Self {
+        let recorder = Self(Rc::new(PhaseState {
+            started: clock.now(),
+            clock,
+            timings: RefCell::default(),
+            on_phase,
+        }));
+        recorder.report(Phase::Started);
+        recorder
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.0.clock.now().saturating_duration_since(self.0.started)
+    }
+
+    fn report(&self, phase: Phase) {
+        if let Some(on_phase) = &self.0.on_phase {
+            on_phase(phase);
+        }
+    }
+
+    /// Records that `phase` was reached, unless it already was.
+    pub fn reach(&self, phase: Phase) {
+        {
+            let mut timings = self.0.timings.borrow_mut();
+            let Some(reached) = timings.get_mut(phase) else {
+                return;
+            };
+            if reached.is_some() {
+                return;
+            }
+            *reached = Some(self.elapsed());
+        }
+        self.report(phase);
+    }
+
+    /// Records that an include was resolved, which may turn out to be the last.
+    pub fn fragment_completed(&self) {
+        self.0.timings.borrow_mut().last_fragment_complete = Some(self.elapsed());
+    }
+
+    /// Records that the output was ended, returning the timings of the document.
+    pub fn finish(&self) -> PhaseTimings {
+        if self.0.timings.borrow().last_fragment_complete.is_some() {
+            self.report(Phase::LastFragmentComplete);
+        }
+        self.reach(Phase::Finalized);
+        *self.0.timings.borrow()
+    }
+}
+
+impl Default for PhaseRecorder {
+    fn default() -> Self {
+        Self::start(Clock::system(), None)
+    }
+}
+
+impl std::fmt::Debug for PhaseRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PhaseRecorder")
+            .field(&self.0.timings.borrow())
+            .finish()
+    }
+}
+
+/// A kind of callback given to the processor, timed in [`CallbackTimings`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CallbackKind {
+    /// The `dispatch_fragment_request` callback, including the requests of
+    /// [`crate::Processor::prefetch`].
+    Dispatcher,
+    /// The `process_fragment_response` callback.
+    ResponseProcessor,
+    /// A [`crate::SchemeHandler`].
+    SchemeHandler,
+    /// The [`crate::Processor::on_warning`] callback.
+    WarningHandler,
+    /// The [`crate::Processor::on_phase`] callback.
+    PhaseHandler,
+    /// A [`crate::BoundarySink`].
+    BoundarySink,
+}
+
+impl CallbackKind {
+    /// The name of the kind of callback, e.g. `response_processor`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dispatcher => "dispatcher",
+            Self::ResponseProcessor => "response_processor",
+            Self::SchemeHandler => "scheme_handler",
+            Self::WarningHandler => "warning_handler",
+            Self::PhaseHandler => "phase_handler",
+            Self::BoundarySink => "boundary_sink",
+        }
+    }
+}
+
+/// How often a kind of callback was called, and how long it took altogether.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CallbackTime {
+    pub calls: usize,
+    pub total: Duration,
+}
+
+/// The time spent in each kind of callback while processing a document, measured with the
+/// [`crate::Configuration::clock`], to tell slow callbacks apart from slow fragments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CallbackTimings {
+    pub dispatcher: CallbackTime,
+    pub response_processor: CallbackTime,
+    pub scheme_handler: CallbackTime,
+    pub warning_handler: CallbackTime,
+    pub phase_handler: CallbackTime,
+    pub boundary_sink: CallbackTime,
+}
+
+impl CallbackTimings {
+    /// The time spent in callbacks of the given kind.
+    pub fn get(&self, kind: CallbackKind) -> CallbackTime {
+        match kind {
+            CallbackKind::Dispatcher => self.dispatcher,
+            CallbackKind::ResponseProcessor => self.response_processor,
+            CallbackKind::SchemeHandler => self.scheme_handler,
+            CallbackKind::WarningHandler => self.warning_handler,
+            CallbackKind::PhaseHandler => self.phase_handler,
+            CallbackKind::BoundarySink => self.boundary_sink,
+        }
+    }
+
+    fn get_mut(&mut self, kind: CallbackKind) -> &mut CallbackTime {
+        match kind {
+            CallbackKind::Dispatcher => &mut self.dispatcher,
+            CallbackKind::ResponseProcessor => &mut self.response_processor,
+            CallbackKind::SchemeHandler => &mut self.scheme_handler,
+            CallbackKind::WarningHandler => &mut self.warning_handler,
+            CallbackKind::PhaseHandler => &mut self.phase_handler,
+            CallbackKind::BoundarySink => &mut self.boundary_sink,
+        }
+    }
+
+    /// The time spent in all callbacks.
+    pub fn total(&self) -> Duration {
+        [
+            self.dispatcher,
+            self.response_processor,
+            self.scheme_handler,
+            self.warning_handler,
+            self.phase_handler,
+            self.boundary_sink,
+        ]
+        .iter()
+        .map(|time| time.total)
+        .sum()
+    }
+}
+
+/// Times the callbacks of a document, adding up their [`CallbackTimings`].
+#[derive(Default)]
+pub(crate) struct CallbackTimer {
+    clock: Clock,
+    // How long a single call can take before it's reported, if it is
+    threshold: Option<Duration>,
+    timings: RefCell<CallbackTimings>,
+}
+
+impl CallbackTimer {
+    pub fn new(clock: Clock, threshold: Option<Duration>) -> Self {
+        Self {
+            clock,
+            threshold,
+            timings: RefCell::default(),
+        }
+    }
+
+    /// Calls `call`, a callback of the given kind, returning its result along with how long it
+    /// took if that's over the threshold.
+    pub fn time<T>(&self, kind: CallbackKind, call: impl FnOnce() -> T) -> (T, Option<Duration>) {
+        let started = self.clock.now();
+        let result = call();
+        let elapsed = self.clock.now().saturating_duration_since(started);
+        {
+            let mut timings = self.timings.borrow_mut();
+            let time = timings.get_mut(kind);
+            time.calls += 1;
+            time.total += elapsed;
+        }
+        let overrun = self.threshold.is_some_and(|threshold| elapsed > threshold);
+        (result, overrun.then_some(elapsed))
+    }
+
+    pub fn timings(&self) -> CallbackTimings {
+        *self.timings.borrow()
+    }
+}
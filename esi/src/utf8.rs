@@ -0,0 +1,139 @@
+use crate::Utf8Policy;
+use fastly::{mime, Body, Response};
+use std::io::{self, Read};
+
+// The size of the chunks fragment bodies are decoded in
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// The body of a fragment response, checked against a [`Utf8Policy`] as it's read.
+///
+/// Unless the policy is [`Utf8Policy::Passthrough`], a body whose `Content-Type` declares the
+/// ISO-8859-1 charset is transcoded to UTF-8, and any other body is decoded as UTF-8. Invalid
+/// sequences are replaced with U+FFFD, or fail the read with [`io::ErrorKind::InvalidData`].
+pub(crate) struct Utf8Body<R> {
+    inner: R,
+    policy: Utf8Policy,
+    // Whether the body is transcoded from ISO-8859-1
+    latin1: bool,
+    chunk: Vec<u8>,
+    // Bytes read but not decoded yet, which are only ever an incomplete sequence
+    pending: Vec<u8>,
+    // Decoded bytes that haven't been read yet, from `offset`
+    decoded: Vec<u8>,
+    offset: usize,
+}
+
+impl Utf8Body<Body> {
+    pub fn new(res: Response, policy: Utf8Policy) -> Self {
+        let latin1 = res
+            .get_content_type()
+            .and_then(|mime| {
+                mime.get_param(mime::CHARSET)
+                    .map(|charset| is_latin1(charset.as_str()))
+            })
+            .unwrap_or(false);
+        Self {
+            inner: res.into_body(),
+            policy,
+            latin1,
+            chunk: match policy {
+                Utf8Policy::Passthrough => Vec::new(),
+                _ => vec![0; CHUNK_SIZE],
+            },
+            pending: Vec::new(),
+            decoded: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl<R: Read> Utf8Body<R> {
+    // Decodes the next chunk of the body into `decoded`. Returns `false` at the end of the body.
+    fn decode_chunk(&mut self) -> io::Result<bool> {
+        let read = self.inner.read(&mut self.chunk)?;
+        if read == 0 {
+            if self.pending.is_empty() {
+                return Ok(false);
+            }
+            // The body ends partway through a sequence.
+            self.pending.clear();
+            return invalid(&mut self.decoded, self.policy).map(|()| true);
+        }
+
+        if self.latin1 {
+            for &byte in &self.chunk[..read] {
+                let mut buf = [0; 2];
+                let encoded = char::from(byte).encode_utf8(&mut buf);
+                self.decoded.extend_from_slice(encoded.as_bytes());
+            }
+            return Ok(true);
+        }
+
+        self.pending.extend_from_slice(&self.chunk[..read]);
+        let pending = std::mem::take(&mut self.pending);
+        let mut input = &pending[..];
+        loop {
+            match std::str::from_utf8(input) {
+                Ok(valid) => {
+                    self.decoded.extend_from_slice(valid.as_bytes());
+                    input = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, rest) = input.split_at(err.valid_up_to());
+                    self.decoded.extend_from_slice(valid);
+                    let Some(len) = err.error_len() else {
+                        // An incomplete sequence, which the next chunk may finish.
+                        input = rest;
+                        break;
+                    };
+                    invalid(&mut self.decoded, self.policy)?;
+                    input = &rest[len..];
+                }
+            }
+        }
+        self.pending = input.to_vec();
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Utf8Body<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.policy == Utf8Policy::Passthrough {
+            return self.inner.read(buf);
+        }
+
+        while self.offset == self.decoded.len() {
+            self.decoded.clear();
+            self.offset = 0;
+            if !self.decode_chunk()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.decoded.len() - self.offset);
+        buf[..len].copy_from_slice(&self.decoded[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+// Handles an invalid sequence according to `policy`.
+fn invalid(decoded: &mut Vec<u8>, policy: Utf8Policy) -> io::Result<()> {
+    match policy {
+        Utf8Policy::Reject => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "fragment body is not valid UTF-8",
+        )),
+        _ => {
+            decoded.extend_from_slice("\u{FFFD}".as_bytes());
+            Ok(())
+        }
+    }
+}
+
+fn is_latin1(charset: &str) -> bool {
+    ["iso-8859-1", "iso8859-1", "latin1", "l1"]
+        .iter()
+        .any(|name| charset.eq_ignore_ascii_case(name))
+}
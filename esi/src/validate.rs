@@ -0,0 +1,286 @@
+use crate::warning::Warnings;
+use crate::Warning;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Checks the composed output as it's written, for
+/// [`crate::Configuration::debug_validate_output`], raising a [`Warning::InvalidOutput`] for
+/// each problem found rather than failing.
+///
+/// The output is checked for invalid UTF-8, tags in the ESI namespace that weren't processed,
+/// fragment marker comments that aren't properly nested or that end up inside a tag, and markup
+/// left unterminated at the end. When the output starts with an XML declaration, its elements
+/// are also checked for being balanced.
+///
+/// The checker only looks at the structure of the markup, not at its meaning, so comments,
+/// CDATA sections and processing instructions are skipped over, and a `<` that can't start
+/// markup is taken as text, as browsers do.
+#[derive(Clone)]
+pub(crate) struct OutputValidator(Rc<RefCell<ValidatorState>>);
+
+struct ValidatorState {
+    // The prefix of ESI tag names, e.g. `esi:`
+    prefix: Vec<u8>,
+    // The number of bytes checked so far
+    offset: usize,
+    // The start of a UTF-8 sequence that continues in the next write
+    partial_char: Vec<u8>,
+    // The markup being read, which is checked once it's complete
+    markup: Option<Markup>,
+    // The slots of the fragment markers started and not ended yet, with their offsets
+    markers: Vec<(usize, usize)>,
+    // Whether anything other than whitespace has been written
+    has_content: bool,
+    // The elements open at this point, with their offsets, if the output is XML
+    elements: Option<Vec<(Vec<u8>, usize)>>,
+    warnings: Warnings,
+}
+
+// A tag, comment or other markup, from its `<`
+struct Markup {
+    offset: usize,
+    bytes: Vec<u8>,
+    // The quote the value of an attribute of a tag is in, if it is
+    quote: Option<u8>,
+}
+
+impl Markup {
+    const COMMENT: &'static [u8] = b"<!--";
+    const CDATA: &'static [u8] = b"<![CDATA[";
+
+    // Adds a byte to the markup, returning whether it's now complete.
+    fn push(&mut self, byte: u8) -> bool {
+        self.bytes.push(byte);
+        let bytes = &self.bytes;
+        if bytes.starts_with(Self::COMMENT) {
+            return bytes.len() >= 7 && bytes.ends_with(b"-->");
+        }
+        if bytes.starts_with(Self::CDATA) {
+            return bytes.len() >= 12 && bytes.ends_with(b"]]>");
+        }
+        if Self::COMMENT.starts_with(bytes) || Self::CDATA.starts_with(bytes) {
+            return false;
+        }
+        if bytes.starts_with(b"<?") {
+            return bytes.len() >= 4 && bytes.ends_with(b"?>");
+        }
+        match self.quote {
+            Some(quote) if byte == quote => self.quote = None,
+            Some(_) => {}
+            None if byte == b'"' || byte == b'\'' => {
+                if !bytes.starts_with(b"<!") {
+                    self.quote = Some(byte);
+                }
+            }
+            None => return byte == b'>',
+        }
+        false
+    }
+
+    // The name of a start or end tag, e.g. `p` for `</p >`
+    fn tag_name(&self) -> &[u8] {
+        let name = self.bytes[1..]
+            .strip_prefix(b"/")
+            .unwrap_or(&self.bytes[1..]);
+        let end = name
+            .iter()
+            .position(|b| b.is_ascii_whitespace() || matches!(b, b'/' | b'>'))
+            .unwrap_or(name.len());
+        &name[..end]
+    }
+}
+
+// Whether `byte` can follow `<` to start markup, rather than being text
+fn starts_markup(byte: u8) -> bool {
+    byte.is_ascii_alphabetic() || matches!(byte, b'/' | b'!' | b'?' | b'_')
+}
+
+// Reads the slot of a fragment marker comment, e.g. `("start", 2)` for
+// `<!--esi-frag start slot=2 src=... -->`.
+fn parse_marker(comment: &[u8]) -> Option<(&str, usize)> {
+    let comment = std::str::from_utf8(comment).ok()?;
+    let rest = comment.strip_prefix("<!--esi-frag ")?;
+    let (kind, rest) = rest.split_once(' ')?;
+    let slot = rest.strip_prefix("slot=")?;
+    let end = slot
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(slot.len());
+    Some((kind, slot[..end].parse().ok()?))
+}
+
+impl OutputValidator {
+    pub fn new(namespace: &str, warnings: Warnings) -> Self {
+        Self(Rc::new(RefCell::new(ValidatorState {
+            prefix: format!("{namespace}:").into_bytes(),
+            offset: 0,
+            partial_char: Vec::new(),
+            markup: None,
+            markers: Vec::new(),
+            has_content: false,
+            elements: None,
+            warnings,
+        })))
+    }
+
+    /// Checks the next bytes of the output.
+    pub fn check(&self, bytes: &[u8]) {
+        let mut state = self.0.borrow_mut();
+        state.check_utf8(bytes);
+        for &byte in bytes {
+            state.check_byte(byte);
+            state.offset += 1;
+        }
+    }
+
+    /// Checks that nothing was left unfinished at the end of the output.
+    pub fn finish(&self) {
+        let mut state = self.0.borrow_mut();
+        let end = state.offset;
+        if !state.partial_char.is_empty() {
+            state.violation(end - state.partial_char.len(), "truncated UTF-8 sequence");
+        }
+        if let Some(markup) = state.markup.take() {
+            state.violation(markup.offset, "unterminated markup");
+        }
+        for (slot, offset) in std::mem::take(&mut state.markers) {
+            state.violation(
+                offset,
+                format!("fragment marker for slot {slot} is never ended"),
+            );
+        }
+        for (name, offset) in state.elements.take().unwrap_or_default() {
+            let problem = format!(
+                "element `{}` is never closed",
+                String::from_utf8_lossy(&name)
+            );
+            state.violation(offset, problem);
+        }
+    }
+}
+
+impl ValidatorState {
+    fn violation(&self, offset: usize, problem: impl Into<String>) {
+        self.warnings.push(Warning::InvalidOutput {
+            offset,
+            problem: problem.into(),
+        });
+    }
+
+    fn check_utf8(&mut self, bytes: &[u8]) {
+        // Only the bytes that can't start a new sequence are carried over from the last write.
+        let mut pending = std::mem::take(&mut self.partial_char);
+        let start = self.offset - pending.len();
+        pending.extend_from_slice(bytes);
+        let mut checked = 0;
+        while checked < pending.len() {
+            match std::str::from_utf8(&pending[checked..]) {
+                Ok(_) => return,
+                Err(err) => {
+                    let valid = checked + err.valid_up_to();
+                    match err.error_len() {
+                        Some(len) => {
+                            self.violation(start + valid, "invalid UTF-8");
+                            checked = valid + len;
+                        }
+                        None => {
+                            self.partial_char = pending[valid..].to_vec();
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_byte(&mut self, byte: u8) {
+        let Some(markup) = &mut self.markup else {
+            if byte == b'<' {
+                self.markup = Some(Markup {
+                    offset: self.offset,
+                    bytes: vec![byte],
+                    quote: None,
+                });
+            } else if !byte.is_ascii_whitespace() {
+                self.has_content = true;
+            }
+            return;
+        };
+        if markup.bytes.len() == 1 && !starts_markup(byte) {
+            // A `<` in text, like `a < b`.
+            self.markup = None;
+            self.has_content = true;
+            return self.check_byte(byte);
+        }
+        if markup.push(byte) {
+            let markup = self.markup.take().expect("the markup was just completed");
+            self.check_markup(&markup);
+            self.has_content = true;
+        }
+    }
+
+    fn check_markup(&mut self, markup: &Markup) {
+        let bytes = &markup.bytes;
+        if bytes.starts_with(Markup::COMMENT) {
+            self.check_marker(markup);
+            return;
+        }
+        if bytes.starts_with(b"<?xml") && !self.has_content {
+            self.elements = Some(Vec::new());
+        }
+        if bytes.starts_with(b"<!") || bytes.starts_with(b"<?") {
+            return;
+        }
+
+        let name = markup.tag_name();
+        if name.starts_with(&self.prefix) {
+            let tag = String::from_utf8_lossy(name);
+            self.violation(markup.offset, format!("unprocessed `{tag}` tag"));
+        }
+        if bytes.windows(12).any(|window| window == b"<!--esi-frag") {
+            self.violation(markup.offset, "fragment marker inside a tag");
+        }
+        let Some(elements) = &mut self.elements else {
+            return;
+        };
+        let problem = if bytes.starts_with(b"</") {
+            match elements.pop() {
+                Some((open, _)) if open == name => None,
+                Some((open, _)) => Some(format!(
+                    "`{}` closes `{}`",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(&open)
+                )),
+                None => Some(format!(
+                    "`{}` closes nothing",
+                    String::from_utf8_lossy(name)
+                )),
+            }
+        } else {
+            if !bytes.ends_with(b"/>") {
+                elements.push((name.to_vec(), markup.offset));
+            }
+            None
+        };
+        if let Some(problem) = problem {
+            self.violation(markup.offset, problem);
+        }
+    }
+
+    fn check_marker(&mut self, markup: &Markup) {
+        match parse_marker(&markup.bytes) {
+            Some(("start", slot)) => self.markers.push((slot, markup.offset)),
+            Some(("end", slot)) => match self.markers.pop() {
+                Some((open, _)) if open == slot => {}
+                Some((open, _)) => self.violation(
+                    markup.offset,
+                    format!("fragment marker for slot {slot} ends inside slot {open}"),
+                ),
+                None => self.violation(
+                    markup.offset,
+                    format!("fragment marker for slot {slot} ends without starting"),
+                ),
+            },
+            _ => {}
+        }
+    }
+}
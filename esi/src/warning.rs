@@ -0,0 +1,355 @@
+use crate::timings::CallbackTimer;
+use crate::{CallbackKind, CallbackTimings, ComplianceLevel, ExecutionError, Result};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A recoverable issue with an ESI document, which was worked around rather than failing.
+///
+/// Every warning carries the byte position in the source document of the tag it's about. Issues
+/// found while processing the content of an `esi:try` block, rather than while parsing it, are
+/// reported at the start of the block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// Markup that couldn't be parsed, which is skipped.
+    MalformedMarkup { position: usize, message: String },
+    /// A tag in the ESI namespace that isn't supported, which is left out of the output along
+    /// with its closing tag, keeping its content.
+    UnknownTag { position: usize, tag: String },
+    /// An attribute with a value that can't be used, which is ignored.
+    InvalidAttribute {
+        position: usize,
+        tag: String,
+        attribute: String,
+        value: String,
+    },
+    /// An attribute repeated on a tag. The first value is used, and the others are ignored.
+    DuplicateAttribute {
+        position: usize,
+        tag: String,
+        attribute: String,
+    },
+    /// A supported tag that has no effect where it appears, which is dropped.
+    IgnoredTag {
+        position: usize,
+        tag: String,
+        reason: String,
+    },
+    /// An expression that couldn't be evaluated, which is left as it is.
+    ExpressionError { position: usize, expression: String },
+    /// A fragment URL that had to be rewritten before it could be requested.
+    UrlNormalized {
+        position: usize,
+        url: String,
+        normalized: String,
+    },
+    /// A feature outside the ESI 1.0 specification, with [`crate::ComplianceLevel::Warn`]. The
+    /// `code` is one of those listed for [`crate::ComplianceLevel`], and `detail` names the
+    /// tag, attribute, variable or function.
+    SpecDeviation {
+        position: usize,
+        code: &'static str,
+        detail: String,
+    },
+    /// A configuration option that has no effect on the way the document is processed. It's
+    /// reported at the start of the document.
+    IgnoredOption {
+        position: usize,
+        option: &'static str,
+        reason: String,
+    },
+    /// A fragment request that the dispatcher failed without sending it, with
+    /// [`crate::PendingFragmentContent::FailFast`]. The URL is redacted like
+    /// [`crate::IncludeReport::url`].
+    FragmentFailedFast {
+        position: usize,
+        url: String,
+        reason: String,
+    },
+    /// A problem with the composed output, found with
+    /// [`crate::Configuration::debug_validate_output`]. Unlike other warnings, it's about a byte
+    /// offset in the output rather than a position in the source document.
+    InvalidOutput { offset: usize, problem: String },
+    /// A feature that has to come before the client response starts, but came after it, and
+    /// was ignored: an `esi:response` tag after content, as the status and headers are sent
+    /// with the first byte of the body, or an option that depends on the whole output, such as
+    /// [`crate::Configuration::compute_etag`], when the output is streamed. Options are reported
+    /// at the start of the document. With [`crate::Configuration::strict_prestream`], processing
+    /// fails with [`crate::ExecutionError::TooLate`] instead.
+    TooLate { position: usize, feature: String },
+    /// A single call of a callback that took longer than
+    /// [`crate::Configuration::callback_overrun_threshold`]. Callbacks about a fragment, such as
+    /// the dispatcher and the response processor, are reported at the position of its include.
+    SlowCallback {
+        position: usize,
+        callback: CallbackKind,
+        elapsed: Duration,
+    },
+}
+
+impl Warning {
+    /// Returns the byte position in the source document that the warning is about, or in the
+    /// output for [`Self::InvalidOutput`].
+    pub fn position(&self) -> usize {
+        match self {
+            Self::MalformedMarkup { position, .. }
+            | Self::UnknownTag { position, .. }
+            | Self::InvalidAttribute { position, .. }
+            | Self::DuplicateAttribute { position, .. }
+            | Self::IgnoredTag { position, .. }
+            | Self::ExpressionError { position, .. }
+            | Self::UrlNormalized { position, .. }
+            | Self::SpecDeviation { position, .. }
+            | Self::IgnoredOption { position, .. }
+            | Self::FragmentFailedFast { position, .. }
+            | Self::TooLate { position, .. }
+            | Self::SlowCallback { position, .. }
+            | Self::InvalidOutput {
+                offset: position, ..
+            } => *position,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedMarkup { position, message } => {
+                write!(f, "malformed markup at {position}: {message}")
+            }
+            Self::UnknownTag { position, tag } => {
+                write!(f, "unknown tag `{tag}` at {position}")
+            }
+            Self::InvalidAttribute {
+                position,
+                tag,
+                attribute,
+                value,
+            } => write!(
+                f,
+                "invalid `{attribute}` attribute `{value}` on `{tag}` at {position}"
+            ),
+            Self::DuplicateAttribute {
+                position,
+                tag,
+                attribute,
+            } => write!(
+                f,
+                "duplicate `{attribute}` attribute on `{tag}` at {position}, using the first"
+            ),
+            Self::IgnoredTag {
+                position,
+                tag,
+                reason,
+            } => write!(f, "ignored `{tag}` at {position}: {reason}"),
+            Self::ExpressionError {
+                position,
+                expression,
+            } => write!(f, "invalid expression `{expression}` at {position}"),
+            Self::UrlNormalized {
+                position,
+                url,
+                normalized,
+            } => write!(
+                f,
+                "URL `{url}` at {position} was rewritten to `{normalized}`"
+            ),
+            Self::SpecDeviation {
+                position,
+                code,
+                detail,
+            } => write!(f, "`{detail}` at {position} isn't part of ESI 1.0 ({code})"),
+            Self::IgnoredOption {
+                position,
+                option,
+                reason,
+            } => write!(f, "ignored option `{option}` at {position}: {reason}"),
+            Self::FragmentFailedFast {
+                position,
+                url,
+                reason,
+            } => write!(f, "fragment `{url}` at {position} failed fast: {reason}"),
+            Self::InvalidOutput { offset, problem } => {
+                write!(f, "invalid output at byte {offset}: {problem}")
+            }
+            Self::TooLate { position, feature } => write!(
+                f,
+                "`{feature}` at {position} came after the response started, and was ignored"
+            ),
+            Self::SlowCallback {
+                position,
+                callback,
+                elapsed,
+            } => write!(
+                f,
+                "`{}` callback for {position} took {}ms",
+                callback.name(),
+                elapsed.as_millis()
+            ),
+        }
+    }
+}
+
+type WarningHandler = dyn Fn(&Warning);
+
+/// The warnings raised while processing a document, shared by the parser and the processor.
+#[derive(Clone, Default)]
+pub(crate) struct Warnings(Rc<WarningsState>);
+
+#[derive(Default)]
+struct WarningsState {
+    warnings: RefCell<Vec<Warning>>,
+    // The number of warnings that didn't fit under the cap
+    dropped: Cell<usize>,
+    max_warnings: usize,
+    // The position of the tag currently being processed
+    position: Cell<usize>,
+    on_warning: Option<Rc<WarningHandler>>,
+    // Whether deviations from ESI 1.0 are reported
+    compliance: ComplianceLevel,
+    // The first deviation found with `ComplianceLevel::Enforce`, or other error found where
+    // failing isn't possible, until it's raised
+    violation: RefCell<Option<ExecutionError>>,
+    // Where the time spent in callbacks is added up
+    callbacks: CallbackTimer,
+}
+
+impl fmt::Debug for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Warnings")
+            .field("warnings", &self.0.warnings.borrow().len())
+            .field("dropped", &self.0.dropped.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Warnings {
+    pub fn new(
+        max_warnings: usize,
+        on_warning: Option<Rc<WarningHandler>>,
+        compliance: ComplianceLevel,
+        callbacks: CallbackTimer,
+    ) -> Self {
+        Self(Rc::new(WarningsState {
+            max_warnings,
+            on_warning,
+            compliance,
+            callbacks,
+            ..WarningsState::default()
+        }))
+    }
+
+    /// Records a warning. The callback sees every warning, even those over the cap.
+    pub fn push(&self, warning: Warning) {
+        log::warn!("{warning}");
+        let overrun = self.0.on_warning.as_ref().and_then(|on_warning| {
+            let ((), overrun) = self
+                .0
+                .callbacks
+                .time(CallbackKind::WarningHandler, || on_warning(&warning));
+            overrun
+        });
+        self.record(warning);
+        // The callback isn't called with its own overruns, so that a slow one doesn't recurse.
+        if let Some(elapsed) = overrun {
+            let warning = Warning::SlowCallback {
+                position: self.position(),
+                callback: CallbackKind::WarningHandler,
+                elapsed,
+            };
+            log::warn!("{warning}");
+            self.record(warning);
+        }
+    }
+
+    fn record(&self, warning: Warning) {
+        let mut warnings = self.0.warnings.borrow_mut();
+        if warnings.len() < self.0.max_warnings {
+            warnings.push(warning);
+        } else {
+            self.0.dropped.set(self.0.dropped.get() + 1);
+        }
+    }
+
+    /// Calls `call`, a callback of the given kind, timing it. A call that takes longer than the
+    /// threshold raises a [`Warning::SlowCallback`] at `position`.
+    pub fn time_callback<T>(
+        &self,
+        kind: CallbackKind,
+        position: usize,
+        call: impl FnOnce() -> T,
+    ) -> T {
+        let (result, overrun) = self.0.callbacks.time(kind, call);
+        if let Some(elapsed) = overrun {
+            self.push(Warning::SlowCallback {
+                position,
+                callback: kind,
+                elapsed,
+            });
+        }
+        result
+    }
+
+    /// Returns the time spent in callbacks so far.
+    pub fn callback_timings(&self) -> CallbackTimings {
+        self.0.callbacks.timings()
+    }
+
+    /// Returns the position of the tag currently being processed.
+    pub fn position(&self) -> usize {
+        self.0.position.get()
+    }
+
+    pub fn set_position(&self, position: usize) {
+        self.0.position.set(position);
+    }
+
+    /// Reports the use of a feature outside ESI 1.0 at `position`, depending on the compliance
+    /// level. When deviations are enforced, the first one is kept to be raised by
+    /// [`Self::check_compliance`], so that it can be found where failing isn't possible.
+    pub fn deviation(&self, position: usize, code: &'static str, detail: impl Into<String>) {
+        match self.0.compliance {
+            ComplianceLevel::Off => {}
+            ComplianceLevel::Warn => self.push(Warning::SpecDeviation {
+                position,
+                code,
+                detail: detail.into(),
+            }),
+            ComplianceLevel::Enforce => {
+                let mut violation = self.0.violation.borrow_mut();
+                if violation.is_none() {
+                    *violation = Some(ExecutionError::SpecDeviation(
+                        code.to_string(),
+                        detail.into(),
+                        position,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Keeps `err` to be raised by [`Self::check_compliance`] like an enforced deviation, for
+    /// errors found where failing isn't possible.
+    pub fn fail_later(&self, err: ExecutionError) {
+        let mut violation = self.0.violation.borrow_mut();
+        if violation.is_none() {
+            *violation = Some(err);
+        }
+    }
+
+    /// Fails with the first deviation found since the last check, if deviations are enforced, or
+    /// the first error kept with [`Self::fail_later`].
+    pub fn check_compliance(&self) -> Result<()> {
+        match self.0.violation.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the recorded warnings and the number that were dropped.
+    pub fn take(&self) -> (Vec<Warning>, usize) {
+        (self.0.warnings.take(), self.0.dropped.replace(0))
+    }
+}
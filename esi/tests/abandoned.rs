@@ -0,0 +1,77 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+use std::time::SystemTime;
+
+// The first include fails without an `alt` or `onerror="continue"`, which fails processing when
+// the queue is first polled, before `<p>` is written. By then, every include has been dispatched
+// but `/c`, which waits for its `esi:except` arm to be reached.
+const TEMPLATE: &str = concat!(
+    r#"<esi:include src="/fail"/><esi:include src="/a"/>"#,
+    r#"<esi:try><esi:attempt><esi:include src="/b?token=secret"/></esi:attempt>"#,
+    r#"<esi:except><esi:include src="/c"/></esi:except></esi:try>"#,
+    r#"<esi:group name="g"><esi:include src="/d"/><esi:include src="/e"/></esi:group>"#,
+    "<p>after</p>",
+);
+
+fn process(configuration: Configuration) -> Processor {
+    Processor::new(None, configuration)
+}
+
+fn fail(processor: Processor) -> ExecutionError {
+    processor
+        .process_document(
+            Reader::from_str(TEMPLATE),
+            &mut Writer::new(Vec::new()),
+            Some(&|req| {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    match req.get_path() {
+                        "/fail" => Response::from_status(500),
+                        path => Response::from_body(path.to_string()),
+                    },
+                ))
+            }),
+            None,
+        )
+        .expect_err("the first include fails processing")
+}
+
+#[test]
+fn fragments_queued_when_processing_fails_are_reported() {
+    let started = SystemTime::now();
+    let processor = process(Configuration::default());
+    let outcome = processor.outcome();
+
+    let err = fail(processor);
+
+    assert!(
+        matches!(err, ExecutionError::FragmentFailed { url, status: 500, .. } if url.ends_with("/fail"))
+    );
+    let abandoned = outcome.abandoned();
+    let urls: Vec<_> = abandoned.iter().map(|a| a.url.as_str()).collect();
+    assert_eq!(
+        urls,
+        [
+            "http://localhost/a",
+            "http://localhost/b",
+            "http://localhost/d",
+            "http://localhost/e",
+        ]
+    );
+    for fragment in &abandoned {
+        assert!(!fragment.alt);
+        assert_eq!(fragment.status, None);
+        assert!(fragment.dispatched_at >= started - std::time::Duration::from_millis(1));
+        assert!(fragment.dispatched_at <= SystemTime::now());
+    }
+}
+
+#[test]
+fn abandoned_fragments_can_be_polled_for_their_status() {
+    let processor = process(Configuration::default().with_poll_abandoned(true));
+    let outcome = processor.outcome();
+
+    fail(processor);
+
+    let statuses: Vec<_> = outcome.abandoned().iter().map(|a| a.status).collect();
+    assert_eq!(statuses, [Some(200); 4]);
+}
@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, StatusCodes, Writer,
+};
+use fastly::Response;
+
+#[test]
+fn status_codes_parse_codes_and_ranges() -> Result<(), ExecutionError> {
+    let codes: StatusCodes = "404, 204-206,302".parse()?;
+
+    for status in [204, 205, 206, 302, 404] {
+        assert!(codes.contains(status), "{status} should be in {codes}");
+    }
+    for status in [200, 203, 207, 301, 403, 405] {
+        assert!(!codes.contains(status), "{status} shouldn't be in {codes}");
+    }
+    assert_eq!(codes.to_string(), "404,204-206,302");
+
+    Ok(())
+}
+
+#[test]
+fn status_codes_reject_invalid_lists() {
+    for invalid in [
+        "",
+        "404,",
+        "abc",
+        "99",
+        "1000",
+        "206-204",
+        "200-",
+        "-200",
+        "200-300-400",
+        "4O4",
+    ] {
+        assert!(
+            matches!(
+                invalid.parse::<StatusCodes>(),
+                Err(ExecutionError::InvalidStatusCodes(value)) if value == invalid
+            ),
+            "`{invalid}` should be rejected"
+        );
+    }
+}
+
+// Processes `template`, returning the output and the paths that were requested.
+fn process(
+    configuration: Configuration,
+    template: &str,
+) -> Result<(String, Vec<String>), ExecutionError> {
+    let requested = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&requested);
+
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let path = req.get_path().to_string();
+            seen.borrow_mut().push(path.clone());
+            let response = match path.as_str() {
+                "/not-found" => Response::from_status(404).with_body("not found page"),
+                "/redirect" => Response::from_status(302).with_body("redirect page"),
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    Ok((
+        String::from_utf8(writer.into_inner()).unwrap(),
+        requested.take(),
+    ))
+}
+
+#[test]
+fn accepted_status_does_not_use_alt() -> Result<(), ExecutionError> {
+    let (output, requested) = process(
+        Configuration::default(),
+        r#"<esi:include src="/not-found" alt="/alt" accept-status="404"/><esi:include src="/a"/>"#,
+    )?;
+
+    assert_eq!(output, "not found page[/a]");
+    assert_eq!(requested, ["/not-found", "/a"]);
+
+    Ok(())
+}
+
+#[test]
+fn accepted_status_body_can_be_left_out() -> Result<(), ExecutionError> {
+    let (output, requested) = process(
+        Configuration::default().with_empty_body_status("302-304,404".parse()?),
+        r#"<esi:try><esi:attempt><esi:include src="/not-found" alt="/alt" accept-status="404"/><esi:include src="/redirect" accept-status="300-399"/></esi:attempt><esi:except>failed</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "");
+    assert_eq!(requested, ["/not-found", "/redirect"]);
+
+    Ok(())
+}
+
+#[test]
+fn configured_accept_status_applies_to_includes_without_the_attribute() -> Result<(), ExecutionError>
+{
+    let configuration = Configuration::default().with_accept_status("404".parse()?);
+
+    let (output, _) = process(
+        configuration.clone(),
+        r#"<esi:include src="/not-found" alt="/alt"/>"#,
+    )?;
+    assert_eq!(output, "not found page");
+
+    // The attribute replaces the configured statuses.
+    let (output, requested) = process(
+        configuration,
+        r#"<esi:include src="/not-found" alt="/alt" accept-status="410"/>"#,
+    )?;
+    assert_eq!(output, "[/alt]");
+    assert_eq!(requested, ["/not-found", "/alt"]);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_accept_status_fails() {
+    let res = process(
+        Configuration::default(),
+        r#"<esi:include src="/a" accept-status="40x"/>"#,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidAttributeValue(tag, attr, value))
+            if tag == "esi:include" && attr == "accept-status" && value == "40x"
+    ));
+}
@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::attributes::AddHeader;
+use esi::{
+    parse_tags, Configuration, Event, ExecutionError, PendingFragmentContent, Processor, Reader,
+    Tag, Writer,
+};
+use fastly::{Request, Response};
+
+// Processes `template` and returns a line for each dispatched request, with its path and the
+// values of its `x-a` and `x-b` headers. Requests for `/fail` fail.
+fn dispatched_requests(template: &str) -> Result<Vec<String>, ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page?lang=fr")
+                .with_header("cookie", "auth=secret; uid=1")
+                .with_header("x-a", "original"),
+        ),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let values = |name| {
+                req.get_header_all_str(name)
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            dispatched.borrow_mut().push(format!(
+                "{} x-a={} x-b={}",
+                req.get_path(),
+                values("x-a"),
+                values("x-b")
+            ));
+            Ok(match req.get_path() {
+                "/fail" => Response::from_status(500).into(),
+                _ => PendingFragmentContent::NoContent,
+            })
+        }),
+        None,
+    )?;
+
+    Ok(requests.take())
+}
+
+#[test]
+fn headers_are_added_to_their_include_only() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(
+            r#"<esi:include src="/a" add-header="X-B: 1"/><esi:include src="/b"/>"#
+        )?,
+        ["/a x-a=original x-b=1", "/b x-a=original x-b="]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn header_values_are_evaluated() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(
+            r#"<esi:include src="/a" add-header="X-B: token=$(HTTP_COOKIE{auth}); lang=$(QUERY_STRING{lang})"/>"#
+        )?,
+        ["/a x-a=original x-b=token=secret; lang=fr"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lists_and_repeated_attributes_add_every_header() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(
+            r#"<esi:include src="/a" add-header="X-A: 1|X-B: $(HTTP_COOKIE{missing}|none)" add-header="x-b: 2"/>"#
+        )?,
+        ["/a x-a=original,1 x-b=none,2"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn headers_are_added_to_the_alt_request() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(r#"<esi:include src="/fail" alt="/b" add-header="X-B: 1"/>"#)?,
+        ["/fail x-a=original x-b=1", "/b x-a=original x-b=1"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn invalid_header_name_is_an_error() {
+    let res = dispatched_requests(r#"<esi:include src="/a" add-header="X B: 1"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidRequestHeader(name)) if name == "X B"
+    ));
+}
+
+#[test]
+fn invalid_header_value_is_an_error() {
+    let res = dispatched_requests(r#"<esi:include src="/a" add-header="X-B: a&#10;b"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidRequestHeader(name)) if name == "X-B"
+    ));
+}
+
+#[test]
+fn header_without_a_value_is_invalid() {
+    let res = dispatched_requests(r#"<esi:include src="/a" add-header="X-B"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidAttributeValue(_, attribute, _)) if attribute == "add-header"
+    ));
+}
+
+#[test]
+fn headers_are_parsed_as_written() -> Result<(), ExecutionError> {
+    let mut parsed = None;
+    parse_tags(
+        "esi",
+        &mut Reader::from_str(
+            r#"<esi:include src="/a" add-header="X-A: $(HTTP_COOKIE{a}|x) | X-B:"/>"#,
+        ),
+        &mut |event| {
+            if let Event::ESI(Tag::Include(include)) = event {
+                parsed = include.get::<AddHeader>().cloned();
+            }
+            Ok(())
+        },
+    )?;
+
+    assert_eq!(
+        parsed,
+        Some(AddHeader(vec![
+            ("X-A".to_string(), "$(HTTP_COOKIE{a}|x)".to_string()),
+            ("X-B".to_string(), String::new()),
+        ]))
+    );
+
+    Ok(())
+}
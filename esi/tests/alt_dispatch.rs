@@ -0,0 +1,172 @@
+use esi::{
+    Configuration, ExecutionError, IncludeOutcome, PendingFragmentContent, ProcessingReport,
+    Processor, Reader, Writer,
+};
+use fastly::Response;
+
+// Processes the template with a dispatcher that can't send requests to primary.example.com, as
+// if its backend wasn't configured, nor to any host when `alt_fails` is set.
+fn process(template: &str, alt_fails: bool) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            if req.get_url().host_str() == Some("primary.example.com") || alt_fails {
+                return Err(ExecutionError::InvalidRequestUrl(
+                    req.get_url_str().to_string(),
+                ));
+            }
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_url_str())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn alt_is_requested_when_the_primary_fails_to_dispatch() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<p>a</p><esi:include src="http://primary.example.com/x" alt="http://alt.example.com/x"/><p>b</p>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "<p>a</p>[http://alt.example.com/x]<p>b</p>");
+    assert_eq!(report.includes.len(), 1);
+    assert_eq!(report.includes[0].outcome, IncludeOutcome::Alt);
+
+    Ok(())
+}
+
+#[test]
+fn failed_dispatch_without_alt_is_an_error() {
+    let res = process(
+        r#"<esi:include src="http://primary.example.com/x"/>"#,
+        false,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidRequestUrl(url)) if url == "http://primary.example.com/x"
+    ));
+}
+
+#[test]
+fn failed_dispatch_of_both_is_an_error() {
+    let res = process(
+        r#"<esi:include src="http://primary.example.com/x" alt="http://alt.example.com/x"/>"#,
+        true,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidRequestUrl(url)) if url == "http://alt.example.com/x"
+    ));
+}
+
+#[test]
+fn failed_dispatch_of_both_continues_on_error() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<p>a</p><esi:include src="http://primary.example.com/x" alt="http://alt.example.com/x" onerror="continue"/><p>b</p>"#,
+        true,
+    )?;
+
+    assert_eq!(output, "<p>a</p><p>b</p>");
+    assert_eq!(report.includes.len(), 1);
+    assert_eq!(report.includes[0].outcome, IncludeOutcome::Failed);
+
+    Ok(())
+}
+
+#[test]
+fn failed_dispatch_without_alt_continues_on_error() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<p>a</p><esi:include src="http://primary.example.com/x" onerror="continue"/><p>b</p>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "<p>a</p><p>b</p>");
+    assert_eq!(report.includes.len(), 1);
+    assert_eq!(report.includes[0].outcome, IncludeOutcome::Failed);
+
+    Ok(())
+}
+
+#[test]
+fn failed_dispatch_in_an_attempt_continues_on_error() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:try><esi:attempt><p>a</p><esi:include src="http://primary.example.com/x" onerror="continue"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "<p>a</p>");
+
+    Ok(())
+}
+
+#[test]
+fn invalid_src_is_an_error() {
+    let res = process(r#"<esi:include src="http://[oops/x"/>"#, false);
+
+    assert!(matches!(res, Err(ExecutionError::InvalidRequestUrl(_))));
+}
+
+#[test]
+fn invalid_src_continues_on_error() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<p>a</p><esi:include src="http://[oops/x" onerror="continue"/><esi:try><esi:attempt><esi:include src="http://[oops/y" onerror="continue"/>c</esi:attempt></esi:try><p>b</p>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "<p>a</p>c<p>b</p>");
+
+    Ok(())
+}
+
+#[test]
+fn invalid_src_falls_back_to_alt() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:include src="http://[oops/x" alt="http://alt.example.com/x"/>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "[http://alt.example.com/x]");
+    assert_eq!(report.includes[0].outcome, IncludeOutcome::Alt);
+
+    Ok(())
+}
+
+#[test]
+fn src_without_a_host_is_an_error() {
+    for src in ["mailto:x", "urn:x", "file:///x"] {
+        let res = process(&format!(r#"<esi:include src="{src}"/>"#), false);
+
+        assert!(
+            matches!(&res, Err(ExecutionError::InvalidRequestUrl(url)) if url == src),
+            "{src}: {res:?}"
+        );
+    }
+}
+
+#[test]
+fn src_without_a_host_falls_back_to_alt() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:include src="mailto:x" alt="http://alt.example.com/x"/>"#,
+        false,
+    )?;
+
+    assert_eq!(output, "[http://alt.example.com/x]");
+    let outcomes: Vec<_> = report
+        .includes
+        .iter()
+        .map(|include| &include.outcome)
+        .collect();
+    assert_eq!(outcomes, [&IncludeOutcome::Failed, &IncludeOutcome::Alt]);
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::{Request, Response};
+
+fn process(input: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page?lang=fr&id=42")
+                .with_header("accept-language", "en-GB, fr;q=0.8")
+                .with_header("cookie", "theme=dark; session=abc"),
+        ),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn templated_attributes_are_evaluated() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<html esi:attr-lang="$(QUERY_STRING{lang})" class="page" esi:attr-data-theme="theme-$(HTTP_COOKIE{theme})"><p>hi</p></html>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"<html lang="fr" class="page" data-theme="theme-dark"><p>hi</p></html>"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn empty_templated_attributes_are_omitted() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<img src="/a.png" esi:attr-alt="$(QUERY_STRING{missing})" esi:attr-title="$(HTTP_X_MISSING|'none')"/>"#,
+    )?;
+
+    assert_eq!(output, r#"<img src="/a.png" title="none"/>"#);
+
+    Ok(())
+}
+
+#[test]
+fn templated_attribute_values_are_escaped() -> Result<(), ExecutionError> {
+    let output = process(r#"<div esi:attr-data-host="&quot;$(HTTP_HOST)&quot;"></div>"#)?;
+
+    assert_eq!(
+        output,
+        r#"<div data-host="&quot;www.example.com&quot;"></div>"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn templated_attributes_are_evaluated_inside_try() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:include src="/first"/><esi:try><esi:attempt><p esi:attr-id="item-$(QUERY_STRING{id})"><esi:include src="/attempt"/></p></esi:attempt><esi:except><p esi:attr-lang="$(QUERY_STRING{lang})"/></esi:except></esi:try><span esi:attr-data-en="$(HTTP_ACCEPT_LANGUAGE{en-gb})"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"[/first]<p id="item-42">[/attempt]</p><span data-en="true"/>"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn elements_without_templated_attributes_are_unchanged() -> Result<(), ExecutionError> {
+    let input =
+        r#"<a href="/x?a=1&amp;b=2" data-attr='single' esi-attr-x="$(HTTP_HOST)">$(HTTP_HOST)</a>"#;
+
+    let output = process(input)?;
+
+    assert_eq!(output, input);
+
+    Ok(())
+}
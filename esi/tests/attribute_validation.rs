@@ -0,0 +1,119 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::Response;
+
+fn process_with(
+    configuration: Configuration,
+    input: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+fn process(input: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    process_with(Configuration::default(), input)
+}
+
+#[test]
+fn repeated_attributes_use_the_first_with_a_warning() -> Result<(), ExecutionError> {
+    let input = r#"<p>x</p><esi:include src="/a" alt="/b" src="/c" onerror="continue" alt="/d" onerror="fail"/>"#;
+
+    let (output, report) = process(input)?;
+
+    assert_eq!(output, "<p>x</p>[/a]");
+    let duplicate = |attribute: &str| Warning::DuplicateAttribute {
+        position: input.find("<esi:include").unwrap(),
+        tag: "esi:include".to_string(),
+        attribute: attribute.to_string(),
+    };
+    assert_eq!(
+        report.warnings,
+        [duplicate("src"), duplicate("alt"), duplicate("onerror")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn empty_or_valueless_urls_are_rejected_where_they_are() {
+    let cases = [
+        (r#"<p>x</p><esi:include src=""/>"#, "src"),
+        (r#"<p>x</p><esi:include src="  "/>"#, "src"),
+        (r#"<p>x</p><esi:include src/>"#, "src"),
+        (r#"<p>x</p><esi:include alt src="/a"/>"#, "alt"),
+        (r#"<p>x</p><esi:include src="/a" alt=''/>"#, "alt"),
+        (
+            r#"<p>x</p><esi:try><esi:attempt><esi:include alt src="/a"/></esi:attempt></esi:try>"#,
+            "alt",
+        ),
+    ];
+
+    for (input, attribute) in cases {
+        let result = process(input);
+
+        assert!(
+            matches!(
+                &result,
+                Err(ExecutionError::EmptyAttribute(tag, name, position))
+                    if tag == "esi:include"
+                        && name == attribute
+                        && *position == input.find("<esi:include").unwrap()
+            ),
+            "{input}: {result:?}"
+        );
+    }
+}
+
+#[test]
+fn attribute_values_over_the_limit_are_rejected() -> Result<(), ExecutionError> {
+    let long = format!("/{}", "a".repeat(16 * 1024));
+    let cases = [
+        (format!(r#"<esi:include src="{long}"/>"#), "src"),
+        (format!(r#"<esi:include src="/a" alt="{long}"/>"#), "alt"),
+        (format!(r#"<esi:include srcset="/a {long}"/>"#), "srcset"),
+        (
+            format!(r#"<esi:include src="/a" esi:note="{long}"/>"#),
+            "note",
+        ),
+    ];
+
+    for (input, attribute) in &cases {
+        let result = process(input);
+
+        assert!(
+            matches!(
+                &result,
+                Err(ExecutionError::AttributeTooLarge(tag, name, 0, 16384))
+                    if tag == "esi:include" && name == attribute
+            ),
+            "{attribute}: {:?}",
+            result.map(|(output, _)| output.len())
+        );
+    }
+
+    // Attributes that aren't read from the tag don't count, and the limit can be raised.
+    let (output, _) = process(&format!(r#"<esi:include src="/a" data-x="{long}"/>"#))?;
+    assert_eq!(output, "[/a]");
+    let (output, _) = process_with(
+        Configuration::default().with_max_attribute_length(32 * 1024),
+        &cases[0].0,
+    )?;
+    assert_eq!(output, format!("[{long}]"));
+
+    Ok(())
+}
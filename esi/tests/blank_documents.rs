@@ -0,0 +1,158 @@
+use esi::{
+    may_contain_esi, Configuration, ExecutionError, PendingFragmentContent, ProcessingReport,
+    Processor, Reader, Warning, Writer,
+};
+use fastly::http::header;
+use fastly::Response;
+use std::cell::Cell;
+use std::rc::Rc;
+
+const BLANK_DOCUMENTS: [&str; 2] = ["", " \n\t  \r\n"];
+
+// Configurations that would otherwise raise warnings or fail for the entry points tested
+fn configurations() -> [Configuration; 3] {
+    [
+        Configuration::default(),
+        Configuration::preset_json_api(),
+        Configuration::default()
+            .with_compute_etag(true)
+            .with_strict_prestream(true),
+    ]
+}
+
+// A processor that counts the calls to each of its callbacks and to the dispatcher
+fn processor(configuration: Configuration, calls: &Rc<Cell<usize>>) -> Processor {
+    let (on_warning, on_phase) = (calls.clone(), calls.clone());
+    Processor::new(None, configuration)
+        .on_warning(move |_: &Warning| on_warning.set(on_warning.get() + 1))
+        .on_phase(move |_| on_phase.set(on_phase.get() + 1))
+}
+
+fn dispatch(
+    calls: &Rc<Cell<usize>>,
+) -> impl Fn(fastly::Request) -> esi::Result<PendingFragmentContent> {
+    let calls = calls.clone();
+    move |_| {
+        calls.set(calls.get() + 1);
+        Ok(PendingFragmentContent::NoContent)
+    }
+}
+
+fn assert_blank_report(report: &ProcessingReport, document: &str) {
+    assert_eq!(report.bytes_written, document.len());
+    assert!(report.includes.is_empty());
+    assert!(report.warnings.is_empty());
+    assert!(!report.truncated);
+}
+
+#[test]
+fn blank_documents_are_copied_without_callbacks() -> Result<(), ExecutionError> {
+    for configuration in configurations() {
+        for document in BLANK_DOCUMENTS {
+            let calls = Rc::new(Cell::new(0));
+            let mut writer = Writer::new(Vec::new());
+            let report = processor(configuration.clone(), &calls).process_document(
+                Reader::from_str(document),
+                &mut writer,
+                Some(&dispatch(&calls)),
+                Some(&|_, resp| Ok(resp)),
+            )?;
+
+            assert_eq!(writer.into_inner(), document.as_bytes());
+            assert_blank_report(&report, document);
+            assert_eq!(report.etag, None);
+            assert_eq!(calls.get(), 0);
+
+            let calls = Rc::new(Cell::new(0));
+            let mut writer = Writer::new(Vec::new());
+            let report = processor(configuration.clone(), &calls).process_with_validation(
+                Reader::from_str(document),
+                &mut writer,
+                Some(&dispatch(&calls)),
+                None,
+            )?;
+
+            assert_eq!(writer.into_inner(), document.as_bytes());
+            assert_blank_report(&report, document);
+            assert_eq!(report.etag.is_some(), configuration.compute_etag);
+            assert_eq!(calls.get(), 0);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn blank_documents_are_returned_as_responses() -> Result<(), ExecutionError> {
+    for configuration in configurations() {
+        for document in BLANK_DOCUMENTS {
+            let calls = Rc::new(Cell::new(0));
+            let (mut resp, report) = processor(configuration.clone(), &calls).process_to_response(
+                &mut Response::from_body(document)
+                    .with_header(header::CONTENT_LENGTH, document.len().to_string()),
+                None,
+                Some(&dispatch(&calls)),
+                None,
+            )?;
+
+            assert_eq!(resp.get_status(), 200);
+            let content_length = document.is_empty().then_some("0");
+            assert_eq!(resp.get_header_str(header::CONTENT_LENGTH), content_length);
+            assert_eq!(resp.take_body_str(), document);
+            assert_blank_report(&report, document);
+            assert_eq!(resp.get_header_str(header::ETAG), report.etag.as_deref());
+            assert_eq!(report.etag.is_some(), configuration.compute_etag);
+            assert_eq!(calls.get(), 0);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn blank_documents_have_no_esi() {
+    for document in BLANK_DOCUMENTS {
+        assert!(!may_contain_esi(document.as_bytes(), "esi"));
+    }
+}
+
+#[test]
+fn leading_whitespace_is_kept_in_front_of_content() -> Result<(), ExecutionError> {
+    let document = "\n  <p>x</p><esi:include src=\"/a\"/>  ";
+    let mut writer = Writer::new(Vec::new());
+    let report = Processor::new(None, Configuration::default()).process_document(
+        Reader::from_str(document),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    assert_eq!(
+        String::from_utf8(writer.into_inner()).unwrap(),
+        "\n  <p>x</p>[/a]  "
+    );
+    assert_eq!(report.includes.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn positions_count_leading_whitespace() -> Result<(), ExecutionError> {
+    let (_, report) = Processor::new(None, Configuration::default()).process_to_response(
+        &mut Response::from_body("  <p>x</p><esi:response status=\"404\"/>"),
+        None,
+        None,
+        None,
+    )?;
+
+    assert!(matches!(
+        report.warnings.as_slice(),
+        [Warning::TooLate { position: 10, .. }]
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Writer,
+};
+use fastly::Response;
+
+// The body of `/slow`, which is only written once the document it's in has been parsed
+const FRAGMENT_SIZE: usize = 500;
+
+fn process(template: &str) -> Result<ProcessingReport, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            let body = match req.get_path() {
+                "/slow" => "s".repeat(FRAGMENT_SIZE),
+                path => format!("[{path}]"),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(body),
+            ))
+        }),
+        None,
+    )
+}
+
+#[test]
+fn content_behind_a_try_block_is_counted_with_its_buffer() -> Result<(), ExecutionError> {
+    // An `esi:try` block stays queued until the whole document has been parsed, so everything
+    // after it is buffered, and then so is the fragment in the nested group while it's polled.
+    let trailing = format!("<p>{}</p>", "x".repeat(1000)).repeat(4);
+    let template = format!(
+        r#"<esi:try><esi:attempt><esi:group name="g"><esi:include src="/slow"/></esi:group></esi:attempt></esi:try>{trailing}"#
+    );
+
+    let report = process(&template)?;
+
+    // The buffer of the group is copied into that of the arm before it's given back.
+    let expected = trailing.len() + 2 * FRAGMENT_SIZE;
+    assert!(
+        report.peak_buffered_bytes.abs_diff(expected) <= 16,
+        "expected about {expected} bytes, got {}",
+        report.peak_buffered_bytes
+    );
+    assert_eq!(report.peak_queue_len, 2);
+    assert_eq!(report.bytes_written, trailing.len() + FRAGMENT_SIZE);
+
+    Ok(())
+}
+
+#[test]
+fn streamed_content_is_not_counted() -> Result<(), ExecutionError> {
+    let report = process(r#"<p>a</p><esi:include src="/slow"/><p>b</p>"#)?;
+
+    assert_eq!(report.peak_buffered_bytes, 0);
+    assert_eq!(report.peak_queue_len, 1);
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use esi::{
+    CallbackKind, Clock, Configuration, ExecutionError, PendingFragmentContent, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::Response;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const TEMPLATE: &str = r#"<p>a</p><esi:include src="/a"/><esi:include src="/b"/>"#;
+
+// A clock that only moves when it's told to, as if the callbacks that move it were slow.
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<Instant>>);
+
+impl ManualClock {
+    fn new() -> Self {
+        Self(Rc::new(Cell::new(Instant::now())))
+    }
+
+    fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+
+    fn clock(&self) -> Clock {
+        let now = Rc::clone(&self.0);
+        Clock::new(move || now.get())
+    }
+}
+
+fn slow_callbacks(warnings: &[Warning]) -> Vec<(usize, CallbackKind, Duration)> {
+    warnings
+        .iter()
+        .filter_map(|warning| match warning {
+            Warning::SlowCallback {
+                position,
+                callback,
+                elapsed,
+            } => Some((*position, *callback, *elapsed)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn slow_response_processor_is_timed_and_reported() -> Result<(), ExecutionError> {
+    let time = ManualClock::new();
+    let processor = Processor::new(None, Configuration::default().with_clock(time.clock()));
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&|_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body("x"),
+            ))
+        }),
+        Some(&{
+            let time = time.clone();
+            move |req, res| {
+                // Only the second fragment takes long to process.
+                if req.get_path() == "/b" {
+                    time.advance(Duration::from_millis(80));
+                }
+                Ok(res)
+            }
+        }),
+    )?;
+
+    assert_eq!(report.callbacks.response_processor.calls, 2);
+    assert_eq!(
+        report.callbacks.response_processor.total,
+        Duration::from_millis(80)
+    );
+    assert_eq!(report.callbacks.dispatcher.calls, 2);
+    assert_eq!(report.callbacks.dispatcher.total, Duration::ZERO);
+    assert_eq!(report.callbacks.total(), Duration::from_millis(80));
+    // Reported at the position of the second include.
+    assert_eq!(
+        slow_callbacks(&report.warnings),
+        [(
+            TEMPLATE.find(r#"<esi:include src="/b""#).unwrap(),
+            CallbackKind::ResponseProcessor,
+            Duration::from_millis(80)
+        )]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fast_callbacks_raise_no_warnings() -> Result<(), ExecutionError> {
+    let time = ManualClock::new();
+    let processor = Processor::new(None, Configuration::default().with_clock(time.clock()))
+        .on_phase({
+            let time = time.clone();
+            move |_| time.advance(Duration::from_millis(50))
+        });
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&|_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body("x"),
+            ))
+        }),
+        None,
+    )?;
+
+    // Calls that take exactly the threshold aren't over it.
+    assert_eq!(slow_callbacks(&report.warnings), []);
+    assert_eq!(report.callbacks.phase_handler.calls, 6);
+    assert_eq!(
+        report.callbacks.phase_handler.total,
+        Duration::from_millis(300)
+    );
+    assert_eq!(report.callbacks.response_processor.calls, 0);
+
+    Ok(())
+}
+
+#[test]
+fn threshold_can_be_changed_or_disabled() -> Result<(), ExecutionError> {
+    let process = |threshold: Option<Duration>| {
+        let time = ManualClock::new();
+        let dispatch_time = time.clone();
+        let processor = Processor::new(
+            None,
+            Configuration::default()
+                .with_clock(time.clock())
+                .with_callback_overrun_threshold(threshold),
+        );
+        let mut writer = Writer::new(Vec::new());
+        processor.process_document(
+            Reader::from_str(TEMPLATE),
+            &mut writer,
+            Some(&move |_| {
+                dispatch_time.advance(Duration::from_millis(20));
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_body("x"),
+                ))
+            }),
+            None,
+        )
+    };
+
+    let report = process(Some(Duration::from_millis(10)))?;
+    assert_eq!(
+        slow_callbacks(&report.warnings)
+            .iter()
+            .map(|(_, callback, _)| *callback)
+            .collect::<Vec<_>>(),
+        [CallbackKind::Dispatcher, CallbackKind::Dispatcher]
+    );
+
+    let report = process(None)?;
+    assert_eq!(slow_callbacks(&report.warnings), []);
+    assert_eq!(report.callbacks.dispatcher.total, Duration::from_millis(40));
+
+    Ok(())
+}
+
+#[test]
+fn slow_warning_handler_is_not_called_with_its_own_overruns() -> Result<(), ExecutionError> {
+    let time = ManualClock::new();
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let processor = Processor::new(None, Configuration::default().with_clock(time.clock()))
+        .on_warning({
+            let time = time.clone();
+            let seen = Rc::clone(&seen);
+            move |warning| {
+                time.advance(Duration::from_millis(60));
+                seen.borrow_mut().push(warning.clone());
+            }
+        });
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str("<p>a</p><esi:foo/>"),
+        &mut writer,
+        None,
+        None,
+    )?;
+
+    assert_eq!(seen.borrow().len(), 1);
+    assert!(matches!(seen.borrow()[0], Warning::UnknownTag { .. }));
+    assert_eq!(
+        slow_callbacks(&report.warnings),
+        [(8, CallbackKind::WarningHandler, Duration::from_millis(60))]
+    );
+    assert_eq!(report.callbacks.warning_handler.calls, 1);
+
+    Ok(())
+}
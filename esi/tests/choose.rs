@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    parse_tags, Configuration, Event, ExecutionError, PendingFragmentContent, Processor, Reader,
+    Tag, Writer,
+};
+use fastly::{Request, Response};
+
+const ACCOUNT_TEMPLATE: &str = r#"<esi:choose><esi:when test="$(HTTP_COOKIE{logged_in})=='1'"><esi:include src="/account"/></esi:when><esi:otherwise><esi:include src="/login-promo"/></esi:otherwise></esi:choose>"#;
+
+// Processes `template` for a request with the given cookies, returning the output and the paths
+// of the fragments that were requested.
+fn process(template: &str, cookies: &str) -> Result<(String, Vec<String>), ExecutionError> {
+    let requested = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requested);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page").with_header("cookie", cookies)),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().push(req.get_path().to_string());
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    let requested = requested.take();
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), requested))
+}
+
+#[test]
+fn only_the_chosen_branch_is_fetched() -> Result<(), ExecutionError> {
+    let (logged_in, logged_in_requests) = process(ACCOUNT_TEMPLATE, "logged_in=1")?;
+    let (logged_out, logged_out_requests) = process(ACCOUNT_TEMPLATE, "logged_in=0")?;
+
+    assert_eq!(logged_in, "[/account]");
+    assert_eq!(logged_in_requests, ["/account"]);
+    assert_eq!(logged_out, "[/login-promo]");
+    assert_eq!(logged_out_requests, ["/login-promo"]);
+
+    Ok(())
+}
+
+#[test]
+fn first_true_branch_wins() -> Result<(), ExecutionError> {
+    let template = r#"<esi:choose>
+<esi:when test="$(HTTP_COOKIE{group})!='beta'"><p>stable</p></esi:when>
+<esi:when test="$(HTTP_COOKIE{features}) has 'search'"><p>search</p></esi:when>
+<esi:when test="$(HTTP_COOKIE{group})=='beta'"><p>beta</p></esi:when>
+</esi:choose>"#;
+
+    let (output, _) = process(template, "group=beta; features=maps,search")?;
+
+    assert_eq!(output.trim(), "<p>search</p>");
+
+    Ok(())
+}
+
+#[test]
+fn nothing_is_written_without_a_matching_branch() -> Result<(), ExecutionError> {
+    let (output, requested) = process(
+        r#"<p>a</p><esi:choose><esi:when test="$(HTTP_COOKIE{missing})"><esi:include src="/x"/></esi:when></esi:choose><p>b</p>"#,
+        "",
+    )?;
+
+    assert_eq!(output, "<p>a</p><p>b</p>");
+    assert!(requested.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn choose_inside_attempt_is_buffered() -> Result<(), ExecutionError> {
+    let template = format!(
+        r#"<esi:try><esi:attempt><b>{ACCOUNT_TEMPLATE}</b></esi:attempt><esi:except>x</esi:except></esi:try>"#
+    );
+
+    let (output, requested) = process(&template, "logged_in=1")?;
+
+    assert_eq!(output, "<b>[/account]</b>");
+    assert_eq!(requested, ["/account"]);
+
+    Ok(())
+}
+
+#[test]
+fn choose_is_parsed_into_branches() -> Result<(), ExecutionError> {
+    let mut reader = Reader::from_str(ACCOUNT_TEMPLATE);
+    let mut tags = Vec::new();
+    parse_tags("esi", &mut reader, &mut |event| {
+        if let Event::ESI(Tag::Choose {
+            when_branches,
+            otherwise_events,
+        }) = event
+        {
+            let tests: Vec<_> = when_branches
+                .iter()
+                .map(|(test, events)| (test.clone(), events.len()))
+                .collect();
+            tags.push((tests, otherwise_events.len()));
+        }
+        Ok(())
+    })?;
+
+    assert_eq!(
+        tags,
+        [(vec![("$(HTTP_COOKIE{logged_in})=='1'".to_string(), 1)], 1)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn when_outside_choose_is_an_error() {
+    let res = process(r#"<esi:when test="1"><p>a</p></esi:when>"#, "");
+
+    assert!(matches!(res, Err(ExecutionError::UnexpectedOpeningTag(_))));
+}
+
+#[test]
+fn when_without_test_is_an_error() {
+    let res = process("<esi:choose><esi:when><p>a</p></esi:when></esi:choose>", "");
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::MissingRequiredParameter(tag, attribute))
+            if tag == "esi:when" && attribute == "test"
+    ));
+}
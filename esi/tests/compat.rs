@@ -0,0 +1,77 @@
+#![cfg(feature = "compat")]
+#![allow(deprecated)]
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::{Request, Response};
+use std::cell::RefCell;
+
+const TEMPLATE: &str = r#"<p>start</p><esi:include src="/a"/><esi:try><esi:attempt><esi:include src="/b"/></esi:attempt><esi:except>oops</esi:except></esi:try><esi:include src="/c"/><p>end</p>"#;
+
+fn fragment(req: &Request) -> PendingFragmentContent {
+    PendingFragmentContent::CompletedRequest(Response::from_body(format!("[{}]", req.get_path())))
+}
+
+#[test]
+fn legacy_and_current_apis_produce_the_same_output() -> Result<(), ExecutionError> {
+    let legacy = esi::compat::Processor::new(TEMPLATE, None, Configuration::default())
+        .execute(Some(&|(req, _)| Ok(fragment(&req))), None)?
+        .into_string();
+
+    let mut writer = Writer::new(Vec::new());
+    Processor::new(None, Configuration::default()).process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&|req| Ok(fragment(&req))),
+        None,
+    )?;
+
+    assert_eq!(legacy, String::from_utf8(writer.into_inner()).unwrap());
+    assert_eq!(legacy, "<p>start</p>[/a][/b][/c]<p>end</p>");
+
+    Ok(())
+}
+
+#[test]
+fn legacy_dispatcher_indexes_follow_document_order() -> Result<(), ExecutionError> {
+    let dispatched = RefCell::new(Vec::new());
+    esi::compat::Processor::new(TEMPLATE, None, Configuration::default()).execute(
+        Some(&|(req, index)| {
+            dispatched
+                .borrow_mut()
+                .push((req.get_path().to_string(), index));
+            Ok(fragment(&req))
+        }),
+        None,
+    )?;
+
+    assert_eq!(
+        dispatched.into_inner(),
+        [
+            ("/a".to_string(), 0),
+            ("/b".to_string(), 1),
+            ("/c".to_string(), 2)
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn legacy_api_fails_like_the_current_one() {
+    let failing = |_| Err(ExecutionError::InvalidRequestUrl("/a".to_string()));
+
+    let legacy = esi::compat::Processor::new(TEMPLATE, None, Configuration::default())
+        .execute(Some(&|(req, _)| failing(req)), None);
+    let current = Processor::new(None, Configuration::default()).process_document(
+        Reader::from_str(TEMPLATE),
+        &mut Writer::new(Vec::new()),
+        Some(&failing),
+        None,
+    );
+
+    assert!(matches!(legacy, Err(ExecutionError::InvalidRequestUrl(ref url)) if url == "/a"));
+    assert_eq!(
+        legacy.unwrap_err().to_string(),
+        current.unwrap_err().to_string()
+    );
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use esi::{
+    Configuration, DebugHeader, ExecutionError, KeySpec, OversizedDocumentPolicy, Processor,
+    Reader, Writer,
+};
+use fastly::http::header;
+
+#[test]
+fn every_option_is_described() {
+    let configuration = Configuration::default();
+    // Naming every field makes this fail to compile when an option is added, until it's listed.
+    let Configuration {
+        namespace: _,
+        is_escaped: _,
+        strict_urls: _,
+        vars_in_attributes: _,
+        preserve_host: _,
+        upgrade_insecure_fragments: _,
+        header_forwarding: _,
+        forward_headers_override: _,
+        scheme_handlers: _,
+        accept_status: _,
+        empty_body_status: _,
+        validate_fragment_utf8: _,
+        error_body_snippet: _,
+        prefetch_except: _,
+        fragment_key: _,
+        fragment_deduplication: _,
+        trace_events: _,
+        debug_header: _,
+        compute_etag: _,
+        require_surrogate_control: _,
+        max_warnings: _,
+        max_document_size: _,
+        max_events: _,
+        max_attribute_length: _,
+        max_include_depth: _,
+        max_fragments: _,
+        max_fragment_size: _,
+        oversized_document: _,
+        process_in_comments: _,
+        debug_comments: _,
+        debug_validate_output: _,
+        spec_compliance: _,
+        strict_prestream: _,
+        strict_mode: _,
+        output_mode: _,
+        poll_abandoned: _,
+        retry_pass: _,
+        fragment_markers: _,
+        deadline: _,
+        fragment_timeout: _,
+        clock: _,
+        geo_resolver: _,
+        callback_overrun_threshold: _,
+        scheduling: _,
+        reader_options: _,
+    } = &configuration;
+    let fields = [
+        "namespace",
+        "is_escaped",
+        "strict_urls",
+        "vars_in_attributes",
+        "preserve_host",
+        "upgrade_insecure_fragments",
+        "header_forwarding",
+        "forward_headers_override",
+        "scheme_handlers",
+        "accept_status",
+        "empty_body_status",
+        "validate_fragment_utf8",
+        "error_body_snippet",
+        "prefetch_except",
+        "fragment_key",
+        "fragment_deduplication",
+        "trace_events",
+        "debug_header",
+        "compute_etag",
+        "require_surrogate_control",
+        "max_warnings",
+        "max_document_size",
+        "max_events",
+        "max_attribute_length",
+        "max_include_depth",
+        "max_fragments",
+        "max_fragment_size",
+        "oversized_document",
+        "process_in_comments",
+        "debug_comments",
+        "debug_validate_output",
+        "spec_compliance",
+        "strict_prestream",
+        "strict_mode",
+        "output_mode",
+        "poll_abandoned",
+        "retry_pass",
+        "fragment_markers",
+        "deadline_ms",
+        "fragment_timeout_ms",
+        "clock",
+        "geo_resolver",
+        "callback_overrun_threshold_ms",
+        "scheduling",
+        "reader_options",
+    ];
+
+    let description = configuration.describe();
+    let names: Vec<_> = description.options.iter().map(|(name, _)| *name).collect();
+
+    assert_eq!(names, fields);
+}
+
+#[test]
+fn description_lists_the_effective_options() {
+    let description = Configuration::default()
+        .with_namespace("app")
+        .with_scheme_handler("Frag", |url| Err(format!("unknown service in {url}")))
+        .with_fragment_key(KeySpec::default().with_header(header::COOKIE))
+        .with_debug_header(DebugHeader::Verbose)
+        .with_oversized_document_policy(OversizedDocumentPolicy::Fail)
+        .with_deadline(Duration::from_millis(250))
+        .describe();
+
+    assert_eq!(
+        description.to_string(),
+        "namespace=app is_escaped=true strict_urls=false vars_in_attributes=true preserve_host=false upgrade_insecure_fragments=allow header_forwarding=all forward_headers_override=replace scheme_handlers=frag accept_status=\"\" empty_body_status=204,304 validate_fragment_utf8=passthrough error_body_snippet=256 prefetch_except=false fragment_key=cookie fragment_deduplication=false trace_events=false debug_header=verbose compute_etag=false require_surrogate_control=false max_warnings=100 max_document_size=none max_events=none max_attribute_length=16384 max_include_depth=none max_fragments=none max_fragment_size=none oversized_document=fail process_in_comments=false debug_comments=false debug_validate_output=false spec_compliance=off strict_prestream=false strict_mode=false output_mode=writer poll_abandoned=false retry_pass=none fragment_markers=none deadline_ms=250 fragment_timeout_ms=none clock=system geo_resolver=host callback_overrun_threshold_ms=50 scheduling=\"events_per_drain_check 1 max_drain_per_check none\" reader_options=\"check_end_names false trim_text false expand_empty_elements false strict false\""
+    );
+    assert_eq!(
+        Configuration::default()
+            .describe()
+            .get("oversized_document"),
+        Some("truncate <!-- esi: document truncated -->")
+    );
+}
+
+#[test]
+fn report_carries_the_hash_of_the_configuration() -> Result<(), ExecutionError> {
+    let process = |configuration: Configuration| {
+        Processor::new(None, configuration).process_document(
+            Reader::from_str("<p>hi</p>"),
+            &mut Writer::new(Vec::new()),
+            None,
+            None,
+        )
+    };
+
+    let default = process(Configuration::default())?;
+    let renamed = process(Configuration::default().with_namespace("app"))?;
+
+    assert_eq!(
+        default.config_hash,
+        Configuration::default().describe().hash()
+    );
+    assert_ne!(default.config_hash, renamed.config_hash);
+
+    Ok(())
+}
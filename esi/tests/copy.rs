@@ -0,0 +1,317 @@
+use esi::{
+    copy_fragment_body, Configuration, CopyBudget, CopyOutcome, ExecutionError,
+    OversizedDocumentPolicy, PendingFragmentContent, ProcessingReport, Processor, Progress, Reader,
+    Writer,
+};
+use fastly::Response;
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// An in-memory body that runs a hook before handing out each chunk.
+struct HookedBody<F: FnMut(usize) -> io::Result<()>> {
+    data: io::Cursor<Vec<u8>>,
+    chunk_size: usize,
+    reads: usize,
+    hook: F,
+}
+
+impl<F: FnMut(usize) -> io::Result<()>> HookedBody<F> {
+    fn new(len: usize, chunk_size: usize, hook: F) -> Self {
+        Self {
+            data: io::Cursor::new((0..len).map(|i| (i % 251) as u8).collect()),
+            chunk_size,
+            reads: 0,
+            hook,
+        }
+    }
+}
+
+impl<F: FnMut(usize) -> io::Result<()>> Read for HookedBody<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        (self.hook)(self.reads)?;
+        let len = buf.len().min(self.chunk_size);
+        self.data.read(&mut buf[..len])
+    }
+}
+
+// A sink that counts how often it's flushed.
+#[derive(Default)]
+struct FlushCountingSink {
+    data: Vec<u8>,
+    flushes: usize,
+}
+
+impl Write for FlushCountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+// A sink that fails every write, like a client stream after a disconnect.
+struct DisconnectedSink;
+
+impl Write for DisconnectedSink {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn budget() -> CopyBudget {
+    CopyBudget::new()
+        .with_chunk_size(100)
+        .with_check_interval(2)
+}
+
+#[test]
+fn whole_body_is_copied() {
+    let body = HookedBody::new(10_050, 100, |_| Ok(()));
+    let mut sink = Vec::new();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget());
+
+    assert!(matches!(outcome, CopyOutcome::Complete { bytes: 10_050 }));
+    assert_eq!(sink.len(), 10_050);
+    assert_eq!(sink[249..252], [249, 250, 0]);
+}
+
+// The sink is flushed after every two chunks, and once more at the end.
+#[test]
+fn sink_is_flushed_as_the_body_is_copied() {
+    let body = HookedBody::new(1_050, 100, |_| Ok(()));
+    let mut sink = FlushCountingSink::default();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget());
+
+    assert!(matches!(outcome, CopyOutcome::Complete { bytes: 1_050 }));
+    assert_eq!(sink.data.len(), 1_050);
+    assert_eq!(sink.flushes, 6);
+}
+
+#[test]
+fn clones_of_a_budget_copy_bodies_one_after_another() {
+    let budget = budget();
+    let shared = budget.clone();
+    let mut sink = Vec::new();
+
+    copy_fragment_body(HookedBody::new(250, 100, |_| Ok(())), &mut sink, &budget);
+    copy_fragment_body(HookedBody::new(50, 100, |_| Ok(())), &mut sink, &shared);
+
+    assert_eq!(sink.len(), 300);
+    assert_eq!(sink[249..251], [249, 0]);
+}
+
+#[test]
+fn copy_stops_when_deadline_passes_mid_copy() {
+    let budget = budget().with_deadline(Instant::now() + Duration::from_millis(20));
+    // The third chunk takes long enough for the deadline to pass.
+    let body = HookedBody::new(10_000, 100, |read| {
+        if read == 3 {
+            sleep(Duration::from_millis(40));
+        }
+        Ok(())
+    });
+    let mut sink = Vec::new();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget);
+
+    // The deadline is checked every two chunks, so the fourth is still copied.
+    assert!(matches!(
+        outcome,
+        CopyOutcome::DeadlineExceeded { bytes: 400 }
+    ));
+    assert_eq!(sink.len(), 400);
+    assert!(!budget.is_client_gone());
+}
+
+#[test]
+fn copy_stops_when_client_is_marked_gone() {
+    let budget = budget().with_check_interval(1);
+    let flag = budget.clone();
+    let body = HookedBody::new(10_000, 100, move |read| {
+        if read == 3 {
+            flag.mark_client_gone();
+        }
+        Ok(())
+    });
+    let mut sink = Vec::new();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget);
+
+    assert!(matches!(outcome, CopyOutcome::ClientGone { bytes: 300 }));
+    assert_eq!(sink.len(), 300);
+}
+
+#[test]
+fn failed_write_marks_client_gone() {
+    let budget = budget();
+    let body = HookedBody::new(1_000, 100, |_| Ok(()));
+
+    let outcome = copy_fragment_body(body, &mut DisconnectedSink, &budget);
+
+    assert!(matches!(outcome, CopyOutcome::ClientGone { bytes: 0 }));
+    assert!(budget.is_client_gone());
+}
+
+#[test]
+fn failed_read_is_reported_with_bytes_copied() {
+    let body = HookedBody::new(1_000, 100, |read| {
+        if read == 4 {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        Ok(())
+    });
+    let mut sink = Vec::new();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget());
+
+    let CopyOutcome::ReadFailed { bytes, error } = outcome else {
+        panic!("expected a read failure, got {outcome:?}");
+    };
+    assert_eq!(bytes, 300);
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(sink.len(), 300);
+}
+
+#[test]
+fn copy_stops_before_the_chunk_over_the_max_body_size() {
+    let body = HookedBody::new(1_000, 100, |_| Ok(()));
+    let mut sink = Vec::new();
+
+    let outcome = copy_fragment_body(body, &mut sink, &budget().with_max_body_size(250));
+
+    assert!(matches!(
+        outcome,
+        CopyOutcome::TooLarge {
+            bytes: 200,
+            limit: 250
+        }
+    ));
+    assert_eq!(sink.len(), 200);
+}
+
+// A body of a few megabytes of printable characters, which only repeats every 95 bytes.
+fn large_body() -> String {
+    (0..3 * 1024 * 1024 + 17)
+        .map(|i| char::from(b' ' + (i % 95) as u8))
+        .collect()
+}
+
+fn process_large(template: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|_| Ok(Response::from_body(large_body()).into())),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+// A top-level fragment is streamed to the output, and never buffered as a whole.
+#[test]
+fn large_fragment_is_streamed_whole() -> Result<(), ExecutionError> {
+    let (output, report) = process_large(r#"<p>a</p><esi:include src="/large"/><p>b</p>"#)?;
+
+    assert_eq!(output, format!("<p>a</p>{}<p>b</p>", large_body()));
+    assert_eq!(report.peak_buffered_bytes, 0);
+
+    Ok(())
+}
+
+// Inside an `esi:try` block, the fragment is held in the buffer of the arm until it's written.
+#[test]
+fn large_fragment_in_an_attempt_is_written_whole() -> Result<(), ExecutionError> {
+    let (output, report) = process_large(
+        r#"<esi:try><esi:attempt><esi:include src="/large"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, large_body());
+    assert!(report.peak_buffered_bytes >= large_body().len());
+
+    Ok(())
+}
+
+const TEMPLATE: &str = r#"<p>before</p><esi:include src="/a"/><p>after</p>"#;
+
+// A fragment that takes longer to arrive than the deadline allows
+fn slow_fragment() -> esi::Result<PendingFragmentContent> {
+    sleep(Duration::from_millis(40));
+    Ok(Response::from_body("[a]").into())
+}
+
+fn configuration() -> Configuration {
+    Configuration::default().with_deadline(Duration::from_millis(10))
+}
+
+#[test]
+fn queue_is_dropped_and_output_truncated_at_deadline() -> Result<(), ExecutionError> {
+    let processor = Processor::new(None, configuration());
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&|_| slow_fragment()),
+        None,
+    )?;
+
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+    assert_eq!(output, "<p>before</p><!-- esi: document truncated -->");
+    assert!(report.truncated);
+    assert_eq!(report.bytes_written, output.len());
+
+    Ok(())
+}
+
+#[test]
+fn session_is_truncated_at_deadline() -> Result<(), ExecutionError> {
+    let processor = Processor::new(None, configuration());
+    let dispatch = |_| slow_fragment();
+    let mut session = processor.session(Reader::from_str(TEMPLATE), Some(&dispatch), None);
+    let mut writer = Writer::new(Vec::new());
+
+    let Progress::Complete(report) = session.poll_progress(&mut writer)? else {
+        panic!("expected the session to complete without waiting");
+    };
+
+    assert_eq!(
+        String::from_utf8(writer.into_inner()).unwrap(),
+        "<p>before</p><!-- esi: document truncated -->"
+    );
+    assert!(report.truncated);
+
+    Ok(())
+}
+
+#[test]
+fn deadline_fails_processing_with_fail_policy() {
+    let processor = Processor::new(
+        None,
+        configuration().with_oversized_document_policy(OversizedDocumentPolicy::Fail),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let result = processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&|_| slow_fragment()),
+        None,
+    );
+
+    assert!(matches!(result, Err(ExecutionError::DeadlineExceeded)));
+    assert_eq!(writer.into_inner(), b"<p>before</p>");
+}
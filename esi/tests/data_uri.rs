@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+
+fn process(input: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| panic!("data URIs must not be dispatched: {}", req.get_url())),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn base64_data_uri_is_included() -> Result<(), ExecutionError> {
+    let output = process(r#"<p><esi:include src="data:text/html;base64,PGI+aGk8L2I+"/></p>"#)?;
+
+    assert_eq!(output, "<p><b>hi</b></p>");
+
+    Ok(())
+}
+
+#[test]
+fn percent_encoded_data_uri_is_included() -> Result<(), ExecutionError> {
+    let output = process(r#"<p><esi:include src="data:,hello%20world%21"/></p>"#)?;
+
+    assert_eq!(output, "<p>hello world!</p>");
+
+    Ok(())
+}
+
+#[test]
+fn data_uri_is_included_inside_try() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:try><esi:attempt><esi:include src="data:,attempt"/></esi:attempt><esi:except>except</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "attempt");
+
+    Ok(())
+}
+
+#[test]
+fn data_uri_media_type_is_the_content_type() -> Result<(), ExecutionError> {
+    let content_types = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&content_types);
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+    processor.process_document(
+        Reader::from_str(
+            r#"<esi:include src="data:application/json,{}"/><esi:include src="data:,"/>"#,
+        ),
+        &mut writer,
+        None,
+        Some(&move |_req, resp| {
+            seen.borrow_mut()
+                .push(resp.get_header_str("content-type").unwrap().to_string());
+            Ok(resp)
+        }),
+    )?;
+
+    assert_eq!(
+        *content_types.borrow(),
+        ["application/json", "text/plain;charset=US-ASCII"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn malformed_data_uri_fails() {
+    let res = process(r#"<esi:include src="data:text/html;base64,not*base64"/>"#);
+
+    assert!(matches!(res, Err(ExecutionError::InvalidDataUri(_))));
+}
+
+#[test]
+fn malformed_data_uri_can_continue() -> Result<(), ExecutionError> {
+    let output = process(r#"<p><esi:include src="data:text/html" onerror="continue"/></p>"#)?;
+
+    assert_eq!(output, "<p></p>");
+
+    Ok(())
+}
+
+#[test]
+fn malformed_data_uri_falls_back_to_alt() -> Result<(), ExecutionError> {
+    let dispatched = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&dispatched);
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+    processor.process_document(
+        Reader::from_str(r#"<esi:include src="data:;base64,%%%" alt="/fallback"/>"#),
+        &mut writer,
+        Some(&move |req| {
+            seen.borrow_mut().push(req.get_path().to_string());
+            Ok(PendingFragmentContent::NoContent)
+        }),
+        None,
+    )?;
+
+    assert_eq!(*dispatched.borrow(), ["/fallback"]);
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::{Request, Response};
+
+fn process_with(
+    configuration: Configuration,
+    input: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page?slot=1")
+                .with_header("cookie", "uid=user-2; session=s3cret")
+                .with_header("authorization", "Bearer token"),
+        ),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+fn process(input: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    process_with(Configuration::default().with_debug_comments(true), input)
+}
+
+#[test]
+fn comment_text_is_evaluated() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<p>a</p><esi:comment text="uid is $(HTTP_COOKIE{uid}), bucket $bucket($(HTTP_COOKIE{uid}), 2)"/><esi:include src="/b"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        "<p>a</p><!-- debug: raw='uid is $(HTTP_COOKIE{uid}), bucket $bucket($(HTTP_COOKIE{uid}), 2)' value='uid is user-2, bucket 1' -->[/b]"
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn evaluation_errors_are_written_into_the_comment() -> Result<(), ExecutionError> {
+    let (output, report) = process(r#"<esi:comment text="$bucket('x', 0)"/>"#)?;
+
+    assert_eq!(
+        output,
+        "<!-- debug: raw='$bucket('x', 0)' value='' errors='$bucket('x', 0)' -->"
+    );
+    assert!(matches!(
+        report.warnings.as_slice(),
+        [Warning::ExpressionError { expression, .. }] if expression == "$bucket('x', 0)"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn sensitive_headers_are_redacted_and_dashes_escaped() -> Result<(), ExecutionError> {
+    let (output, _) =
+        process(r#"<esi:comment text="-->$(HTTP_COOKIE) $(HTTP_AUTHORIZATION)"></esi:comment>"#)?;
+
+    assert_eq!(
+        output,
+        "<!-- debug: raw='- ->$(HTTP_COOKIE) $(HTTP_AUTHORIZATION)' value='- ->[redacted] [redacted]' -->"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn comments_are_left_out_unless_enabled() -> Result<(), ExecutionError> {
+    let template = r#"<p>a</p><esi:comment text="$(HTTP_COOKIE{uid})"/><esi:comment text="note"/>"#;
+
+    let (output, _) = process_with(Configuration::default(), template)?;
+    assert_eq!(output, "<p>a</p>");
+
+    // Comments without a `text` attribute are always left out.
+    let (output, _) = process(r#"<p>a</p><esi:comment/>"#)?;
+    assert_eq!(output, "<p>a</p>");
+
+    Ok(())
+}
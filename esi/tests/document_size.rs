@@ -0,0 +1,119 @@
+use esi::{
+    Configuration, ExecutionError, OversizedDocumentPolicy, PendingFragmentContent,
+    ProcessingReport, Processor, Progress, Reader, Writer,
+};
+use fastly::Response;
+
+// The leading `esi:try` stays queued until the whole document has been parsed, so the content
+// after it is buffered behind the include when the limit is reached.
+const TEMPLATE: &str = r#"<esi:try><esi:attempt><esi:include src="/a"/></esi:attempt></esi:try><p>two</p><p>three</p>"#;
+
+fn fragment_content(path: &str) -> PendingFragmentContent {
+    Response::from_body(format!("[{path}]")).into()
+}
+
+fn process(
+    template: &str,
+    configuration: Configuration,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| Ok(fragment_content(req.get_path()))),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+// A limit that ends in the middle of the text of the last paragraph
+fn limit_within_last_paragraph() -> usize {
+    TEMPLATE.find("three").unwrap() + 2
+}
+
+#[test]
+fn oversized_document_is_truncated_behind_pending_include() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        TEMPLATE,
+        Configuration::default().with_max_document_size(limit_within_last_paragraph()),
+    )?;
+
+    assert_eq!(output, "[/a]<p>two</p><p><!-- esi: document truncated -->");
+    assert!(report.truncated);
+    assert_eq!(report.bytes_written, output.len());
+
+    Ok(())
+}
+
+#[test]
+fn truncation_marker_is_configurable() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        "<p>one</p><p>two</p>",
+        Configuration::default()
+            .with_max_document_size(14)
+            .with_oversized_document_policy(OversizedDocumentPolicy::Truncate {
+                marker: "<!-- cut -->".to_string(),
+            }),
+    )?;
+
+    assert_eq!(output, "<p>one</p><p><!-- cut -->");
+
+    Ok(())
+}
+
+#[test]
+fn oversized_document_fails_with_fail_policy() {
+    let res = process(
+        TEMPLATE,
+        Configuration::default()
+            .with_max_document_size(limit_within_last_paragraph())
+            .with_oversized_document_policy(OversizedDocumentPolicy::Fail),
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::DocumentTooLarge(size)) if size == limit_within_last_paragraph()
+    ));
+}
+
+#[test]
+fn document_at_the_limit_is_not_truncated() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        TEMPLATE,
+        Configuration::default().with_max_document_size(TEMPLATE.len()),
+    )?;
+
+    assert_eq!(output, "[/a]<p>two</p><p>three</p>");
+    assert!(!report.truncated);
+
+    Ok(())
+}
+
+#[test]
+fn session_truncates_oversized_document() -> Result<(), ExecutionError> {
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_max_document_size(limit_within_last_paragraph()),
+    );
+    let mut session = processor.session(
+        Reader::from_str(TEMPLATE),
+        Some(&|req| Ok(fragment_content(req.get_path()))),
+        None,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let Progress::Complete(report) = session.poll_progress(&mut writer)? else {
+        panic!("completed fragments never need waiting on");
+    };
+
+    assert_eq!(
+        String::from_utf8(writer.into_inner()).unwrap(),
+        "[/a]<p>two</p><p><!-- esi: document truncated -->"
+    );
+    assert!(report.truncated);
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+
+const TEMPLATE: &str = r#"<p>a</p><esi:include src="/one"/><esi:include src="/two"/><p>b</p>"#;
+
+fn fragment_content(req: Request) -> esi::Result<PendingFragmentContent> {
+    Ok(Response::from_body(format!("[{}]", req.get_path())).into())
+}
+
+fn process_to_response(
+    template: &str,
+    if_none_match: Option<&str>,
+) -> Result<(Response, ProcessingReport), ExecutionError> {
+    let mut req = Request::get("http://www.example.com/page");
+    if let Some(etag) = if_none_match {
+        req.set_header(header::IF_NONE_MATCH, etag);
+    }
+    let processor = Processor::new(Some(req), Configuration::default().with_compute_etag(true));
+
+    processor.process_to_response(
+        &mut Response::from_body(template),
+        None,
+        Some(&fragment_content),
+        None,
+    )
+}
+
+#[test]
+fn matching_if_none_match_gets_not_modified() -> Result<(), ExecutionError> {
+    let (mut resp, report) = process_to_response(TEMPLATE, None)?;
+    let etag = report.etag.expect("the output should be hashed");
+
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(resp.get_header_str(header::ETAG), Some(etag.as_str()));
+    assert_eq!(resp.take_body_str(), "<p>a</p>[/one][/two]<p>b</p>");
+
+    let (mut resp, _) = process_to_response(TEMPLATE, Some(&format!("\"other\", W/{etag}")))?;
+
+    assert_eq!(resp.get_status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.get_header_str(header::ETAG), Some(etag.as_str()));
+    assert_eq!(resp.take_body_str(), "");
+
+    let (resp, _) = process_to_response(TEMPLATE, Some("\"other\""))?;
+    assert_eq!(resp.get_status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[test]
+fn identical_renders_get_the_same_etag() -> Result<(), ExecutionError> {
+    let (_, first) = process_to_response(TEMPLATE, None)?;
+    let (_, second) = process_to_response(TEMPLATE, None)?;
+    let (_, other) = process_to_response(&TEMPLATE.replace("/two", "/three"), None)?;
+
+    assert!(first.etag.is_some());
+    assert_eq!(first.etag, second.etag);
+    assert_ne!(first.etag, other.etag);
+
+    Ok(())
+}
+
+#[test]
+fn streamed_output_is_not_hashed() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default().with_compute_etag(true);
+
+    let mut writer = Writer::new(Vec::new());
+    let streamed = Processor::new(None, configuration.clone()).process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&fragment_content),
+        None,
+    )?;
+
+    assert_eq!(streamed.etag, None);
+    assert!(matches!(
+        streamed.warnings.as_slice(),
+        [Warning::TooLate { feature, position: 0 }] if feature == "compute_etag"
+    ));
+
+    // Validating critical includes composes the whole output before any of it is written.
+    let mut writer = Writer::new(Vec::new());
+    let validated = Processor::new(None, configuration).process_with_validation(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&fragment_content),
+        None,
+    )?;
+
+    assert!(validated.warnings.is_empty());
+    assert_eq!(validated.etag, process_to_response(TEMPLATE, None)?.1.etag);
+
+    Ok(())
+}
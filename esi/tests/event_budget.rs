@@ -0,0 +1,100 @@
+use esi::{
+    Configuration, ExecutionError, OversizedDocumentPolicy, ProcessingReport, Processor, Reader,
+    Writer, EVENT_BUDGET_CHECK_INTERVAL,
+};
+use std::time::{Duration, Instant};
+
+const MAX_EVENTS: usize = 10_000;
+
+// A document of a million events that's only 7 MB, so it stays under any sensible size limit
+fn million_events() -> String {
+    "<b></b>".repeat(500_000)
+}
+
+fn process(
+    template: &str,
+    configuration: Configuration,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|_| panic!("the document has no includes")),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn document_with_too_many_events_is_truncated() -> Result<(), ExecutionError> {
+    let template = million_events();
+    let started = Instant::now();
+
+    let (output, report) = process(
+        &template,
+        Configuration::default().with_max_events(MAX_EVENTS),
+    )?;
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // The limit is noticed at the first check past it, before the event it's noticed at is
+    // processed.
+    let checked_at = MAX_EVENTS.next_multiple_of(EVENT_BUDGET_CHECK_INTERVAL);
+    assert_eq!(report.events_parsed, checked_at);
+    assert!(report.truncated);
+    let expected = format!(
+        "{}<b><!-- esi: document truncated -->",
+        "<b></b>".repeat((checked_at - 1) / 2)
+    );
+    assert_eq!(output, expected);
+
+    Ok(())
+}
+
+#[test]
+fn document_with_too_many_events_fails_with_fail_policy() {
+    let template = million_events();
+    let started = Instant::now();
+
+    let res = process(
+        &template,
+        Configuration::default()
+            .with_max_events(MAX_EVENTS)
+            .with_oversized_document_policy(OversizedDocumentPolicy::Fail),
+    );
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(matches!(
+        res,
+        Err(ExecutionError::EventBudgetExceeded(MAX_EVENTS))
+    ));
+}
+
+#[test]
+fn events_past_the_last_check_are_caught_at_the_end() {
+    // Fewer events than the check interval, but more than the limit
+    let res = process(
+        "<p>one</p><p>two</p>",
+        Configuration::default()
+            .with_max_events(4)
+            .with_oversized_document_policy(OversizedDocumentPolicy::Fail),
+    );
+
+    assert!(matches!(res, Err(ExecutionError::EventBudgetExceeded(4))));
+}
+
+#[test]
+fn document_within_the_limit_is_complete() -> Result<(), ExecutionError> {
+    let template = "<p>one</p><p>two</p>";
+
+    // Six text and tag events, and the end of the document
+    let (output, report) = process(template, Configuration::default().with_max_events(7))?;
+
+    assert_eq!(output, template);
+    assert_eq!(report.events_parsed, 7);
+    assert!(!report.truncated);
+
+    Ok(())
+}
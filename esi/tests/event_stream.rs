@@ -0,0 +1,126 @@
+use esi::{parse_tags, Event, EventStream, ExecutionError, Reader, Tag, TryArmKind};
+
+const FIXTURES: &[&str] = &[
+    include_str!("golden/plain_html/template.html"),
+    include_str!("golden/include_positions/template.html"),
+    include_str!("golden/alt_fallback/template.html"),
+    include_str!("golden/onerror_continue/template.html"),
+    include_str!("golden/nested_try/template.html"),
+    include_str!("golden/remove_comment/template.html"),
+    include_str!("golden/include_fallback/template.html"),
+    include_str!("golden/vars_value/template.html"),
+    include_str!("golden/json_escaped/template.json"),
+];
+
+// The events the callback API reports for the document, as text to compare.
+fn parse_with_callback(template: &str) -> Result<Vec<String>, ExecutionError> {
+    let mut events = Vec::new();
+    parse_tags("esi", &mut Reader::from_str(template), &mut |event| {
+        events.push(format!("{event:?}"));
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+#[test]
+fn streams_the_events_the_callback_reports() -> Result<(), ExecutionError> {
+    for template in FIXTURES {
+        let streamed = EventStream::new("esi", &mut Reader::from_str(template))
+            .map(|event| event.map(|event| format!("{event:?}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(streamed, parse_with_callback(template)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn events_are_parsed_as_they_are_pulled() -> Result<(), ExecutionError> {
+    let template = "<p>a</p><esi:include src=\"/b\"/><p>c</p>";
+    let mut reader = Reader::from_str(template);
+    let mut stream = EventStream::new("esi", &mut reader);
+
+    let first = stream.next().unwrap()?;
+    assert!(matches!(first, Event::XML(_)));
+    drop(stream);
+    assert!(reader.buffer_position() < template.len());
+
+    Ok(())
+}
+
+#[test]
+fn try_blocks_are_yielded_whole() -> Result<(), ExecutionError> {
+    let events = EventStream::new(
+        "esi",
+        &mut Reader::from_str(
+            "<esi:try><esi:attempt><esi:include src=\"/a\"/></esi:attempt><esi:except>x</esi:except></esi:try>",
+        ),
+    )
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let [Event::ESI(Tag::Try { arms })] = events.as_slice() else {
+        panic!("expected a single try block, got {events:?}");
+    };
+    assert_eq!(
+        arms.iter().map(|arm| &arm.kind).collect::<Vec<_>>(),
+        [&TryArmKind::Attempt, &TryArmKind::Except]
+    );
+    assert!(matches!(
+        arms[0].events.as_slice(),
+        [Event::ESI(Tag::Include(include))] if include.src == "/a"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn iteration_ends_after_an_error() {
+    let mut reader = Reader::from_str("<p>a</p></esi:try><p>b</p>");
+    let results = EventStream::new("esi", &mut reader).collect::<Vec<_>>();
+
+    let (last, events) = results.split_last().unwrap();
+    assert_eq!(events.len(), 3);
+    assert!(events
+        .iter()
+        .all(|event| matches!(event, Ok(Event::XML(_)))));
+    assert!(matches!(last, Err(ExecutionError::UnexpectedClosingTag(_))));
+}
+
+#[test]
+fn callback_sees_the_events_before_an_error() {
+    let mut events = 0;
+    let result = parse_tags(
+        "esi",
+        &mut Reader::from_str("<p>a</p></esi:try><p>b</p>"),
+        &mut |_| {
+            events += 1;
+            Ok(())
+        },
+    );
+
+    assert_eq!(events, 3);
+    assert!(matches!(
+        result,
+        Err(ExecutionError::UnexpectedClosingTag(_))
+    ));
+}
+
+#[test]
+fn callback_errors_stop_parsing() {
+    let mut events = 0;
+    let result = parse_tags(
+        "esi",
+        &mut Reader::from_str("<p>a</p><p>b</p>"),
+        &mut |_| {
+            events += 1;
+            Err(ExecutionError::UnexpectedEndOfDocument)
+        },
+    );
+
+    assert_eq!(events, 1);
+    assert!(matches!(
+        result,
+        Err(ExecutionError::UnexpectedEndOfDocument)
+    ));
+}
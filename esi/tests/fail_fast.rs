@@ -0,0 +1,119 @@
+use esi::{
+    Configuration, ExecutionError, FailurePhase, IncludeOutcome, PendingFragmentContent,
+    ProcessingReport, Processor, Reader, Warning, Writer, FAIL_FAST_HEADER,
+};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+type ResponseProcessor = dyn Fn(&mut Request, Response) -> esi::Result<Response>;
+
+// Processes the template with a dispatcher that fails requests to `/down` fast, as if a circuit
+// breaker for its backend was open, returning the output, the report and the paths of the
+// requests that were actually sent.
+fn process(
+    template: &str,
+    process_response: Option<&ResponseProcessor>,
+) -> Result<(String, ProcessingReport, Vec<String>), ExecutionError> {
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&sent);
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            if req.get_path() == "/down" {
+                return Ok(PendingFragmentContent::FailFast(
+                    "circuit open for catalog".to_string(),
+                ));
+            }
+            dispatched.borrow_mut().push(req.get_path().to_string());
+            Ok(Response::from_body(format!("[{}]", req.get_path())).into())
+        }),
+        process_response,
+    )?;
+
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+    let sent = sent.take();
+    Ok((output, report, sent))
+}
+
+#[test]
+fn an_include_failed_fast_renders_its_alt() -> Result<(), ExecutionError> {
+    let (output, report, sent) = process(
+        r#"<p>a</p><esi:include src="/down" alt="/backup"/><p>b</p>"#,
+        None,
+    )?;
+
+    assert_eq!(output, "<p>a</p>[/backup]<p>b</p>");
+    assert_eq!(sent, ["/backup"]);
+    assert_eq!(report.includes.len(), 1);
+    assert_eq!(report.includes[0].outcome, IncludeOutcome::Alt);
+    assert_eq!(
+        report.warnings,
+        [Warning::FragmentFailedFast {
+            position: 8,
+            url: "http://localhost/down".to_string(),
+            reason: "circuit open for catalog".to_string(),
+        }]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn an_include_failed_fast_without_alt_is_handled_like_any_failure() -> Result<(), ExecutionError> {
+    let (output, report, _) = process(
+        r#"<esi:include src="/down" onerror="continue"/><esi:include src="/down"><i>none</i></esi:include>"#,
+        None,
+    )?;
+
+    assert_eq!(output, "<i>none</i>");
+    // As for any failure, writing the fallback content counts as resolving the include.
+    let outcomes: Vec<_> = report
+        .includes
+        .iter()
+        .map(|include| include.outcome)
+        .collect();
+    assert_eq!(outcomes, [IncludeOutcome::Failed, IncludeOutcome::Ok]);
+
+    let err = process(r#"<esi:include src="/down"/>"#, None).unwrap_err();
+    assert!(matches!(
+        err,
+        ExecutionError::FragmentFailed {
+            status: 503,
+            phase: FailurePhase::Primary,
+            ..
+        }
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn the_response_processor_sees_a_marked_503() -> Result<(), ExecutionError> {
+    // Even when the include accepts it, the stand-in response counts as a failure.
+    let (output, ..) = process(
+        r#"<esi:include src="/down" accept-status="503" onerror="continue"/>"#,
+        None,
+    )?;
+    assert_eq!(output, "");
+
+    // A response processor can serve stale content in its place.
+    let (output, ..) = process(
+        r#"<esi:include src="/down"/>"#,
+        Some(&|_req, resp| {
+            assert_eq!(resp.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+            Ok(match resp.get_header_str(FAIL_FAST_HEADER) {
+                Some(reason) => Response::from_body(format!("[stale: {reason}]")),
+                None => resp,
+            })
+        }),
+    )?;
+    assert_eq!(output, "[stale: circuit open for catalog]");
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use esi::failure::{resolve_fragment_failure, FailureMechanisms, FailureResolution, PRECEDENCE};
+use esi::{
+    Configuration, ExecutionError, InsecureFragmentPolicy, PendingFragmentContent, Processor,
+    Reader, Writer,
+};
+use fastly::Response;
+
+#[test]
+fn documented_precedence_matches_the_constant() {
+    let rendered = PRECEDENCE
+        .iter()
+        .enumerate()
+        .map(|(i, (name, description))| format!("//! {}. {name}: {description}", i + 1))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    assert!(
+        include_str!("../src/failure.rs").contains(&rendered),
+        "the documentation of esi::failure doesn't list PRECEDENCE:\n{rendered}"
+    );
+}
+
+// Each step of `PRECEDENCE`, with whether it applies to an include and what it resolves to then
+type Step = (
+    fn(&FailureMechanisms) -> bool,
+    fn(&FailureMechanisms) -> FailureResolution,
+);
+
+const STEPS: [Step; 6] = [
+    (|m| m.status_accepted, |_| FailureResolution::Accepted),
+    (|m| m.alt, |_| FailureResolution::Alt),
+    (|m| m.fallback, |_| FailureResolution::Fallback),
+    (
+        |m| m.continue_on_error,
+        |m| match m.retry_pass {
+            true => FailureResolution::Retry,
+            false => FailureResolution::Skip,
+        },
+    ),
+    (|m| m.in_block, |_| FailureResolution::FailBlock),
+    (|_| true, |_| FailureResolution::FailDocument),
+];
+
+#[test]
+fn every_combination_resolves_to_the_first_step_that_applies() {
+    assert_eq!(STEPS.len(), PRECEDENCE.len());
+
+    for bits in 0..1 << 6 {
+        let mechanisms = FailureMechanisms {
+            status_accepted: bits & 1 != 0,
+            alt: bits & 2 != 0,
+            fallback: bits & 4 != 0,
+            continue_on_error: bits & 8 != 0,
+            retry_pass: bits & 16 != 0,
+            in_block: bits & 32 != 0,
+        };
+        let (step, (_, resolve)) = STEPS
+            .iter()
+            .enumerate()
+            .find(|(_, (applies, _))| applies(&mechanisms))
+            .unwrap();
+
+        assert_eq!(
+            resolve_fragment_failure(&mechanisms),
+            resolve(&mechanisms),
+            "{mechanisms:?} should be resolved by step {}, {}",
+            step + 1,
+            PRECEDENCE[step].0
+        );
+    }
+}
+
+// Process a single include whose requests all fail, and return the output.
+fn process_failing(configuration: Configuration, include: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(&format!("<p>{include}</p>")),
+        &mut writer,
+        Some(&|_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_status(500),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn failed_alt_falls_back_to_the_content_before_onerror() -> Result<(), ExecutionError> {
+    let output = process_failing(
+        Configuration::default(),
+        r#"<esi:include src="/a" alt="/b" onerror="continue">fallback</esi:include>"#,
+    )?;
+
+    assert_eq!(output, "<p>fallback</p>");
+
+    Ok(())
+}
+
+#[test]
+fn rejected_urls_fall_back_to_the_content_too() -> Result<(), ExecutionError> {
+    let configuration =
+        || Configuration::default().with_upgrade_insecure_fragments(InsecureFragmentPolicy::Reject);
+
+    // A rejected `src` without an `alt`
+    let output = process_failing(
+        configuration(),
+        r#"<esi:include src="http://example.com/a">fallback</esi:include>"#,
+    )?;
+    assert_eq!(output, "<p>fallback</p>");
+
+    // A rejected `alt` after a rejected `src`
+    let output = process_failing(
+        configuration(),
+        r#"<esi:include src="http://example.com/a" alt="http://example.com/b">fallback</esi:include>"#,
+    )?;
+    assert_eq!(output, "<p>fallback</p>");
+
+    // A rejected `alt` after a failed `src`
+    let output = process_failing(
+        configuration(),
+        r#"<esi:include src="https://example.com/a" alt="http://example.com/b">fallback</esi:include>"#,
+    )?;
+    assert_eq!(output, "<p>fallback</p>");
+
+    // Without fallback content, the rejection fails processing.
+    let res = process_failing(
+        configuration(),
+        r#"<esi:include src="https://example.com/a" alt="http://example.com/b"/>"#,
+    );
+    assert!(matches!(res, Err(ExecutionError::InsecureFragmentUrl(_))));
+
+    Ok(())
+}
+
+#[test]
+fn failures_inside_groups_fail_the_group_unless_the_include_handles_them(
+) -> Result<(), ExecutionError> {
+    let output = process_failing(
+        Configuration::default(),
+        r#"<esi:group name="g" onerror="omit"><esi:include src="/a"/>kept</esi:group>"#,
+    )?;
+    assert_eq!(output, "<p></p>");
+
+    let output = process_failing(
+        Configuration::default(),
+        r#"<esi:group name="g" onerror="omit"><esi:include src="/a" onerror="continue"/>kept</esi:group>"#,
+    )?;
+    assert_eq!(output, "<p>kept</p>");
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use std::cell::Cell;
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration;
+
+use esi::{
+    Configuration, ExecutionError, OutputConditions, OutputEnding, OversizedDocumentPolicy,
+    PendingFragmentContent, Processor, Reader, Writer,
+};
+use fastly::Response;
+
+const MARKER: &str = "<!-- esi: document truncated -->";
+
+// `/flaky` fails the first time it's requested, `/down` always fails, and `/slow` takes a while.
+fn process(template: &str, configuration: Configuration) -> Result<String, ExecutionError> {
+    let flaky_attempts = Cell::new(0);
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let response = match req.get_path() {
+                "/flaky" if flaky_attempts.replace(flaky_attempts.get() + 1) == 0 => {
+                    Response::from_status(503)
+                }
+                "/down" => Response::from_status(503),
+                "/slow" => {
+                    sleep(Duration::from_millis(200));
+                    Response::from_body("[/slow]")
+                }
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+// An output whose client has gone away.
+struct Disconnected;
+
+impl Write for Disconnected {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+#[test]
+fn every_combination_of_conditions_has_one_ending() {
+    let truncate = OversizedDocumentPolicy::Truncate {
+        marker: MARKER.to_string(),
+    };
+    for bits in 0..64 {
+        let conditions = OutputConditions {
+            deadline_hit: bits & 1 != 0,
+            size_limit_hit: bits & 2 != 0,
+            fragment_failed: bits & 4 != 0,
+            client_disconnected: bits & 8 != 0,
+            retry_pass: bits & 16 != 0,
+            event_limit_hit: bits & 32 != 0,
+        };
+        let limit_hit =
+            conditions.deadline_hit || conditions.size_limit_hit || conditions.event_limit_hit;
+
+        for policy in [&truncate, &OversizedDocumentPolicy::Fail] {
+            let expected = if conditions.client_disconnected {
+                OutputEnding::Disconnected
+            } else if conditions.fragment_failed
+                || (limit_hit && *policy == OversizedDocumentPolicy::Fail)
+            {
+                OutputEnding::Failed
+            } else if limit_hit {
+                OutputEnding::Truncated
+            } else {
+                OutputEnding::Complete {
+                    retries: conditions.retry_pass,
+                }
+            };
+            assert_eq!(
+                conditions.ending(policy),
+                expected,
+                "{conditions:?} with {policy:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn a_deadline_during_the_document_drops_the_retries() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default()
+        .with_deadline(Duration::from_millis(100))
+        .with_retry_pass(5, Duration::ZERO);
+    let output = process(
+        r#"<p>a</p><esi:include src="/flaky" onerror="continue"/><esi:include src="/slow"/><p>b</p>"#,
+        configuration,
+    )?;
+
+    assert_eq!(output, format!("<p>a</p>{MARKER}"));
+
+    Ok(())
+}
+
+#[test]
+fn retries_are_only_appended_to_a_complete_document() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<p>a</p><esi:include src="/flaky" onerror="continue"/><p>b</p>"#,
+        Configuration::default().with_retry_pass(5, Duration::ZERO),
+    )?;
+
+    assert_eq!(
+        output,
+        concat!(
+            r#"<p>a</p><template data-esi-slot="0"></template><p>b</p>"#,
+            r#"<template data-esi-slot-content="0">[/flaky]</template>"#,
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn a_failed_fragment_is_not_followed_by_the_truncation_marker() {
+    let template = r#"<esi:include src="/down"/><p>one</p><p>two</p>"#;
+    let configuration = Configuration::default().with_max_document_size(template.len() - 4);
+
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+    let res = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|_| Ok(Response::from_status(503).into())),
+        None,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::FragmentFailed { status: 503, .. })
+    ));
+    assert!(!String::from_utf8(writer.into_inner())
+        .unwrap()
+        .contains(MARKER));
+}
+
+#[test]
+fn a_disconnected_client_gets_no_truncation_marker() {
+    let processor = Processor::new(None, Configuration::default().with_deadline(Duration::ZERO));
+    let outcome = processor.outcome();
+    let mut writer = Writer::new(Disconnected);
+
+    let res = processor.process_document(
+        Reader::from_str("<p>a</p><p>b</p>"),
+        &mut writer,
+        None,
+        None,
+    );
+
+    assert!(matches!(res, Err(ExecutionError::WriterError(_))));
+    assert!(!outcome.recovery_write_allowed());
+}
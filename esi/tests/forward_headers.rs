@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, ForwardHeadersOverride, HeaderForwarding,
+    PendingFragmentContent, Processor, Reader, Writer, FORWARDED_HEADERS_HEADER,
+};
+use fastly::http::header;
+use fastly::{Request, Response};
+
+// Processes `template` and returns a line for each dispatched request, with the headers it carried.
+fn dispatched_headers(
+    configuration: Configuration,
+    template: &str,
+) -> Result<Vec<String>, ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page")
+                .with_header("authorization", "Bearer token")
+                .with_header("accept-language", "fr")
+                .with_header("cookie", "uid=1"),
+        ),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let header = |name| req.get_header_str(name).unwrap_or("-").to_string();
+            dispatched.borrow_mut().push(format!(
+                "{} host={} auth={} lang={} cookie={} forwarded={}",
+                req.get_path(),
+                header("host"),
+                header("authorization"),
+                header("accept-language"),
+                header("cookie"),
+                header(FORWARDED_HEADERS_HEADER),
+            ));
+            Ok(match req.get_path() {
+                "/fail" => Response::from_status(500).into(),
+                _ => PendingFragmentContent::NoContent,
+            })
+        }),
+        None,
+    )?;
+
+    Ok(requests.take())
+}
+
+const TEMPLATE: &str = r#"<esi:include src="/plain"/><esi:include src="/account" forward-headers="Authorization accept-language"/><esi:include src="/bare" forward-headers=""/>"#;
+
+#[test]
+fn include_override_with_no_global_forwarding() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default().with_header_forwarding(HeaderForwarding::None);
+
+    assert_eq!(
+        dispatched_headers(configuration, TEMPLATE)?,
+        [
+            "/plain host=www.example.com auth=- lang=- cookie=- forwarded=",
+            "/account host=www.example.com auth=Bearer token lang=fr cookie=- forwarded=authorization accept-language",
+            "/bare host=www.example.com auth=- lang=- cookie=- forwarded=",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn include_override_with_global_forwarding_of_all_headers() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_headers(Configuration::default(), TEMPLATE)?,
+        [
+            "/plain host=www.example.com auth=Bearer token lang=fr cookie=uid=1 forwarded=-",
+            "/account host=www.example.com auth=Bearer token lang=fr cookie=- forwarded=authorization accept-language",
+            "/bare host=www.example.com auth=- lang=- cookie=- forwarded=",
+        ]
+    );
+
+    // Extending every header leaves nothing out, apart from with an empty list.
+    let configuration =
+        Configuration::default().with_forward_headers_override(ForwardHeadersOverride::Extend);
+    assert_eq!(
+        dispatched_headers(configuration, TEMPLATE)?,
+        [
+            "/plain host=www.example.com auth=Bearer token lang=fr cookie=uid=1 forwarded=-",
+            "/account host=www.example.com auth=Bearer token lang=fr cookie=uid=1 forwarded=-",
+            "/bare host=www.example.com auth=- lang=- cookie=- forwarded=",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn include_override_extends_the_global_allowlist() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default()
+        .with_header_forwarding(HeaderForwarding::Allowlist(vec![header::COOKIE]))
+        .with_forward_headers_override(ForwardHeadersOverride::Extend);
+
+    assert_eq!(
+        dispatched_headers(configuration, TEMPLATE)?,
+        [
+            "/plain host=www.example.com auth=- lang=- cookie=uid=1 forwarded=cookie",
+            "/account host=www.example.com auth=Bearer token lang=fr cookie=uid=1 forwarded=cookie authorization accept-language",
+            "/bare host=www.example.com auth=- lang=- cookie=- forwarded=",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn alt_requests_forward_the_same_headers() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default().with_header_forwarding(HeaderForwarding::None);
+
+    assert_eq!(
+        dispatched_headers(
+            configuration,
+            r#"<esi:include src="/fail" alt="http://alt.example.com/alt" forward-headers="authorization"/>"#,
+        )?,
+        [
+            "/fail host=www.example.com auth=Bearer token lang=- cookie=- forwarded=authorization",
+            "/alt host=alt.example.com auth=Bearer token lang=- cookie=- forwarded=authorization",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn invalid_header_names_are_rejected() {
+    let result = dispatched_headers(
+        Configuration::default(),
+        r#"<esi:include src="/a" forward-headers="accept language:"/>"#,
+    );
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::InvalidAttributeValue(tag, attribute, _))
+            if tag == "esi:include" && attribute == "forward-headers"
+    ));
+}
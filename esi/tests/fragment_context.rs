@@ -0,0 +1,115 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use esi::{
+    Configuration, ExecutionError, FragmentContext, PendingFragmentContent, Processor, Reader,
+    TryArmKind, Writer,
+};
+use fastly::{Request, Response};
+
+// Processes the template with a dispatcher that fails the paths in `failing`, returning the output
+// and the context of every fragment response, in the order they were processed.
+fn process(
+    configuration: Configuration,
+    template: &str,
+    failing: &'static [&'static str],
+) -> Result<(String, Vec<FragmentContext>), ExecutionError> {
+    let contexts = Rc::new(RefCell::new(Vec::new()));
+    let processed = Rc::clone(&contexts);
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page?lang=fr")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document_with_context(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            let response = match failing.contains(&req.get_path()) {
+                true => Response::from_status(500),
+                false => Response::from_body(format!("[{}]", req.get_path())),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        Some(&move |context, _, resp| {
+            processed.borrow_mut().push(context.clone());
+            Ok(resp)
+        }),
+    )?;
+
+    let contexts = contexts.take();
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), contexts))
+}
+
+fn summary(context: &FragmentContext) -> (&str, bool, Option<&TryArmKind>, usize) {
+    (
+        context.src.as_str(),
+        context.is_alt,
+        context.arm.as_ref(),
+        context.index,
+    )
+}
+
+#[test]
+fn contexts_describe_where_fragments_come_from() -> Result<(), ExecutionError> {
+    let (output, contexts) = process(
+        Configuration::default(),
+        r#"<esi:include src="/a?lang=$(QUERY_STRING{lang})" alt="/b"/><esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:except><esi:include src="/c"/></esi:except></esi:try><esi:include src="/d"/>"#,
+        &["/a", "/fail"],
+    )?;
+
+    assert_eq!(output, "[/b][/c][/d]");
+    assert_eq!(
+        contexts.iter().map(summary).collect::<Vec<_>>(),
+        [
+            ("/a?lang=$(QUERY_STRING{lang})", false, None, 0),
+            ("/a?lang=$(QUERY_STRING{lang})", true, None, 0),
+            ("/fail", false, Some(&TryArmKind::Attempt), 1),
+            ("/c", false, Some(&TryArmKind::Except), 2),
+            ("/d", false, None, 3),
+        ]
+    );
+    assert!(contexts.iter().all(|context| !context.is_retry));
+
+    Ok(())
+}
+
+#[test]
+fn includes_in_blocks_inside_arms_have_the_arm() -> Result<(), ExecutionError> {
+    let (_, contexts) = process(
+        Configuration::default(),
+        r#"<esi:try><esi:attempt><esi:group name="g"><esi:include src="/a"/></esi:group></esi:attempt></esi:try><esi:group name="h"><esi:include src="/b"/></esi:group>"#,
+        &[],
+    )?;
+
+    assert_eq!(
+        contexts.iter().map(summary).collect::<Vec<_>>(),
+        [
+            ("/a", false, Some(&TryArmKind::Attempt), 0),
+            ("/b", false, None, 1),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn retries_share_the_index_of_their_include() -> Result<(), ExecutionError> {
+    let (_, contexts) = process(
+        Configuration::default().with_retry_pass(1, Duration::ZERO),
+        r#"<esi:include src="/ok"/><esi:include src="/fail" onerror="continue"/>"#,
+        &["/fail"],
+    )?;
+
+    assert_eq!(
+        contexts
+            .iter()
+            .map(|context| (context.src.as_str(), context.index, context.is_retry))
+            .collect::<Vec<_>>(),
+        [("/ok", 0, false), ("/fail", 1, false), ("/fail", 1, true)]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::{Request, Response};
+
+// Processes the template with a dispatcher that fails `/fail` and answers any other path with the
+// path in brackets, returning the output and the URL of every request it was given.
+fn process(
+    configuration: Configuration,
+    template: &str,
+) -> Result<(String, Vec<String>), ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page").with_header("cookie", "uid=1")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().push(req.get_path().to_string());
+            let response = match req.get_path() {
+                "/fail" => Response::from_status(500),
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    let requests = requests.take();
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), requests))
+}
+
+#[test]
+fn identical_includes_are_dispatched_once() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        Configuration::default().with_fragment_deduplication(true),
+        r#"<esi:include src="/a"/><p>x</p><esi:include src="/a"/><esi:include src="/a"/>"#,
+    )?;
+
+    assert_eq!(output, "[/a]<p>x</p>[/a][/a]");
+    assert_eq!(requests, ["/a"]);
+
+    Ok(())
+}
+
+#[test]
+fn identical_includes_are_dispatched_each_time_by_default() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        Configuration::default(),
+        r#"<esi:include src="/a"/><esi:include src="/a"/><esi:include src="/a"/>"#,
+    )?;
+
+    assert_eq!(output, "[/a][/a][/a]");
+    assert_eq!(requests, ["/a", "/a", "/a"]);
+
+    Ok(())
+}
+
+#[test]
+fn includes_in_blocks_share_requests() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        Configuration::default().with_fragment_deduplication(true),
+        r#"<esi:include src="/a"/><esi:try><esi:attempt><esi:include src="/a"/><esi:include src="/b"/></esi:attempt></esi:try><esi:group name="g"><esi:include src="/b"/></esi:group>"#,
+    )?;
+
+    assert_eq!(output, "[/a][/a][/b][/b]");
+    assert_eq!(requests, ["/a", "/b"]);
+
+    Ok(())
+}
+
+#[test]
+fn includes_forwarding_different_headers_are_dispatched_separately() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        Configuration::default().with_fragment_deduplication(true),
+        r#"<esi:include src="/a"/><esi:include src="/a" forward-headers="cookie"/><esi:include src="/a" forward-headers="cookie"/>"#,
+    )?;
+
+    assert_eq!(output, "[/a][/a][/a]");
+    assert_eq!(requests, ["/a", "/a"]);
+
+    Ok(())
+}
+
+// Each include sharing a failed request falls back in its own way.
+#[test]
+fn failures_are_handled_per_include() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        Configuration::default().with_fragment_deduplication(true),
+        r#"<esi:include src="/fail" alt="/a"/><esi:include src="/fail" onerror="continue"/><esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "[/a]x");
+    assert_eq!(requests, ["/fail", "/a"]);
+
+    Ok(())
+}
+
+#[test]
+fn shared_failure_without_fallback_is_an_error() {
+    let res = process(
+        Configuration::default().with_fragment_deduplication(true),
+        r#"<esi:include src="/a"/><esi:include src="/fail" onerror="continue"/><esi:include src="/fail"/>"#,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::FragmentFailed { url, status: 500, .. }) if url.ends_with("/fail")
+    ));
+}
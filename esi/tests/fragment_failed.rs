@@ -0,0 +1,99 @@
+use esi::{
+    Configuration, ExecutionError, FailurePhase, PendingFragmentContent, Processor, Reader, Writer,
+};
+use fastly::http::header;
+use fastly::{Request, Response};
+
+const ERROR_BODY: &str = "{\"error\": \"upstream timeout\",\n \"trace_id\": \"4bf92f35\"}";
+
+// `/fail` and `/fail-too` fail with a JSON error envelope, and `/echo` with one that repeats the
+// cookie it was sent.
+fn process(template: &str, configuration: Configuration) -> ExecutionError {
+    let mut req = Request::get("http://www.example.com/page");
+    req.set_header(header::COOKIE, "session=s3cr3t");
+    let processor = Processor::new(Some(req), configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor
+        .process_document(
+            Reader::from_str(template),
+            &mut writer,
+            Some(&|req| {
+                let body = match req.get_path() {
+                    "/echo" => format!(
+                        "{{\"error\": \"forbidden\", \"cookie\": \"{}\"}}",
+                        req.get_header_str(header::COOKIE).unwrap_or_default()
+                    ),
+                    _ => ERROR_BODY.to_string(),
+                };
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_status(502).with_body(body),
+                ))
+            }),
+            None,
+        )
+        .unwrap_err()
+}
+
+#[test]
+fn a_failed_include_without_alt_keeps_the_start_of_the_body() {
+    let err = process(r#"<esi:include src="/fail"/>"#, Configuration::default());
+
+    assert!(matches!(
+        &err,
+        ExecutionError::FragmentFailed {
+            url,
+            status: 502,
+            phase: FailurePhase::Primary,
+            body_snippet: Some(snippet),
+        } if url == "http://www.example.com/fail" && snippet == ERROR_BODY
+    ));
+    assert_eq!(
+        err.to_string(),
+        r#"fragment `http://www.example.com/fail` failed with status 502 (primary), body: "{\"error\": \"upstream timeout\",\n \"trace_id\": \"4bf92f35\"}""#
+    );
+}
+
+#[test]
+fn an_include_whose_alt_fails_too_reports_the_alt() {
+    let err = process(
+        r#"<esi:include src="/fail" alt="/fail-too"/>"#,
+        Configuration::default(),
+    );
+
+    assert!(matches!(
+        &err,
+        ExecutionError::FragmentFailed {
+            url,
+            status: 502,
+            phase: FailurePhase::Alt,
+            body_snippet: Some(_),
+        } if url == "http://www.example.com/fail-too"
+    ));
+}
+
+#[test]
+fn the_snippet_is_limited_and_redacted() {
+    let err = process(
+        r#"<esi:include src="/echo"/>"#,
+        Configuration::default().with_error_body_snippet(40),
+    );
+    // The limit falls in the middle of the cookie, which is still redacted.
+    assert!(matches!(
+        err,
+        ExecutionError::FragmentFailed { body_snippet: Some(snippet), .. }
+            if snippet == r#"{"error": "forbidden", "cookie": "[redac"#
+    ));
+
+    let err = process(
+        r#"<esi:include src="/fail"/>"#,
+        Configuration::default().with_error_body_snippet(0),
+    );
+    assert!(matches!(
+        err,
+        ExecutionError::FragmentFailed {
+            body_snippet: None,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,58 @@
+use esi::{FragmentKey, KeySpec};
+use fastly::http::header;
+use fastly::Request;
+
+fn request(cookie: Option<&str>) -> Request {
+    let request = Request::get("https://www.example.com/slot?id=1");
+    match cookie {
+        Some(cookie) => request.with_header(header::COOKIE, cookie),
+        None => request,
+    }
+}
+
+#[test]
+fn key_is_the_method_and_url_by_default() {
+    let key = FragmentKey::compute(&request(Some("uid=1")), &KeySpec::default());
+
+    assert_eq!(key.as_str(), "GET https://www.example.com/slot?id=1");
+    assert_eq!(
+        key,
+        FragmentKey::compute(&request(None), &KeySpec::default())
+    );
+}
+
+#[test]
+fn same_url_with_differing_cookies_has_distinct_keys() {
+    let spec = KeySpec::default().with_header(header::COOKIE);
+
+    let forwarded = FragmentKey::compute(&request(Some("uid=1")), &spec);
+    let stripped = FragmentKey::compute(&request(None), &spec);
+    let empty = FragmentKey::compute(&request(Some("")), &spec);
+
+    assert_eq!(
+        forwarded.as_str(),
+        "GET https://www.example.com/slot?id=1\ncookie: uid=1"
+    );
+    assert_ne!(forwarded, stripped);
+    assert_ne!(stripped, empty);
+    assert_eq!(
+        forwarded,
+        FragmentKey::compute(&request(Some("uid=1")), &spec)
+    );
+}
+
+#[test]
+fn key_headers_are_ordered_by_name() {
+    let spec = KeySpec::default()
+        .with_header(header::COOKIE)
+        .with_header(header::ACCEPT_LANGUAGE)
+        .with_header(header::COOKIE);
+
+    assert_eq!(spec.headers(), [header::ACCEPT_LANGUAGE, header::COOKIE]);
+    assert_eq!(
+        spec,
+        KeySpec::default()
+            .with_header(header::ACCEPT_LANGUAGE)
+            .with_header(header::COOKIE)
+    );
+}
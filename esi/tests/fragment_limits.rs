@@ -0,0 +1,179 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer,
+    TRUNCATED_FRAGMENT_MARKER,
+};
+use fastly::Response;
+
+// Processes the template with a dispatcher that answers `/big` with a body of `big` bytes, fails
+// `/fail`, and answers any other path with the path in brackets.
+fn process(
+    configuration: Configuration,
+    template: &str,
+    big: usize,
+) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let response = match req.get_path() {
+                "/big" => Response::from_body("x".repeat(big)),
+                "/fail" => Response::from_status(500),
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn includes_past_the_limit_fail_processing() {
+    let res = process(
+        Configuration::default().with_max_fragments(2),
+        r#"<esi:include src="/a"/><esi:include src="/b"/><esi:include src="/c"/>"#,
+        0,
+    );
+
+    assert!(matches!(res, Err(ExecutionError::TooManyFragments(2))));
+}
+
+#[test]
+fn includes_up_to_the_limit_are_written() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragments(2),
+        r#"<esi:include src="/a"/><esi:include src="/b"/>"#,
+        0,
+    )?;
+
+    assert_eq!(output, "[/a][/b]");
+
+    Ok(())
+}
+
+#[test]
+fn includes_in_attempts_count_toward_the_limit() {
+    let res = process(
+        Configuration::default().with_max_fragments(1),
+        r#"<esi:include src="/a"/><esi:try><esi:attempt><esi:include src="/b"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+        0,
+    );
+
+    assert!(matches!(res, Err(ExecutionError::TooManyFragments(1))));
+}
+
+// The request for an `alt` URL belongs to the include it falls back for.
+#[test]
+fn alt_requests_do_not_count_toward_the_limit() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragments(1),
+        r#"<esi:include src="/fail" alt="/a"/>"#,
+        0,
+    )?;
+
+    assert_eq!(output, "[/a]");
+
+    Ok(())
+}
+
+#[test]
+fn fragments_up_to_the_size_limit_are_written() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragment_size(100),
+        r#"<esi:include src="/big"/>"#,
+        100,
+    )?;
+
+    assert_eq!(output, "x".repeat(100));
+
+    Ok(())
+}
+
+#[test]
+fn fragment_over_the_size_limit_fails_processing() {
+    let res = process(
+        Configuration::default().with_max_fragment_size(20),
+        r#"<esi:include src="/big"/>"#,
+        100,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::FragmentTooLarge(url, 20)) if url.ends_with("/big")
+    ));
+}
+
+#[test]
+fn fragment_over_the_size_limit_falls_back_to_alt() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragment_size(20),
+        r#"<p>a</p><esi:include src="/big" alt="/a"/><p>b</p>"#,
+        100,
+    )?;
+
+    assert_eq!(output, "<p>a</p>[/a]<p>b</p>");
+
+    Ok(())
+}
+
+#[test]
+fn fragment_over_the_size_limit_continues_on_error() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragment_size(20),
+        r#"<p>a</p><esi:include src="/big" onerror="continue"/><p>b</p>"#,
+        100,
+    )?;
+
+    assert_eq!(output, "<p>a</p><p>b</p>");
+
+    Ok(())
+}
+
+// What was streamed of a fragment before it went over the limit stays written, and is marked.
+#[test]
+fn fragment_over_the_size_limit_is_truncated_at_top_level() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragment_size(10_000),
+        r#"<p>a</p><esi:include src="/big" onerror="continue"/><p>b</p>"#,
+        20_000,
+    )?;
+
+    let written = output
+        .strip_prefix("<p>a</p>")
+        .and_then(|rest| rest.strip_suffix("<p>b</p>"))
+        .and_then(|rest| rest.strip_suffix(TRUNCATED_FRAGMENT_MARKER))
+        .unwrap();
+    assert!(!written.is_empty() && written.len() <= 10_000);
+    assert!(written.bytes().all(|b| b == b'x'));
+
+    Ok(())
+}
+
+#[test]
+fn fragment_over_the_size_limit_in_an_attempt_runs_the_except() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_max_fragment_size(10_000),
+        r#"<esi:try><esi:attempt><p>a</p><esi:include src="/big"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+        20_000,
+    )?;
+
+    assert_eq!(output, "x");
+
+    Ok(())
+}
+
+#[test]
+fn limit_errors_describe_the_limit() {
+    assert_eq!(
+        ExecutionError::TooManyFragments(50).to_string(),
+        "document has more than 50 includes"
+    );
+    assert_eq!(
+        ExecutionError::FragmentTooLarge("http://localhost/big".to_string(), 1024).to_string(),
+        "body of fragment `http://localhost/big` is over the limit of 1024 bytes"
+    );
+}
@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+use esi::{
+    BoundarySink, Configuration, ExecutionError, FragmentBoundary, MarkerStyle,
+    PendingFragmentContent, Processor, Reader, Writer,
+};
+use fastly::Response;
+
+const TEMPLATE: &str = concat!(
+    r#"<p>a</p><esi:include src="/ok"/>"#,
+    r#"<esi:try><esi:attempt><esi:include src="/ok?arm=1"/><esi:include src="/down"/></esi:attempt>"#,
+    r#"<esi:except><esi:include src="/ok?arm=2"/></esi:except></esi:try>"#,
+    r#"<esi:group name="pair" onerror="omit"><esi:include src="/down" alt="/ok?alt"/></esi:group>"#,
+    r#"<esi:include src="/flaky" onerror="continue"/><p>b</p>"#,
+);
+
+// An output that can be looked at while it's being written.
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn process(configuration: Configuration, output: SharedOutput) -> Result<String, ExecutionError> {
+    let attempts = RefCell::new(0);
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(output.clone());
+
+    processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&move |req| {
+            let response = match req.get_path() {
+                "/down" => Response::from_status(503),
+                // Fails the first time, then succeeds when it's retried.
+                "/flaky" if attempts.replace_with(|n| *n + 1) == 0 => Response::from_status(503),
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    let output = output.0.borrow().clone();
+    Ok(String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn comments_mark_every_kind_of_fragment_content() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default()
+        .with_fragment_markers(MarkerStyle::HtmlComment)
+        .with_retry_pass(1, Duration::ZERO);
+
+    assert_eq!(
+        process(configuration, SharedOutput::default())?,
+        concat!(
+            "<p>a</p><!--esi-frag start slot=0 src=http://localhost/ok -->[/ok]<!--esi-frag end slot=0 -->",
+            "<!--esi-frag start slot=3 src=http://localhost/ok -->[/ok]<!--esi-frag end slot=3 -->",
+            "<!--esi-frag start slot=4 src=http://localhost/ok -->[/ok]<!--esi-frag end slot=4 -->",
+            r#"<!--esi-frag start slot=5 src=http://localhost/flaky --><template data-esi-slot="0"></template><!--esi-frag end slot=5 -->"#,
+            "<p>b</p>",
+            r#"<template data-esi-slot-content="0"><!--esi-frag start slot=5 src=http://localhost/flaky -->[/flaky]<!--esi-frag end slot=5 --></template>"#,
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn callbacks_report_boundaries_where_comments_would_be() -> Result<(), ExecutionError> {
+    let with_comments = process(
+        Configuration::default().with_fragment_markers(MarkerStyle::HtmlComment),
+        SharedOutput::default(),
+    )?;
+
+    let output = SharedOutput::default();
+    let boundaries = Rc::new(RefCell::new(Vec::new()));
+    let sink = {
+        let output = output.clone();
+        let boundaries = Rc::clone(&boundaries);
+        BoundarySink::new(move |boundary: &FragmentBoundary| {
+            let offset = output.0.borrow().len();
+            boundaries.borrow_mut().push((offset, boundary.clone()));
+        })
+    };
+    let without_comments = process(
+        Configuration::default().with_fragment_markers(MarkerStyle::Callback(sink)),
+        output,
+    )?;
+
+    // Putting the comments in at the offsets the callback saw gives the same output.
+    let mut rebuilt = without_comments.clone();
+    for (offset, boundary) in boundaries.take().iter().rev() {
+        rebuilt.insert_str(*offset, &boundary.to_string());
+    }
+    assert!(!without_comments.contains("esi-frag"));
+    assert_eq!(rebuilt, with_comments);
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+
+// Source markup with namespace declarations around an include whose body leaves tags unclosed
+// and closes tags it never opened.
+const CONTENT: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><use xlink:href="#icon"/></svg><x:list xmlns:x="urn:example"><x:item><esi:include src="/unbalanced"/></x:item><x:item a="1 &amp; 2">after</x:item></x:list>"##;
+
+const EXPECTED: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><use xlink:href="#icon"/></svg><x:list xmlns:x="urn:example"><x:item></span><div><p>open</x:item><x:item a="1 &amp; 2">after</x:item></x:list>"##;
+
+fn process(template: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let mut reader = Reader::from_str(template);
+    reader.config_mut().check_end_names = false;
+
+    processor.process_document(
+        reader,
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                match req.get_path() {
+                    "/unbalanced" => Response::from_body("</span><div><p>open"),
+                    _ => Response::new(),
+                },
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn streamed_output_keeps_unbalanced_fragment_markup() -> Result<(), ExecutionError> {
+    assert_eq!(process(CONTENT)?, EXPECTED);
+
+    Ok(())
+}
+
+#[test]
+fn buffered_output_matches_streamed_output() -> Result<(), ExecutionError> {
+    // The leading try stays queued until the end of the document, so all of the content after it
+    // is buffered rather than streamed.
+    let buffered = process(&format!(
+        r#"<esi:try><esi:attempt><esi:include src="/empty"/></esi:attempt></esi:try>{CONTENT}"#
+    ))?;
+
+    assert_eq!(buffered, EXPECTED);
+
+    Ok(())
+}
+
+#[test]
+fn try_arm_output_matches_streamed_output() -> Result<(), ExecutionError> {
+    let output = process(&format!(
+        r#"<esi:try><esi:attempt>{CONTENT}</esi:attempt><esi:except>failed</esi:except></esi:try>"#
+    ))?;
+
+    assert_eq!(output, EXPECTED);
+
+    Ok(())
+}
+
+#[test]
+fn nested_try_arm_output_matches_streamed_output() -> Result<(), ExecutionError> {
+    let output = process(&format!(
+        r#"<esi:try><esi:attempt><esi:try><esi:attempt>{CONTENT}</esi:attempt></esi:try>{CONTENT}</esi:attempt></esi:try>"#
+    ))?;
+
+    assert_eq!(output, format!("{EXPECTED}{EXPECTED}"));
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+
+fn process(configuration: Configuration, template: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn fragments_that_are_ready_in_time_are_written() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_fragment_timeout(Duration::from_secs(1)),
+        r#"<p>a</p><esi:include src="/one"/><esi:include src="/two"/><p>b</p>"#,
+    )?;
+
+    assert_eq!(output, "<p>a</p>[/one][/two]<p>b</p>");
+
+    Ok(())
+}
+
+// A response that's already complete is used even when the timeout has no time to spare.
+#[test]
+fn completed_responses_are_not_timed_out() -> Result<(), ExecutionError> {
+    let output = process(
+        Configuration::default().with_fragment_timeout(Duration::ZERO),
+        r#"<esi:try><esi:attempt><esi:include src="/one"/></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "[/one]");
+
+    Ok(())
+}
+
+#[test]
+fn timeout_is_described() {
+    let configuration = Configuration::default();
+    assert_eq!(
+        configuration.describe().get("fragment_timeout_ms"),
+        Some("none")
+    );
+
+    let configuration = configuration.with_fragment_timeout(Duration::from_millis(250));
+    assert_eq!(
+        configuration.describe().get("fragment_timeout_ms"),
+        Some("250")
+    );
+}
+
+#[test]
+fn timeout_error_names_the_fragment() {
+    let err = ExecutionError::FragmentTimeout(
+        "http://localhost/slow".to_string(),
+        Duration::from_millis(1200),
+    );
+
+    assert_eq!(
+        err.to_string(),
+        "fragment `http://localhost/slow` timed out after 1200ms"
+    );
+}
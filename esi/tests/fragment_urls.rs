@@ -0,0 +1,507 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, FailurePhase, InsecureFragmentPolicy, PendingFragmentContent,
+    Processor, Reader, Writer,
+};
+use fastly::{Request, Response};
+
+// Every include has a primary `src` under /p/ that fails, and an `alt` under /a/ that is skipped,
+// so that the dispatcher sees both URLs for every position in the template. The alt in the attempt
+// arm fails too, so that the except arm is used.
+const TEMPLATE: &str = r#"<html>
+<esi:include src="/p/top?a=1&amp;b=2" alt="/a/top?a=1&amp;b=2"/>
+<esi:try>
+    <esi:attempt>
+        <esi:try>
+            <esi:attempt>
+                <esi:include src="/p/nested?a=1&amp;b=2" alt="/a/nested?a=1&amp;b=2"/>
+            </esi:attempt>
+            <esi:except></esi:except>
+        </esi:try>
+        <esi:include src="/p/attempt?a=1&amp;b=2" alt="/a/attempt?a=1&amp;b=2"/>
+    </esi:attempt>
+    <esi:except>
+        <esi:include src="/p/except?a=1&amp;b=2" alt="/a/except?a=1&amp;b=2"/>
+    </esi:except>
+</esi:try>
+</html>"#;
+
+fn dispatched_urls(configuration: Configuration) -> Result<Vec<String>, ExecutionError> {
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&urls);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&move |req| {
+            let url = req.get_url_str().to_string();
+            let fails = req.get_path().starts_with("/p/") || req.get_path() == "/a/attempt";
+            dispatched.borrow_mut().push(url);
+            if fails {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_status(500),
+                ))
+            } else {
+                Ok(PendingFragmentContent::NoContent)
+            }
+        }),
+        None,
+    )?;
+
+    let mut urls = urls.take();
+    urls.sort();
+    Ok(urls)
+}
+
+fn expected_urls(query: &str) -> Vec<String> {
+    let mut urls: Vec<String> = ["a", "p"]
+        .iter()
+        .flat_map(|kind| {
+            ["attempt", "except", "nested", "top"]
+                .iter()
+                .map(move |position| format!("http://www.example.com/{kind}/{position}?{query}"))
+        })
+        .collect();
+    urls.sort();
+    urls
+}
+
+#[test]
+fn escaped_urls_are_unescaped_at_every_position() -> Result<(), ExecutionError> {
+    let urls = dispatched_urls(Configuration::default())?;
+
+    assert_eq!(urls, expected_urls("a=1&b=2"));
+
+    Ok(())
+}
+
+#[test]
+fn unescaped_urls_are_left_untouched_at_every_position() -> Result<(), ExecutionError> {
+    let urls = dispatched_urls(Configuration::default().with_escaped(false))?;
+
+    assert_eq!(urls, expected_urls("a=1&amp;b=2"));
+
+    Ok(())
+}
+
+// Dispatch a single include whose `src` fails, so that its `alt` is dispatched too.
+fn dispatched_include_urls(
+    configuration: Configuration,
+    src: &str,
+    alt: &str,
+) -> Result<Vec<String>, ExecutionError> {
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&urls);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(&format!(r#"<esi:include src="{src}" alt="{alt}"/>"#)),
+        &mut writer,
+        Some(&move |req| {
+            let is_primary = dispatched.borrow().is_empty();
+            dispatched.borrow_mut().push(req.get_url_str().to_string());
+            if is_primary {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_status(500),
+                ))
+            } else {
+                Ok(PendingFragmentContent::NoContent)
+            }
+        }),
+        None,
+    )?;
+
+    Ok(urls.take())
+}
+
+#[test]
+fn urls_are_unescaped_once_whether_or_not_they_were_escaped() -> Result<(), ExecutionError> {
+    // The attribute as written, then the URL dispatched with escaping on and off.
+    let cases = [
+        ("/f;v=1?a=1&b=2", "/f;v=1?a=1&b=2", "/f;v=1?a=1&b=2"),
+        ("/f;v=1?a=1&amp;b=2", "/f;v=1?a=1&b=2", "/f;v=1?a=1&amp;b=2"),
+        ("/f?a=1&b=2&amp;c=3", "/f?a=1&b=2&c=3", "/f?a=1&b=2&amp;c=3"),
+        // Unescaped, the `#` starts a fragment identifier.
+        ("/f?a=&#38;&amp;b=&#x26;", "/f?a=&&b=&", "/f?a=&"),
+        ("/f?a=&amp;amp;", "/f?a=&amp;", "/f?a=&amp;amp;"),
+        ("/f?a=&unknown;&b", "/f?a=&unknown;&b", "/f?a=&unknown;&b"),
+        ("/f?m=&lt;x&gt;", "/f?m=%3Cx%3E", "/f?m=&lt;x&gt;"),
+    ];
+
+    for (written, escaped, unescaped) in cases {
+        for (is_escaped, expected) in [(true, escaped), (false, unescaped)] {
+            let configuration = Configuration::default().with_escaped(is_escaped);
+            let alt = written.replacen("/f", "/alt", 1);
+            let urls = dispatched_include_urls(configuration, written, &alt)?;
+
+            let expected = format!("http://www.example.com{expected}");
+            assert_eq!(
+                urls,
+                [expected.clone(), expected.replacen("/f", "/alt", 1)],
+                "{written} with escaping {is_escaped}"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn unsafe_characters_are_percent_encoded() -> Result<(), ExecutionError> {
+    let urls = dispatched_include_urls(
+        Configuration::default(),
+        "/search?q=red shoes&amp;f={a|b}",
+        "http://other.example.com/a `b`?q=&quot;^&quot;",
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/search?q=red%20shoes&f=%7Ba%7Cb%7D",
+            "http://other.example.com/a%20%60b%60?q=%22%5E%22",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn percent_encoded_sequences_are_not_encoded_twice() -> Result<(), ExecutionError> {
+    let urls = dispatched_include_urls(
+        Configuration::default(),
+        "/search?q=red%20shoes 100%",
+        "http://other.example.com/100%25 off",
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/search?q=red%20shoes%20100%25",
+            "http://other.example.com/100%25%20off",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unicode_characters_are_percent_encoded() -> Result<(), ExecutionError> {
+    let urls = dispatched_include_urls(
+        Configuration::default(),
+        "/caf\u{e9}?q=cr\u{e8}me br\u{fb}l\u{e9}e",
+        "http://other.example.com/\u{e9}t\u{e9}",
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/caf%C3%A9?q=cr%C3%A8me%20br%C3%BBl%C3%A9e",
+            "http://other.example.com/%C3%A9t%C3%A9",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fragment_identifiers_are_left_out_of_the_path_and_query() -> Result<(), ExecutionError> {
+    let urls = dispatched_include_urls(
+        Configuration::default(),
+        "/a b?q=1#top of page",
+        "http://other.example.com/a b#top",
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/a%20b?q=1",
+            "http://other.example.com/a%20b#top",
+        ]
+    );
+
+    Ok(())
+}
+
+// The rejected `src` is never requested, so the `alt` is the first request, which fails.
+#[test]
+fn strict_urls_reject_unsafe_characters() {
+    let res = dispatched_include_urls(
+        Configuration::default().with_strict_urls(true),
+        "/search?q=red shoes",
+        "/fallback",
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::FragmentFailed { url, phase: FailurePhase::Alt, .. })
+            if url == "http://www.example.com/fallback"
+    ));
+}
+
+#[test]
+fn strict_urls_accept_percent_encoded_urls() -> Result<(), ExecutionError> {
+    let urls = dispatched_include_urls(
+        Configuration::default().with_strict_urls(true),
+        "/search?q=red%20shoes#results",
+        "/fallback",
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/search?q=red%20shoes",
+            "http://www.example.com/fallback",
+        ]
+    );
+
+    Ok(())
+}
+
+// Dispatch a single include and return the Host headers its request was sent with.
+fn dispatched_host_headers(
+    configuration: Configuration,
+    original: Request,
+    src: &str,
+) -> Result<Vec<String>, ExecutionError> {
+    let hosts = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&hosts);
+
+    let processor = Processor::new(Some(original), configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(&format!(r#"<esi:include src="{src}"/>"#)),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().extend(
+                req.get_header_all_str("host")
+                    .into_iter()
+                    .map(str::to_string),
+            );
+            Ok(PendingFragmentContent::NoContent)
+        }),
+        None,
+    )?;
+
+    Ok(hosts.take())
+}
+
+// An original request carrying two Host headers, neither of which matches its URL
+fn request_with_odd_host_headers() -> Request {
+    let mut req = Request::get("http://origin.example.com:8080/page");
+    req.append_header("host", "www.example.com");
+    req.append_header("HOST", "Other.Example.com");
+    req
+}
+
+#[test]
+fn relative_src_sends_the_original_url_host() -> Result<(), ExecutionError> {
+    let hosts = dispatched_host_headers(
+        Configuration::default(),
+        request_with_odd_host_headers(),
+        "/fragment",
+    )?;
+
+    assert_eq!(hosts, ["origin.example.com:8080"]);
+
+    Ok(())
+}
+
+#[test]
+fn absolute_src_sends_the_fragment_host() -> Result<(), ExecutionError> {
+    let hosts = dispatched_host_headers(
+        Configuration::default().with_preserve_host(true),
+        request_with_odd_host_headers(),
+        "http://fragments.example.net/fragment",
+    )?;
+
+    assert_eq!(hosts, ["fragments.example.net"]);
+
+    Ok(())
+}
+
+#[test]
+fn relative_src_can_preserve_the_original_host_header() -> Result<(), ExecutionError> {
+    let hosts = dispatched_host_headers(
+        Configuration::default().with_preserve_host(true),
+        request_with_odd_host_headers(),
+        "/fragment",
+    )?;
+
+    assert_eq!(hosts, ["www.example.com"]);
+
+    Ok(())
+}
+
+// Dispatch includes against an `http` original request and return the URLs that were requested.
+fn dispatched_template_urls(
+    configuration: Configuration,
+    template: &str,
+) -> Result<Vec<String>, ExecutionError> {
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&urls);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().push(req.get_url_str().to_string());
+            Ok(PendingFragmentContent::NoContent)
+        }),
+        None,
+    )?;
+
+    Ok(urls.take())
+}
+
+const INSECURE_TEMPLATE: &str = r#"<esi:include src="/relative"/><esi:include src="http://other.example.com/absolute"/><esi:include src="https://other.example.com/secure"/><esi:include src="data:,inline"/>"#;
+
+#[test]
+fn insecure_fragment_urls_are_allowed_by_default() -> Result<(), ExecutionError> {
+    let urls = dispatched_template_urls(Configuration::default(), INSECURE_TEMPLATE)?;
+
+    assert_eq!(
+        urls,
+        [
+            "http://www.example.com/relative",
+            "http://other.example.com/absolute",
+            "https://other.example.com/secure",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn insecure_fragment_urls_can_be_upgraded() -> Result<(), ExecutionError> {
+    let urls = dispatched_template_urls(
+        Configuration::default().with_upgrade_insecure_fragments(InsecureFragmentPolicy::Upgrade),
+        r#"<esi:include src="http://other.example.com:8080/port"/><esi:include src="http://other.example.com/fail" alt="http://other.example.com/alt"/>"#,
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "https://other.example.com:8080/port",
+            "https://other.example.com/fail",
+        ]
+    );
+
+    let urls = dispatched_template_urls(
+        Configuration::default().with_upgrade_insecure_fragments(InsecureFragmentPolicy::Upgrade),
+        INSECURE_TEMPLATE,
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "https://www.example.com/relative",
+            "https://other.example.com/absolute",
+            "https://other.example.com/secure",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn insecure_fragment_urls_can_be_rejected() {
+    let res = dispatched_template_urls(
+        Configuration::default().with_upgrade_insecure_fragments(InsecureFragmentPolicy::Reject),
+        r#"<esi:include src="https://other.example.com/secure"/><esi:include src="/relative"/>"#,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InsecureFragmentUrl(url)) if url == "http://www.example.com/relative"
+    ));
+}
+
+#[test]
+fn rejected_fragment_urls_fall_back_to_alt_or_continue() -> Result<(), ExecutionError> {
+    let urls = dispatched_template_urls(
+        Configuration::default().with_upgrade_insecure_fragments(InsecureFragmentPolicy::Reject),
+        r#"<esi:include src="http://other.example.com/a" alt="https://other.example.com/alt"/><esi:include src="http://other.example.com/b" onerror="continue"/><esi:include src="http://other.example.com/c" alt="/insecure-alt" onerror="continue"/><esi:include src="data:,inline"/><esi:include src="https://other.example.com/secure"/>"#,
+    )?;
+
+    assert_eq!(
+        urls,
+        [
+            "https://other.example.com/alt",
+            "https://other.example.com/secure",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn alt_requests_carry_the_headers_of_the_original_request() -> Result<(), ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page")
+                .with_header("cookie", "uid=1")
+                .with_header("accept-language", "fr"),
+        ),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(
+            r#"<esi:include src="/fail" alt="http://alt.example.com/alt"/><esi:include srcset="/one /fail" alt="/alt"/>"#,
+        ),
+        &mut writer,
+        Some(&move |req| {
+            let header = |name| req.get_header_str(name).unwrap_or("-").to_string();
+            dispatched.borrow_mut().push(format!(
+                "{} host={} cookie={} lang={} srcset={}",
+                req.get_path(),
+                header("host"),
+                header("cookie"),
+                header("accept-language"),
+                header(esi::SRCSET_INDEX_HEADER),
+            ));
+            Ok(match req.get_path() {
+                "/fail" => Response::from_status(500).into(),
+                _ => PendingFragmentContent::NoContent,
+            })
+        }),
+        None,
+    )?;
+
+    assert_eq!(
+        requests.take(),
+        [
+            "/fail host=www.example.com cookie=uid=1 lang=fr srcset=-",
+            "/one host=www.example.com cookie=uid=1 lang=fr srcset=0",
+            "/fail host=www.example.com cookie=uid=1 lang=fr srcset=1",
+            "/alt host=alt.example.com cookie=uid=1 lang=fr srcset=-",
+            "/alt host=www.example.com cookie=uid=1 lang=fr srcset=1",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::{Request, Response};
+
+fn process(input: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page?slot=1&q=fish%20%26%20chips")
+                .with_header("cookie", "uid=user-2; other=x"),
+        ),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn bucket_selects_include_src() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:include src="/promo/v$bucket($(HTTP_COOKIE{uid}), 2)"/><esi:include src="/promo/v$bucket($(HTTP_COOKIE{missing}), 2)" alt="/alt/$bucket('alice', 10)"/>"#,
+    )?;
+
+    assert_eq!(output, "[/promo/v1][/promo/v0]");
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+// The hash is part of the contract: the same input has to get the same bucket in every release.
+#[test]
+fn bucket_is_stable() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<i esi:attr-a="$bucket('user-1', 10)" esi:attr-b="$bucket('user-2', 10)" esi:attr-c="$bucket('abc123', 10)" esi:attr-d="$bucket('alice', 10)" esi:attr-e="$bucket('alice', 1)"/>"#,
+    )?;
+
+    assert_eq!(output, r#"<i a="8" b="1" c="7" d="3" e="0"/>"#);
+
+    Ok(())
+}
+
+#[test]
+fn bucket_spreads_inputs_evenly() -> Result<(), ExecutionError> {
+    let input: String = (0..1000)
+        .map(|i| format!(r#"<i esi:attr-b="$bucket('user-{i}', 4)"/>"#))
+        .collect();
+
+    let (output, _) = process(&input)?;
+
+    for bucket in 0..4 {
+        let count = output.matches(&format!(r#"b="{bucket}""#)).count();
+        assert!((200..300).contains(&count), "bucket {bucket} has {count}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn pick_selects_an_argument() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:include src="$pick($(QUERY_STRING{slot}), '/a', '/b,c', $pick(0, '/nested'))"/><i esi:attr-x="$pick($bucket($(HTTP_COOKIE{uid}), 2), 'red', 'blue')"/>"#,
+    )?;
+
+    assert_eq!(output, r#"[/b,c]<i x="blue"/>"#);
+
+    Ok(())
+}
+
+#[test]
+fn index_and_rindex_find_substrings() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$index('banana', 'a')" esi:attr-b="$rindex('banana', 'a')" esi:attr-c="$index('banana', 'nan')" esi:attr-d="$rindex('banana', 'x')" esi:attr-e="$index($(HTTP_COOKIE{uid}), '-')" esi:attr-f="$rindex('ééa', 'a')"/>"#,
+    )?;
+
+    assert_eq!(output, r#"<i a="1" b="5" c="2" d="-1" e="4" f="2"/>"#);
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn index_selects_include_src() -> Result<(), ExecutionError> {
+    let (output, _) = process(r#"<esi:include src="/pos/$rindex($(HTTP_COOKIE{uid}), 'r')"/>"#)?;
+
+    assert_eq!(output, "[/pos/3]");
+
+    Ok(())
+}
+
+#[test]
+fn index_and_rindex_in_vars() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:vars><p>$index($(HTTP_COOKIE{uid}), 'user')/$rindex($(HTTP_HOST), '.')</p></esi:vars><esi:vars name="$rindex($(HTTP_COOKIE{uid}), 'x')"/>"#,
+    )?;
+
+    assert_eq!(output, "<p>0/11</p>-1");
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn substr_takes_part_of_a_string() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$substr('abcdef', 2)" esi:attr-b="$substr('abcdef', 1, 3)" esi:attr-c="$substr('abcdef', -2)" esi:attr-d="$substr('abcdef', 1, -2)" esi:attr-e="[$substr('abc', 5)]" esi:attr-f="$substr('abc', -10, 2)" esi:attr-g="[$substr('abc', 2, -3)]" esi:attr-h="[$substr('', 0, 1)]" esi:attr-i="$substr('ééé', 1, 1)" esi:attr-j="$substr('abc', 1, 99999999999999999)"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"<i a="cdef" b="bcd" c="ef" d="bcd" e="[]" f="ab" g="[]" h="[]" i="é" j="bc"/>"#
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn len_lower_and_upper() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$len('abc')" esi:attr-b="$len('')" esi:attr-c="$len('éé')" esi:attr-d="$lower('AbC-É')" esi:attr-e="$upper('abc-é')" esi:attr-f="[$upper('')]" esi:attr-g="$len($(HTTP_COOKIE{missing}))"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"<i a="3" b="0" c="2" d="abc-é" e="ABC-É" f="[]" g="0"/>"#
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn replace_replaces_occurrences() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$replace('a-b-c', '-', '/')" esi:attr-b="$replace('a-b-c', '-', '', 1)" esi:attr-c="$replace('abc', 'x', 'y')" esi:attr-d="[$replace('', 'x', 'y')]" esi:attr-e="$replace('aaa', 'a', 'b', 0)"/>"#,
+    )?;
+
+    assert_eq!(output, r#"<i a="a/b/c" b="ab-c" c="abc" d="[]" e="aaa"/>"#);
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn string_functions_compose() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:include src="/u/$lower($substr($(HTTP_COOKIE{uid}), 0, 4))/$upper($replace($(HTTP_COOKIE{uid}), 'user-', ''))/$len($substr('abcdef', -3))"/>"#,
+    )?;
+
+    assert_eq!(output, "[/u/user/2/3]");
+
+    Ok(())
+}
+
+#[test]
+fn string_functions_in_vars() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:vars><p>$substr($(HTTP_HOST), 4, 7)|$len($(HTTP_COOKIE{uid}))|$lower('MiXeD')|$upper($(HTTP_COOKIE{uid}))|$replace($(HTTP_HOST), '.', '_')</p></esi:vars><esi:vars name="$upper($substr($(HTTP_HOST), 0, 3))"/>"#,
+    )?;
+
+    assert_eq!(output, "<p>example|6|mixed|USER-2|www_example_com</p>WWW");
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn invalid_string_function_calls_raise_warnings() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$substr('abc', x)" esi:attr-b="$substr('abc', 0, two)" esi:attr-c="$substr('abc')" esi:attr-d="$len('a', 'b')" esi:attr-e="$replace('abc', '', 'x')" esi:attr-f="$replace('abc', 'a', 'x', -1)" esi:attr-g="$upper('a', 'b')"/>"#,
+    )?;
+
+    assert_eq!(output, "<i/>");
+    assert_eq!(report.warnings.len(), 7);
+    assert!(report
+        .warnings
+        .iter()
+        .all(|warning| matches!(warning, Warning::ExpressionError { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn url_encode_keeps_values_from_corrupting_urls() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:include src="/search/$url_encode($(QUERY_STRING{q}))"/><i esi:attr-a="$url_encode('café~-_.')" esi:attr-b="[$url_encode('')]"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"[/search/fish%20%26%20chips]<i a="caf%C3%A9~-_." b="[]"/>"#
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn url_decode_reverses_url_encode() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<i esi:attr-a="$url_decode($url_encode('café / crème?'))" esi:attr-b="$url_decode('a%20b+c%2')" esi:attr-c="$url_decode('%FF')"/>"#,
+    )?;
+
+    assert_eq!(output, r#"<i a="café / crème?" b="a b+c%2" c="�"/>"#);
+
+    Ok(())
+}
+
+#[test]
+fn html_encode_escapes_markup() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:include src="/h/$html_encode('&lt;b&gt;&amp;&quot;')"/><esi:include src="/h/$html_encode($upper('é'))"/>"#,
+    )?;
+
+    assert_eq!(output, "[/h/&lt;b&gt;&amp;&quot;][/h/%C3%89]");
+
+    Ok(())
+}
+
+#[test]
+fn url_encoding_round_trips_in_vars() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<esi:vars><p>$url_decode($url_encode($url_decode($(QUERY_STRING{q}))))|$url_encode($url_decode('%zz%4'))|$url_decode($url_encode($url_decode('%zz%4')))|$url_decode('%FF%')</p></esi:vars><esi:vars name="$url_decode($url_encode('café / crème?'))"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        "<p>fish &amp; chips|%25zz%254|%zz%4|\u{FFFD}%</p>café / crème?"
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn invalid_function_calls_raise_warnings() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<i esi:attr-a="$bucket('x', 0)" esi:attr-b="$bucket('x', two)" esi:attr-c="$pick(3, 'a', 'b')" esi:attr-d="$pick(first, 'a')" esi:attr-e="$bucket('x', 2" esi:attr-f="$5 $other(1)" esi:attr-g="$index('x', '')"/>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"<i e="$bucket(&apos;x&apos;, 2" f="$5 $other(1)"/>"#
+    );
+    let expressions: Vec<_> = report
+        .warnings
+        .iter()
+        .map(|warning| match warning {
+            Warning::ExpressionError { expression, .. } => expression.as_str(),
+            warning => panic!("unexpected warning {warning}"),
+        })
+        .collect();
+    assert_eq!(
+        expressions,
+        [
+            "$bucket('x', 0)",
+            "$bucket('x', two)",
+            "$pick(3, 'a', 'b')",
+            "$pick(first, 'a')",
+            "$bucket('x', 2",
+            "$index('x', '')",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, GeoData, GeoResolver, PendingFragmentContent, Processor, Reader,
+    Writer,
+};
+use fastly::{Request, Response};
+
+fn san_francisco() -> GeoData {
+    GeoData {
+        country_code: "US".to_string(),
+        country_code3: "USA".to_string(),
+        country_name: "United States of America".to_string(),
+        region: "CA".to_string(),
+        city: "san francisco".to_string(),
+        latitude: 37.77,
+        longitude: -122.41,
+        postal_code: "94107".to_string(),
+    }
+}
+
+// Processes the template for a request that isn't the client request, so there's no geolocation
+// for it.
+fn process(template: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(Reader::from_str(template), &mut writer, None, None)?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn keys_select_a_field() {
+    let geo = san_francisco();
+
+    assert_eq!(geo.get("country_code").as_deref(), Some("US"));
+    assert_eq!(geo.get("country_code3").as_deref(), Some("USA"));
+    assert_eq!(
+        geo.get("country_name").as_deref(),
+        Some("United States of America")
+    );
+    assert_eq!(geo.get("region").as_deref(), Some("CA"));
+    assert_eq!(geo.get("city").as_deref(), Some("san francisco"));
+    assert_eq!(geo.get("lat").as_deref(), Some("37.77"));
+    assert_eq!(geo.get("long").as_deref(), Some("-122.41"));
+    assert_eq!(geo.get("postal_code").as_deref(), Some("94107"));
+}
+
+#[test]
+fn keys_ignore_case() {
+    assert_eq!(san_francisco().get("COUNTRY_CODE").as_deref(), Some("US"));
+}
+
+#[test]
+fn unknown_keys_have_no_value() {
+    assert_eq!(san_francisco().get("continent"), None);
+    assert_eq!(san_francisco().get(""), None);
+}
+
+#[test]
+fn display_lists_every_field() {
+    assert_eq!(
+        san_francisco().to_string(),
+        "country_code=US; country_code3=USA; country_name=United States of America; region=CA; \
+         city=san francisco; lat=37.77; long=-122.41; postal_code=94107"
+    );
+}
+
+#[test]
+fn missing_geolocation_falls_back_to_the_default() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<html esi:attr-data-country="$(GEO{country_code}|US)" esi:attr-data-city="$(GEO{city})"></html>"#,
+    )?;
+
+    assert_eq!(output, r#"<html data-country="US"></html>"#);
+
+    Ok(())
+}
+
+#[test]
+fn missing_geolocation_leaves_the_dictionary_empty() -> Result<(), ExecutionError> {
+    let output = process(r#"<p esi:attr-title="$(GEO)">x</p>"#)?;
+
+    assert_eq!(output, "<p>x</p>");
+
+    Ok(())
+}
+
+// Counts the lookups of a resolver that places every client in San Francisco.
+fn counting_resolver() -> (GeoResolver, Rc<Cell<usize>>) {
+    let lookups = Rc::new(Cell::new(0));
+    let counted = Rc::clone(&lookups);
+    let resolver = GeoResolver::new(move |_| {
+        counted.set(counted.get() + 1);
+        Some(san_francisco())
+    });
+    (resolver, lookups)
+}
+
+#[test]
+fn geolocation_is_looked_up_once_per_document() -> Result<(), ExecutionError> {
+    let template = r#"<p esi:attr-title="$(GEO{country_code})">$(GEO{city})</p><esi:vars>$(GEO{region}) $(GEO{missing}|none) $(GEO{postal_code}|00000)</esi:vars><esi:include src="/$(GEO{country_code})/$lower($(GEO{region}))"/><esi:choose><esi:when test="$(GEO{country_code}) == 'US'">us</esi:when></esi:choose>"#;
+    let (resolver, lookups) = counting_resolver();
+    let configuration = Configuration::default().with_geo_resolver(resolver);
+
+    for _ in 0..2 {
+        let processor = Processor::new(
+            Some(Request::get("http://www.example.com/page")),
+            configuration.clone(),
+        );
+        let mut writer = Writer::new(Vec::new());
+        processor.process_document(
+            Reader::from_str(template),
+            &mut writer,
+            Some(&|req| {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_body(format!("[{}]", req.get_path())),
+                ))
+            }),
+            None,
+        )?;
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            r#"<p title="US">$(GEO{city})</p>CA none 94107[/US/ca]us"#
+        );
+    }
+
+    // Each document looks it up again, however many times it's referenced.
+    assert_eq!(lookups.get(), 2);
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! End-to-end tests comparing processed documents with known-good output.
+//!
+//! Each case in `tests/golden/<name>/` consists of a template, the fragments it includes and the
+//! expected output. `fragments.txt` lists each fragment as a `## <path> <status>` line followed by
+//! its body. Fragments that aren't listed return a 404.
+//!
+//! Tests run without filesystem access, so cases are embedded at compile time. To regenerate the
+//! expected output after an intentional change, run `ESI_BLESS=1 cargo test --test golden --
+//! --nocapture` and copy the printed output of each case into its expected file.
+
+use std::collections::HashMap;
+use std::io::BufReader;
+
+use esi::{
+    Configuration, ExecutionError, MarkerStyle, OutputMode, PendingFragmentContent, Processor,
+    Reader, Warning, Writer,
+};
+use fastly::Response;
+
+const BLESS: bool = option_env!("ESI_BLESS").is_some();
+
+// Parses a `fragments.txt` fixture into bodies and statuses, keyed by path and query.
+fn load_fragments(fixture: &str) -> HashMap<String, (u16, String)> {
+    let mut fragments = HashMap::new();
+    let mut current: Option<(String, u16, Vec<&str>)> = None;
+
+    for line in fixture.lines() {
+        if let Some(header) = line.strip_prefix("## ") {
+            if let Some((path, status, body)) = current.take() {
+                fragments.insert(path, (status, body.join("\n")));
+            }
+            let (path, status) = header
+                .rsplit_once(' ')
+                .unwrap_or_else(|| panic!("invalid fragment header: {line}"));
+            let status = status
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid fragment status: {line}"));
+            current = Some((path.to_string(), status, Vec::new()));
+        } else if let Some((_, _, body)) = current.as_mut() {
+            body.push(line);
+        } else {
+            panic!("fragment body without a header: {line}");
+        }
+    }
+    if let Some((path, status, body)) = current {
+        fragments.insert(path, (status, body.join("\n")));
+    }
+
+    fragments
+}
+
+fn process(
+    template: &str,
+    fragments: &str,
+    configuration: Configuration,
+) -> Result<String, ExecutionError> {
+    let fragments = load_fragments(fragments);
+
+    // Templates are read the same way `Processor::process_response` reads them, so that HTML
+    // void elements don't need closing.
+    let mut reader = Reader::from_str(template);
+    reader.config_mut().check_end_names = false;
+
+    // Every case's output is also checked for well-formedness as it's written.
+    let processor = Processor::new(None, configuration.with_debug_validate_output(true));
+    let mut writer = Writer::new(Vec::new());
+
+    // Every fragment is complete as soon as it's dispatched, so the output doesn't depend on
+    // request timing.
+    let report = processor.process_document(
+        reader,
+        &mut writer,
+        Some(&move |req| {
+            let key = match req.get_query_str() {
+                Some(query) => format!("{}?{}", req.get_path(), query),
+                None => req.get_path().to_string(),
+            };
+            let response = match fragments.get(&key) {
+                Some((status, body)) => Response::from_status(*status).with_body(body.as_str()),
+                None => Response::from_status(404),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    let invalid: Vec<_> = report
+        .warnings
+        .iter()
+        .filter(|warning| matches!(warning, Warning::InvalidOutput { .. }))
+        .map(ToString::to_string)
+        .collect();
+    assert!(invalid.is_empty(), "invalid output: {invalid:#?}");
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+fn check(name: &str, expected_file: &str, actual: &str, expected: &str) {
+    if BLESS {
+        println!("---- tests/golden/{name}/{expected_file} ----\n{actual}");
+    } else {
+        assert_eq!(
+            actual, expected,
+            "output of golden case `{name}` doesn't match {expected_file}"
+        );
+    }
+}
+
+macro_rules! golden_case {
+    ($name:ident, $extension:literal) => {
+        golden_case!($name, $extension, Configuration::default());
+    };
+    ($name:ident, $extension:literal, $configuration:expr) => {
+        #[test]
+        fn $name() -> Result<(), ExecutionError> {
+            let actual = process(
+                include_str!(concat!(
+                    "golden/",
+                    stringify!($name),
+                    "/template.",
+                    $extension
+                )),
+                include_str!(concat!("golden/", stringify!($name), "/fragments.txt")),
+                $configuration,
+            )?;
+
+            check(
+                stringify!($name),
+                concat!("expected.", $extension),
+                &actual,
+                include_str!(concat!(
+                    "golden/",
+                    stringify!($name),
+                    "/expected.",
+                    $extension
+                )),
+            );
+
+            Ok(())
+        }
+    };
+}
+
+golden_case!(plain_html, "html");
+golden_case!(include_positions, "html");
+golden_case!(alt_fallback, "html");
+golden_case!(onerror_continue, "html");
+golden_case!(nested_try, "html");
+golden_case!(remove_comment, "html");
+golden_case!(include_fallback, "html");
+golden_case!(
+    raw_passthrough,
+    "html",
+    Configuration::default().with_output_mode(OutputMode::RawPassthrough)
+);
+golden_case!(
+    fragment_markers,
+    "html",
+    Configuration::default().with_fragment_markers(MarkerStyle::HtmlComment)
+);
+golden_case!(
+    json_escaped,
+    "json",
+    Configuration::default().with_escaped(false)
+);
+golden_case!(vars_value, "html");
+golden_case!(
+    vars_value_json,
+    "json",
+    Configuration::default().with_escaped(false)
+);
+
+// A page of around 500KB. The leading `esi:try` stays queued until the whole document has been
+// parsed, so all of the content goes through the buffer rather than being streamed directly.
+#[test]
+fn large_page_is_buffered_intact() -> Result<(), ExecutionError> {
+    const BLOCK: &str = "<article class=\"item\"><h2>Item title</h2><p>Lorem ipsum dolor sit amet, consectetur adipiscing elit &amp; more.</p></article>\n";
+    let count = 500 * 1024 / BLOCK.len();
+    let body = BLOCK.repeat(count);
+
+    let template = format!(
+        r#"<esi:try><esi:attempt><esi:include src="/header"/></esi:attempt><esi:except></esi:except></esi:try>{body}<esi:include src="/footer"/>"#
+    );
+    let fragments = "## /header 200\n<header/>\n## /footer 200\n<footer/>";
+
+    let actual = process(&template, fragments, Configuration::default())?;
+
+    assert!(body.len() >= 500 * 1000);
+    assert_eq!(actual, format!("<header/>{body}<footer/>"));
+
+    Ok(())
+}
+
+// Reading the template a few bytes at a time makes events span several reads of the source, so
+// the bytes copied out have to be kept across them.
+#[test]
+fn raw_passthrough_copies_the_source_across_reads() -> Result<(), ExecutionError> {
+    let template = include_str!("golden/plain_html/template.html")
+        .replace("<!DOCTYPE html>", "<!doctype html>")
+        .replace("<br/>", "<BR >");
+    let process = |output_mode| {
+        let mut reader = Reader::from_reader(BufReader::with_capacity(7, template.as_bytes()));
+        reader.config_mut().check_end_names = false;
+        let mut writer = Writer::new(Vec::new());
+        Processor::new(None, Configuration::default().with_output_mode(output_mode))
+            .process_document(reader, &mut writer, None, None)
+            .map(|_| String::from_utf8(writer.into_inner()).unwrap())
+    };
+
+    assert_eq!(process(OutputMode::RawPassthrough)?, template);
+    // Written back out event by event, the doctype is normalized.
+    assert_ne!(process(OutputMode::Writer)?, template);
+
+    Ok(())
+}
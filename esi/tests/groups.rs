@@ -0,0 +1,178 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+
+fn process_with(configuration: Configuration, template: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+    // Like `Processor::process_response`, don't let the XML reader reject mismatched tags.
+    let mut reader = Reader::from_str(template);
+    reader.config_mut().check_end_names = false;
+
+    processor.process_document(
+        reader,
+        &mut writer,
+        Some(&|req| {
+            Ok(match req.get_path() {
+                path if path.starts_with("/fail") => Response::from_status(500).into(),
+                path => PendingFragmentContent::CompletedRequest(Response::from_body(format!(
+                    "[{path}]"
+                ))),
+            })
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+fn process(template: &str) -> Result<String, ExecutionError> {
+    process_with(Configuration::default(), template)
+}
+
+// A row of cards, the second of which is `card`
+fn cards(onerror: &str, card: &str) -> String {
+    format!(
+        r#"<p>before</p><esi:group name="recs" {onerror}><ul><li><esi:include src="/card/1"/></li><li><esi:include src="{card}"/></li></ul></esi:group><p>after</p>"#
+    )
+}
+
+#[test]
+fn group_is_written_in_order_once_every_include_succeeds() -> Result<(), ExecutionError> {
+    let output = process(&cards("", "/card/2"))?;
+
+    assert_eq!(
+        output,
+        "<p>before</p><ul><li>[/card/1]</li><li>[/card/2]</li></ul><p>after</p>"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn group_with_a_failed_include_is_omitted() -> Result<(), ExecutionError> {
+    let output = process(&cards(r#"onerror="omit""#, "/fail"))?;
+
+    assert_eq!(output, "<p>before</p><p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn group_with_a_failed_include_is_replaced_by_its_placeholder() -> Result<(), ExecutionError> {
+    let output = process(&cards(
+        r#"onerror="placeholder" placeholder="&lt;p&gt;no recommendations&lt;/p&gt;""#,
+        "/fail",
+    ))?;
+
+    assert_eq!(output, "<p>before</p><p>no recommendations</p><p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn group_with_a_failed_include_can_fail_processing() {
+    let result = process(&cards(r#"onerror="fail""#, "/fail"));
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::GroupFailed(name, url, 500))
+            if name == "recs" && url == "http://localhost/fail"
+    ));
+}
+
+#[test]
+fn includes_rescued_by_alt_or_continue_do_not_fail_the_group() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:group name="recs"><esi:include src="/fail/1" alt="/card/1"/><esi:include src="/fail/2" onerror="continue"/><esi:include src="/card/3"/></esi:group>"#,
+    )?;
+
+    assert_eq!(output, "[/card/1][/card/3]");
+
+    Ok(())
+}
+
+#[test]
+fn include_with_a_fallback_does_not_fail_the_group() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:group name="recs" onerror="fail"><esi:include src="/fail"><i>none</i></esi:include><esi:include src="/card/2"/></esi:group>"#,
+    )?;
+
+    assert_eq!(output, "<i>none</i>[/card/2]");
+
+    Ok(())
+}
+
+#[test]
+fn failing_group_fails_the_attempt_it_is_in() -> Result<(), ExecutionError> {
+    let output = process(&format!(
+        "<esi:try><esi:attempt>{}</esi:attempt><esi:except>except</esi:except></esi:try>",
+        cards(r#"onerror="fail""#, "/fail")
+    ))?;
+
+    assert_eq!(output, "except");
+
+    Ok(())
+}
+
+#[test]
+fn omitted_group_leaves_the_rest_of_the_attempt() -> Result<(), ExecutionError> {
+    let output = process(&format!(
+        "<esi:try><esi:attempt>{}</esi:attempt><esi:except>except</esi:except></esi:try>",
+        cards("", "/fail")
+    ))?;
+
+    assert_eq!(output, "<p>before</p><p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn nested_groups_are_rejected() {
+    let result = process(
+        r#"<esi:group name="outer"><esi:group name="inner"><esi:include src="/a"/></esi:group></esi:group>"#,
+    );
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::UnexpectedOpeningTag(tag)) if tag == "esi:group"
+    ));
+}
+
+#[test]
+fn group_crossing_an_arm_boundary_is_rejected() {
+    let result = process(
+        r#"<esi:try><esi:attempt><esi:group name="recs"></esi:attempt></esi:group></esi:try>"#,
+    );
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::UnexpectedClosingTag(tag)) if tag == "esi:attempt"
+    ));
+}
+
+#[test]
+fn group_without_a_name_is_rejected() {
+    let result = process(r#"<esi:group><esi:include src="/a"/></esi:group>"#);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::MissingRequiredParameter(tag, parameter))
+            if tag == "esi:group" && parameter == "name"
+    ));
+}
+
+#[test]
+fn group_cut_by_the_document_size_limit_is_left_out_whole() -> Result<(), ExecutionError> {
+    let template = cards("", "/card/2");
+    // The limit falls between the two includes of the group.
+    let limit = template.find("/card/2").unwrap();
+
+    let output = process_with(
+        Configuration::default().with_max_document_size(limit),
+        &template,
+    )?;
+
+    assert_eq!(output, "<p>before</p><!-- esi: document truncated -->");
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use esi::{propagate_headers, Configuration, ExecutionError, Processor, ResponseBody};
+use fastly::http::header;
+use fastly::Response;
+
+// A source document response, as a backend would return it
+fn source() -> Response {
+    let mut response = Response::from_body("<p>hello</p>")
+        .with_header(header::CONTENT_LENGTH, "12")
+        .with_header(header::CONTENT_ENCODING, "br")
+        .with_header(header::TRANSFER_ENCODING, "chunked")
+        .with_header(header::CACHE_CONTROL, "max-age=60")
+        .with_header(header::VARY, "Accept-Encoding")
+        .with_header("set-cookie", "a=1");
+    response.append_header("set-cookie", "b=2");
+    response
+}
+
+#[test]
+fn processed_body_drops_the_headers_of_the_source_body() {
+    let mut client = Response::new().with_header(header::CACHE_CONTROL, "no-store");
+
+    propagate_headers(&source(), &mut client, ResponseBody::Processed);
+
+    assert_eq!(client.get_header_str(header::CONTENT_LENGTH), None);
+    assert_eq!(client.get_header_str(header::CONTENT_ENCODING), None);
+    assert_eq!(client.get_header_str(header::TRANSFER_ENCODING), None);
+    assert_eq!(
+        client.get_header_str(header::CACHE_CONTROL),
+        Some("max-age=60")
+    );
+    assert_eq!(client.get_header_str(header::VARY), Some("Accept-Encoding"));
+    assert_eq!(client.get_header_all_str("set-cookie"), ["a=1", "b=2"]);
+}
+
+#[test]
+fn passthrough_body_keeps_its_length_and_encoding() {
+    let mut client = Response::new();
+
+    propagate_headers(&source(), &mut client, ResponseBody::Passthrough);
+
+    assert_eq!(client.get_header_str(header::CONTENT_LENGTH), Some("12"));
+    assert_eq!(client.get_header_str(header::CONTENT_ENCODING), Some("br"));
+    assert_eq!(client.get_header_str(header::TRANSFER_ENCODING), None);
+    assert_eq!(
+        client.get_header_str(header::CACHE_CONTROL),
+        Some("max-age=60")
+    );
+}
+
+#[test]
+fn processed_response_does_not_inherit_the_source_length() -> Result<(), ExecutionError> {
+    let mut document = Response::from_body(
+        r#"<esi:response header-content-length="3" header-x-page="home"/><p><esi:include src="data:text/plain,hello"/></p>"#,
+    );
+    let mut metadata = Response::new();
+    propagate_headers(&source(), &mut metadata, ResponseBody::Passthrough);
+
+    let (response, _) = Processor::new(None, Configuration::default()).process_to_response(
+        &mut document,
+        Some(metadata),
+        None,
+        None,
+    )?;
+
+    assert_eq!(response.get_header_str(header::CONTENT_LENGTH), None);
+    assert_eq!(response.get_header_str(header::CONTENT_ENCODING), None);
+    assert_eq!(response.get_header_str("x-page"), Some("home"));
+    assert_eq!(
+        response.get_header_str(header::CACHE_CONTROL),
+        Some("max-age=60")
+    );
+    assert_eq!(response.into_body_str(), "<p>hello</p>");
+
+    Ok(())
+}
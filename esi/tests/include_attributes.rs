@@ -0,0 +1,137 @@
+use esi::attributes::{
+    AcceptStatus, Critical, ForwardHeaders, FromAttribute, Headers, Method, NoStore, Parsed, Raw,
+};
+use esi::{parse_tags, Event, ExecutionError, Include, Reader, Tag};
+use fastly::http::{HeaderName, Method as HttpMethod};
+
+fn parse_include(input: &str) -> Result<Include, ExecutionError> {
+    let mut include = None;
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Include(parsed)) = event {
+            include = Some(parsed);
+        }
+        Ok(())
+    })?;
+    Ok(include.expect("an include"))
+}
+
+#[test]
+fn flags_warn_about_values_other_than_true_and_false() {
+    assert_eq!(
+        Critical::from_attribute("true"),
+        Parsed::Valid(Critical(true))
+    );
+    assert_eq!(
+        Critical::from_attribute("false"),
+        Parsed::Valid(Critical(false))
+    );
+    assert_eq!(
+        Critical::from_attribute("yes"),
+        Parsed::Warn(Critical(false))
+    );
+    assert_eq!(Raw::from_attribute("true"), Parsed::Valid(Raw(true)));
+    assert_eq!(Raw::from_attribute("TRUE"), Parsed::Warn(Raw(false)));
+}
+
+#[test]
+fn accept_status_is_parsed_into_codes() {
+    let Parsed::Valid(AcceptStatus(codes)) = AcceptStatus::from_attribute("404, 500-502") else {
+        panic!("accept-status should parse");
+    };
+    assert!(codes.contains(404) && codes.contains(501) && !codes.contains(503));
+    assert_eq!(AcceptStatus::from_attribute("4o4"), Parsed::Invalid);
+}
+
+#[test]
+fn forward_headers_are_parsed_into_names() {
+    assert_eq!(
+        ForwardHeaders::from_attribute(" Accept-Language\tcookie "),
+        Parsed::Valid(ForwardHeaders(vec![
+            HeaderName::from_static("accept-language"),
+            HeaderName::from_static("cookie"),
+        ]))
+    );
+    assert_eq!(
+        ForwardHeaders::from_attribute(""),
+        Parsed::Valid(ForwardHeaders(Vec::new()))
+    );
+    assert_eq!(ForwardHeaders::from_attribute("x-a x(b)"), Parsed::Invalid);
+}
+
+#[test]
+fn headers_are_parsed_from_a_comma_separated_list() {
+    assert_eq!(
+        Headers::from_attribute(" Cookie,accept-language, "),
+        Parsed::Valid(Headers(vec![
+            HeaderName::from_static("cookie"),
+            HeaderName::from_static("accept-language"),
+        ]))
+    );
+    assert_eq!(
+        Headers::from_attribute(""),
+        Parsed::Valid(Headers(Vec::new()))
+    );
+    assert_eq!(Headers::from_attribute("x-a, x(b)"), Parsed::Invalid);
+}
+
+#[test]
+fn methods_are_parsed_in_any_case() {
+    assert_eq!(
+        Method::from_attribute("post"),
+        Parsed::Valid(Method(HttpMethod::POST))
+    );
+    assert_eq!(
+        Method::from_attribute("HEAD"),
+        Parsed::Valid(Method(HttpMethod::HEAD))
+    );
+    assert_eq!(Method::from_attribute("PUT"), Parsed::Invalid);
+    assert_eq!(
+        NoStore::from_attribute("true"),
+        Parsed::Valid(NoStore(true))
+    );
+}
+
+#[test]
+fn parsed_includes_hold_the_attributes_that_were_set() -> Result<(), ExecutionError> {
+    let include = parse_include(
+        r#"<esi:include src="/a" critical="true" accept-status="404" raw="maybe" esi:ttl="60"/>"#,
+    )?;
+
+    assert_eq!(include.get::<Critical>(), Some(&Critical(true)));
+    assert!(include
+        .get::<AcceptStatus>()
+        .is_some_and(|codes| codes.0.contains(404)));
+    assert_eq!(include.get::<Raw>(), Some(&Raw(false)));
+    assert_eq!(include.get::<ForwardHeaders>(), None);
+    assert_eq!(include.extensions, [("ttl".to_string(), "60".to_string())]);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_attribute_values_fail_the_include() {
+    let res = parse_include(r#"<esi:include src="/a" forward-headers="x(b)"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidAttributeValue(tag, attribute, value))
+            if tag == "esi:include" && attribute == "forward-headers" && value == "x(b)"
+    ));
+}
+
+#[test]
+fn includes_can_be_built_with_attributes() {
+    let include = Include::new("/a")
+        .with_alt("/b")
+        .with_continue_on_error(true)
+        .with_attribute(Raw(true))
+        .with_attribute(Critical(false))
+        .with_attribute(Critical(true));
+
+    assert_eq!(include.src, "/a");
+    assert_eq!(include.alt.as_deref(), Some("/b"));
+    assert!(include.continue_on_error);
+    assert_eq!(include.get::<Raw>(), Some(&Raw(true)));
+    assert_eq!(include.get::<Critical>(), Some(&Critical(true)));
+    assert_eq!(include.get::<AcceptStatus>(), None);
+}
@@ -0,0 +1,122 @@
+use std::cell::{Cell, RefCell};
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer,
+    INCLUDE_CHAIN_HEADER,
+};
+use fastly::{Request, Response};
+
+thread_local! {
+    // The include chain of every fragment request, in the order they were dispatched
+    static CHAINS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    // The maximum include depth of every processor
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(3) };
+}
+
+// Fragments whose bodies include other fragments: `/a` and `/b` include each other, `/self` and
+// `/loop` include themselves, and `/1` starts a chain three fragments deep.
+fn dispatch(req: Request) -> esi::Result<PendingFragmentContent> {
+    if let Some(chain) = req.get_header_str(INCLUDE_CHAIN_HEADER) {
+        CHAINS.with_borrow_mut(|chains| chains.push(chain.to_string()));
+    }
+    let body = match req.get_path() {
+        "/a" => r#"a(<esi:include src="/b"/>)"#,
+        "/b" => r#"b(<esi:include src="/a"/>)"#,
+        "/self" => r#"self(<esi:include src="/self"/>)"#,
+        "/loop" => r#"loop(<esi:include src="/loop" onerror="continue"/>)"#,
+        "/1" => r#"1(<esi:include src="/2"/>)"#,
+        "/2" => r#"2(<esi:include src="/3"/>)"#,
+        "/3" => "3",
+        _ => return Ok(Response::from_status(404).into()),
+    };
+    Ok(Response::from_body(body).into())
+}
+
+// Processes the body of a fragment as an ESI document, with the fragment request as its original
+// request so that it carries on the include chain.
+fn process_fragment(req: &mut Request, res: Response) -> esi::Result<Response> {
+    let configuration = Configuration::default().with_max_include_depth(MAX_DEPTH.get());
+    let processor = Processor::new(Some(req.clone_without_body()), configuration);
+    let mut writer = Writer::new(Vec::new());
+    processor.process_document(
+        Reader::from_reader(res.into_body()),
+        &mut writer,
+        Some(&dispatch),
+        Some(&process_fragment),
+    )?;
+    Ok(Response::from_body(writer.into_inner()))
+}
+
+fn process(template: &str, max_depth: usize) -> Result<String, ExecutionError> {
+    CHAINS.with_borrow_mut(Vec::clear);
+    MAX_DEPTH.set(max_depth);
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        Configuration::default().with_max_include_depth(max_depth),
+    );
+    let mut writer = Writer::new(Vec::new());
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&dispatch),
+        Some(&process_fragment),
+    )?;
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+#[test]
+fn two_fragments_that_include_each_other_are_a_cycle() {
+    let err = process(r#"<esi:include src="/a"/>"#, 3).unwrap_err();
+
+    assert!(matches!(
+        &err,
+        ExecutionError::IncludeCycle(chain) if chain == &[
+            "http://www.example.com/page",
+            "http://www.example.com/a",
+            "http://www.example.com/b",
+            "http://www.example.com/a",
+        ]
+    ));
+    assert_eq!(
+        err.to_string(),
+        "include cycle: http://www.example.com/page -> http://www.example.com/a -> http://www.example.com/b -> http://www.example.com/a"
+    );
+}
+
+#[test]
+fn a_fragment_that_includes_itself_is_a_cycle() -> Result<(), ExecutionError> {
+    let err = process(r#"<esi:include src="/self"/>"#, 3).unwrap_err();
+
+    assert!(matches!(
+        &err,
+        ExecutionError::IncludeCycle(chain) if chain.len() == 3 && chain[1] == chain[2]
+    ));
+
+    // Like any failed include, the include that closes the cycle can be skipped.
+    assert_eq!(process(r#"<esi:include src="/loop"/>"#, 3)?, "loop()");
+
+    Ok(())
+}
+
+#[test]
+fn chains_below_the_maximum_depth_are_processed() -> Result<(), ExecutionError> {
+    let output = process(r#"<p><esi:include src="/1"/></p>"#, 3)?;
+
+    assert_eq!(output, "<p>1(2(3))</p>");
+    assert_eq!(
+        CHAINS.with_borrow(Clone::clone),
+        [
+            "http://www.example.com/page http://www.example.com/1",
+            "http://www.example.com/page http://www.example.com/1 http://www.example.com/2",
+            "http://www.example.com/page http://www.example.com/1 http://www.example.com/2 http://www.example.com/3",
+        ]
+    );
+
+    let err = process(r#"<p><esi:include src="/1"/></p>"#, 2).unwrap_err();
+    assert!(matches!(
+        err,
+        ExecutionError::IncludeTooDeep(url, 2) if url == "http://www.example.com/3"
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+#![cfg(feature = "metrics")]
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+// The counters in a snapshot, as their name, sorted labels and value
+type Counters = Vec<(String, Vec<(String, String)>, u64)>;
+
+fn counter(counters: &Counters, name: &str, labels: &[(&str, &str)]) -> u64 {
+    counters
+        .iter()
+        .find(|(counter_name, counter_labels, _)| {
+            counter_name == name
+                && counter_labels
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .eq(labels.iter().copied())
+        })
+        .map_or(0, |(_, _, value)| *value)
+}
+
+#[test]
+fn counters_fire_for_success_and_alt_fallback() -> Result<(), ExecutionError> {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    let (output, report) = metrics::with_local_recorder(&recorder, || {
+        let processor = Processor::new(None, Configuration::default());
+        let mut writer = Writer::new(Vec::new());
+        let report = processor.process_document(
+            Reader::from_str(
+                r#"<esi:include src="/ok"/><esi:include src="/missing" alt="/fallback"/>"#,
+            ),
+            &mut writer,
+            Some(&|req| {
+                let response = match req.get_path() {
+                    "/missing" => Response::from_status(404),
+                    path => Response::from_body(format!("[{path}]")),
+                };
+                Ok(PendingFragmentContent::CompletedRequest(response))
+            }),
+            None,
+        )?;
+        Ok::<_, ExecutionError>((String::from_utf8(writer.into_inner()).unwrap(), report))
+    })?;
+    assert_eq!(output, "[/ok][/fallback]");
+
+    let mut counters: Counters = Vec::new();
+    let mut histograms = Vec::new();
+    let mut gauges = Vec::new();
+    for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+        let name = key.key().name().to_string();
+        match value {
+            DebugValue::Counter(value) => {
+                let mut labels: Vec<_> = key
+                    .key()
+                    .labels()
+                    .map(|label| (label.key().to_string(), label.value().to_string()))
+                    .collect();
+                labels.sort();
+                counters.push((name, labels, value));
+            }
+            DebugValue::Histogram(values) => histograms.push((name, values.len())),
+            DebugValue::Gauge(value) => gauges.push((name, value.into_inner())),
+        }
+    }
+
+    assert_eq!(
+        counter(&counters, esi::metrics::DOCUMENTS, &[("result", "ok")]),
+        1
+    );
+    assert_eq!(
+        counter(&counters, esi::metrics::BYTES_WRITTEN, &[]),
+        report.bytes_written as u64
+    );
+    assert_eq!(
+        counter(
+            &counters,
+            esi::metrics::FRAGMENT_REQUESTS,
+            &[("alt_used", "false")]
+        ),
+        2
+    );
+    assert_eq!(
+        counter(
+            &counters,
+            esi::metrics::FRAGMENT_REQUESTS,
+            &[("alt_used", "true")]
+        ),
+        1
+    );
+    for (status_class, alt_used) in [("2xx", "false"), ("4xx", "false"), ("2xx", "true")] {
+        assert_eq!(
+            counter(
+                &counters,
+                esi::metrics::FRAGMENT_RESPONSES,
+                &[("alt_used", alt_used), ("status_class", status_class)]
+            ),
+            1,
+            "{status_class} response with alt_used={alt_used}"
+        );
+    }
+    assert_eq!(
+        counter(&counters, esi::metrics::INCLUDES, &[("outcome", "ok")]),
+        1
+    );
+    assert_eq!(
+        counter(&counters, esi::metrics::INCLUDES, &[("outcome", "alt")]),
+        1
+    );
+    assert_eq!(
+        counter(
+            &counters,
+            esi::metrics::FRAGMENT_ERRORS,
+            &[("alt_used", "false")]
+        ),
+        0
+    );
+
+    histograms.sort();
+    assert_eq!(
+        histograms,
+        [
+            (esi::metrics::DOCUMENT_DURATION.to_string(), 1),
+            (esi::metrics::INCLUDE_DURATION.to_string(), 1),
+            (esi::metrics::INCLUDE_DURATION.to_string(), 1),
+        ]
+    );
+
+    gauges.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        gauges,
+        [
+            (
+                esi::metrics::PEAK_BUFFERED_BYTES.to_string(),
+                report.peak_buffered_bytes as f64
+            ),
+            (
+                esi::metrics::PEAK_QUEUE_LEN.to_string(),
+                report.peak_queue_len as f64
+            ),
+        ]
+    );
+
+    Ok(())
+}
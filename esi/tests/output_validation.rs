@@ -0,0 +1,134 @@
+use esi::{
+    Configuration, ExecutionError, MarkerStyle, PendingFragmentContent, Processor, Reader, Warning,
+    Writer,
+};
+use fastly::Response;
+
+// The offsets in the output of the problems found in it, with their descriptions
+type Problems = Vec<(usize, String)>;
+
+// Processes `input` with output validation on, with each fragment's body given by `fragment`,
+// returning the output and the problems found in it.
+fn process(
+    configuration: Configuration,
+    input: &str,
+    fragment: &'static [u8],
+) -> Result<(Vec<u8>, Problems), ExecutionError> {
+    let processor = Processor::new(None, configuration.with_debug_validate_output(true));
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&move |_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(fragment),
+            ))
+        }),
+        None,
+    )?;
+
+    let problems = report
+        .warnings
+        .into_iter()
+        .filter_map(|warning| match warning {
+            Warning::InvalidOutput { offset, problem } => Some((offset, problem)),
+            _ => None,
+        })
+        .collect();
+    Ok((writer.into_inner(), problems))
+}
+
+#[test]
+fn well_formed_output_has_no_problems() -> Result<(), ExecutionError> {
+    let (output, problems) = process(
+        Configuration::default().with_fragment_markers(MarkerStyle::HtmlComment),
+        r#"<p title="a > b">1 < 2</p><!-- <esi:x> --><esi:include src="/a"/><script>if (a<b) {}</script>"#,
+        "<b>é</b>".as_bytes(),
+    )?;
+
+    assert!(String::from_utf8(output).is_ok());
+    assert_eq!(problems, []);
+
+    Ok(())
+}
+
+#[test]
+fn unprocessed_esi_tags_are_reported() -> Result<(), ExecutionError> {
+    let (output, problems) = process(
+        Configuration::default(),
+        r#"<p></p><esi:include src="/a"/>"#,
+        br#"<esi:include src="/b"/>"#,
+    )?;
+
+    assert_eq!(output, br#"<p></p><esi:include src="/b"/>"#);
+    assert_eq!(problems, [(7, "unprocessed `esi:include` tag".to_string())]);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_utf8_is_reported_at_its_offset() -> Result<(), ExecutionError> {
+    let (_, problems) = process(
+        Configuration::default(),
+        r#"<p>é</p><esi:include src="/a"/>"#,
+        b"ok\xff\xc3",
+    )?;
+
+    assert_eq!(
+        problems,
+        [
+            (11, "invalid UTF-8".to_string()),
+            (12, "truncated UTF-8 sequence".to_string()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn markers_must_nest_outside_of_tags() -> Result<(), ExecutionError> {
+    let (_, problems) = process(
+        Configuration::default().with_fragment_markers(MarkerStyle::HtmlComment),
+        r#"<esi:include src="/a"/>"#,
+        b"<a title=\"<!--esi-frag start slot=7 -->\">x</a><!--esi-frag end slot=9 -->",
+    )?;
+
+    assert_eq!(
+        problems,
+        [
+            (52, "fragment marker inside a tag".to_string()),
+            (
+                98,
+                "fragment marker for slot 9 ends inside slot 0".to_string()
+            ),
+            (
+                125,
+                "fragment marker for slot 0 ends without starting".to_string()
+            ),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn xml_output_must_be_balanced() -> Result<(), ExecutionError> {
+    let template = r#"<?xml version="1.0"?><feed><entry><esi:include src="/a"/></entry></feed>"#;
+
+    let (_, problems) = process(Configuration::default(), template, b"<title>x")?;
+    assert_eq!(
+        problems,
+        [
+            (42, "`entry` closes `title`".to_string()),
+            (50, "`feed` closes `entry`".to_string()),
+            (21, "element `feed` is never closed".to_string()),
+        ]
+    );
+
+    // Without the declaration, the output is taken as HTML, where elements needn't be closed.
+    let (_, problems) = process(Configuration::default(), &template[21..], b"<title>x")?;
+    assert_eq!(problems, []);
+
+    Ok(())
+}
@@ -1,4 +1,5 @@
-use esi::{parse_tags, Event, ExecutionError, Tag};
+use esi::attributes::{AcceptStatus, Critical};
+use esi::{parse_tags, Event, ExecutionError, Include, Tag, TryArmKind};
 use quick_xml::Reader;
 
 use std::sync::Once;
@@ -18,11 +19,12 @@ fn parse_basic_include() -> Result<(), ExecutionError> {
     let mut parsed = false;
 
     parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             src,
             alt,
             continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, "https://example.com/hello");
             assert_eq!(alt, None);
@@ -45,11 +47,12 @@ fn parse_advanced_include_with_namespace() -> Result<(), ExecutionError> {
     let mut parsed = false;
 
     parse_tags("app", &mut Reader::from_str(input), &mut |event| {
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             src,
             alt,
             continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, "abc");
             assert_eq!(alt, Some("def".to_string()));
@@ -72,11 +75,12 @@ fn parse_open_include() -> Result<(), ExecutionError> {
     let mut parsed = false;
 
     parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             src,
             alt,
             continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, "abc");
             assert_eq!(alt, Some("def".to_string()));
@@ -115,11 +119,12 @@ fn parse_basic_include_with_onerror() -> Result<(), ExecutionError> {
     let mut parsed = false;
 
     parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             src,
             alt,
             continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, "/_fragments/content.html");
             assert_eq!(alt, None);
@@ -143,11 +148,12 @@ fn parse_try_accept_only_include() -> Result<(), ExecutionError> {
     let mut parsed = false;
 
     parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             src,
             alt,
             continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, "abc");
             assert_eq!(alt, Some("def".to_string()));
@@ -183,29 +189,30 @@ fn parse_try_accept_except_include() -> Result<(), ExecutionError> {
 
     parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
         println!("Event - {event:?}");
-        if let Event::ESI(Tag::Include {
+        if let Event::ESI(Tag::Include(Include {
             ref src,
             ref alt,
             ref continue_on_error,
-        }) = event
+            ..
+        })) = event
         {
             assert_eq!(src, &"/foo");
             assert_eq!(alt, &None);
             assert!(!continue_on_error);
             plain_include_parsed = true;
         }
-        if let Event::ESI(Tag::Try {
-            attempt_events,
-            except_events,
-        }) = event
-        {
+        if let Event::ESI(Tag::Try { arms }) = event {
+            let [attempt, except] = <[_; 2]>::try_from(arms).unwrap();
+            assert_eq!(attempt.kind, TryArmKind::Attempt);
+            assert_eq!(except.kind, TryArmKind::Except);
             // process accept tasks
-            for attempt_event in attempt_events {
-                if let Event::ESI(Tag::Include {
+            for attempt_event in attempt.events {
+                if let Event::ESI(Tag::Include(Include {
                     src,
                     alt,
                     continue_on_error,
-                }) = attempt_event
+                    ..
+                })) = attempt_event
                 {
                     assert_eq!(src, "/abc");
                     assert_eq!(alt, None);
@@ -214,12 +221,13 @@ fn parse_try_accept_except_include() -> Result<(), ExecutionError> {
                 }
             }
             // process except tasks
-            for except_event in except_events {
-                if let Event::ESI(Tag::Include {
+            for except_event in except.events {
+                if let Event::ESI(Tag::Include(Include {
                     src,
                     alt,
                     continue_on_error,
-                }) = except_event
+                    ..
+                })) = except_event
                 {
                     assert_eq!(src, "/xyz");
                     assert_eq!(alt, None);
@@ -237,3 +245,395 @@ fn parse_try_accept_except_include() -> Result<(), ExecutionError> {
 
     Ok(())
 }
+
+// Collects the `src` of every include, including those nested in `esi:try` arms and `esi:group`
+// blocks.
+fn collect_includes(events: Vec<Event>, includes: &mut Vec<String>) {
+    for event in events {
+        match event {
+            Event::ESI(Tag::Include(Include { src, .. })) => includes.push(src),
+            Event::ESI(Tag::Try { arms }) => {
+                for arm in arms {
+                    collect_includes(arm.events, includes);
+                }
+            }
+            Event::ESI(Tag::Group { events, .. }) => collect_includes(events, includes),
+            Event::ESI(Tag::Choose {
+                when_branches,
+                otherwise_events,
+            }) => {
+                for (_, events) in when_branches {
+                    collect_includes(events, includes);
+                }
+                collect_includes(otherwise_events, includes);
+            }
+            Event::ESI(
+                Tag::ResponseDirective { .. }
+                | Tag::DebugComment { .. }
+                | Tag::VarsValue { .. }
+                | Tag::Vars { .. },
+            )
+            | Event::XML(_) => {}
+        }
+    }
+}
+
+fn parse_includes(input: &str) -> Result<Vec<String>, ExecutionError> {
+    // Like `Processor::process_response`, don't let the XML reader reject mismatched tags.
+    let mut reader = Reader::from_str(input);
+    reader.config_mut().check_end_names = false;
+
+    let mut includes = Vec::new();
+    parse_tags("esi", &mut reader, &mut |event| {
+        collect_includes(vec![event], &mut includes);
+        Ok(())
+    })?;
+    Ok(includes)
+}
+
+#[test]
+fn parse_include_inside_remove() -> Result<(), ExecutionError> {
+    setup();
+
+    let includes = parse_includes(
+        r#"<esi:remove><esi:include src="/removed"/></esi:remove><esi:include src="/kept"/>"#,
+    )?;
+
+    assert_eq!(includes, ["/kept"]);
+
+    Ok(())
+}
+
+#[test]
+fn parse_include_inside_remove_inside_attempt() -> Result<(), ExecutionError> {
+    setup();
+
+    let includes = parse_includes(
+        r#"<esi:try><esi:attempt><esi:remove><esi:include src="/removed"/></esi:remove><esi:include src="/attempt"/></esi:attempt><esi:except><esi:include src="/except"/></esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(includes, ["/attempt", "/except"]);
+
+    Ok(())
+}
+
+#[test]
+fn parse_remove_around_whole_try() -> Result<(), ExecutionError> {
+    setup();
+
+    let includes = parse_includes(
+        r#"<esi:remove><esi:try><esi:attempt><esi:include src="/removed"/></esi:attempt><esi:except></esi:except></esi:try></esi:remove><esi:include src="/kept"/>"#,
+    )?;
+
+    assert_eq!(includes, ["/kept"]);
+
+    Ok(())
+}
+
+#[test]
+fn parse_remove_spanning_into_attempt() {
+    setup();
+
+    let input = r#"<esi:try><esi:remove><esi:attempt></esi:remove><esi:include src="/a"/></esi:attempt></esi:try>"#;
+
+    let res = parse_includes(input);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::TagCrossesArmBoundary(tag, 9)) if tag == "esi:remove"
+    ));
+}
+
+#[test]
+fn parse_remove_spanning_out_of_attempt() {
+    setup();
+
+    let input = r#"<esi:try><esi:attempt><esi:remove></esi:attempt><esi:except></esi:remove></esi:except></esi:try>"#;
+
+    let res = parse_includes(input);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::TagCrossesArmBoundary(tag, 22)) if tag == "esi:remove"
+    ));
+}
+
+#[test]
+fn parse_nested_remove() -> Result<(), ExecutionError> {
+    setup();
+
+    let includes = parse_includes(
+        r#"<esi:remove><esi:remove><esi:include src="/inner"/></esi:remove><esi:include src="/outer"/></esi:remove><esi:include src="/kept"/>"#,
+    )?;
+
+    assert_eq!(includes, ["/kept"]);
+
+    Ok(())
+}
+
+#[test]
+fn parse_unclosed_remove() {
+    setup();
+
+    let res = parse_includes(r#"<esi:remove><esi:include src="/removed"/>"#);
+
+    assert!(matches!(res, Err(ExecutionError::UnexpectedEndOfDocument)));
+}
+
+// Only the configured namespace is recognized, so the tags of any other are plain content.
+#[test]
+fn parse_tags_of_one_namespace_only() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<app:include src="/a"/><esi:include src="/b"/>"#;
+    let mut includes = Vec::new();
+    let mut xml_events = 0;
+
+    parse_tags("app", &mut Reader::from_str(input), &mut |event| {
+        match event {
+            Event::ESI(Tag::Include(Include { src, .. })) => includes.push(src),
+            Event::ESI(_) => {}
+            Event::XML(_) => xml_events += 1,
+        }
+        Ok(())
+    })?;
+
+    assert_eq!(includes, ["/a"]);
+    assert_eq!(xml_events, 1);
+
+    Ok(())
+}
+
+#[test]
+fn parse_lookalike_tags_as_content() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<esi:includes src="/a"/><esi:include-all src="/b"/><esi:tryhard><esi:attempts></esi:attempts></esi:tryhard><esi:removed>kept</esi:removed><esi:commentary/><esix:include src="/c"/>"#;
+    let mut esi_events = 0;
+    let mut xml_events = 0;
+
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        match event {
+            Event::ESI(_) => esi_events += 1,
+            Event::XML(_) => xml_events += 1,
+        }
+        Ok(())
+    })?;
+
+    // The lookalike tags in the `esi` namespace are left out as unknown tags, keeping only the
+    // text inside them and the tag in another namespace.
+    assert_eq!(esi_events, 0);
+    assert_eq!(xml_events, 2);
+
+    Ok(())
+}
+
+#[test]
+fn parse_try_without_except() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<esi:try><esi:attempt><esi:include src="/a"/></esi:attempt></esi:try>"#;
+    let mut parsed = false;
+
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Try { arms }) = event {
+            assert_eq!(arms.len(), 1);
+            assert_eq!(arms[0].kind, TryArmKind::Attempt);
+            assert_eq!(arms[0].events.len(), 1);
+            parsed = true;
+        }
+        Ok(())
+    })?;
+
+    assert!(parsed);
+
+    Ok(())
+}
+
+#[test]
+fn parse_try_arms_in_order_with_except_last() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<esi:try><esi:attempt><esi:include src="/a"/></esi:attempt><esi:except><esi:include src="/d"/></esi:except><esi:retry><esi:include src="/b"/></esi:retry><esi:attempt><esi:include src="/c"/></esi:attempt></esi:try>"#;
+    let mut arms = Vec::new();
+
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Try { arms: try_arms }) = event {
+            for arm in try_arms {
+                let mut includes = Vec::new();
+                collect_includes(arm.events, &mut includes);
+                arms.push((arm.kind, includes));
+            }
+        }
+        Ok(())
+    })?;
+
+    assert_eq!(
+        arms,
+        [
+            (TryArmKind::Attempt, vec!["/a".to_string()]),
+            (
+                TryArmKind::Custom("retry".to_string()),
+                vec!["/b".to_string()]
+            ),
+            (TryArmKind::Attempt, vec!["/c".to_string()]),
+            (TryArmKind::Except, vec!["/d".to_string()]),
+        ]
+    );
+
+    Ok(())
+}
+
+// The attributes of a parsed include, in a form that's easy to compare
+type IncludeAttributes = (String, Option<String>, bool, bool, Option<String>);
+
+fn parse_include_attributes(input: &str) -> Result<Vec<IncludeAttributes>, ExecutionError> {
+    let mut includes = Vec::new();
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Include(include)) = event {
+            let critical = include.get::<Critical>().is_some_and(|critical| critical.0);
+            let accept_status = include
+                .get::<AcceptStatus>()
+                .map(|codes| codes.0.to_string());
+            includes.push((
+                include.src,
+                include.alt,
+                include.continue_on_error,
+                critical,
+                accept_status,
+            ));
+        }
+        Ok(())
+    })?;
+    Ok(includes)
+}
+
+#[test]
+fn parse_include_attributes_in_any_form() -> Result<(), ExecutionError> {
+    setup();
+
+    let cases = [
+        (
+            r#"<esi:include src="/a"/>"#,
+            ("/a", None, false, false, None),
+        ),
+        (
+            r#"<esi:include critical="true" accept-status="404" onerror="continue" alt="/b" src="/a"/>"#,
+            ("/a", Some("/b"), true, true, Some("404")),
+        ),
+        (
+            r#"<esi:include src='/a?x=1&amp;y=2' alt='/b'/>"#,
+            ("/a?x=1&amp;y=2", Some("/b"), false, false, None),
+        ),
+        (
+            r#"<esi:include src="/first" src="/second" data-src="/other"/>"#,
+            ("/first", None, false, false, None),
+        ),
+        (
+            r#"<esi:include src="/a" onerror="abort" critical="false"/>"#,
+            ("/a", None, false, false, None),
+        ),
+    ];
+
+    for (input, (src, alt, continue_on_error, critical, accept_status)) in cases {
+        let includes = parse_include_attributes(input)?;
+        assert_eq!(
+            includes,
+            [(
+                src.to_string(),
+                alt.map(str::to_string),
+                continue_on_error,
+                critical,
+                accept_status.map(str::to_string),
+            )],
+            "{input}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn namespaced_include_attributes_are_extensions() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<app:include src="/a" app:backend="catalog" app:hint="a&amp;b" esi:other="x" data-id="1"/>"#;
+    let mut extensions = None;
+
+    parse_tags("app", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Include(Include {
+            extensions: include_extensions,
+            ..
+        })) = event
+        {
+            extensions = Some(include_extensions);
+        }
+        Ok(())
+    })?;
+
+    assert_eq!(
+        extensions.unwrap(),
+        [
+            ("backend".to_string(), "catalog".to_string()),
+            ("hint".to_string(), "a&b".to_string()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn open_include_body_is_its_fallback() -> Result<(), ExecutionError> {
+    setup();
+
+    let input = r#"<esi:include src="/a"><p class="x">a &amp; b<esi:include src="/b"/></p></esi:include><esi:include src="/c"> </esi:include><esi:include srcset="/d /e">d</esi:include><esi:include src="/f"/>"#;
+    let mut fallbacks = Vec::new();
+
+    parse_tags("esi", &mut Reader::from_str(input), &mut |event| {
+        if let Event::ESI(Tag::Include(Include { src, fallback, .. })) = event {
+            fallbacks.push((src, fallback.map(|f| String::from_utf8(f).unwrap())));
+        }
+        Ok(())
+    })?;
+
+    assert_eq!(
+        fallbacks,
+        [
+            (
+                "/a".to_string(),
+                Some(r#"<p class="x">a &amp; b</p>"#.to_string())
+            ),
+            ("/c".to_string(), None),
+            ("/d".to_string(), Some("d".to_string())),
+            ("/e".to_string(), Some("d".to_string())),
+            ("/f".to_string(), None),
+        ]
+    );
+
+    Ok(())
+}
+
+// Times the parsing of a document with many includes. Run it with
+// `cargo test --test parse -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_parse_many_includes() -> Result<(), ExecutionError> {
+    const ROUNDS: u32 = 20;
+    let input = (0..1000)
+        .map(|i| {
+            format!(r#"<p>item {i}</p><esi:include src="/fragment/{i}?a=1&amp;b=2" alt="/fallback/{i}" onerror="continue" data-id="{i}"/>"#)
+        })
+        .collect::<String>();
+
+    let started = std::time::Instant::now();
+    for _ in 0..ROUNDS {
+        let includes = parse_include_attributes(&input)?;
+        assert_eq!(includes.len(), 1000);
+    }
+    println!(
+        "parsed 1000 includes in {:?} per round",
+        started.elapsed() / ROUNDS
+    );
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use esi::{
+    Clock, Configuration, ExecutionError, PendingFragmentContent, Phase, ProcessingReport,
+    Processor, Reader, Writer,
+};
+use fastly::Response;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// A clock that moves forward a millisecond every time it's read
+fn ticking_clock() -> Clock {
+    let start = Instant::now();
+    let ticks = Cell::new(0);
+    Clock::new(move || {
+        ticks.set(ticks.get() + 1);
+        start + Duration::from_millis(ticks.get())
+    })
+}
+
+// Processes the template, returning the report and the phases in the order they were reported.
+fn process(template: &str) -> Result<(ProcessingReport, Vec<Phase>), ExecutionError> {
+    let reached = Rc::new(RefCell::new(Vec::new()));
+    let on_phase = Rc::clone(&reached);
+    let processor = Processor::new(None, Configuration::default().with_clock(ticking_clock()))
+        .on_phase(move |phase| on_phase.borrow_mut().push(phase));
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    let reached = reached.take();
+    Ok((report, reached))
+}
+
+#[test]
+fn phases_are_timed_in_the_order_they_are_reached() -> Result<(), ExecutionError> {
+    let (report, reached) =
+        process(r#"<p>head</p><esi:include src="/a"/><p>body</p><esi:include src="/b"/>"#)?;
+
+    assert_eq!(
+        reached,
+        [
+            Phase::Started,
+            Phase::FirstOutputByte,
+            Phase::FirstDispatch,
+            Phase::ParseComplete,
+            Phase::LastFragmentComplete,
+            Phase::Finalized,
+        ]
+    );
+    let timings: Vec<Duration> = reached
+        .iter()
+        .map(|phase| report.phases.get(*phase).expect("the phase was reached"))
+        .collect();
+    assert!(
+        timings.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{timings:?}"
+    );
+    assert!(report.phases.first_output_byte < report.phases.last_fragment_complete);
+
+    Ok(())
+}
+
+#[test]
+fn a_document_without_includes_has_no_fragment_phases() -> Result<(), ExecutionError> {
+    let (report, reached) = process("<p>static</p>")?;
+
+    assert_eq!(
+        reached,
+        [
+            Phase::Started,
+            Phase::FirstOutputByte,
+            Phase::ParseComplete,
+            Phase::Finalized,
+        ]
+    );
+    assert_eq!(report.phases.first_dispatch, None);
+    assert_eq!(report.phases.last_fragment_complete, None);
+    // Timing the `on_phase` call for the start reads the clock twice before the first byte.
+    assert!(report
+        .phases
+        .to_server_timing()
+        .starts_with("esi-ttfb;dur=3.0, esi-parse;dur="));
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Progress,
+    Reader, Scheduling, SchedulingStats, Writer,
+};
+use fastly::http::request::PollResult;
+use fastly::{Backend, Request, Response};
+
+#[test]
+fn completed_fragments_are_drained_while_parsing() -> Result<(), ExecutionError> {
+    let input = r#"<p>one</p><esi:include src="/1" onerror="continue"/><p>two</p><esi:include src="/2" onerror="continue"/><p>three</p>"#;
+
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&calls);
+    let processed = Rc::clone(&calls);
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&move |req| {
+            dispatched
+                .borrow_mut()
+                .push(format!("dispatch {}", req.get_path()));
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_status(503),
+            ))
+        }),
+        Some(&move |req, resp| {
+            processed
+                .borrow_mut()
+                .push(format!("process {}", req.get_path()));
+            Ok(resp)
+        }),
+    )?;
+
+    // The first fragment is already complete, so it's handled before the second is even parsed.
+    assert_eq!(
+        *calls.borrow(),
+        ["dispatch /1", "process /1", "dispatch /2", "process /2"]
+    );
+    assert_eq!(
+        String::from_utf8(writer.into_inner()).unwrap(),
+        "<p>one</p><p>two</p><p>three</p>"
+    );
+
+    Ok(())
+}
+
+// Process the document with fragments that complete straight away, and return the calls made to
+// the dispatcher and the response processor in order, along with the report.
+fn process_scheduled(
+    configuration: Configuration,
+    input: &str,
+) -> Result<(Vec<String>, ProcessingReport), ExecutionError> {
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&calls);
+    let processed = Rc::clone(&calls);
+
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&move |req| {
+            dispatched
+                .borrow_mut()
+                .push(format!("dispatch {}", req.get_path()));
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body("fragment"),
+            ))
+        }),
+        Some(&move |req, resp| {
+            processed
+                .borrow_mut()
+                .push(format!("process {}", req.get_path()));
+            Ok(resp)
+        }),
+    )?;
+
+    Ok((calls.take(), report))
+}
+
+fn scheduling(events_per_drain_check: usize, max_drain_per_check: Option<usize>) -> Configuration {
+    Configuration::default().with_scheduling(Scheduling {
+        events_per_drain_check,
+        max_drain_per_check,
+    })
+}
+
+#[test]
+fn checking_less_often_dispatches_later_includes_sooner_but_buffers_more(
+) -> Result<(), ExecutionError> {
+    let input = format!(
+        r#"<esi:include src="/early"/>{}<esi:include src="/late"/><p>end</p>"#,
+        "<p>filler</p>".repeat(10)
+    );
+
+    let (often, often_report) = process_scheduled(scheduling(1, None), &input)?;
+    let (rarely, rarely_report) = process_scheduled(scheduling(100, None), &input)?;
+
+    // Checking at every event, the early fragment is written before the late one is dispatched.
+    assert_eq!(
+        often,
+        [
+            "dispatch /early",
+            "process /early",
+            "dispatch /late",
+            "process /late"
+        ]
+    );
+    // Checking rarely, the late one is dispatched first, with the filler buffered behind the
+    // early one in the meantime.
+    assert_eq!(
+        rarely,
+        [
+            "dispatch /early",
+            "dispatch /late",
+            "process /early",
+            "process /late"
+        ]
+    );
+    assert!(often_report.peak_buffered_bytes < "<p>filler</p>".len());
+    assert!(rarely_report.peak_buffered_bytes >= "<p>filler</p>".len() * 10);
+
+    assert_eq!(
+        often_report.scheduling,
+        SchedulingStats {
+            drain_checks: 2,
+            drained_fragments: 2,
+            max_events_between_checks: 2,
+        }
+    );
+    assert_eq!(rarely_report.scheduling, SchedulingStats::default());
+
+    Ok(())
+}
+
+#[test]
+fn checks_write_out_at_most_max_drain_per_check_fragments() -> Result<(), ExecutionError> {
+    let input =
+        r#"<esi:include src="/1"/><esi:include src="/2"/><esi:include src="/3"/><p>a</p><p>b</p>"#;
+
+    let (_, unlimited) = process_scheduled(scheduling(1, None), input)?;
+    let (_, limited) = process_scheduled(scheduling(1, Some(1)), input)?;
+
+    assert_eq!(
+        unlimited.scheduling,
+        SchedulingStats {
+            drain_checks: 1,
+            drained_fragments: 3,
+            max_events_between_checks: 4,
+        }
+    );
+    // One fragment is written at each event, and the content queued behind them at the next.
+    assert_eq!(
+        limited.scheduling,
+        SchedulingStats {
+            drain_checks: 4,
+            drained_fragments: 3,
+            max_events_between_checks: 4,
+        }
+    );
+    assert_eq!(unlimited.bytes_written, limited.bytes_written);
+
+    Ok(())
+}
+
+// Only `/slow` is sent to a backend, so the later fragments have completed while it's still
+// pending. They're held back all the same, and nothing is drained out of order.
+#[test]
+fn output_is_in_document_order_when_the_first_fragment_is_slowest() -> Result<(), ExecutionError> {
+    let input = r#"<esi:include src="/slow"/><esi:include src="/medium"/><esi:include src="/fast"/><p>end</p>"#;
+    // Nothing listens on this port, so the request is pending until the connection is refused.
+    let backend = Backend::builder("slow", "127.0.0.1:1").finish().unwrap();
+
+    // The refusal often arrives before the session polls the request, in which case it's never
+    // lent out and fails the document, so keep trying until it is.
+    let dispatch = |req: Request| match req.get_path() {
+        "/slow" => Ok(req.send_async(backend.clone())?.into()),
+        path => Ok(Response::from_body(format!("{path},")).into()),
+    };
+    for _ in 0..1000 {
+        let processor = Processor::new(None, Configuration::default());
+        let mut session = processor.session(Reader::from_str(input), Some(&dispatch), None);
+        let mut writer = Writer::new(Vec::new());
+
+        let Ok(Progress::NeedsWait(pending_request)) = session.poll_progress(&mut writer) else {
+            continue;
+        };
+        assert_eq!(pending_request.sent_req().get_path(), "/slow");
+        assert!(writer.get_ref().is_empty());
+
+        session.resume(PollResult::Done(Ok(Response::from_body("/slow,"))))?;
+        let Progress::Complete(report) = session.poll_progress(&mut writer)? else {
+            panic!("the other fragments have already completed");
+        };
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "/slow,/medium,/fast,<p>end</p>"
+        );
+        assert_eq!(
+            report.scheduling,
+            SchedulingStats {
+                drain_checks: 1,
+                drained_fragments: 0,
+                max_events_between_checks: 4,
+            }
+        );
+        return Ok(());
+    }
+
+    panic!("the request to /slow was never pending");
+}
+
+#[test]
+fn nested_fragments_are_written_in_document_order() -> Result<(), ExecutionError> {
+    let input = r#"<esi:try><esi:attempt><esi:include src="/1"/><esi:group name="g"><esi:include src="/2"/></esi:group></esi:attempt><esi:except>x</esi:except></esi:try><esi:include src="/3"/><esi:group name="h"><esi:include src="/4"/></esi:group>"#;
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    assert_eq!(
+        String::from_utf8(writer.into_inner()).unwrap(),
+        "[/1][/2][/3][/4]"
+    );
+
+    Ok(())
+}
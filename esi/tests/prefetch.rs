@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, PrefetchReport, Processor, Reader,
+};
+use fastly::{Request, Response};
+
+const TEMPLATE: &str = r#"<html>
+<esi:include src="/header"/>
+<esi:include src="/header"/>
+<esi:try>
+    <esi:attempt><esi:include src="/attempt"/></esi:attempt>
+    <esi:except><esi:include src="/except"/></esi:except>
+</esi:try>
+<esi:include src="/broken" alt="/fallback"/>
+<esi:include src="/empty"/>
+<esi:include src="data:,inline"/>
+</html>"#;
+
+type Cache = HashMap<String, Vec<u8>>;
+
+// Prefetch the template, returning the report, the cache and the paths that were dispatched.
+fn prefetch(
+    configuration: Configuration,
+) -> Result<(PrefetchReport, Cache, Vec<String>), ExecutionError> {
+    let dispatched = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&dispatched);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page")),
+        configuration,
+    );
+    let mut cache = HashMap::new();
+    let report = processor.prefetch(
+        Reader::from_str(TEMPLATE),
+        Some(&move |req| {
+            seen.borrow_mut().push(req.get_path().to_string());
+            Ok(match req.get_path() {
+                "/broken" => Response::from_status(500).into(),
+                "/empty" => PendingFragmentContent::NoContent,
+                path => Response::from_body(format!("[{path}]")).into(),
+            })
+        }),
+        &mut cache,
+    )?;
+
+    Ok((report, cache, dispatched.take()))
+}
+
+fn cached(entries: &[&str]) -> Cache {
+    entries
+        .iter()
+        .map(|path| {
+            (
+                format!("http://www.example.com{path}"),
+                format!("[{path}]").into_bytes(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn prefetch_fills_the_cache() -> Result<(), ExecutionError> {
+    let (report, cache, dispatched) = prefetch(Configuration::default())?;
+
+    assert_eq!(dispatched, ["/header", "/attempt", "/broken", "/empty"]);
+    assert_eq!(cache, cached(&["/header", "/attempt"]));
+    assert_eq!(
+        (report.warmed, report.failed, report.skipped),
+        // The duplicate header, the empty fragment and the data URI are skipped.
+        (2, 1, 3)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn prefetch_can_include_except_arms() -> Result<(), ExecutionError> {
+    let (report, cache, dispatched) =
+        prefetch(Configuration::default().with_prefetch_except(true))?;
+
+    assert_eq!(
+        dispatched,
+        ["/header", "/attempt", "/except", "/broken", "/empty"]
+    );
+    assert_eq!(cache, cached(&["/header", "/attempt", "/except"]));
+    assert_eq!((report.warmed, report.failed, report.skipped), (3, 1, 3));
+
+    Ok(())
+}
+
+#[test]
+fn prefetch_skips_duplicates_by_their_evaluated_url() -> Result<(), ExecutionError> {
+    let dispatched = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&dispatched);
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page?a=1&b=2")),
+        Configuration::default(),
+    );
+
+    let report = processor.prefetch(
+        Reader::from_str(
+            r#"<esi:include src="/p?v=$(QUERY_STRING{a})"/><esi:include src="/p?v=$(QUERY_STRING{b})"/><esi:include src="/p?v=1"/>"#,
+        ),
+        Some(&move |req| {
+            seen.borrow_mut().push(req.get_url_str().to_string());
+            Ok(Response::from_body("[p]").into())
+        }),
+        &mut HashMap::new(),
+    )?;
+
+    assert_eq!(
+        dispatched.take(),
+        [
+            "http://www.example.com/p?v=1",
+            "http://www.example.com/p?v=2"
+        ]
+    );
+    assert_eq!((report.warmed, report.skipped), (2, 1));
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Writer,
+};
+use fastly::{Request, Response};
+
+fn original_request() -> Request {
+    Request::get("http://www.example.com/page")
+        .with_header("accept-language", "fr")
+        .with_header("cookie", "uid=1")
+        .with_header("x-tenant", "acme")
+}
+
+fn process(
+    configuration: Configuration,
+    template: &str,
+    fragment: &'static [u8],
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(Some(original_request()), configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(fragment),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+// The options of `configuration` that differ from the default, as they're described.
+fn changed_options(configuration: &Configuration) -> Vec<String> {
+    let default = Configuration::default().describe();
+    configuration
+        .describe()
+        .options
+        .into_iter()
+        .filter(|(name, value)| default.get(name) != Some(value.as_str()))
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect()
+}
+
+// Pins what each preset changes, so that a new option, or a changed default, is looked at for
+// each of them.
+#[test]
+fn presets_differ_from_the_default_as_documented() {
+    assert_eq!(
+        changed_options(&Configuration::preset_html_storefront()),
+        [
+            "header_forwarding=allowlist accept-language,cookie",
+            "validate_fragment_utf8=lossy",
+            "max_document_size=1048576",
+            "deadline_ms=5000",
+        ]
+    );
+    assert_eq!(
+        changed_options(&Configuration::preset_json_api()),
+        [
+            "is_escaped=false",
+            "strict_urls=true",
+            "validate_fragment_utf8=reject",
+            "max_document_size=1048576",
+            "oversized_document=fail",
+            "output_mode=raw_passthrough",
+        ]
+    );
+    assert_eq!(
+        changed_options(&Configuration::preset_spec_strict()),
+        [
+            "strict_urls=true",
+            "oversized_document=fail",
+            "spec_compliance=enforce",
+            "strict_prestream=true",
+            "strict_mode=true",
+        ]
+    );
+}
+
+// The same oversized document with a `srcset` include is cut short with a comment, fails, and
+// is rejected outright before anything is fetched.
+#[test]
+fn oversized_document_diverges_across_presets() -> Result<(), ExecutionError> {
+    let template = format!(
+        r#"<p><esi:include srcset="/a /b"/></p>{}"#,
+        "<p>lorem ipsum</p>".repeat(100_000)
+    );
+
+    let (output, report) = process(Configuration::preset_html_storefront(), &template, b"[f]")?;
+    assert!(output.starts_with("<p>[f][f]</p><p>lorem ipsum</p>"));
+    assert!(output.ends_with("<!-- esi: document truncated -->"));
+    assert!(report.truncated);
+
+    assert!(matches!(
+        process(Configuration::preset_json_api(), &template, b"[f]"),
+        Err(ExecutionError::DocumentTooLarge(1048576))
+    ));
+
+    assert!(matches!(
+        process(Configuration::preset_spec_strict(), &template, b"[f]"),
+        Err(ExecutionError::SpecDeviation(..))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn invalid_utf8_diverges_across_presets() -> Result<(), ExecutionError> {
+    let template = r#"<esi:include src="/a"/>"#;
+
+    let (output, _) = process(Configuration::preset_html_storefront(), template, b"a\xffb")?;
+    assert_eq!(output, "a\u{fffd}b");
+
+    assert!(matches!(
+        process(Configuration::preset_json_api(), template, b"a\xffb"),
+        Err(ExecutionError::FragmentBodyError(..))
+    ));
+
+    let (output, _) = process(Configuration::preset_spec_strict(), template, b"ab")?;
+    assert_eq!(output, "ab");
+
+    Ok(())
+}
+
+#[test]
+fn storefront_forwards_only_language_and_cookie() -> Result<(), ExecutionError> {
+    let forwarded = Rc::new(RefCell::new(Vec::new()));
+    let processor = Processor::new(
+        Some(original_request()),
+        Configuration::preset_html_storefront(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(r#"<esi:include src="/a"/>"#),
+        &mut writer,
+        Some(&{
+            let forwarded = Rc::clone(&forwarded);
+            move |req| {
+                forwarded.borrow_mut().extend(
+                    ["accept-language", "cookie", "x-tenant"]
+                        .into_iter()
+                        .filter(|name| req.get_header(*name).is_some()),
+                );
+                Ok(PendingFragmentContent::CompletedRequest(Response::new()))
+            }
+        }),
+        None,
+    )?;
+
+    assert_eq!(*forwarded.borrow(), ["accept-language", "cookie"]);
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Warning};
+use fastly::Response;
+use quick_xml::{Reader, Writer};
+
+const LATE_RESPONSE: &str = r#"<p>Found</p><esi:response status="404"/><p>end</p>"#;
+
+fn process_to_response(configuration: Configuration) -> Result<Vec<Warning>, ExecutionError> {
+    let (resp, report) = Processor::new(None, configuration).process_to_response(
+        &mut Response::from_body(LATE_RESPONSE),
+        Some(Response::from_status(200)),
+        Some(&|_| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(""),
+            ))
+        }),
+        None,
+    )?;
+
+    assert_eq!(resp.get_status(), 200);
+    Ok(report.warnings)
+}
+
+#[test]
+fn late_response_directive_is_reported_with_its_position() -> Result<(), ExecutionError> {
+    let warnings = process_to_response(Configuration::default())?;
+
+    assert!(matches!(
+        warnings.as_slice(),
+        [Warning::TooLate { feature, position: 12 }] if feature == "esi:response"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn late_response_directive_fails_in_strict_mode() {
+    let res = process_to_response(Configuration::default().with_strict_prestream(true));
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::TooLate(feature, 12)) if feature == "esi:response"
+    ));
+}
+
+#[test]
+fn streamed_etag_fails_in_strict_mode() {
+    let configuration = Configuration::default()
+        .with_compute_etag(true)
+        .with_strict_prestream(true);
+    let mut writer = Writer::new(Vec::new());
+    let res = Processor::new(None, configuration).process_document(
+        Reader::from_str("<p>hi</p>"),
+        &mut writer,
+        None,
+        None,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::TooLate(feature, 0)) if feature == "compute_etag"
+    ));
+    assert!(writer.into_inner().is_empty());
+}
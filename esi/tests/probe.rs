@@ -0,0 +1,71 @@
+use esi::{
+    may_contain_esi, Configuration, ExecutionError, NamespaceProbe, Processor, Reader, Writer,
+};
+
+// ESI tag names everywhere but in element names
+const NO_TAGS: &str = r#"<p>Use esi:include and esi:try, or &lt;esi:include src="/a"/&gt;.</p><a title="esi:include" href="/esi:remove">esi:comment</a>"#;
+
+// ESI tags that the processor leaves alone, in a comment and a CDATA section
+const TAGS_IN_MARKUP: &str = r#"<!-- <esi:include src="/a"/> --><script><![CDATA[x = "<esi:include src='/b'/>";]]></script>"#;
+
+#[test]
+fn probe_ignores_tag_names_outside_element_names() {
+    assert!(!may_contain_esi(NO_TAGS.as_bytes(), "esi"));
+}
+
+#[test]
+fn probe_finds_tags() {
+    assert!(may_contain_esi(br#"<p><esi:include src="/a"/></p>"#, "esi"));
+    assert!(may_contain_esi(b"<app:vars/>", "app"));
+    assert!(!may_contain_esi(b"<esi:vars/>", "app"));
+}
+
+#[test]
+fn probe_finds_tags_straddling_chunks() {
+    let document = br#"<p>text</p><esi:include src="/a"/>"#;
+    let tag = 11;
+
+    for split in 0..=document.len() {
+        for second_split in split..=document.len() {
+            let mut probe = NamespaceProbe::new("esi");
+            let chunks = [
+                &document[..split],
+                &document[split..second_split],
+                &document[second_split..],
+            ];
+            let found: Vec<bool> = chunks.iter().map(|chunk| probe.feed(chunk)).collect();
+
+            // The tag is found in the chunk that completes its `<esi:` prefix.
+            let complete = tag + "<esi:".len();
+            let expected = [split >= complete, second_split >= complete, true];
+            assert_eq!(found, expected, "split at {split} and {second_split}");
+            assert!(probe.found());
+        }
+    }
+}
+
+#[test]
+fn probe_is_conservative_about_comments_and_cdata() {
+    assert!(may_contain_esi(TAGS_IN_MARKUP.as_bytes(), "esi"));
+}
+
+#[test]
+fn tag_names_outside_element_names_are_not_processed() -> Result<(), ExecutionError> {
+    for document in [NO_TAGS, TAGS_IN_MARKUP] {
+        let processor = Processor::new(None, Configuration::default());
+        let mut writer = Writer::new(Vec::new());
+
+        let report = processor.process_document(
+            Reader::from_str(document),
+            &mut writer,
+            Some(&|_| panic!("nothing should be dispatched")),
+            None,
+        )?;
+
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), document);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert!(report.includes.is_empty());
+    }
+
+    Ok(())
+}
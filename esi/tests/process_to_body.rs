@@ -0,0 +1,65 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Warning};
+use fastly::{Request, Response};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn fragment_content(req: Request) -> esi::Result<PendingFragmentContent> {
+    Ok(Response::from_body(format!("[{}]", req.get_path())).into())
+}
+
+#[test]
+fn fragments_are_assembled_into_the_body() -> Result<(), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+
+    let (body, report) = processor.process_to_body(
+        &mut Response::from_body(
+            r#"<p>a</p><esi:include src="/one"/><p>b</p><esi:include src="/two"/><p>c</p>"#,
+        ),
+        Some(&fragment_content),
+        None,
+    )?;
+
+    let output = body.into_string();
+    assert_eq!(output, "<p>a</p>[/one]<p>b</p>[/two]<p>c</p>");
+    assert_eq!(report.bytes_written, output.len());
+    assert_eq!(report.includes.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn body_is_hashed_with_compute_etag() -> Result<(), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default().with_compute_etag(true));
+
+    let (_, report) = processor.process_to_body(
+        &mut Response::from_body(r#"<p>a</p><esi:include src="/one"/>"#),
+        Some(&fragment_content),
+        None,
+    )?;
+
+    assert!(report.etag.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn response_directives_are_too_late() -> Result<(), ExecutionError> {
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    let raised = Rc::clone(&warnings);
+    let processor = Processor::new(None, Configuration::default())
+        .on_warning(move |warning| raised.borrow_mut().push(warning.clone()));
+
+    let (body, _) = processor.process_to_body(
+        &mut Response::from_body(r#"<esi:response status="404"/><p>a</p>"#),
+        Some(&fragment_content),
+        None,
+    )?;
+
+    assert_eq!(body.into_string(), "<p>a</p>");
+    assert!(matches!(
+        warnings.borrow().as_slice(),
+        [Warning::TooLate { feature, .. }] if feature == "esi:response"
+    ));
+
+    Ok(())
+}
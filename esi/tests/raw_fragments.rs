@@ -0,0 +1,71 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Utf8Policy, Writer,
+};
+use fastly::Response;
+
+// A Latin-1 "é" in a body declared as ISO-8859-1, which is transcoded unless it's raw
+const LATIN1_BODY: &[u8] = b"<svg>caf\xe9</svg>";
+// An invalid UTF-8 sequence, which is replaced unless it's raw
+const BROKEN_BODY: &[u8] = b"{\"a\":\"\xff\"}";
+
+fn process(
+    policy: Utf8Policy,
+    template: &str,
+) -> Result<(Vec<u8>, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_validate_fragment_utf8(policy),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            let resp = match req.get_path() {
+                "/latin1" => Response::from_body(LATIN1_BODY)
+                    .with_header("content-type", "text/html; charset=iso-8859-1"),
+                _ => Response::from_body(BROKEN_BODY),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(resp))
+        }),
+        None,
+    )?;
+
+    Ok((writer.into_inner(), report))
+}
+
+#[test]
+fn raw_fragments_skip_the_utf8_policy() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        Utf8Policy::Lossy,
+        r#"<esi:include src="/latin1"/>|<esi:include src="/latin1" raw="true"/>|<esi:include src="/broken"/>|<esi:include src="/broken" raw="true"/>"#,
+    )?;
+
+    let expected = [
+        "<svg>café</svg>|".as_bytes(),
+        LATIN1_BODY,
+        "|{\"a\":\"\u{fffd}\"}|".as_bytes(),
+        BROKEN_BODY,
+    ]
+    .concat();
+    assert_eq!(output, expected);
+    let raw: Vec<bool> = report.includes.iter().map(|include| include.raw).collect();
+    assert_eq!(raw, [false, true, false, true]);
+
+    Ok(())
+}
+
+#[test]
+fn raw_fragments_in_try_blocks_skip_the_utf8_policy() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        Utf8Policy::Reject,
+        r#"<esi:try><esi:attempt><esi:include src="/broken" raw="true"/></esi:attempt><esi:except>except</esi:except></esi:try>|<esi:try><esi:attempt><esi:include src="/broken"/></esi:attempt><esi:except>except</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, [BROKEN_BODY, b"|except"].concat());
+    assert!(report.includes[0].raw);
+
+    Ok(())
+}
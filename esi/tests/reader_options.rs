@@ -0,0 +1,93 @@
+use esi::{
+    Configuration, ExecutionError, ProcessingReport, Processor, Reader, ReaderOptions, Writer,
+};
+use fastly::Body;
+
+fn process(
+    configuration: Configuration,
+    template: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let reader = processor.reader_with_config(Body::from(template));
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(reader, &mut writer, None, None)?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn unclosed_void_elements_are_parsed_by_default() -> Result<(), ExecutionError> {
+    let template = r#"<p>one<br>two<img src="a.png"></p>"#;
+
+    let (output, report) = process(Configuration::default(), template)?;
+
+    assert_eq!(output, template);
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn strict_reader_fails_with_the_location_of_the_markup() {
+    let res = process(
+        Configuration::default().with_reader_options(ReaderOptions::strict()),
+        "<html>\n<body>\n<p>one<br>two</p>\n</body>\n</html>",
+    );
+
+    let Err(err @ ExecutionError::XMLError(_, Some((3, 14)))) = res else {
+        panic!("unexpected result: {res:?}");
+    };
+    assert!(err.to_string().contains("at line 3, column 14"));
+}
+
+#[test]
+fn strict_reader_applies_to_readers_of_their_own() {
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_reader_options(ReaderOptions::strict()),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let res = processor.process_document(
+        Reader::from_str("<div>\n  <span>a</div>"),
+        &mut writer,
+        None,
+        None,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::XMLError(_, Some((2, 10))))
+    ));
+}
+
+#[test]
+fn text_is_trimmed() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        Configuration::default().with_reader_options(ReaderOptions {
+            trim_text: true,
+            ..ReaderOptions::default()
+        }),
+        "<ul>\n  <li> a </li>\n  <li>b</li>\n</ul>\n",
+    )?;
+
+    assert_eq!(output, "<ul><li>a</li><li>b</li></ul>");
+
+    Ok(())
+}
+
+#[test]
+fn empty_elements_are_expanded() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        Configuration::default().with_reader_options(ReaderOptions {
+            expand_empty_elements: true,
+            ..ReaderOptions::default()
+        }),
+        "<p>a<br/>b</p>",
+    )?;
+
+    assert_eq!(output, "<p>a<br></br>b</p>");
+
+    Ok(())
+}
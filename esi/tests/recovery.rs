@@ -0,0 +1,156 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingOutcome, Processor, Reader,
+    Writer,
+};
+use fastly::Response;
+use std::io::{self, Write};
+
+const ERROR_FRAGMENT: &[u8] = b"<p>error</p>";
+
+// An output that records flushes, and rejects any write that would take it over its capacity.
+#[derive(Default)]
+struct Sink {
+    data: Vec<u8>,
+    capacity: Option<usize>,
+    flushes: usize,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self
+            .capacity
+            .is_some_and(|capacity| self.data.len() + buf.len() > capacity)
+        {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+// Processes a template whose includes all fail, then writes the error fragment if the outcome
+// allows it, the way an application recovering from an error would.
+fn process_and_recover(
+    template: &str,
+    capacity: Option<usize>,
+) -> (Result<(), ExecutionError>, ProcessingOutcome, Sink) {
+    let processor = Processor::new(None, Configuration::default());
+    let outcome = processor.outcome();
+    let mut writer = Writer::new(Sink {
+        capacity,
+        ..Sink::default()
+    });
+
+    let result = processor
+        .process_document(
+            Reader::from_str(template),
+            &mut writer,
+            Some(&|_| {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_status(500),
+                ))
+            }),
+            None,
+        )
+        .map(|_| ());
+
+    let mut sink = writer.into_inner();
+    if result.is_err() && outcome.recovery_write_allowed() {
+        sink.write_all(ERROR_FRAGMENT).unwrap();
+    }
+    (result, outcome, sink)
+}
+
+#[test]
+fn recovery_follows_content_before_parse_error() {
+    let (result, outcome, sink) = process_and_recover("<p>one</p><esi:include/><p>two</p>", None);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::MissingRequiredParameter(..))
+    ));
+    assert!(outcome.recovery_write_allowed());
+    assert_eq!(outcome.bytes_written(), 10);
+    assert!(sink.flushes > 0);
+    assert_eq!(sink.data, b"<p>one</p><p>error</p>");
+}
+
+#[test]
+fn recovery_follows_content_before_failed_fragment() {
+    let (result, outcome, sink) =
+        process_and_recover(r#"<p>one</p><esi:include src="/a"/><p>two</p>"#, None);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::FragmentFailed { status: 500, .. })
+    ));
+    assert!(outcome.recovery_write_allowed());
+    assert_eq!(outcome.bytes_written(), 10);
+    assert_eq!(sink.data, b"<p>one</p><p>error</p>");
+}
+
+#[test]
+fn recovery_is_not_allowed_after_write_error() {
+    // The opening tag of the second paragraph doesn't fit.
+    let (result, outcome, sink) = process_and_recover("<p>one</p><p>two</p>", Some(12));
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::WriterError(err)) if err.kind() == io::ErrorKind::BrokenPipe
+    ));
+    assert!(!outcome.recovery_write_allowed());
+    assert_eq!(outcome.bytes_written(), 10);
+    assert_eq!(sink.data, b"<p>one</p>");
+}
+
+#[test]
+fn write_error_for_buffered_content_is_returned() {
+    // The `esi:except` arm is buffered while the attempt is pending, and doesn't fit once it's
+    // written out.
+    let (result, outcome, sink) = process_and_recover(
+        "<p>one</p><esi:try><esi:attempt><esi:include src=\"/a\"/></esi:attempt><esi:except><p>error arm</p></esi:except></esi:try>",
+        Some(12),
+    );
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::WriterError(err)) if err.kind() == io::ErrorKind::BrokenPipe
+    ));
+    assert!(!outcome.recovery_write_allowed());
+    assert_eq!(sink.data, b"<p>one</p>");
+}
+
+#[test]
+fn failed_event_is_written_whole_or_not_at_all() {
+    // The element with attributes is written in several pieces by the XML writer, but only ever
+    // reaches the output as a whole.
+    let (result, _, sink) =
+        process_and_recover(r#"<p>one</p><a href="/x" class="link">two</a>"#, Some(20));
+
+    assert!(result.is_err());
+    assert_eq!(sink.data, b"<p>one</p>");
+}
+
+#[test]
+fn session_output_is_flushed_on_error() {
+    let processor = Processor::new(None, Configuration::default());
+    let outcome = processor.outcome();
+    let mut session = processor.session(Reader::from_str("<p>one</p><esi:include/>"), None, None);
+    let mut writer = Writer::new(Sink::default());
+
+    let result = session.poll_progress(&mut writer);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::MissingRequiredParameter(..))
+    ));
+    assert!(outcome.recovery_write_allowed());
+    let sink = writer.into_inner();
+    assert!(sink.flushes > 0);
+    assert_eq!(sink.data, b"<p>one</p>");
+}
@@ -0,0 +1,162 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, DebugHeader, ExecutionError, IncludeOutcome, PendingFragmentContent,
+    ProcessingReport, Processor, Reader, Writer,
+};
+use fastly::{Request, Response};
+
+// Process `input` and return the number of bytes written at the moment each fragment was dispatched.
+fn bytes_written_at_dispatch(input: &str) -> Result<(Vec<usize>, usize, usize), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let bytes_written = processor.bytes_written();
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let seen_by_dispatcher = Rc::clone(&seen);
+
+    let mut writer = Writer::new(Vec::new());
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&move |_req| {
+            seen_by_dispatcher.borrow_mut().push(bytes_written.get());
+            Ok(PendingFragmentContent::NoContent)
+        }),
+        None,
+    )?;
+
+    let seen = seen.take();
+    Ok((seen, report.bytes_written, writer.into_inner().len()))
+}
+
+#[test]
+fn nothing_is_written_before_a_leading_include() -> Result<(), ExecutionError> {
+    let (seen, _, _) = bytes_written_at_dispatch(r#"<esi:include src="/a"/><p>after</p>"#)?;
+
+    assert_eq!(seen, [0]);
+
+    Ok(())
+}
+
+#[test]
+fn leading_content_is_counted_before_an_include() -> Result<(), ExecutionError> {
+    let (seen, reported, output_len) = bytes_written_at_dispatch(
+        r#"<p>before</p><esi:include src="/a"/><p>middle</p><esi:include src="/b"/>"#,
+    )?;
+
+    assert_eq!(
+        seen,
+        ["<p>before</p>".len(), "<p>before</p><p>middle</p>".len()]
+    );
+    assert_eq!(reported, output_len);
+
+    Ok(())
+}
+
+#[test]
+fn bytes_written_handle_outlives_a_failed_document() {
+    let processor = Processor::new(None, Configuration::default());
+    let bytes_written = processor.bytes_written();
+
+    let mut writer = Writer::new(Vec::new());
+    let res = processor.process_document(
+        Reader::from_str("<p>before</p><esi:include/>"),
+        &mut writer,
+        None,
+        None,
+    );
+
+    assert!(res.is_err());
+    assert_eq!(bytes_written.get(), "<p>before</p>".len());
+}
+
+// Includes that succeed, fall back to their alt, are skipped by the dispatcher, and fail with
+// `onerror="continue"`, both at the top level and inside an `esi:try`. Skipped includes are
+// resolved as soon as they're dispatched.
+const SLOTS_TEMPLATE: &str = r#"<esi:include src="/ok?token=secret"/><esi:include src="/fail" alt="/alt"/><esi:include src="/skip"/><esi:include src="/fail?token=secret" onerror="continue"/><esi:try><esi:attempt><esi:include src="/ok"/></esi:attempt><esi:except><esi:include src="/fail" alt="/alt"/></esi:except></esi:try>"#;
+
+fn slot_content(req: &Request) -> PendingFragmentContent {
+    match req.get_path() {
+        "/fail" => Response::from_status(500).into(),
+        "/skip" => PendingFragmentContent::NoContent,
+        path => Response::from_body(path.to_string()).into(),
+    }
+}
+
+fn slots_report() -> Result<ProcessingReport, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(SLOTS_TEMPLATE),
+        &mut writer,
+        Some(&|req| Ok(slot_content(&req))),
+        None,
+    )
+}
+
+// The debug header without the timing, which varies between runs
+fn without_timing(header: &str) -> String {
+    let (counts, rest) = header.split_once(" ms=").unwrap();
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+    format!("{counts}{rest}")
+}
+
+#[test]
+fn report_counts_include_outcomes() -> Result<(), ExecutionError> {
+    let report = slots_report()?;
+
+    // The except arm isn't needed once the attempt succeeds, so its fallback never completes.
+    let outcomes: Vec<IncludeOutcome> = report.includes.iter().map(|i| i.outcome).collect();
+    assert_eq!(
+        outcomes,
+        [
+            IncludeOutcome::Ok,
+            IncludeOutcome::Ok,
+            IncludeOutcome::Alt,
+            IncludeOutcome::Failed,
+            IncludeOutcome::Ok,
+        ]
+    );
+    assert_eq!(
+        without_timing(&report.to_debug_header()),
+        "slots=5 ok=3 alt=1 err=1"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verbose_debug_header_redacts_urls() -> Result<(), ExecutionError> {
+    let report = slots_report()?;
+
+    assert_eq!(
+        without_timing(&report.to_verbose_debug_header()),
+        "slots=5 ok=3 alt=1 err=1; ok http://localhost/skip, ok http://localhost/ok, alt http://localhost/alt, err http://localhost/fail, ok http://localhost/ok"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn debug_header_is_added_to_buffered_responses() -> Result<(), ExecutionError> {
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_debug_header(DebugHeader::Summary),
+    );
+
+    let (resp, report) = processor.process_to_response(
+        &mut Response::from_body(SLOTS_TEMPLATE),
+        None,
+        Some(&|req| Ok(slot_content(&req))),
+        None,
+    )?;
+
+    assert_eq!(
+        resp.get_header_str(ProcessingReport::DEBUG_HEADER_NAME),
+        Some(report.to_debug_header().as_str())
+    );
+
+    Ok(())
+}
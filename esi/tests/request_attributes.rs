@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::{Request, Response};
+
+// Processes `template` and returns a line for each dispatched request, with its method and the
+// names of the headers it carried, sorted. Requests for `/fail` fail.
+fn dispatched_requests(template: &str) -> Result<Vec<String>, ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page")
+                .with_header("authorization", "Bearer token")
+                .with_header("accept-language", "fr")
+                .with_header("cookie", "uid=1"),
+        ),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let mut names: Vec<&str> = req.get_header_names().map(|name| name.as_str()).collect();
+            names.sort_unstable();
+            dispatched.borrow_mut().push(format!(
+                "{} {} {}",
+                req.get_method(),
+                req.get_path(),
+                names.join(" ")
+            ));
+            Ok(match req.get_path() {
+                "/fail" => Response::from_status(500).into(),
+                _ => PendingFragmentContent::NoContent,
+            })
+        }),
+        None,
+    )?;
+
+    Ok(requests.take())
+}
+
+#[test]
+fn method_is_set_on_the_request_and_its_alt() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(
+            r#"<esi:include src="/a" method="post"/><esi:include src="/fail" alt="/b" method="HEAD"/>"#
+        )?,
+        [
+            "POST /a accept-language authorization cookie host",
+            "HEAD /fail accept-language authorization cookie host",
+            "HEAD /b accept-language authorization cookie host",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn headers_forwards_only_the_listed_headers() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(r#"<esi:include src="/a" headers="Cookie, accept-language"/>"#)?,
+        ["GET /a accept-language cookie host x-esi-forwarded-headers"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn forward_headers_wins_over_headers() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(
+            r#"<esi:include src="/a" headers="cookie" forward-headers="authorization"/>"#
+        )?,
+        ["GET /a authorization host x-esi-forwarded-headers"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_store_keeps_the_request_as_it_is_otherwise() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(r#"<esi:include src="/a" no-store="true"/>"#)?,
+        ["GET /a accept-language authorization cookie host"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn includes_without_the_attributes_are_unchanged() -> Result<(), ExecutionError> {
+    assert_eq!(
+        dispatched_requests(r#"<esi:include src="/fail" alt="/b" dca="esi"/>"#)?,
+        [
+            "GET /fail accept-language authorization cookie host",
+            "GET /b accept-language authorization cookie host",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn invalid_method_fails_the_include() {
+    let res = dispatched_requests(r#"<esi:include src="/a" method="DELETE"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidAttributeValue(tag, attribute, value))
+            if tag == "esi:include" && attribute == "method" && value == "DELETE"
+    ));
+}
@@ -0,0 +1,72 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor};
+use fastly::Response;
+
+fn process(template: &str) -> Result<Response, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+
+    let (resp, _) = processor.process_to_response(
+        &mut Response::from_body(template),
+        Some(Response::from_status(200).with_header("cache-control", "max-age=60")),
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(resp)
+}
+
+#[test]
+fn response_directive_before_content_is_applied() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        "\n  <esi:response status=\"404\" header-Cache-Control=\"private, no-store\" header-X-Page-Kind=\"not-found\"/>\n<p>Not found</p><esi:include src=\"/footer\"/>",
+    )?;
+
+    assert_eq!(resp.get_status(), 404);
+    assert_eq!(
+        resp.get_header_str("cache-control"),
+        Some("private, no-store")
+    );
+    assert_eq!(resp.get_header_str("x-page-kind"), Some("not-found"));
+    assert_eq!(resp.take_body_str(), "\n  \n<p>Not found</p>[/footer]");
+
+    Ok(())
+}
+
+#[test]
+fn response_directive_after_content_is_ignored() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        r#"<p>Found</p><esi:response status="404" header-Cache-Control="private"/><p>end</p>"#,
+    )?;
+
+    assert_eq!(resp.get_status(), 200);
+    assert_eq!(resp.get_header_str("cache-control"), Some("max-age=60"));
+    assert_eq!(resp.take_body_str(), "<p>Found</p><p>end</p>");
+
+    Ok(())
+}
+
+#[test]
+fn response_directive_inside_try_is_ignored() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        r#"<esi:try><esi:attempt><esi:response status="500"/><esi:include src="/a"/></esi:attempt><esi:except></esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(resp.get_status(), 200);
+    assert_eq!(resp.take_body_str(), "[/a]");
+
+    Ok(())
+}
+
+#[test]
+fn response_directive_with_invalid_status_fails() {
+    let res = process(r#"<esi:response status="not-a-status"/>"#);
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::InvalidAttributeValue(tag, attr, value))
+            if tag == "esi:response" && attr == "status" && value == "not-a-status"
+    ));
+}
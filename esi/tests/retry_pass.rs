@@ -0,0 +1,172 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use esi::{
+    Configuration, ExecutionError, IncludeOutcome, PendingFragmentContent, ProcessingReport,
+    Processor, Progress, Reader, Writer, RETRY_HEADER,
+};
+use fastly::Response;
+
+const TEMPLATE: &str = r#"<p>a</p><esi:include src="/flaky" onerror="continue"/><p>b</p><esi:include src="/down" onerror="continue"/><p>c</p>"#;
+
+// A backend where `/flaky` fails the first time it's requested, and `/down` always fails. Returns
+// the requests made, each with its retry header.
+fn backend() -> (
+    impl Fn(fastly::Request) -> esi::Result<PendingFragmentContent>,
+    Rc<RefCell<Vec<String>>>,
+) {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let log = Rc::clone(&requests);
+    let attempts = RefCell::new(HashMap::new());
+    let dispatch = move |req: fastly::Request| {
+        let path = req.get_path().to_string();
+        log.borrow_mut().push(format!(
+            "{path} retry={}",
+            req.get_header_str(RETRY_HEADER).unwrap_or("-")
+        ));
+        let mut attempts = attempts.borrow_mut();
+        let attempt = attempts.entry(path.clone()).or_insert(0);
+        *attempt += 1;
+        Ok(match (path.as_str(), *attempt) {
+            ("/flaky", 1) | ("/down", _) => Response::from_status(503).into(),
+            _ => Response::from_body(format!("[{path}]")).into(),
+        })
+    };
+    (dispatch, requests)
+}
+
+fn process(
+    configuration: Configuration,
+) -> Result<(String, ProcessingReport, Vec<String>), ExecutionError> {
+    let (dispatch, requests) = backend();
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(TEMPLATE),
+        &mut writer,
+        Some(&dispatch),
+        None,
+    )?;
+
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+    Ok((output, report, requests.take()))
+}
+
+fn outcomes(report: &ProcessingReport) -> Vec<IncludeOutcome> {
+    report
+        .includes
+        .iter()
+        .map(|include| include.outcome)
+        .collect()
+}
+
+#[test]
+fn skipped_includes_are_retried_and_appended() -> Result<(), ExecutionError> {
+    let (output, report, requests) =
+        process(Configuration::default().with_retry_pass(5, Duration::ZERO))?;
+
+    assert_eq!(
+        output,
+        concat!(
+            r#"<p>a</p><template data-esi-slot="0"></template>"#,
+            r#"<p>b</p><template data-esi-slot="1"></template><p>c</p>"#,
+            r#"<template data-esi-slot-content="0">[/flaky]</template>"#,
+        )
+    );
+    assert_eq!(
+        requests,
+        [
+            "/flaky retry=-",
+            "/down retry=-",
+            "/flaky retry=0",
+            "/down retry=1"
+        ]
+    );
+    assert_eq!(
+        outcomes(&report),
+        [IncludeOutcome::Retried, IncludeOutcome::Failed]
+    );
+    assert_eq!(
+        report
+            .to_verbose_debug_header()
+            .split_once(" ms=")
+            .unwrap()
+            .0,
+        "slots=2 ok=0 alt=0 err=1 retried=1"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn skipped_includes_are_not_retried_by_default() -> Result<(), ExecutionError> {
+    let (output, report, requests) = process(Configuration::default())?;
+
+    assert_eq!(output, "<p>a</p><p>b</p><p>c</p>");
+    assert_eq!(requests, ["/flaky retry=-", "/down retry=-"]);
+    assert_eq!(
+        outcomes(&report),
+        [IncludeOutcome::Failed, IncludeOutcome::Failed]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn retries_are_limited_in_number() -> Result<(), ExecutionError> {
+    let (_, _, requests) = process(Configuration::default().with_retry_pass(1, Duration::ZERO))?;
+
+    assert_eq!(
+        requests,
+        ["/flaky retry=-", "/down retry=-", "/flaky retry=0"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn retries_need_enough_of_the_deadline_to_remain() -> Result<(), ExecutionError> {
+    let configuration = Configuration::default()
+        .with_deadline(Duration::from_secs(60))
+        .with_retry_pass(5, Duration::from_secs(120));
+    let (output, _, requests) = process(configuration)?;
+
+    assert_eq!(
+        output,
+        concat!(
+            r#"<p>a</p><template data-esi-slot="0"></template>"#,
+            r#"<p>b</p><template data-esi-slot="1"></template><p>c</p>"#,
+        )
+    );
+    assert_eq!(requests, ["/flaky retry=-", "/down retry=-"]);
+
+    Ok(())
+}
+
+#[test]
+fn sessions_retry_skipped_includes() -> Result<(), ExecutionError> {
+    let (dispatch, _) = backend();
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_retry_pass(5, Duration::ZERO),
+    );
+    let mut session = processor.session(Reader::from_str(TEMPLATE), Some(&dispatch), None);
+    let mut writer = Writer::new(Vec::new());
+
+    let Progress::Complete(report) = session.poll_progress(&mut writer)? else {
+        panic!("expected the session to complete without waiting");
+    };
+
+    assert!(String::from_utf8(writer.into_inner())
+        .unwrap()
+        .ends_with(r#"<template data-esi-slot-content="0">[/flaky]</template>"#));
+    assert_eq!(
+        outcomes(&report),
+        [IncludeOutcome::Retried, IncludeOutcome::Failed]
+    );
+
+    Ok(())
+}
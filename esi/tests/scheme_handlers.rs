@@ -0,0 +1,144 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, SchemeTarget, Writer,
+    BACKEND_HINT_HEADER,
+};
+use fastly::http::{header, Url};
+use fastly::{Request, Response};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Resolves `frag://service/path` to the path on the internal host of the service.
+fn resolve(url: &str) -> Result<SchemeTarget, String> {
+    let Some((service, path)) = url
+        .strip_prefix("frag://")
+        .and_then(|url| url.split_once('/'))
+    else {
+        return Err("no service".to_string());
+    };
+    if service == "unknown" {
+        return Err(format!("unknown service `{service}`"));
+    }
+    let url = Url::parse(&format!("https://{service}.mesh.internal:8443/{path}"))
+        .map_err(|err| err.to_string())?;
+    Ok(SchemeTarget::new(url).with_backend(service))
+}
+
+// The URL, Host header and backend hint of a fragment request
+type Sent = (String, String, Option<String>);
+
+// Processes the template, returning the output and the fragment requests. `/fail` responds
+// with a 500.
+fn process(original_url: &str, template: &str) -> Result<(String, Vec<Sent>), ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+    let processor = Processor::new(
+        Some(Request::get(original_url)),
+        Configuration::default().with_scheme_handler("frag", resolve),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().push((
+                req.get_url_str().to_string(),
+                req.get_header_str(header::HOST)
+                    .unwrap_or_default()
+                    .to_string(),
+                req.get_header_str(BACKEND_HINT_HEADER).map(str::to_string),
+            ));
+            let resp = match req.get_path() {
+                "/fail" => Response::from_status(500),
+                path => Response::from_body(format!("[{path}]")),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(resp))
+        }),
+        None,
+    )?;
+
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+    let requests = requests.take();
+    Ok((output, requests))
+}
+
+fn resolved(service: &str, path: &str) -> Sent {
+    (
+        format!("https://{service}.mesh.internal:8443{path}"),
+        format!("{service}.mesh.internal:8443"),
+        Some(service.to_string()),
+    )
+}
+
+#[test]
+fn custom_scheme_urls_are_requested_where_the_handler_says() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        "https://www.example.com/page",
+        r#"<esi:include src="frag://catalog/nav?depth=2"/><esi:include src="/fail" alt="frag://search/box"/>"#,
+    )?;
+
+    assert_eq!(output, "[/nav][/box]");
+    assert_eq!(
+        requests,
+        vec![
+            resolved("catalog", "/nav?depth=2"),
+            (
+                "https://www.example.com/fail".to_string(),
+                "www.example.com".to_string(),
+                None
+            ),
+            resolved("search", "/box"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn custom_scheme_urls_work_in_attempt_arms() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        "https://www.example.com/page",
+        r#"<esi:try><esi:attempt><esi:include src="frag://catalog/nav"/></esi:attempt><esi:except>none</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "[/nav]");
+    assert_eq!(requests, vec![resolved("catalog", "/nav")]);
+
+    Ok(())
+}
+
+#[test]
+fn a_url_the_handler_rejects_falls_back_to_alt() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        "https://www.example.com/page",
+        r#"<esi:include src="frag://unknown/nav" alt="/nav"/>"#,
+    )?;
+
+    assert_eq!(output, "[/nav]");
+    assert_eq!(requests.len(), 1);
+
+    let err = process(
+        "https://www.example.com/page",
+        r#"<esi:include src="frag://unknown/nav"/>"#,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ExecutionError::SchemeHandlerFailed(url, reason)
+            if url == "frag://unknown/nav" && reason == "unknown service `unknown`"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn relative_urls_keep_a_custom_scheme_and_host() -> Result<(), ExecutionError> {
+    let (_, requests) = process(
+        "frag://catalog/page?id=1",
+        r#"<esi:include src="/nav?depth=2"/>"#,
+    )?;
+
+    assert_eq!(requests, vec![resolved("catalog", "/nav?depth=2")]);
+
+    Ok(())
+}
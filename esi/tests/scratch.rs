@@ -0,0 +1,105 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, ProcessorScratch, Reader,
+    Writer,
+};
+use fastly::Response;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts the allocations made by the test binary.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Content is buffered behind each include, and inside the try block.
+const TEMPLATE: &str = r#"<ul><li><esi:include src="/a"/></li><li><esi:include src="/b"/></li></ul><esi:try><esi:attempt><p><esi:include src="/c"/></p></esi:attempt><esi:except><p>except</p></esi:except></esi:try><p>after</p>"#;
+
+fn process(template: &str, scratch: Option<&ProcessorScratch>) -> Result<String, ExecutionError> {
+    let mut processor = Processor::new(None, Configuration::default());
+    if let Some(scratch) = scratch {
+        processor = processor.with_scratch(scratch);
+    }
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            Ok(match req.get_path() {
+                "/fail" => Response::from_status(500).into(),
+                path => PendingFragmentContent::CompletedRequest(Response::from_body(format!(
+                    "[{path}]"
+                ))),
+            })
+        }),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+// Counts the allocations made while processing the template.
+fn allocations(scratch: Option<&ProcessorScratch>) -> Result<usize, ExecutionError> {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    process(TEMPLATE, scratch)?;
+    Ok(ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+#[test]
+fn reused_scratch_saves_allocations() -> Result<(), ExecutionError> {
+    let scratch = ProcessorScratch::with_capacity(8, 256);
+    // The first document sizes the buffers for the ones after it.
+    allocations(Some(&scratch))?;
+
+    let without_scratch = allocations(None)?;
+    let with_scratch = allocations(Some(&scratch))?;
+
+    assert!(
+        with_scratch < without_scratch,
+        "{with_scratch} allocations with a scratch, {without_scratch} without"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn nothing_leaks_between_documents_sharing_a_scratch() -> Result<(), ExecutionError> {
+    let scratch = ProcessorScratch::new();
+    let second = r#"<esi:include src="/x"/><i>second</i><esi:try><esi:attempt><esi:include src="/y"/></esi:attempt></esi:try>"#;
+
+    // A failed document leaves content queued in its buffers.
+    let failed = process(
+        r#"<esi:include src="/a"/><b>first</b><esi:try><esi:attempt><u>in try</u><esi:include src="/fail"/></esi:attempt></esi:try><esi:include src="/fail"/><s>queued</s>"#,
+        Some(&scratch),
+    );
+    assert!(failed.is_err());
+    let after_failure = process(second, Some(&scratch))?;
+    let after_success = process(second, Some(&scratch))?;
+
+    let expected = process(second, None)?;
+    assert_eq!(expected, "[/x]<i>second</i>[/y]");
+    assert_eq!(after_failure, expected);
+    assert_eq!(after_success, expected);
+    assert_eq!(process(TEMPLATE, Some(&scratch))?, process(TEMPLATE, None)?);
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Checks the serialized form of the public data types against golden JSON fixtures in
+//! `tests/serialization/`, so that a renamed field is caught before it breaks consumers.
+
+#![cfg(feature = "serde")]
+
+use std::time::{Duration, Instant};
+
+use esi::{
+    parse_tags, Clock, Configuration, DebugHeader, Event, ExecutionError, InsecureFragmentPolicy,
+    PendingFragmentContent, Processor, Reader, StatusCodes, Writer,
+};
+use fastly::Response;
+
+fn check(name: &str, actual: &impl serde::Serialize, expected: &str) {
+    let actual = serde_json::to_string_pretty(actual).unwrap();
+    assert_eq!(
+        actual,
+        expected.trim_end(),
+        "serialized {name} doesn't match tests/serialization/{name}.json"
+    );
+}
+
+#[test]
+fn processing_report_matches_fixture() -> Result<(), ExecutionError> {
+    // The time stands still, so every phase that's reached is at zero.
+    let now = Instant::now();
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_clock(Clock::new(move || now)),
+    );
+    let mut writer = Writer::new(Vec::new());
+    let mut report = processor.process_document(
+        Reader::from_str(
+            r#"<esi:include src="/ok?token=secret"/><esi:include src="/missing" alt="/fallback"/><esi:foo/>"#,
+        ),
+        &mut writer,
+        Some(&|req| {
+            let response = match req.get_path() {
+                "/missing" => Response::from_status(404),
+                _ => Response::from_body("x"),
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+    report.elapsed = Duration::from_micros(1500);
+    // The hash changes along with the options, which are checked by the configuration fixture.
+    report.config_hash = 0x0123_4567_89ab_cdef;
+
+    check("report", &report, include_str!("serialization/report.json"));
+
+    Ok(())
+}
+
+#[test]
+fn configuration_matches_fixture() {
+    let configuration = Configuration::default()
+        .with_accept_status("404,500-599".parse().unwrap())
+        .with_upgrade_insecure_fragments(InsecureFragmentPolicy::Upgrade)
+        .with_debug_header(DebugHeader::Verbose)
+        .with_max_document_size(1024)
+        .with_deadline(Duration::from_millis(2500));
+
+    check(
+        "configuration",
+        &configuration,
+        include_str!("serialization/configuration.json"),
+    );
+    check(
+        "description",
+        &configuration.describe(),
+        include_str!("serialization/description.json"),
+    );
+}
+
+#[test]
+fn events_match_fixture() -> Result<(), ExecutionError> {
+    let mut events: Vec<Event> = Vec::new();
+    parse_tags(
+        "esi",
+        &mut Reader::from_str(
+            r#"<p>hi</p><esi:include src="/a" alt="/b" onerror="continue" accept-status="404"/><esi:try><esi:attempt><br/></esi:attempt><esi:except>text</esi:except></esi:try><esi:group name="g" onerror="fail"><br/></esi:group><esi:response status="201" header-x-a="1"/>"#,
+        ),
+        &mut |event| {
+            events.push(event);
+            Ok(())
+        },
+    )?;
+
+    check("events", &events, include_str!("serialization/events.json"));
+
+    Ok(())
+}
+
+#[test]
+fn string_types_round_trip() {
+    let codes: StatusCodes = "404, 500-599".parse().unwrap();
+    let json = serde_json::to_string(&codes).unwrap();
+    assert_eq!(json, r#""404,500-599""#);
+    assert_eq!(serde_json::from_str::<StatusCodes>(&json).unwrap(), codes);
+    assert!(serde_json::from_str::<StatusCodes>(r#""600-500""#).is_err());
+
+    for policy in [
+        InsecureFragmentPolicy::Allow,
+        InsecureFragmentPolicy::Upgrade,
+        InsecureFragmentPolicy::Reject,
+    ] {
+        let json = serde_json::to_string(&policy).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InsecureFragmentPolicy>(&json).unwrap(),
+            policy
+        );
+    }
+    assert!(serde_json::from_str::<InsecureFragmentPolicy>(r#""Allow""#).is_err());
+
+    for debug_header in [DebugHeader::Summary, DebugHeader::Verbose] {
+        let json = serde_json::to_string(&debug_header).unwrap();
+        assert_eq!(
+            serde_json::from_str::<DebugHeader>(&json).unwrap(),
+            debug_header
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Progress,
+    Reader, Writer,
+};
+use fastly::http::request::PollResult;
+use fastly::Response;
+
+const TEMPLATE: &str = r#"<p>start</p><esi:include src="/one"/><p>middle</p><esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:except><esi:include src="/two"/></esi:except></esi:try><esi:include src="/fail" alt="/three"/><esi:include src="/empty"/><p>end</p>"#;
+
+// The mock content of each fragment
+fn fragment_content(path: &str) -> PendingFragmentContent {
+    match path {
+        "/fail" => Response::from_status(500).into(),
+        "/empty" => PendingFragmentContent::NoContent,
+        path => Response::from_body(format!("[{path}]")).into(),
+    }
+}
+
+fn blocking_output(template: &str) -> Result<String, ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| Ok(fragment_content(req.get_path()))),
+        None,
+    )?;
+
+    Ok(String::from_utf8(writer.into_inner()).unwrap())
+}
+
+fn session_output(template: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut session = processor.session(
+        Reader::from_str(template),
+        Some(&|req| Ok(fragment_content(req.get_path()))),
+        None,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    // Every fragment has completed by the time it's dispatched, so there's never anything to wait on.
+    let Progress::Complete(report) = session.poll_progress(&mut writer)? else {
+        panic!("completed fragments never need waiting on");
+    };
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn session_output_matches_blocking_output() -> Result<(), ExecutionError> {
+    let (output, report) = session_output(TEMPLATE)?;
+
+    assert_eq!(output, blocking_output(TEMPLATE)?);
+    assert_eq!(
+        output,
+        "<p>start</p>[/one]<p>middle</p>[/two][/three]<p>end</p>"
+    );
+    assert_eq!(report.bytes_written, output.len());
+
+    Ok(())
+}
+
+#[test]
+fn session_fails_like_blocking_processing() {
+    let template = r#"<p>start</p><esi:include src="/fail"/>"#;
+
+    assert!(matches!(
+        session_output(template),
+        Err(ExecutionError::FragmentFailed { url, status: 500, .. }) if url == "http://localhost/fail"
+    ));
+    assert!(matches!(
+        blocking_output(template),
+        Err(ExecutionError::FragmentFailed { url, status: 500, .. }) if url == "http://localhost/fail"
+    ));
+}
+
+#[test]
+fn resuming_without_a_pending_request_fails() {
+    let processor = Processor::new(None, Configuration::default());
+    let mut session = processor.session(Reader::from_str("<p>content</p>"), None, None);
+
+    let res = session.resume(PollResult::Done(Ok(Response::new())));
+
+    assert!(matches!(res, Err(ExecutionError::InvalidSessionState(_))));
+}
@@ -0,0 +1,113 @@
+use esi::{
+    ComplianceLevel, Configuration, ExecutionError, PendingFragmentContent, ProcessingReport,
+    Processor, Reader, Warning, Writer,
+};
+use fastly::{Request, Response};
+
+// Relies on a dozen of the extensions of this crate, one or two per line.
+const EXTENSIONS: &str = r#"<esi:response status="200"/>
+<html esi:attr-lang="$(QUERY_STRING{lang})" esi:attr-data-ip="$(HTTP_X_FORWARDED_FOR)">
+<esi:include src="/a/$(REQUEST_PATH)"/>
+<esi:include src="/b" critical="true" accept-status="404"/>
+<esi:include srcset="/c /d"/>
+<esi:include src="/e/$bucket($(HTTP_COOKIE{uid}), 2)"/>
+<esi:include src="data:,inline"/>
+<esi:include src="/f"><p>fallback</p></esi:include>
+<esi:include src="/g" esi:slot="x"/>
+<esi:group name="g"><esi:include src="/h"/></esi:group>
+<esi:try><esi:attempt><esi:include src="/i"/></esi:attempt><esi:retry><esi:include src="/j"/></esi:retry></esi:try>
+</html>"#;
+
+// Only uses what ESI 1.0 defines.
+const SPEC: &str = r#"<html>
+<esi:comment text="spec only"/>
+<esi:remove><a href="/fallback">fallback</a></esi:remove>
+<esi:include src="/a?id=$(QUERY_STRING{id})" alt="/b" onerror="continue"/>
+<esi:try>
+<esi:attempt><esi:include src="/c/$(HTTP_COOKIE{uid})"/></esi:attempt>
+<esi:except><p>except</p></esi:except>
+</esi:try>
+</html>"#;
+
+fn process(level: ComplianceLevel, template: &str) -> Result<ProcessingReport, ExecutionError> {
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page?lang=en").with_header("cookie", "uid=1")),
+        Configuration::default().with_spec_compliance(level),
+    );
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut Writer::new(Vec::new()),
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )
+}
+
+#[test]
+fn deviations_are_reported_in_document_order() -> Result<(), ExecutionError> {
+    let report = process(ComplianceLevel::Warn, EXTENSIONS)?;
+
+    let deviations: Vec<_> = report
+        .warnings
+        .iter()
+        .filter_map(|warning| match warning {
+            Warning::SpecDeviation { code, detail, .. } => Some(format!("{code} {detail}")),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        deviations,
+        [
+            "tag esi:response",
+            "attribute-template esi:attr-lang",
+            "attribute-template esi:attr-data-ip",
+            "variable HTTP_X_FORWARDED_FOR",
+            "variable REQUEST_PATH",
+            "include-attribute critical",
+            "include-attribute accept-status",
+            "include-attribute srcset",
+            "function $bucket",
+            "data-uri data:,inline",
+            "include-body esi:include",
+            "include-attribute esi:slot",
+            "tag esi:group",
+            "tag esi:retry",
+            "missing-except esi:try",
+            "multiple-attempts esi:try",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn enforced_compliance_fails_at_the_first_deviation() {
+    let template = "<p>ok</p>\n<esi:group name=\"g\"></esi:group>";
+
+    let result = process(ComplianceLevel::Enforce, template);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::SpecDeviation(code, detail, 10))
+            if code == "tag" && detail == "esi:group"
+    ));
+}
+
+#[test]
+fn spec_templates_have_no_deviations() -> Result<(), ExecutionError> {
+    let report = process(ComplianceLevel::Enforce, SPEC)?;
+    assert!(report.warnings.is_empty());
+
+    // Deviations are only looked for when asked.
+    let report = process(ComplianceLevel::Off, EXTENSIONS)?;
+    assert!(!report
+        .warnings
+        .iter()
+        .any(|warning| matches!(warning, Warning::SpecDeviation { .. })));
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::attributes::Critical;
+use esi::{
+    parse_tags, Configuration, Event, ExecutionError, PendingFragmentContent, Processor, Reader,
+    Tag, Writer, SRCSET_INDEX_HEADER,
+};
+use fastly::{Request, Response};
+
+// The path and srcset index of a fragment request
+type Dispatched = (String, Option<String>);
+
+// Processes a template, returning the output and every fragment request made.
+fn process(template: &str) -> Result<(String, Vec<Dispatched>), ExecutionError> {
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    let processor = Processor::new(
+        Some(Request::get("http://www.example.com/page?a=1&b=7")),
+        Configuration::default(),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let path = req.get_path().to_string();
+            dispatched.borrow_mut().push((
+                path.clone(),
+                req.get_header_str(SRCSET_INDEX_HEADER).map(str::to_string),
+            ));
+            let response = if path.starts_with("/missing") {
+                Response::from_status(404)
+            } else {
+                Response::from_body(format!("[{path}]"))
+            };
+            Ok(PendingFragmentContent::CompletedRequest(response))
+        }),
+        None,
+    )?;
+
+    let requests = requests.borrow().clone();
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), requests))
+}
+
+#[test]
+fn srcset_expands_to_includes_in_order() -> Result<(), ExecutionError> {
+    let (output, requests) =
+        process(r#"<p><esi:include srcset=" /product/1  /product/7 /product/9 "/></p>"#)?;
+
+    assert_eq!(output, "<p>[/product/1][/product/7][/product/9]</p>");
+    assert_eq!(
+        requests,
+        [
+            ("/product/1".to_string(), Some("0".to_string())),
+            ("/product/7".to_string(), Some("1".to_string())),
+            ("/product/9".to_string(), Some("2".to_string())),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn srcset_entries_are_interpolated_independently() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:include srcset="/p/$(QUERY_STRING{a}) /p/$(QUERY_STRING{b}) /p/$pick(1, 'x', 'y')"/>"#,
+    )?;
+
+    assert_eq!(output, "[/p/1][/p/7][/p/y]");
+
+    Ok(())
+}
+
+#[test]
+fn srcset_entries_share_alt_and_onerror() -> Result<(), ExecutionError> {
+    let (output, requests) = process(
+        r#"<esi:try><esi:attempt><esi:include srcset="/missing/1 /a /missing/2" alt="/fallback" onerror="continue"/></esi:attempt></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "[/fallback][/a][/fallback]");
+    let fallback_indexes: Vec<_> = requests
+        .iter()
+        .filter(|(path, _)| path == "/fallback")
+        .map(|(_, index)| index.as_deref())
+        .collect();
+    assert_eq!(fallback_indexes, [Some("0"), Some("2")]);
+
+    Ok(())
+}
+
+#[test]
+fn plain_src_has_no_srcset_index() -> Result<(), ExecutionError> {
+    let (_, requests) = process(r#"<esi:include src="/a"/>"#)?;
+
+    assert_eq!(requests, [("/a".to_string(), None)]);
+
+    Ok(())
+}
+
+#[test]
+fn srcset_is_parsed_into_indexed_includes() -> Result<(), ExecutionError> {
+    let mut includes = Vec::new();
+    parse_tags(
+        "esi",
+        &mut Reader::from_str(r#"<esi:include srcset="/a /b" critical="true"/>"#),
+        &mut |event| {
+            if let Event::ESI(Tag::Include(include)) = event {
+                let critical = include.get::<Critical>().is_some_and(|critical| critical.0);
+                includes.push((include.src, critical, include.srcset_index));
+            }
+            Ok(())
+        },
+    )?;
+
+    assert_eq!(
+        includes,
+        [
+            ("/a".to_string(), true, Some(0)),
+            ("/b".to_string(), true, Some(1))
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn src_and_srcset_conflict() {
+    let result = process(r#"<esi:include src="/a" srcset="/b /c"/>"#);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::ConflictingParameters(tag, first, second))
+            if tag == "esi:include" && first == "src" && second == "srcset"
+    ));
+}
+
+#[test]
+fn empty_srcset_is_invalid() {
+    let result = process(r#"<esi:include srcset="  "/>"#);
+
+    assert!(matches!(
+        result,
+        Err(ExecutionError::InvalidAttributeValue(_, attribute, _)) if attribute == "srcset"
+    ));
+}
+
+// Each include slot evaluates its URL once, in document order, so rendering the same template
+// again dispatches the same requests in the same order.
+#[test]
+fn srcset_dispatch_is_repeatable_and_in_document_order() -> Result<(), ExecutionError> {
+    let template = r#"<esi:include src="/before/$bucket('a', 10)"/><esi:include srcset="/s/$bucket($(QUERY_STRING{a}), 100) /s/$bucket($(QUERY_STRING{b}), 100) /s/$pick($bucket('c', 2), 'x', 'y')"/><esi:include src="/after/$(QUERY_STRING{b})"/>"#;
+
+    let (first_output, first) = process(template)?;
+    let (second_output, second) = process(template)?;
+
+    assert_eq!(first, second);
+    assert_eq!(first_output, second_output);
+    assert_eq!(
+        first,
+        [
+            ("/before/6".to_string(), None),
+            ("/s/68".to_string(), Some("0".to_string())),
+            ("/s/46".to_string(), Some("1".to_string())),
+            ("/s/x".to_string(), Some("2".to_string())),
+            ("/after/7".to_string(), None),
+        ]
+    );
+    assert_eq!(first_output, "[/before/6][/s/68][/s/46][/s/x][/after/7]");
+
+    Ok(())
+}
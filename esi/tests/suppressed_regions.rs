@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::Response;
+
+// The paths requested, the output and the report of processing a document
+type Processed = (Vec<String>, String, ProcessingReport);
+
+fn process(input: &str, configuration: Configuration) -> Result<Processed, ExecutionError> {
+    let requested = Rc::new(RefCell::new(Vec::new()));
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&{
+            let requested = requested.clone();
+            move |req| {
+                requested.borrow_mut().push(req.get_path().to_string());
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_body(format!("[{}]", req.get_path())),
+                ))
+            }
+        }),
+        None,
+    )?;
+
+    let requested = requested.borrow().clone();
+    Ok((
+        requested,
+        String::from_utf8(writer.into_inner()).unwrap(),
+        report,
+    ))
+}
+
+// Every ESI tag inside a suppressed region is swallowed with a warning, whatever the region and
+// wherever it is.
+#[test]
+fn esi_tags_in_suppressed_regions_are_swallowed() -> Result<(), ExecutionError> {
+    let tags = [
+        (r#"<esi:include src="/x"/>"#, &["esi:include"][..]),
+        (r#"<esi:response status="404"/>"#, &["esi:response"][..]),
+        (
+            r#"<esi:try><esi:attempt><esi:include src="/x"/></esi:attempt></esi:try>"#,
+            &["esi:try", "esi:attempt", "esi:include"][..],
+        ),
+    ];
+    let regions = [
+        ("esi:remove", "<esi:remove>text", "</esi:remove>", ""),
+        ("esi:comment", "<esi:comment>text", "</esi:comment>", ""),
+        (
+            "esi:include",
+            r#"<esi:include src="/body">text"#,
+            "</esi:include>",
+            "[/body]",
+        ),
+    ];
+    let contexts = [
+        ("top level", "", ""),
+        (
+            "attempt",
+            "<esi:try><esi:attempt>",
+            "</esi:attempt></esi:try>",
+        ),
+        (
+            "except",
+            "<esi:try><esi:attempt></esi:attempt><esi:except>",
+            "</esi:except></esi:try>",
+        ),
+    ];
+
+    for (tag, swallowed) in tags {
+        for (region, open, close, region_output) in regions {
+            for (context, before, after) in contexts {
+                let input = format!("<p>{before}{open}{tag}{close}{after}</p>");
+                let cell = format!("{tag} in {region} at {context}");
+
+                let (requested, output, report) = process(&input, Configuration::default())?;
+
+                assert!(!requested.contains(&"/x".to_string()), "{cell}");
+                let expected_output = if context == "except" {
+                    ""
+                } else {
+                    region_output
+                };
+                assert_eq!(output, format!("<p>{expected_output}</p>"), "{cell}");
+                let warnings: Vec<_> = report
+                    .warnings
+                    .iter()
+                    .map(|warning| match warning {
+                        Warning::IgnoredTag {
+                            position,
+                            tag,
+                            reason,
+                        } => {
+                            assert_eq!(reason, &format!("it's inside {region}"), "{cell}");
+                            assert!(input[*position..].starts_with(&format!("<{tag}")), "{cell}");
+                            tag.as_str()
+                        }
+                        warning => panic!("unexpected warning {warning} for {cell}"),
+                    })
+                    .collect();
+                assert_eq!(warnings, swallowed, "{cell}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn comment_content_is_processed_when_enabled() -> Result<(), ExecutionError> {
+    let (requested, output, report) = process(
+        r#"<p><esi:comment>a<esi:include src="/x"/>b</esi:comment></p>"#,
+        Configuration::default().with_process_in_comments(true),
+    )?;
+
+    assert_eq!(requested, ["/x"]);
+    assert_eq!(output, "<p>a[/x]b</p>");
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
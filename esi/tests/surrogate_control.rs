@@ -0,0 +1,154 @@
+use esi::{
+    propagate_headers, Configuration, ExecutionError, PendingFragmentContent, Processor,
+    ResponseBody, SURROGATE_CAPABILITY_HEADER, SURROGATE_CONTROL_HEADER,
+};
+use fastly::{Request, Response};
+
+const TEMPLATE: &str = r#"<p>a</p><esi:include src="/one"/>"#;
+
+fn source(surrogate_control: Option<&str>) -> Response {
+    let resp = Response::from_body(TEMPLATE);
+    match surrogate_control {
+        Some(value) => resp.with_header(SURROGATE_CONTROL_HEADER, value),
+        None => resp,
+    }
+}
+
+fn required() -> Configuration {
+    Configuration::default().with_require_surrogate_control(true)
+}
+
+// Processes `src` into a client response that's given its headers, the way an application would.
+fn process(processor: Processor, mut src: Response) -> Result<Response, ExecutionError> {
+    let mut client_response = Response::new();
+    propagate_headers(&src, &mut client_response, ResponseBody::Processed);
+
+    let (resp, _) = processor.process_to_response(
+        &mut src,
+        Some(client_response),
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(resp)
+}
+
+#[test]
+fn content_directive_asks_for_processing() {
+    assert!(Processor::should_process(
+        &source(Some(r#"content="ESI/1.0""#)),
+        &required()
+    ));
+}
+
+#[test]
+fn missing_header_does_not_ask_for_processing() {
+    assert!(!Processor::should_process(&source(None), &required()));
+    assert!(!Processor::should_process(
+        &source(Some("max-age=300")),
+        &required()
+    ));
+    assert!(!Processor::should_process(
+        &source(Some(r#"content="ESI-Inline/1.0""#)),
+        &required()
+    ));
+}
+
+#[test]
+fn content_directive_is_found_among_others() {
+    for value in [
+        r#"max-age=300, content="ESI/1.0""#,
+        r#"content="ESI-Inline/1.0 ESI/1.0", no-store-remote"#,
+        "no-store, content=ESI/1.0",
+    ] {
+        assert!(
+            Processor::should_process(&source(Some(value)), &required()),
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn every_response_is_processed_unless_required() {
+    assert!(Processor::should_process(
+        &source(None),
+        &Configuration::default()
+    ));
+}
+
+// Without a request, it's not known which surrogate a targeted directive is for.
+#[test]
+fn targeted_directive_is_ignored_without_a_request() {
+    assert!(!Processor::should_process(
+        &source(Some(r#"content="ESI/1.0";edge"#)),
+        &required()
+    ));
+}
+
+#[test]
+fn document_is_processed_when_asked_for() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        Processor::new(None, required()),
+        source(Some(r#"max-age=300, content="ESI/1.0""#)),
+    )?;
+
+    assert_eq!(resp.take_body_str(), "<p>a</p>[/one]");
+    assert!(!resp.contains_header(SURROGATE_CONTROL_HEADER));
+
+    Ok(())
+}
+
+#[test]
+fn document_is_passed_through_when_not_asked_for() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        Processor::new(None, required()),
+        source(Some("max-age=300")),
+    )?;
+
+    assert_eq!(resp.take_body_str(), TEMPLATE);
+    assert!(!resp.contains_header(SURROGATE_CONTROL_HEADER));
+
+    Ok(())
+}
+
+#[test]
+fn header_is_stripped_when_processing_is_not_required() -> Result<(), ExecutionError> {
+    let mut resp = process(
+        Processor::new(None, Configuration::default()),
+        source(Some("max-age=300")),
+    )?;
+
+    assert_eq!(resp.take_body_str(), "<p>a</p>[/one]");
+    assert!(!resp.contains_header(SURROGATE_CONTROL_HEADER));
+
+    Ok(())
+}
+
+#[test]
+fn targeted_directive_applies_to_announced_surrogates() -> Result<(), ExecutionError> {
+    let targeted = r#"content="ESI/1.0";edge"#;
+    let request = |capability: &str| {
+        Request::get("http://www.example.com/").with_header(SURROGATE_CAPABILITY_HEADER, capability)
+    };
+
+    let mut resp = process(
+        Processor::new(
+            Some(request(r#"shield="ESI/1.0", edge="ESI/1.0""#)),
+            required(),
+        ),
+        source(Some(targeted)),
+    )?;
+    assert_eq!(resp.take_body_str(), "<p>a</p>[/one]");
+
+    let mut resp = process(
+        Processor::new(Some(request(r#"shield="ESI/1.0""#)), required()),
+        source(Some(targeted)),
+    )?;
+    assert_eq!(resp.take_body_str(), TEMPLATE);
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+
+fn process(template: &str) -> Result<String, ExecutionError> {
+    process_with_dispatches(template).map(|(output, _)| output)
+}
+
+// Processes `template`, returning the output along with the paths of the dispatched requests.
+fn process_with_dispatches(template: &str) -> Result<(String, Vec<String>), ExecutionError> {
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+    let requests = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&requests);
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            dispatched.borrow_mut().push(req.get_path().to_string());
+            Ok(match req.get_path() {
+                path if path.starts_with("/fail") => Response::from_status(500).into(),
+                path => PendingFragmentContent::CompletedRequest(Response::from_body(format!(
+                    "[{path}]"
+                ))),
+            })
+        }),
+        None,
+    )?;
+
+    Ok((
+        String::from_utf8(writer.into_inner()).unwrap(),
+        requests.take(),
+    ))
+}
+
+#[test]
+fn try_without_except_renders_a_successful_attempt() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<p>before</p><esi:try><esi:attempt><b><esi:include src="/a"/></b></esi:attempt></esi:try><p>after</p>"#,
+    )?;
+
+    assert_eq!(output, "<p>before</p><b>[/a]</b><p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn try_without_except_skips_a_failed_attempt() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<p>before</p><esi:try><esi:attempt><b><esi:include src="/fail"/></b></esi:attempt></esi:try><esi:include src="/b"/><p>after</p>"#,
+    )?;
+
+    assert_eq!(output, "<p>before</p>[/b]<p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn nested_try_without_except_skips_a_failed_attempt() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:try><esi:attempt>outer <esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt></esi:try><esi:include src="/a"/></esi:attempt><esi:except>except</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "outer [/a]");
+
+    Ok(())
+}
+
+// A personalized arm, an anonymous arm and the static except content
+fn tiers(personalized: &str, anonymous: &str) -> String {
+    format!(
+        r#"<esi:try><esi:attempt><esi:include src="{personalized}"/></esi:attempt><esi:attempt><esi:include src="{anonymous}"/></esi:attempt><esi:except>static</esi:except></esi:try>"#
+    )
+}
+
+#[test]
+fn first_arm_that_succeeds_is_rendered() -> Result<(), ExecutionError> {
+    assert_eq!(process(&tiers("/me", "/anon"))?, "[/me]");
+    assert_eq!(process(&tiers("/fail", "/anon"))?, "[/anon]");
+    assert_eq!(process(&tiers("/fail", "/fail"))?, "static");
+
+    Ok(())
+}
+
+#[test]
+fn retry_arm_is_tried_in_order_before_except() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:try><esi:except>static</esi:except><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:retry>retry <esi:include src="/anon"/></esi:retry></esi:try><p>after</p>"#,
+    )?;
+
+    assert_eq!(output, "retry [/anon]<p>after</p>");
+
+    Ok(())
+}
+
+#[test]
+fn try_fails_when_every_arm_fails() {
+    let result = process(
+        r#"<esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:except><esi:include src="/fail"/></esi:except></esi:try>"#,
+    );
+    assert!(matches!(
+        result,
+        Err(ExecutionError::FragmentFailed { url, status: 500, .. }) if url == "http://localhost/fail"
+    ));
+}
+
+// A try nested in each arm of another, with `{outer}` included in the outer attempt and `{inner}`
+// in the nested one
+fn nested(outer: &str, inner: &str) -> String {
+    format!(
+        concat!(
+            r#"<esi:try><esi:attempt><esi:include src="{outer}"/> "#,
+            r#"<esi:try><esi:attempt><esi:include src="{inner}"/></esi:attempt>"#,
+            r#"<esi:except><esi:include src="/inner-except"/></esi:except></esi:try>"#,
+            r#"</esi:attempt><esi:except><esi:include src="/outer-except"/> "#,
+            r#"<esi:try><esi:attempt><esi:include src="/except-attempt"/></esi:attempt>"#,
+            r#"<esi:except><esi:include src="/except-except"/></esi:except></esi:try>"#,
+            r#"</esi:except></esi:try>"#,
+        ),
+        outer = outer,
+        inner = inner,
+    )
+}
+
+#[test]
+fn except_arms_are_not_dispatched_when_attempts_succeed() -> Result<(), ExecutionError> {
+    let (output, dispatched) = process_with_dispatches(&nested("/outer", "/inner"))?;
+
+    assert_eq!(output, "[/outer] [/inner]");
+    assert_eq!(dispatched, ["/outer", "/inner"]);
+
+    Ok(())
+}
+
+#[test]
+fn nested_except_arm_is_used_when_the_inner_attempt_fails() -> Result<(), ExecutionError> {
+    let (output, dispatched) = process_with_dispatches(&nested("/outer", "/fail"))?;
+
+    assert_eq!(output, "[/outer] [/inner-except]");
+    assert_eq!(dispatched, ["/outer", "/fail", "/inner-except"]);
+
+    Ok(())
+}
+
+#[test]
+fn outer_except_arm_dispatches_only_its_attempts() -> Result<(), ExecutionError> {
+    let (output, dispatched) = process_with_dispatches(&nested("/fail", "/inner"))?;
+
+    assert_eq!(output, "[/outer-except] [/except-attempt]");
+    assert_eq!(
+        dispatched,
+        ["/fail", "/inner", "/outer-except", "/except-attempt"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn nested_try_that_fails_falls_back_to_the_outer_except_arm() -> Result<(), ExecutionError> {
+    let output = process(
+        r#"<esi:try><esi:attempt><esi:try><esi:attempt><esi:include src="/fail"/></esi:attempt><esi:except><esi:include src="/fail-too"/></esi:except></esi:try></esi:attempt><esi:except>static</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "static");
+
+    Ok(())
+}
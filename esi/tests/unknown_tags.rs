@@ -0,0 +1,90 @@
+use esi::{Configuration, ExecutionError, ProcessingReport, Processor, Reader, Warning, Writer};
+
+fn process(
+    configuration: Configuration,
+    template: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(None, configuration);
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(Reader::from_str(template), &mut writer, None, None)?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn unknown_tags_are_left_out_keeping_their_content() -> Result<(), ExecutionError> {
+    let template =
+        r#"<p>a</p><esi:inline name="x"><b>kept</b></esi:inline><esi:includ src="/a"/><p>b</p>"#;
+
+    let (output, report) = process(Configuration::default(), template)?;
+
+    assert_eq!(output, "<p>a</p><b>kept</b><p>b</p>");
+    assert_eq!(
+        report.warnings,
+        [
+            Warning::UnknownTag {
+                position: template.find("<esi:inline").unwrap(),
+                tag: "esi:inline".to_string(),
+            },
+            Warning::UnknownTag {
+                position: template.find("<esi:includ ").unwrap(),
+                tag: "esi:includ".to_string(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unknown_tags_in_attempts_are_left_out() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        Configuration::default(),
+        r#"<esi:try><esi:attempt><esi:foo>a</esi:foo></esi:attempt><esi:except>b</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "a");
+
+    Ok(())
+}
+
+#[test]
+fn strict_mode_fails_at_unknown_tags() {
+    let res = process(
+        Configuration::default().with_strict_mode(true),
+        r#"<p>a</p><esi:includ src="/a"/>"#,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::UnknownEsiTag(tag)) if tag == "esi:includ"
+    ));
+}
+
+#[test]
+fn strict_mode_leaves_other_namespaces_alone() -> Result<(), ExecutionError> {
+    let template = r#"<svg:rect/><esix:include src="/a"/><esi:remove><esi:foo/></esi:remove>"#;
+
+    let (output, report) = process(Configuration::default().with_strict_mode(true), template)?;
+
+    assert_eq!(output, r#"<svg:rect/><esix:include src="/a"/>"#);
+    assert!(!report
+        .warnings
+        .iter()
+        .any(|warning| matches!(warning, Warning::UnknownTag { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn nested_remove_blocks_are_removed_in_full() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        Configuration::default(),
+        "<p>a</p><esi:remove><esi:remove>x</esi:remove>y</esi:remove><p>b</p>",
+    )?;
+
+    assert_eq!(output, "<p>a</p><p>b</p>");
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::{Request, Response};
+
+// Returns the path and query of every fragment request made for `template`, in the order they
+// were dispatched. Requests for paths under /fail/ fail.
+fn dispatched(
+    original_url: &str,
+    template: &str,
+    configuration: Configuration,
+) -> Result<Vec<String>, ExecutionError> {
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let dispatched = Rc::clone(&urls);
+
+    let processor = Processor::new(
+        Some(Request::get(original_url).with_header("cookie", "uid=user-2; theme=dark")),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&move |req| {
+            let url = match req.get_query_str() {
+                Some(query) => format!("{}?{}", req.get_path(), query),
+                None => req.get_path().to_string(),
+            };
+            let status = if url.starts_with("/fail/") { 500 } else { 200 };
+            dispatched.borrow_mut().push(url);
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_status(status),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok(urls.take())
+}
+
+#[test]
+fn cookies_are_substituted_in_src() -> Result<(), ExecutionError> {
+    let urls = dispatched(
+        "http://www.example.com/page",
+        r#"<esi:include src="/user/$(HTTP_COOKIE{uid})?theme=$(HTTP_COOKIE{theme})"/>"#,
+        Configuration::default(),
+    )?;
+
+    assert_eq!(urls, ["/user/user-2?theme=dark"]);
+
+    Ok(())
+}
+
+#[test]
+fn query_string_keys_are_substituted_in_src() -> Result<(), ExecutionError> {
+    let urls = dispatched(
+        "http://www.example.com/page?lang=fr&page=2",
+        r#"<esi:include src="/fragment?lang=$(QUERY_STRING{lang})&amp;page=$(QUERY_STRING{page})"/>"#,
+        Configuration::default(),
+    )?;
+
+    assert_eq!(urls, ["/fragment?lang=fr&page=2"]);
+
+    Ok(())
+}
+
+#[test]
+fn defaults_are_used_for_missing_variables() -> Result<(), ExecutionError> {
+    let template = r#"<esi:include src="/list/$(QUERY_STRING{page}|home)"/>"#;
+
+    let without_page = dispatched(
+        "http://www.example.com/page",
+        template,
+        Configuration::default(),
+    )?;
+    let with_page = dispatched(
+        "http://www.example.com/page?page=2",
+        template,
+        Configuration::default(),
+    )?;
+
+    assert_eq!(without_page, ["/list/home"]);
+    assert_eq!(with_page, ["/list/2"]);
+
+    Ok(())
+}
+
+#[test]
+fn variables_are_substituted_in_alt() -> Result<(), ExecutionError> {
+    let urls = dispatched(
+        "http://www.example.com/page",
+        r#"<esi:include src="/fail/$(HTTP_COOKIE{uid})" alt="/alt/$(HTTP_COOKIE{uid})"/>"#,
+        Configuration::default(),
+    )?;
+
+    assert_eq!(urls, ["/fail/user-2", "/alt/user-2"]);
+
+    Ok(())
+}
+
+#[test]
+fn variables_are_left_in_urls_when_turned_off() -> Result<(), ExecutionError> {
+    let urls = dispatched(
+        "http://www.example.com/page",
+        r#"<esi:include src="/user/$(HTTP_COOKIE{uid})"/>"#,
+        Configuration::default().with_vars_in_attributes(false),
+    )?;
+
+    // Braces aren't valid in URLs, so they're percent-encoded.
+    assert_eq!(urls, ["/user/$(HTTP_COOKIE%7Buid%7D)"]);
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Utf8Policy, Writer,
+};
+use fastly::Response;
+
+const LATIN1: &[u8] = include_bytes!("utf8/latin1.html");
+const BROKEN: &[u8] = include_bytes!("utf8/broken.html");
+
+// Serves the fixtures, `/latin1` with its charset declared and `/undeclared` without it.
+fn fragment(path: &str) -> Response {
+    match path {
+        "/latin1" => {
+            Response::from_body(LATIN1).with_header("content-type", "text/html; charset=ISO-8859-1")
+        }
+        "/undeclared" => Response::from_body(LATIN1).with_header("content-type", "text/html"),
+        "/broken" => Response::from_body(BROKEN).with_header("content-type", "text/html"),
+        "/split" => {
+            // A two-byte sequence that straddles the chunks the body is decoded in
+            let mut body = vec![b'a'; 8 * 1024 - 1];
+            body.extend_from_slice("é".as_bytes());
+            Response::from_body(body)
+        }
+        _ => Response::from_body("[alt]"),
+    }
+}
+
+fn process(policy: Utf8Policy, template: &str) -> Result<Vec<u8>, ExecutionError> {
+    let processor = Processor::new(
+        None,
+        Configuration::default().with_validate_fragment_utf8(policy),
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    processor.process_document(
+        Reader::from_str(template),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(fragment(
+                req.get_path(),
+            )))
+        }),
+        None,
+    )?;
+
+    Ok(writer.into_inner())
+}
+
+#[test]
+fn passthrough_copies_bodies_as_they_are() -> Result<(), ExecutionError> {
+    let output = process(
+        Utf8Policy::Passthrough,
+        r#"<esi:include src="/latin1"/><esi:include src="/broken"/>"#,
+    )?;
+
+    assert_eq!(output, [LATIN1, BROKEN].concat());
+
+    Ok(())
+}
+
+#[test]
+fn lossy_transcodes_latin1_and_replaces_invalid_sequences() -> Result<(), ExecutionError> {
+    let output = process(
+        Utf8Policy::Lossy,
+        r#"<esi:include src="/latin1"/><esi:include src="/undeclared"/><esi:include src="/broken"/>"#,
+    )?;
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "<p>Café crème brûlée</p>\n\
+         <p>Caf\u{FFFD} cr\u{FFFD}me br\u{FFFD}l\u{FFFD}e</p>\n\
+         <p>na\u{FFFD}ve \u{FFFD}</p>\n\u{FFFD}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lossy_decodes_sequences_split_across_chunks() -> Result<(), ExecutionError> {
+    let output = process(Utf8Policy::Lossy, r#"<esi:include src="/split"/>"#)?;
+
+    assert_eq!(output.len(), 8 * 1024 + 1);
+    assert!(String::from_utf8(output).unwrap().ends_with("aé"));
+
+    Ok(())
+}
+
+#[test]
+fn reject_fails_invalid_fragments_over_to_alt() -> Result<(), ExecutionError> {
+    let output = process(
+        Utf8Policy::Reject,
+        r#"<esi:include src="/latin1"/><esi:include src="/broken" alt="/alt"/><esi:include src="/undeclared" onerror="continue"/>"#,
+    )?;
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "<p>Café crème brûlée</p>\n[alt]"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_fails_invalid_fragments_in_try_blocks() -> Result<(), ExecutionError> {
+    let output = process(
+        Utf8Policy::Reject,
+        r#"<esi:try><esi:attempt><p>partial</p><esi:include src="/broken"/></esi:attempt><esi:except><esi:include src="/alt"/></esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(String::from_utf8(output).unwrap(), "[alt]");
+
+    let err = process(Utf8Policy::Reject, r#"<esi:include src="/broken"/>"#).unwrap_err();
+    assert!(matches!(
+        err,
+        ExecutionError::FragmentBodyError(url, err)
+            if url == "http://localhost/broken" && err.kind() == std::io::ErrorKind::InvalidData
+    ));
+
+    Ok(())
+}
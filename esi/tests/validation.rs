@@ -0,0 +1,53 @@
+use esi::{Configuration, ExecutionError, PendingFragmentContent, Processor, Reader, Writer};
+use fastly::Response;
+
+#[test]
+fn failed_critical_fragment_writes_nothing() {
+    let input = r#"<p>before</p><esi:include src="/optional" onerror="continue"/><esi:include src="/critical" critical="true" onerror="continue"/><p>after</p>"#;
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let res = processor.process_with_validation(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|_req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_status(500),
+            ))
+        }),
+        None,
+    );
+
+    assert!(matches!(
+        res,
+        Err(ExecutionError::CriticalFragmentFailed(url, _)) if url == "http://localhost/critical"
+    ));
+    assert!(writer.into_inner().is_empty());
+}
+
+#[test]
+fn failed_critical_fragment_uses_alt_before_failing() {
+    let input = r#"<esi:include src="/critical" alt="/fallback" critical="true"/>"#;
+
+    let processor = Processor::new(None, Configuration::default());
+    let mut writer = Writer::new(Vec::new());
+
+    let res = processor.process_with_validation(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            if req.get_path() == "/fallback" {
+                Ok(PendingFragmentContent::NoContent)
+            } else {
+                Ok(PendingFragmentContent::CompletedRequest(
+                    Response::from_status(500),
+                ))
+            }
+        }),
+        None,
+    );
+
+    assert!(res.is_ok());
+    assert!(writer.into_inner().is_empty());
+}
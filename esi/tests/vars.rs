@@ -0,0 +1,114 @@
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::{Request, Response};
+
+fn process_with(
+    configuration: Configuration,
+    input: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let processor = Processor::new(
+        Some(
+            Request::get("http://www.example.com/page?q=%3Cb%3E%26")
+                .with_header("cookie", "uid=user-2"),
+        ),
+        configuration,
+    );
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+fn process(input: &str) -> Result<(String, ProcessingReport), ExecutionError> {
+    process_with(Configuration::default(), input)
+}
+
+#[test]
+fn vars_block_keeps_markup_and_evaluates_variables() -> Result<(), ExecutionError> {
+    let (output, report) = process(
+        r#"<p>a</p><esi:vars><a href="/p?uid=$(HTTP_COOKIE{uid})">$(HTTP_HOST)</a></esi:vars><p>b</p>"#,
+    )?;
+
+    assert_eq!(
+        output,
+        r#"<p>a</p><a href="/p?uid=user-2">www.example.com</a><p>b</p>"#
+    );
+    assert!(report.warnings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn unknown_variables_are_left_as_they_are() -> Result<(), ExecutionError> {
+    let (output, _) = process("<esi:vars>$(UNKNOWN) $(UNKNOWN|'default')</esi:vars>")?;
+
+    assert_eq!(output, "$(UNKNOWN) default");
+
+    Ok(())
+}
+
+#[test]
+fn values_in_vars_block_are_escaped() -> Result<(), ExecutionError> {
+    let (output, _) = process("<esi:vars><p>$(QUERY_STRING{q})</p></esi:vars>")?;
+
+    assert_eq!(output, "<p>&lt;b&gt;&amp;</p>");
+
+    Ok(())
+}
+
+#[test]
+fn values_in_vars_block_are_escaped_for_json() -> Result<(), ExecutionError> {
+    let (output, _) = process_with(
+        Configuration::default().with_escaped(false),
+        r#"<esi:vars>{"q": "$(QUERY_STRING{q})", "uid": "$(HTTP_COOKIE{uid})"}</esi:vars>"#,
+    )?;
+
+    assert_eq!(output, r#"{"q": "<b>&", "uid": "user-2"}"#);
+
+    Ok(())
+}
+
+#[test]
+fn self_closing_vars_writes_only_the_value() -> Result<(), ExecutionError> {
+    let (output, _) = process(r#"<p><esi:vars name="$(HTTP_HOST)"/></p>"#)?;
+
+    assert_eq!(output, "<p>www.example.com</p>");
+
+    Ok(())
+}
+
+#[test]
+fn vars_block_is_buffered_inside_attempt() -> Result<(), ExecutionError> {
+    let (output, _) = process(
+        r#"<esi:try><esi:attempt><esi:include src="/a"/><esi:vars><b>$(HTTP_COOKIE{uid})</b></esi:vars></esi:attempt><esi:except>x</esi:except></esi:try>"#,
+    )?;
+
+    assert_eq!(output, "[/a]<b>user-2</b>");
+
+    Ok(())
+}
+
+#[test]
+fn esi_tags_in_vars_block_are_left_out() -> Result<(), ExecutionError> {
+    let (output, report) = process(r#"<esi:vars><esi:include src="/a"/>$(HTTP_HOST)</esi:vars>"#)?;
+
+    assert_eq!(output, "www.example.com");
+    assert!(matches!(
+        report.warnings.as_slice(),
+        [Warning::IgnoredTag { tag, .. }] if tag == "esi:include"
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use esi::{
+    Configuration, ExecutionError, PendingFragmentContent, ProcessingReport, Processor, Reader,
+    Warning, Writer,
+};
+use fastly::Response;
+
+fn process(
+    processor: Processor,
+    input: &str,
+) -> Result<(String, ProcessingReport), ExecutionError> {
+    let mut writer = Writer::new(Vec::new());
+
+    let report = processor.process_document(
+        Reader::from_str(input),
+        &mut writer,
+        Some(&|req| {
+            Ok(PendingFragmentContent::CompletedRequest(
+                Response::from_body(format!("[{}]", req.get_path())),
+            ))
+        }),
+        None,
+    )?;
+
+    Ok((String::from_utf8(writer.into_inner()).unwrap(), report))
+}
+
+#[test]
+fn sloppy_template_raises_warnings() -> Result<(), ExecutionError> {
+    let input = r#"<esi:include src="/a" onerror="contine"/><esi:foo/><div esi:attr-title="$(BROKEN"/><esi:include src="/b c"/>"#;
+
+    let (output, report) = process(Processor::new(None, Configuration::default()), input)?;
+
+    assert_eq!(output, r#"[/a]<div title="$(BROKEN"/>[/b%20c]"#);
+    assert_eq!(
+        report.warnings,
+        vec![
+            Warning::InvalidAttribute {
+                position: 0,
+                tag: "esi:include".to_string(),
+                attribute: "onerror".to_string(),
+                value: "contine".to_string(),
+            },
+            Warning::UnknownTag {
+                position: input.find("<esi:foo").unwrap(),
+                tag: "esi:foo".to_string(),
+            },
+            Warning::ExpressionError {
+                position: input.find("<div").unwrap(),
+                expression: "$(BROKEN".to_string(),
+            },
+            Warning::UrlNormalized {
+                position: input.find(r#"<esi:include src="/b c""#).unwrap(),
+                url: "/b c".to_string(),
+                normalized: "/b%20c".to_string(),
+            },
+        ]
+    );
+    assert_eq!(report.warnings_dropped, 0);
+
+    Ok(())
+}
+
+#[test]
+fn warnings_inside_try_are_reported_at_the_block() -> Result<(), ExecutionError> {
+    let input = r#"<p>intro</p><esi:try><esi:attempt><esi:response status="404"/><esi:include src="/x y"/></esi:attempt></esi:try>"#;
+
+    let (output, report) = process(Processor::new(None, Configuration::default()), input)?;
+
+    let position = input.find("<esi:try>").unwrap();
+    assert_eq!(output, "<p>intro</p>[/x%20y]");
+    assert_eq!(
+        report.warnings,
+        vec![
+            Warning::IgnoredTag {
+                position,
+                tag: "esi:response".to_string(),
+                reason: "it's inside an esi:try block".to_string(),
+            },
+            Warning::UrlNormalized {
+                position,
+                url: "/x y".to_string(),
+                normalized: "/x%20y".to_string(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn warnings_over_the_limit_are_counted_and_still_passed_to_the_callback(
+) -> Result<(), ExecutionError> {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let processor =
+        Processor::new(None, Configuration::default().with_max_warnings(1)).on_warning({
+            let seen = seen.clone();
+            move |warning| seen.borrow_mut().push(warning.position())
+        });
+    let input = "<esi:one/><esi:two/><esi:three/>";
+
+    let (_, report) = process(processor, input)?;
+
+    assert_eq!(
+        report.warnings,
+        vec![Warning::UnknownTag {
+            position: 0,
+            tag: "esi:one".to_string(),
+        }]
+    );
+    assert_eq!(report.warnings_dropped, 2);
+    assert_eq!(*seen.borrow(), vec![0, 10, 20]);
+
+    Ok(())
+}
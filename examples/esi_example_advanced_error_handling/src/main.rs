@@ -27,6 +27,7 @@ fn main() {
         .is_some_and(|c| c.subtype() == mime::HTML)
     {
         let processor = esi::Processor::new(Some(req), esi::Configuration::default());
+        let outcome = processor.outcome();
 
         // Create a response to send the headers to the client
         let resp = Response::from_status(StatusCode::OK).with_content_type(mime::TEXT_HTML);
@@ -37,30 +38,43 @@ fn main() {
         // Set up an XML writer to write directly to the client output stream.
         let mut xml_writer = Writer::new(output_writer);
 
-        match processor.process_document(
+        match processor.process_document_with_context(
             Reader::from_reader(beresp.take_body()),
             &mut xml_writer,
             Some(&|req| {
                 info!("Sending request {} {}", req.get_method(), req.get_path());
-                Ok(Some(req.with_ttl(120).send_async("mock-s3")?))
+                Ok(req.with_ttl(120).send_async("mock-s3")?.into())
             }),
-            Some(&|req, resp| {
+            Some(&|context, req, resp| {
                 info!(
-                    "Received response for {} {}",
+                    "Received response for include #{} ({}{}, in {:?}): {} {}",
+                    context.index,
+                    context.src,
+                    if context.is_alt { ", alt" } else { "" },
+                    context.arm,
                     req.get_method(),
                     req.get_path()
                 );
                 Ok(resp)
             }),
         ) {
-            Ok(()) => {
+            Ok(_report) => {
                 xml_writer.into_inner().finish().unwrap();
             }
             Err(err) => {
-                error!("error processing ESI document: {}", err);
+                error!(
+                    "error processing ESI document after {} bytes: {}",
+                    outcome.bytes_written(),
+                    err
+                );
+                // The output ends at a clean boundary, so the error message can follow it,
+                // unless the client is gone.
+                if !outcome.recovery_write_allowed() {
+                    return;
+                }
                 let _ = xml_writer
                     .get_mut()
-                    .write(include_bytes!("error.html.fragment"));
+                    .write_all(include_bytes!("error.html.fragment"));
                 xml_writer.into_inner().finish().unwrap_or_else(|_| {
                     error!("error flushing error response to client");
                 });
@@ -33,16 +33,19 @@ fn handle_request(req: Request) -> Result<(), Error> {
     {
         let processor = esi::Processor::new(Some(req), esi::Configuration::default());
 
-        processor.process_response(
+        processor.process_response_with_context(
             &mut beresp,
             None,
             Some(&|req| {
                 info!("Sending request {} {}", req.get_method(), req.get_path());
-                Ok(Some(req.with_ttl(120).send_async("mock-s3")?))
+                Ok(req.with_ttl(120).send_async("mock-s3")?.into())
             }),
-            Some(&|req, mut resp| {
+            Some(&|context, req, mut resp| {
                 info!(
-                    "Received response for {} {}",
+                    "Received response for include #{} ({}{}): {} {}",
+                    context.index,
+                    context.src,
+                    if context.is_alt { ", alt" } else { "" },
                     req.get_method(),
                     req.get_path()
                 );